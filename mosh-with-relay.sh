@@ -37,7 +37,7 @@ mosh_client_binary="mosh-client"
 relay_server_udprelay_binary="udprelay-rust"
 relay_server_udprelay_binary="./udprelay-rust"
 RELAY_PORT=60017
-RELAY_PSK=uNYDA5QRcvYgp2gfS5v5
+RELAY_PSK="$UDPRELAY_PSK"
 
 # shellcheck disable=SC2116,SC2028
 EOL=$(echo '\00\07\01\00')
@@ -229,6 +229,11 @@ verbose_echo "Getting hostname from ssh config"
 RELAY_SERVER_HOSTNAME="$(retrieve_hostname_from_ssh_config $RELAY_SERVER_SSH_NAME)"
 
 
+if [ -n "$RELAY_SERVER_SSH_NAME" ] && [ -z "$RELAY_PSK" ]; then
+    error_echo "No relay PSK given; pass --relay-server-psk or set UDPRELAY_PSK"
+    exit 1
+fi
+
 if [ -z "$RELAY_SERVER_SSH_NAME" ]; then
     # directly use mosh to connects
     verbose_echo "connecting directly to target server without udp relay"
@@ -263,9 +268,9 @@ verbose_echo "Got $RELAY_SERVER_IP"
 verbose_echo "Testing if udp daemon is running on relay server $RELAY_SERVER_HOSTNAME"
 if [ "$(printf $OPS_PONG)" != "$(printf $OPS_PING | socat -t 0.6 - UDP4:$RELAY_SERVER_IP:$RELAY_PORT 2>/dev/null)" ]; then
     verbose_echo "Nope. Starting udp daemon on relay server $RELAY_SERVER_HOSTNAME"
-    # server is not up
+    # server is not up; this script still speaks the legacy handshake
     ssh "$RELAY_SERVER_SSH_NAME" 'bash -s'<<EOF
-    "$relay_server_udprelay_binary" "$RELAY_PORT" -d
+    UDPRELAY_PSK="$RELAY_PSK" "$relay_server_udprelay_binary" "$RELAY_PORT" -d --legacy-handshake
 EOF
 else
     verbose_echo "Replay server is up."
@@ -0,0 +1,171 @@
+//! Accounting log: one JSON object per line for every session lifecycle event,
+//! for billing and auditing.
+//!
+//! | `event` | Written when |
+//! |---|---|
+//! | `paired` | two peers are paired |
+//! | `resumed` | a peer resumed its session from a new address |
+//! | `closed` | a pair is torn down; `reason` is `inactivity`, `disconnect`, `kick`, `shutdown` or `unreachable` |
+//! | `joined` | a peer joins a group (in group mode) |
+//! | `left` | a peer leaves a group, for the same reasons as `closed` |
+//!
+//! Every event carries its `time` (unix seconds), the session `secret` and the
+//! name of the `key` the peers authenticated with.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_json::{json, Value};
+use tracing::warn;
+
+use crate::group::Member;
+use crate::peer::RecipientData;
+
+/// Why a session, or a group member, went away.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum CloseReason {
+    Inactivity,
+    Disconnect,
+    /// Only through the control socket, which is Unix-only.
+    #[cfg_attr(not(unix), allow(dead_code))]
+    Kick,
+    Shutdown,
+    /// The peer was sent to another instance of the cluster.
+    Redirect,
+    /// Sends to the peer kept failing, see
+    /// [`Config::max_send_failures`](crate::Config::max_send_failures).
+    Unreachable,
+    /// The peer sent a new handshake from its address, e.g. after a restart.
+    Restart,
+}
+
+impl CloseReason {
+    fn as_str(self) -> &'static str {
+        match self {
+            CloseReason::Inactivity => "inactivity",
+            CloseReason::Disconnect => "disconnect",
+            CloseReason::Kick => "kick",
+            CloseReason::Shutdown => "shutdown",
+            CloseReason::Redirect => "redirect",
+            CloseReason::Unreachable => "unreachable",
+            CloseReason::Restart => "restart",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct AccountingLog {
+    file: File,
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+fn peer(peer: &RecipientData) -> Value {
+    json!({
+        "addr": peer.recipient.addr.to_string(),
+        "packets": peer.packets,
+        "bytes": peer.bytes,
+    })
+}
+
+fn event(event: &str, secret: &[u8], key: &str, mut fields: Value) -> Value {
+    fields["event"] = event.into();
+    fields["time"] = unix_time().into();
+    fields["secret"] = String::from_utf8_lossy(secret).into();
+    fields["key"] = key.into();
+    fields
+}
+
+/// The `paired` event of `first` and `second`, also passed to the
+/// [hooks](crate::hooks).
+pub(crate) fn paired(first: &RecipientData, second: &RecipientData) -> Value {
+    let peers = [&first.recipient.addr, &second.recipient.addr].map(|addr| addr.to_string());
+    event(
+        "paired",
+        &first.secret,
+        &first.key,
+        json!({ "peers": peers }),
+    )
+}
+
+/// The `closed` event of the pair of `a` and `b`, with the traffic each of
+/// them sent, also passed to the [hooks](crate::hooks).
+pub(crate) fn closed(a: &RecipientData, b: &RecipientData, reason: CloseReason) -> Value {
+    event(
+        "closed",
+        &a.secret,
+        &a.key,
+        json!({
+            "reason": reason.as_str(),
+            "duration_secs": a.started.elapsed().as_secs_f64(),
+            "peers": [peer(a), peer(b)],
+        }),
+    )
+}
+
+impl AccountingLog {
+    /// Opens `path` for appending, creating it if needed.
+    pub(crate) fn open(path: &Path) -> io::Result<AccountingLog> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(AccountingLog { file })
+    }
+
+    /// Appends `event`, built by [`paired`] or [`closed`], as a line.
+    pub(crate) fn record(&mut self, event: &Value) {
+        let mut line = event.to_string();
+        line.push('\n');
+        if let Err(e) = self.file.write_all(line.as_bytes()) {
+            warn!("Cannot write to the accounting log: {e}");
+        }
+    }
+
+    pub(crate) fn resumed(&mut self, peer: &RecipientData, old_addr: &SocketAddr) {
+        self.record(&event(
+            "resumed",
+            &peer.secret,
+            &peer.key,
+            json!({
+                "old_addr": old_addr.to_string(),
+                "addr": peer.recipient.addr.to_string(),
+            }),
+        ));
+    }
+
+    pub(crate) fn joined(&mut self, secret: &[u8], key: &str, addr: &SocketAddr) {
+        self.record(&event(
+            "joined",
+            secret,
+            key,
+            json!({ "addr": addr.to_string() }),
+        ));
+    }
+
+    pub(crate) fn left(
+        &mut self,
+        secret: &[u8],
+        key: &str,
+        addr: &SocketAddr,
+        member: &Member,
+        reason: CloseReason,
+    ) {
+        self.record(&event(
+            "left",
+            secret,
+            key,
+            json!({
+                "reason": reason.as_str(),
+                "duration_secs": member.joined.elapsed().as_secs_f64(),
+                "addr": addr.to_string(),
+                "packets": member.packets,
+                "bytes": member.bytes,
+            }),
+        ));
+    }
+}
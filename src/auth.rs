@@ -0,0 +1,331 @@
+//! Challenge-response authentication of the v2 handshake.
+//!
+//! Instead of sending the pre-shared key in cleartext, a peer asks for a
+//! challenge and proves knowledge of the key with
+//! `HMAC-SHA256(psk, nonce || secret)`.
+//!
+//! The relay keeps no state per challenge: a nonce is the time it was issued
+//! and a random salt, followed by a MAC over both and the peer's address, keyed
+//! with a random per-process key. A nonce is thus only valid for the address it
+//! was issued to and for [`CHALLENGE_LIFETIME`]. Within that lifetime, a
+//! [`ReplayWindow`] remembers the nonces already answered so that a captured
+//! response cannot be replayed.
+//!
+//! With pairing codes, the session secret itself must be a short numeric code
+//! derived from the pre-shared key and the current time window, as TOTP
+//! (RFC 6238) over HMAC-SHA256: [`pairing_code`].
+
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, KeyInit, Mac};
+use sha2::{Digest, Sha256};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Length of a challenge nonce: an 8-byte timestamp, an 8-byte salt and a 16-byte tag.
+pub const NONCE_LEN: usize = 32;
+/// Length of the MAC proving knowledge of the pre-shared key.
+pub const MAC_LEN: usize = 32;
+/// Length of the SHA-256 digest of a pre-shared key.
+pub const PSK_HASH_LEN: usize = 32;
+/// How long an issued challenge can be answered.
+pub const CHALLENGE_LIFETIME: Duration = Duration::from_secs(30);
+
+const TAG_START: usize = 16;
+
+/// Length of the time window of a pairing code.
+pub const CODE_STEP: Duration = Duration::from_secs(30);
+/// Number of digits of a pairing code.
+pub const CODE_DIGITS: u32 = 6;
+/// Time windows before and after the current one whose codes are still
+/// accepted, for clock skew between the peers and the relay.
+pub const CODE_SKEW_STEPS: u64 = 1;
+
+/// Shortest pre-shared key accepted without `insecure_open`.
+pub const MIN_PSK_LEN: usize = 16;
+/// Lowest estimated entropy, in bits, of a pre-shared key accepted without `insecure_open`.
+pub const MIN_PSK_ENTROPY_BITS: f64 = 48.0;
+
+/// Rough entropy estimate of `psk`: its length times the Shannon entropy of
+/// its characters. Enough to catch short or repetitive keys, not to grade
+/// passphrases.
+pub fn estimate_entropy_bits(psk: &str) -> f64 {
+    let mut counts = HashMap::new();
+    for c in psk.chars() {
+        *counts.entry(c).or_insert(0usize) += 1;
+    }
+    let len = psk.chars().count() as f64;
+    let per_char: f64 = counts
+        .values()
+        .map(|&count| {
+            let p = count as f64 / len;
+            p * (1.0 / p).log2()
+        })
+        .sum();
+    per_char * len
+}
+
+/// Explains why `psk` is too weak to be used, if it is.
+pub fn check_psk_strength(psk: &str) -> Result<(), String> {
+    if psk.chars().count() < MIN_PSK_LEN {
+        return Err(format!(
+            "pre-shared key is shorter than {MIN_PSK_LEN} characters"
+        ));
+    }
+    let bits = estimate_entropy_bits(psk);
+    if bits < MIN_PSK_ENTROPY_BITS {
+        return Err(format!(
+            "pre-shared key is too predictable ({bits:.0} bits estimated, {MIN_PSK_ENTROPY_BITS} required)"
+        ));
+    }
+    Ok(())
+}
+
+/// SHA-256 digest of `psk`, which the relay may hold instead of the key.
+/// Keys passing [`check_psk_strength`] are too random for a slow password hash
+/// to add anything.
+pub fn hash_psk(psk: &[u8]) -> [u8; PSK_HASH_LEN] {
+    Sha256::digest(psk).into()
+}
+
+/// Parses a digest given as 64 hexadecimal digits, optionally behind `sha256:`.
+pub fn parse_psk_hash(text: &str) -> Option<[u8; PSK_HASH_LEN]> {
+    let hex = text.strip_prefix("sha256:").unwrap_or(text);
+    if hex.len() != 2 * PSK_HASH_LEN || !hex.is_ascii() {
+        return None;
+    }
+    let mut hash = [0u8; PSK_HASH_LEN];
+    for (byte, pair) in hash.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        *byte = u8::from_str_radix(str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+    Some(hash)
+}
+
+/// Computes `HMAC-SHA256(psk, nonce || secret)`, the answer to a challenge.
+pub fn sign(psk: &[u8], nonce: &[u8], secret: &[u8]) -> [u8; MAC_LEN] {
+    let mut mac = HmacSha256::new_from_slice(psk).expect("HMAC can take key of any size");
+    mac.update(nonce);
+    mac.update(secret);
+    mac.finalize().into_bytes().into()
+}
+
+/// Checks the answer to a challenge in constant time.
+pub(crate) fn verify(psk: &[u8], nonce: &[u8], secret: &[u8], answer: &[u8]) -> bool {
+    let mut mac = HmacSha256::new_from_slice(psk).expect("HMAC can take key of any size");
+    mac.update(nonce);
+    mac.update(secret);
+    mac.verify_slice(answer).is_ok()
+}
+
+/// Compares two secrets in time independent of where they differ.
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Pairing code of the time `window` (unix time divided by [`CODE_STEP`]),
+/// derived from `psk`.
+pub fn pairing_code(psk: &[u8], window: u64) -> String {
+    let mut mac = HmacSha256::new_from_slice(psk).expect("HMAC can take key of any size");
+    mac.update(&window.to_be_bytes());
+    let digest = mac.finalize().into_bytes();
+    // dynamic truncation of RFC 4226
+    let offset = usize::from(digest[digest.len() - 1] & 0x0f);
+    let value = u32::from_be_bytes(
+        digest[offset..offset + 4]
+            .try_into()
+            .expect("digest holds 4 bytes past the offset"),
+    ) & 0x7fff_ffff;
+    format!(
+        "{:0width$}",
+        value % 10u32.pow(CODE_DIGITS),
+        width = CODE_DIGITS as usize
+    )
+}
+
+/// Pairing code of the current time window.
+pub fn current_pairing_code(psk: &[u8]) -> String {
+    pairing_code(psk, unix_time() / CODE_STEP.as_secs())
+}
+
+/// Whether `code` is the pairing code of the current time window, or of one
+/// within [`CODE_SKEW_STEPS`] of it, compared in constant time.
+pub(crate) fn is_valid_pairing_code(psk: &[u8], code: &[u8]) -> bool {
+    let window = unix_time() / CODE_STEP.as_secs();
+    (window.saturating_sub(CODE_SKEW_STEPS)..=window + CODE_SKEW_STEPS)
+        .fold(false, |valid, window| {
+            valid | constant_time_eq(pairing_code(psk, window).as_bytes(), code)
+        })
+}
+
+pub fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+/// Whether a nonce issued at `issued` (unix seconds) is past [`CHALLENGE_LIFETIME`].
+pub(crate) fn is_stale(issued: u64) -> bool {
+    unix_time().saturating_sub(issued) > CHALLENGE_LIFETIME.as_secs()
+}
+
+fn issued_at(nonce: &[u8; NONCE_LEN]) -> u64 {
+    u64::from_be_bytes(nonce[..8].try_into().expect("nonce holds a timestamp"))
+}
+
+/// Issues and checks stateless challenge nonces.
+pub(crate) struct Challenger {
+    key: [u8; 32],
+}
+
+impl fmt::Debug for Challenger {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Challenger").finish_non_exhaustive()
+    }
+}
+
+impl Challenger {
+    pub(crate) fn new() -> Challenger {
+        Challenger {
+            key: rand::random(),
+        }
+    }
+
+    fn tag(&self, header: &[u8], addr: &SocketAddr) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.key).expect("HMAC can take key of any size");
+        mac.update(header);
+        match addr.ip() {
+            IpAddr::V4(ip) => mac.update(&ip.octets()),
+            IpAddr::V6(ip) => mac.update(&ip.octets()),
+        }
+        mac.update(&addr.port().to_be_bytes());
+        mac
+    }
+
+    pub(crate) fn issue(&self, addr: &SocketAddr) -> [u8; NONCE_LEN] {
+        self.issue_at(addr, unix_time())
+    }
+
+    /// Issues a nonce to `addr` as if at `issued` (unix seconds).
+    fn issue_at(&self, addr: &SocketAddr, issued: u64) -> [u8; NONCE_LEN] {
+        let mut nonce = [0u8; NONCE_LEN];
+        nonce[..8].copy_from_slice(&issued.to_be_bytes());
+        rand::fill(&mut nonce[8..TAG_START]);
+        let tag = self.tag(&nonce[..TAG_START], addr).finalize().into_bytes();
+        nonce[TAG_START..].copy_from_slice(&tag[..NONCE_LEN - TAG_START]);
+        nonce
+    }
+
+    /// Whether `nonce` was issued by this challenger to `addr` and is still fresh.
+    pub(crate) fn is_valid(&self, nonce: &[u8], addr: &SocketAddr) -> bool {
+        let Ok(nonce) = <&[u8; NONCE_LEN]>::try_from(nonce) else {
+            return false;
+        };
+        if is_stale(issued_at(nonce)) {
+            return false;
+        }
+        self.tag(&nonce[..TAG_START], addr)
+            .verify_truncated_left(&nonce[TAG_START..])
+            .is_ok()
+    }
+}
+
+/// Nonces already answered, remembered until they would be rejected as stale anyway.
+#[derive(Debug, Default)]
+pub(crate) struct ReplayWindow {
+    seen: HashMap<[u8; NONCE_LEN], u64>,
+}
+
+impl ReplayWindow {
+    /// Records a valid nonce, returning `false` if it was answered before.
+    pub(crate) fn insert(&mut self, nonce: &[u8]) -> bool {
+        let nonce: [u8; NONCE_LEN] = nonce.try_into().expect("nonce validated by Challenger");
+        self.seen.insert(nonce, issued_at(&nonce)).is_none()
+    }
+
+    /// Whether the valid `nonce` was answered before.
+    pub(crate) fn contains(&self, nonce: &[u8]) -> bool {
+        let nonce: [u8; NONCE_LEN] = nonce.try_into().expect("nonce validated by Challenger");
+        self.seen.contains_key(&nonce)
+    }
+
+    /// Forgets the nonces past [`CHALLENGE_LIFETIME`].
+    pub(crate) fn prune(&mut self) {
+        self.seen.retain(|_, issued| !is_stale(*issued));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(text: &str) -> SocketAddr {
+        text.parse().expect("valid socket address")
+    }
+
+    #[test]
+    fn challenge_is_bound_to_its_address() {
+        let challenger = Challenger::new();
+        let nonce = challenger.issue(&addr("192.0.2.1:4000"));
+        assert!(challenger.is_valid(&nonce, &addr("192.0.2.1:4000")));
+        assert!(!challenger.is_valid(&nonce, &addr("192.0.2.1:4001")));
+        assert!(!challenger.is_valid(&nonce, &addr("192.0.2.2:4000")));
+        assert!(!challenger.is_valid(&nonce, &addr("[2001:db8::1]:4000")));
+        assert!(!Challenger::new().is_valid(&nonce, &addr("192.0.2.1:4000")));
+    }
+
+    #[test]
+    fn tampered_challenge_is_rejected() {
+        let challenger = Challenger::new();
+        let from = addr("192.0.2.1:4000");
+        let nonce = challenger.issue(&from);
+        for index in [0, 8, TAG_START, NONCE_LEN - 1] {
+            let mut tampered = nonce;
+            tampered[index] ^= 1;
+            assert!(!challenger.is_valid(&tampered, &from));
+        }
+        assert!(!challenger.is_valid(&nonce[..NONCE_LEN - 1], &from));
+        assert!(!challenger.is_valid(&[nonce.as_slice(), &[0]].concat(), &from));
+    }
+
+    #[test]
+    fn challenge_expires() {
+        let challenger = Challenger::new();
+        let from = addr("192.0.2.1:4000");
+        let lifetime = CHALLENGE_LIFETIME.as_secs();
+        let fresh = challenger.issue_at(&from, unix_time() - lifetime + 1);
+        assert!(challenger.is_valid(&fresh, &from));
+        let stale = challenger.issue_at(&from, unix_time() - lifetime - 1);
+        assert!(!challenger.is_valid(&stale, &from));
+    }
+
+    #[test]
+    fn replayed_nonce_is_rejected() {
+        let challenger = Challenger::new();
+        let from = addr("192.0.2.1:4000");
+        let (first, second) = (challenger.issue(&from), challenger.issue(&from));
+        let mut window = ReplayWindow::default();
+        assert!(!window.contains(&first));
+        assert!(window.insert(&first));
+        assert!(window.contains(&first));
+        assert!(!window.insert(&first));
+        assert!(!window.contains(&second));
+        assert!(window.insert(&second));
+    }
+
+    #[test]
+    fn replay_window_forgets_stale_nonces() {
+        let challenger = Challenger::new();
+        let from = addr("192.0.2.1:4000");
+        let fresh = challenger.issue(&from);
+        let stale = challenger.issue_at(&from, unix_time() - CHALLENGE_LIFETIME.as_secs() - 1);
+        let mut window = ReplayWindow::default();
+        window.insert(&fresh);
+        window.insert(&stale);
+        window.prune();
+        assert!(window.contains(&fresh));
+        assert!(!window.contains(&stale));
+    }
+}
@@ -0,0 +1,311 @@
+//! Batched datagram I/O for the relay loops.
+//!
+//! On Linux, each loop iteration receives up to [`BATCH_SIZE`] datagrams with a
+//! single `recvmmsg` call, and the datagrams relayed meanwhile are queued in an
+//! [`Outbox`] and sent with `sendmmsg`. Other platforms fall back to one system
+//! call per datagram.
+//!
+//! With the `io-uring` feature, `crate::uring` serves the relay sockets on
+//! io_uring instead, taking the datagrams queued in the [`Outbox`].
+
+use std::io;
+use std::net::SocketAddr;
+use std::ops::Range;
+use std::sync::Arc;
+
+use tokio::net::UdpSocket;
+#[cfg(not(target_os = "linux"))]
+use tracing::debug;
+
+use crate::peer::try_send;
+
+/// Most datagrams received or sent per system call.
+pub(crate) const BATCH_SIZE: usize = if cfg!(target_os = "linux") { 32 } else { 1 };
+
+/// Buffers receiving a batch of datagrams.
+#[derive(Debug)]
+pub(crate) struct RecvBatch {
+    bufs: Vec<Vec<u8>>,
+    /// Length and sender of each datagram of the last batch.
+    received: Vec<(usize, SocketAddr)>,
+}
+
+impl RecvBatch {
+    /// Receives datagrams of up to `size` bytes.
+    pub(crate) fn new(size: usize) -> RecvBatch {
+        RecvBatch {
+            bufs: vec![vec![0u8; size]; BATCH_SIZE],
+            received: Vec::with_capacity(BATCH_SIZE),
+        }
+    }
+
+    /// Waits for datagrams on `socket`, then receives as many as are queued, up
+    /// to [`BATCH_SIZE`]. Datagrams longer than the buffers are dropped.
+    #[cfg(target_os = "linux")]
+    pub(crate) async fn recv(&mut self, socket: &UdpSocket) -> io::Result<()> {
+        use std::os::fd::AsRawFd;
+        use tokio::io::Interest;
+
+        loop {
+            socket.readable().await?;
+            match socket.try_io(Interest::READABLE, || {
+                sys::recvmmsg(socket.as_raw_fd(), &mut self.bufs, &mut self.received)
+            }) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                received => return received,
+            }
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub(crate) async fn recv(&mut self, socket: &UdpSocket) -> io::Result<()> {
+        self.received.clear();
+        let (n, from) = socket.recv_from(&mut self.bufs[0]).await?;
+        // the datagram was truncated, as far as we can tell
+        if n == self.bufs[0].len() {
+            debug!("Dropping datagram from {from} longer than the receive buffer");
+            return Ok(());
+        }
+        self.received.push((n, from));
+        Ok(())
+    }
+
+    /// Datagrams of the last batch, with their sender.
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (&[u8], SocketAddr)> {
+        self.received
+            .iter()
+            .zip(&self.bufs)
+            .map(|(&(n, from), buf)| (&buf[..n], from))
+    }
+}
+
+/// Datagram queued in an [`Outbox`]: the socket to send it on, its destination
+/// and its bytes among those of the outbox.
+pub(crate) type Queued = (Arc<UdpSocket>, SocketAddr, Range<usize>);
+
+/// Datagrams queued while processing a batch, sent by [`Outbox::flush`].
+#[derive(Debug, Default)]
+pub(crate) struct Outbox {
+    data: Vec<u8>,
+    messages: Vec<Queued>,
+}
+
+impl Outbox {
+    pub(crate) fn push(&mut self, socket: &Arc<UdpSocket>, to: SocketAddr, message: &[u8]) {
+        let start = self.data.len();
+        self.data.extend_from_slice(message);
+        self.messages
+            .push((socket.clone(), to, start..self.data.len()));
+    }
+
+    /// Sends every queued datagram and returns the destinations of those that
+    /// could not be sent; like [`try_send`], datagrams that do not fit in the
+    /// send buffer are dropped without being reported.
+    pub(crate) fn flush(&mut self) -> Vec<SocketAddr> {
+        let mut failed = Vec::new();
+        #[cfg(target_os = "linux")]
+        for run in self
+            .messages
+            .chunk_by(|(a, _, _), (b, _, _)| Arc::ptr_eq(a, b))
+        {
+            for batch in run.chunks(BATCH_SIZE) {
+                sys::sendmmsg(&batch[0].0, &self.data, batch, &mut failed);
+            }
+        }
+        #[cfg(not(target_os = "linux"))]
+        for (socket, to, range) in &self.messages {
+            if let Err(e) = try_send(socket, &self.data[range.clone()], to) {
+                debug!("Cannot send datagram to {to}: {e}");
+                failed.push(*to);
+            }
+        }
+        self.messages.clear();
+        self.data.clear();
+        failed
+    }
+
+    /// Takes the queued datagrams, for sending them another way: the bytes of
+    /// all of them, and the socket, destination and bytes of each.
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    pub(crate) fn take(&mut self) -> (Vec<u8>, Vec<Queued>) {
+        (
+            std::mem::take(&mut self.data),
+            std::mem::take(&mut self.messages),
+        )
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub(crate) mod sys {
+    use std::io;
+    use std::mem;
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6};
+    use std::ops::Range;
+    use std::os::fd::{AsRawFd, RawFd};
+    use std::ptr;
+    use std::sync::Arc;
+
+    use socket2::SockAddr;
+    use tokio::net::UdpSocket;
+    use tracing::debug;
+
+    use super::try_send;
+    use crate::capture;
+
+    pub(crate) fn to_socket_addr(storage: &libc::sockaddr_storage) -> Option<SocketAddr> {
+        match libc::c_int::from(storage.ss_family) {
+            libc::AF_INET => {
+                // SAFETY: the kernel filled in a sockaddr_in for this family
+                let addr = unsafe { &*ptr::from_ref(storage).cast::<libc::sockaddr_in>() };
+                Some(SocketAddr::from((
+                    Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)),
+                    u16::from_be(addr.sin_port),
+                )))
+            }
+            libc::AF_INET6 => {
+                // SAFETY: the kernel filled in a sockaddr_in6 for this family
+                let addr = unsafe { &*ptr::from_ref(storage).cast::<libc::sockaddr_in6>() };
+                Some(
+                    SocketAddrV6::new(
+                        Ipv6Addr::from(addr.sin6_addr.s6_addr),
+                        u16::from_be(addr.sin6_port),
+                        addr.sin6_flowinfo,
+                        addr.sin6_scope_id,
+                    )
+                    .into(),
+                )
+            }
+            _ => None,
+        }
+    }
+
+    fn message_header(
+        name: *mut libc::c_void,
+        name_len: libc::socklen_t,
+        iov: &mut libc::iovec,
+    ) -> libc::mmsghdr {
+        // SAFETY: msghdr is plain data, for which all zeroes is a valid value
+        let mut header: libc::msghdr = unsafe { mem::zeroed() };
+        header.msg_name = name;
+        header.msg_namelen = name_len;
+        header.msg_iov = iov;
+        header.msg_iovlen = 1;
+        libc::mmsghdr {
+            msg_hdr: header,
+            msg_len: 0,
+        }
+    }
+
+    /// Receives up to one datagram per buffer without blocking.
+    pub(super) fn recvmmsg(
+        fd: RawFd,
+        bufs: &mut [Vec<u8>],
+        received: &mut Vec<(usize, SocketAddr)>,
+    ) -> io::Result<()> {
+        received.clear();
+        // SAFETY: sockaddr_storage is plain data, for which all zeroes is a valid value
+        let mut addrs: Vec<libc::sockaddr_storage> = vec![unsafe { mem::zeroed() }; bufs.len()];
+        let mut iovecs: Vec<libc::iovec> = bufs
+            .iter_mut()
+            .map(|buf| libc::iovec {
+                iov_base: buf.as_mut_ptr().cast(),
+                iov_len: buf.len(),
+            })
+            .collect();
+        let mut headers: Vec<libc::mmsghdr> = iovecs
+            .iter_mut()
+            .zip(&mut addrs)
+            .map(|(iov, addr)| {
+                message_header(
+                    ptr::from_mut(addr).cast(),
+                    mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t,
+                    iov,
+                )
+            })
+            .collect();
+        // SAFETY: every header points to a buffer and an address living until the call returns
+        let n = unsafe {
+            libc::recvmmsg(
+                fd,
+                headers.as_mut_ptr(),
+                headers.len() as libc::c_uint,
+                libc::MSG_DONTWAIT as _,
+                ptr::null_mut(),
+            )
+        };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        for (header, addr) in headers.iter().zip(&addrs).take(n as usize) {
+            let Some(from) = to_socket_addr(addr) else {
+                debug!("Dropping datagram from an unsupported address family");
+                continue;
+            };
+            if header.msg_hdr.msg_flags & libc::MSG_TRUNC != 0 {
+                debug!("Dropping datagram from {from} longer than the receive buffer");
+                continue;
+            }
+            received.push((header.msg_len as usize, from));
+        }
+        Ok(())
+    }
+
+    /// Sends `messages`, all queued for `socket`, without blocking, adding the
+    /// destinations of those the kernel refused to `failed`.
+    pub(super) fn sendmmsg(
+        socket: &Arc<UdpSocket>,
+        data: &[u8],
+        messages: &[(Arc<UdpSocket>, SocketAddr, Range<usize>)],
+        failed: &mut Vec<SocketAddr>,
+    ) {
+        if let [(socket, to, range)] = messages {
+            if let Err(e) = try_send(socket, &data[range.clone()], to) {
+                debug!("Cannot send datagram to {to}: {e}");
+                failed.push(*to);
+            }
+            return;
+        }
+        for (_, to, range) in messages {
+            capture::sent(socket, to, &data[range.clone()]);
+        }
+        let addrs: Vec<SockAddr> = messages.iter().map(|(_, to, _)| (*to).into()).collect();
+        let mut iovecs: Vec<libc::iovec> = messages
+            .iter()
+            .map(|(_, _, range)| libc::iovec {
+                // the kernel only reads from the buffer
+                iov_base: data[range.clone()].as_ptr().cast_mut().cast(),
+                iov_len: range.len(),
+            })
+            .collect();
+        let mut headers: Vec<libc::mmsghdr> = iovecs
+            .iter_mut()
+            .zip(&addrs)
+            .map(|(iov, addr)| message_header(addr.as_ptr().cast_mut().cast(), addr.len(), iov))
+            .collect();
+        let mut sent = 0;
+        while sent < headers.len() {
+            // SAFETY: every header points to a buffer and an address living until the call returns
+            let n = unsafe {
+                libc::sendmmsg(
+                    socket.as_raw_fd(),
+                    headers[sent..].as_mut_ptr(),
+                    (headers.len() - sent) as libc::c_uint,
+                    libc::MSG_DONTWAIT as _,
+                )
+            };
+            if n >= 0 {
+                sent += n as usize;
+                continue;
+            }
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::WouldBlock {
+                // a full send buffer drops the datagrams as the network would
+                return;
+            }
+            // skip the datagram the kernel refused, e.g. for an unreachable peer
+            debug!("Cannot send datagram to {}: {e}", messages[sent].1);
+            failed.push(messages[sent].1);
+            sent += 1;
+        }
+    }
+}
@@ -0,0 +1,174 @@
+//! Packet capture of the relay's traffic to a pcap file, for inspecting
+//! pairing failures in Wireshark.
+//!
+//! Every datagram received on a relay socket or sent by the relay is written
+//! with its timestamp as a raw IPv4 or IPv6 packet (link type 101), whose
+//! headers are synthesized from the addresses of both ends; the local address
+//! is unspecified when the socket is bound to all interfaces, and UDP checksums
+//! are left out. With `handshake_only`, only the handshake messages (see
+//! [`Ops::is_handshake`]) are written.
+//!
+//! The capture is process-wide, as datagrams are sent from many places; it is
+//! started once, when the relay is built.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use tokio::net::UdpSocket;
+use tracing::warn;
+
+use crate::protocol::Ops;
+
+/// pcap link type of packets starting with their IP header.
+const LINKTYPE_RAW: u32 = 101;
+const SNAPLEN: u32 = 65535;
+const UDP_HEADER_LEN: usize = 8;
+
+/// Whether a capture was started, sparing the lock otherwise.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static CAPTURE: Mutex<Option<Capture>> = Mutex::new(None);
+
+#[derive(Debug)]
+struct Capture {
+    file: BufWriter<File>,
+    handshake_only: bool,
+    /// Protocol magic of the handshake messages.
+    magic: u8,
+}
+
+/// Creates the pcap file at `path`, truncating it, and starts writing the
+/// relay's traffic to it, or only its handshake messages under the protocol
+/// `magic` with `handshake_only`.
+pub(crate) fn start(path: &Path, handshake_only: bool, magic: u8) -> io::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    // magic, version 2.4, UTC, timestamp accuracy, snapshot length, link type
+    file.write_all(&0xa1b2_c3d4u32.to_le_bytes())?;
+    file.write_all(&2u16.to_le_bytes())?;
+    file.write_all(&4u16.to_le_bytes())?;
+    file.write_all(&[0; 8])?;
+    file.write_all(&SNAPLEN.to_le_bytes())?;
+    file.write_all(&LINKTYPE_RAW.to_le_bytes())?;
+    file.flush()?;
+    *CAPTURE.lock().expect("Capture lock poisoned") = Some(Capture {
+        file,
+        handshake_only,
+        magic,
+    });
+    ENABLED.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Records `datagram`, received on `socket` from `from`.
+pub(crate) fn received(socket: &UdpSocket, from: &SocketAddr, datagram: &[u8]) {
+    if ENABLED.load(Ordering::Relaxed) {
+        if let Ok(local) = socket.local_addr() {
+            record(*from, local, datagram);
+        }
+    }
+}
+
+/// Records `datagram`, sent on `socket` to `to`.
+pub(crate) fn sent(socket: &UdpSocket, to: &SocketAddr, datagram: &[u8]) {
+    if ENABLED.load(Ordering::Relaxed) {
+        if let Ok(local) = socket.local_addr() {
+            record(local, *to, datagram);
+        }
+    }
+}
+
+fn record(source: SocketAddr, destination: SocketAddr, datagram: &[u8]) {
+    let mut capture = CAPTURE.lock().expect("Capture lock poisoned");
+    let Some(capture) = capture.as_mut() else {
+        return;
+    };
+    if capture.handshake_only
+        && !Ops::parse(datagram, capture.magic).is_some_and(|(op, _)| op.is_handshake())
+    {
+        return;
+    }
+    let mut packet = ip_header(source, destination, UDP_HEADER_LEN + datagram.len());
+    packet.extend_from_slice(&source.port().to_be_bytes());
+    packet.extend_from_slice(&destination.port().to_be_bytes());
+    packet.extend_from_slice(&udp_len(UDP_HEADER_LEN + datagram.len()).to_be_bytes());
+    packet.extend_from_slice(&[0, 0]);
+    packet.extend_from_slice(datagram);
+    let captured = &packet[..packet.len().min(SNAPLEN as usize)];
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let written = [
+        (time.as_secs() as u32).to_le_bytes(),
+        time.subsec_micros().to_le_bytes(),
+        (captured.len() as u32).to_le_bytes(),
+        (packet.len() as u32).to_le_bytes(),
+    ]
+    .iter()
+    .try_for_each(|field| capture.file.write_all(field))
+    .and_then(|()| capture.file.write_all(captured))
+    .and_then(|()| capture.file.flush());
+    if let Err(e) = written {
+        warn!("Cannot write to the packet capture, stopping it: {e}");
+        ENABLED.store(false, Ordering::Relaxed);
+    }
+}
+
+fn udp_len(len: usize) -> u16 {
+    u16::try_from(len).unwrap_or(u16::MAX)
+}
+
+/// `ip` as IPv4 if it is one, possibly mapped to IPv6.
+fn to_v4(ip: IpAddr) -> Option<Ipv4Addr> {
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => Some(ip),
+        IpAddr::V6(_) => None,
+    }
+}
+
+/// IP header of a UDP packet of `len` bytes from `source` to `destination`. An
+/// unspecified address takes the family of the other one.
+fn ip_header(source: SocketAddr, destination: SocketAddr, len: usize) -> Vec<u8> {
+    let (source, destination) = match (source.ip(), destination.ip()) {
+        (source, destination) if source.is_unspecified() => (unspecified(destination), destination),
+        (source, destination) if destination.is_unspecified() => (source, unspecified(source)),
+        ips => ips,
+    };
+    if let (Some(source), Some(destination)) = (to_v4(source), to_v4(destination)) {
+        let mut header = vec![0x45, 0];
+        header.extend_from_slice(&udp_len(20 + len).to_be_bytes());
+        // identification, don't fragment, TTL, UDP, checksum (filled in below)
+        header.extend_from_slice(&[0, 0, 0x40, 0, 64, 17, 0, 0]);
+        header.extend_from_slice(&source.octets());
+        header.extend_from_slice(&destination.octets());
+        let sum = header
+            .chunks(2)
+            .map(|word| u32::from(u16::from_be_bytes([word[0], word[1]])))
+            .sum::<u32>();
+        let sum = (sum & 0xffff) + (sum >> 16);
+        let checksum = !((sum & 0xffff) + (sum >> 16)) as u16;
+        header[10..12].copy_from_slice(&checksum.to_be_bytes());
+        return header;
+    }
+    let to_v6 = |ip: IpAddr| match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    };
+    let mut header = vec![0x60, 0, 0, 0];
+    header.extend_from_slice(&udp_len(len).to_be_bytes());
+    // UDP, hop limit
+    header.extend_from_slice(&[17, 64]);
+    header.extend_from_slice(&to_v6(source).octets());
+    header.extend_from_slice(&to_v6(destination).octets());
+    header
+}
+
+fn unspecified(like: IpAddr) -> IpAddr {
+    match to_v4(like) {
+        Some(_) => Ipv4Addr::UNSPECIFIED.into(),
+        None => Ipv6Addr::UNSPECIFIED.into(),
+    }
+}
@@ -0,0 +1,131 @@
+//! Logical channels multiplexed within a pairing, so that a single session can
+//! carry independent streams (e.g. control and media) instead of one pairing
+//! per stream.
+//!
+//! A peer asks for them by setting [`OPTION_CHANNELS`] in its
+//! [`PairingResponse`](crate::protocol::PairingResponse), and a relay running
+//! with `channels` agrees by echoing the bit, as for the other options. Every
+//! datagram between that peer and the relay then starts with the ID of its
+//! channel:
+//!
+//! ```text
+//! +---------+---------+
+//! | Channel | Payload |
+//! | 1 byte  |         |
+//! +---------+---------+
+//! ```
+//!
+//! The relay keeps the packets, bytes and last activity of every channel a peer
+//! sends on, and relays each datagram with its channel ID. A peer without
+//! channels may be paired with one using them: the ID is stripped from what it
+//! gets, and what it sends goes out on [`DEFAULT_CHANNEL`].
+//!
+//! The ID is the first byte of the payload, inside encryption and below any
+//! framing of reliable delivery, forward error correction or compression.
+//! Control messages carry none.
+
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+/// Option bit of a [`PairingResponse`](crate::protocol::PairingResponse)
+/// asking for logical channels.
+pub const OPTION_CHANNELS: u8 = 0x08;
+
+/// Channel the datagrams of a peer without channels are relayed on.
+pub const DEFAULT_CHANNEL: u8 = 0;
+
+/// Activity of one channel of a peer.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ChannelStats {
+    pub(crate) packets: u64,
+    pub(crate) bytes: u64,
+    last_active: Instant,
+}
+
+impl ChannelStats {
+    /// Time since the peer last sent on the channel.
+    pub(crate) fn idle(&self) -> Duration {
+        self.last_active.elapsed()
+    }
+}
+
+/// Channels a peer sent on, by ID.
+#[derive(Debug, Default)]
+pub(crate) struct Channels {
+    stats: BTreeMap<u8, ChannelStats>,
+}
+
+impl Channels {
+    /// Records `datagram` of the peer on its channel. Returns `false` if it is
+    /// too short to carry a channel ID.
+    pub(crate) fn record(&mut self, datagram: &[u8]) -> bool {
+        let Some((&id, payload)) = datagram.split_first() else {
+            return false;
+        };
+        let stats = self.stats.entry(id).or_insert(ChannelStats {
+            packets: 0,
+            bytes: 0,
+            last_active: Instant::now(),
+        });
+        stats.packets += 1;
+        stats.bytes += payload.len() as u64;
+        stats.last_active = Instant::now();
+        true
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (u8, &ChannelStats)> {
+        self.stats.iter().map(|(&id, stats)| (id, stats))
+    }
+}
+
+/// `datagram` from a peer with or without channels (`from_channels`), framed
+/// for a receiver with or without them (`to_channels`).
+pub(crate) fn reframe(datagram: &[u8], from_channels: bool, to_channels: bool) -> Cow<'_, [u8]> {
+    match (from_channels, to_channels) {
+        (true, false) => Cow::Borrowed(&datagram[1..]),
+        (false, true) => Cow::Owned([&[DEFAULT_CHANNEL][..], datagram].concat()),
+        _ => Cow::Borrowed(datagram),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reframe_strips_the_channel_for_a_peer_without_channels() {
+        let reframed = reframe(b"\x07media", true, false);
+        assert!(matches!(reframed, Cow::Borrowed(_)));
+        assert_eq!(&*reframed, b"media");
+    }
+
+    #[test]
+    fn reframe_puts_datagrams_of_a_peer_without_channels_on_the_default_one() {
+        let reframed = reframe(b"media", false, true);
+        assert_eq!(&*reframed, [&[DEFAULT_CHANNEL][..], b"media"].concat());
+        assert_eq!(&*reframe(&reframed, true, false), b"media");
+    }
+
+    #[test]
+    fn reframe_keeps_datagrams_between_alike_peers() {
+        for channels in [false, true] {
+            let reframed = reframe(b"\x07media", channels, channels);
+            assert!(matches!(reframed, Cow::Borrowed(b"\x07media")));
+        }
+    }
+
+    #[test]
+    fn records_activity_by_channel() {
+        let mut channels = Channels::default();
+        assert!(!channels.record(b""));
+        assert!(channels.record(b"\x05hi"));
+        assert!(channels.record(b"\x07media"));
+        assert!(channels.record(b"\x05!"));
+        let stats: Vec<_> = channels
+            .iter()
+            .map(|(id, stats)| (id, stats.packets, stats.bytes))
+            .collect();
+        assert_eq!(stats, [(5, 2, 3), (7, 1, 5)]);
+    }
+}
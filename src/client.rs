@@ -0,0 +1,619 @@
+//! Client side of the relay: pairs with a relay and bridges a local UDP port to
+//! the paired peer, so existing applications can talk to `localhost`.
+
+use std::future::{self, Future};
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use tokio::net::UdpSocket;
+use tokio::time::{self, Instant};
+use tracing::{debug, info, trace, warn};
+use zeroize::Zeroizing;
+
+use crate::auth;
+use crate::compression::{self, OPTION_COMPRESSION};
+#[cfg(feature = "dtls")]
+use crate::dtls;
+use crate::encryption::{Role, SessionCipher};
+use crate::fec::{Fec, MAX_BLOCK_SIZE, OPTION_FEC};
+use crate::peer::Rtt;
+use crate::protocol::{
+    parse_addr, Ops, PairingRequest, PairingResponse, ADDRESS_REQUEST_LEN, PROTOCOL_VERSION,
+    RESUME_TOKEN_LEN, UNNEGOTIATED_VERSION,
+};
+use crate::relay::DEFAULT_RTT_INTERVAL;
+use crate::reliable::{Channel, DEFAULT_RETRANSMIT_INTERVAL, DEFAULT_WINDOW, OPTION_RELIABLE};
+
+/// Settings of a client, see [`run`].
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    /// Address of the relay.
+    pub relay: SocketAddr,
+    /// Session secret shared with the peer to be paired with.
+    pub secret: Vec<u8>,
+    /// Pre-shared key of the relay; empty for relays accepting every peer.
+    pub preshared_key: Zeroizing<String>,
+    /// Pairs with the legacy handshake sending the pre-shared key in cleartext.
+    pub legacy_handshake: bool,
+    /// Encrypts the traffic with the relay, which must run with encryption too
+    /// (see [`crate::encryption`]). Needs the v2 handshake.
+    pub encrypt: bool,
+    /// Asks the relay for reliable delivery of the datagrams between this client
+    /// and the relay (see [`crate::reliable`]). Needs the v2 handshake.
+    pub reliable: bool,
+    /// Asks the relay for forward error correction of the datagrams between this
+    /// client and the relay (see [`crate::fec`]), with a parity datagram after
+    /// every so many datagrams. Needs the v2 handshake.
+    pub fec: Option<u8>,
+    /// Asks the relay to compress the datagrams it relays to this client (see
+    /// [`crate::compression`]). Needs the v2 handshake.
+    pub compress: bool,
+    /// Sends the pairing request over DTLS instead, to relays built with the
+    /// `dtls` feature.
+    pub dtls: Option<DtlsOptions>,
+    /// Local address applications send to.
+    pub local: SocketAddr,
+    /// How long to wait for the relay before sending a handshake message again.
+    pub retry_interval: Duration,
+    /// First command byte of the relay's control messages, see [`crate::protocol`].
+    pub protocol_magic: u8,
+}
+
+/// How a client reaches the DTLS listener of a relay.
+#[derive(Debug, Clone)]
+pub struct DtlsOptions {
+    /// Port of the listener, on the host of the relay.
+    pub port: u16,
+    /// Certificate authority the relay's certificate must be issued by; without
+    /// it, the client authenticates with DTLS-PSK.
+    pub ca_file: Option<PathBuf>,
+    /// Host name the relay's certificate must be valid for; the relay's IP
+    /// address when unset.
+    pub server_name: Option<String>,
+    /// Name of the relay key, the DTLS-PSK identity.
+    pub identity: String,
+}
+
+/// Waits until `socket` receives a message from `from` starting with one of
+/// `ops` under `magic`, returning it with its payload, or gives up at `deadline`.
+async fn recv_op(
+    socket: &UdpSocket,
+    from: SocketAddr,
+    magic: u8,
+    ops: &[Ops],
+    deadline: Instant,
+) -> io::Result<Option<(Ops, Vec<u8>)>> {
+    let mut buf = vec![0u8; 65535];
+    loop {
+        let received = time::timeout_at(deadline, socket.recv_from(&mut buf)).await;
+        let Ok(received) = received else {
+            return Ok(None);
+        };
+        let (n, addr) = received?;
+        match Ops::parse(&buf[..n], magic) {
+            Some((op, payload)) if addr == from && ops.contains(&op) => {
+                return Ok(Some((op, payload.to_vec())))
+            }
+            _ => debug!("Ignoring unexpected message from {addr}"),
+        }
+    }
+}
+
+/// Options byte of the pairing response, asking for the options in `config`.
+fn options(config: &ClientConfig) -> u8 {
+    let delivery = if config.reliable {
+        OPTION_RELIABLE
+    } else if config.fec.is_some() {
+        OPTION_FEC
+    } else {
+        0
+    };
+    let compression = if config.compress {
+        OPTION_COMPRESSION
+    } else {
+        0
+    };
+    delivery | compression
+}
+
+/// How a relay answered a pairing request.
+enum Answer {
+    /// Acknowledged, with the nonce of the challenge (the ticket over DTLS, empty
+    /// for the legacy handshake) and the options the relay agreed to.
+    Acked(Vec<u8>, u8),
+    /// Sent to another instance of its cluster, see [`Ops::Redirect`].
+    Redirected(SocketAddr),
+}
+
+/// Performs a single handshake attempt, returning how the relay answered, if it did.
+async fn try_handshake(socket: &UdpSocket, config: &ClientConfig) -> io::Result<Option<Answer>> {
+    let deadline = Instant::now() + config.retry_interval;
+    let (request, nonce) = match &config.dtls {
+        #[cfg(feature = "dtls")]
+        Some(options) => {
+            let Some(ticket) = dtls::request_ticket(config, options, deadline).await? else {
+                return Ok(None);
+            };
+            (
+                Ops::Redeem.message(config.protocol_magic, &ticket),
+                ticket.to_vec(),
+            )
+        }
+        _ if config.legacy_handshake => {
+            let request = PairingRequest {
+                psk: config.preshared_key.as_bytes(),
+                secret: &config.secret,
+            }
+            .encode(config.protocol_magic);
+            (request, Vec::new())
+        }
+        _ => {
+            socket
+                .send_to(
+                    &Ops::ChallengeRequest.message(config.protocol_magic, &[PROTOCOL_VERSION]),
+                    config.relay,
+                )
+                .await?;
+            let ops = [Ops::Challenge, Ops::UnsupportedVersion];
+            let challenge =
+                recv_op(socket, config.relay, config.protocol_magic, &ops, deadline).await?;
+            let mut nonce = match challenge {
+                Some((Ops::Challenge, payload)) if payload.len() >= auth::NONCE_LEN => payload,
+                Some((Ops::UnsupportedVersion, payload)) => {
+                    return Err(unsupported_version(config.relay, &payload))
+                }
+                _ => return Ok(None),
+            };
+            // relays predating version negotiation send the nonce alone
+            let version = nonce.get(auth::NONCE_LEN).copied();
+            nonce.truncate(auth::NONCE_LEN);
+            debug!(
+                "Relay {} speaks protocol version {}",
+                config.relay,
+                version.unwrap_or(UNNEGOTIATED_VERSION)
+            );
+            let mac = auth::sign(config.preshared_key.as_bytes(), &nonce, &config.secret);
+            let request = PairingResponse {
+                nonce: &nonce,
+                secret: &config.secret,
+                mac: &mac,
+                options: options(config),
+            }
+            .encode(config.protocol_magic);
+            (request, nonce)
+        }
+    };
+    socket.send_to(&request, config.relay).await?;
+    let ops = [
+        Ops::Ack,
+        Ops::Redirect,
+        Ops::UnsupportedVersion,
+        Ops::LimitExceeded,
+        Ops::SecretInUse,
+    ];
+    let answer = recv_op(socket, config.relay, config.protocol_magic, &ops, deadline).await?;
+    Ok(match answer {
+        Some((Ops::Ack, payload)) => match payload.strip_prefix(config.secret.as_slice()) {
+            Some([]) => Some(Answer::Acked(nonce, 0)),
+            Some(&[options]) => Some(Answer::Acked(nonce, options)),
+            _ => None,
+        },
+        Some((Ops::Redirect, payload)) => parse_addr(&payload).map(Answer::Redirected),
+        Some((Ops::UnsupportedVersion, payload)) => {
+            return Err(unsupported_version(config.relay, &payload))
+        }
+        Some((Ops::LimitExceeded, _)) => {
+            return Err(io::Error::new(
+                io::ErrorKind::QuotaExceeded,
+                format!(
+                    "relay {} refuses more sessions from this host",
+                    config.relay
+                ),
+            ))
+        }
+        Some((Ops::SecretInUse, _)) => {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("relay {} reports the secret is in use", config.relay),
+            ))
+        }
+        _ => None,
+    })
+}
+
+/// Error for a relay answering [`Ops::UnsupportedVersion`] with `payload`.
+fn unsupported_version(relay: SocketAddr, payload: &[u8]) -> io::Error {
+    let message = match payload {
+        [min, max, ..] => format!("relay {relay} only accepts protocol versions {min} to {max}"),
+        _ => format!("relay {relay} does not accept the protocol version of this client"),
+    };
+    io::Error::new(io::ErrorKind::Unsupported, message)
+}
+
+/// Pairs `socket` with the relay, retrying until the relay acknowledges.
+/// Returns the address of the relay that did, which differs from `config.relay`
+/// when a relay in cluster mode redirected the client.
+pub async fn handshake(socket: &UdpSocket, config: &ClientConfig) -> io::Result<SocketAddr> {
+    pair(socket, config).await.map(|(relay, ..)| relay)
+}
+
+/// Like [`handshake`], also returning the nonce of the acknowledged handshake
+/// and the options the relay agreed to.
+async fn pair(socket: &UdpSocket, config: &ClientConfig) -> io::Result<(SocketAddr, Vec<u8>, u8)> {
+    let mut config = config.clone();
+    loop {
+        match try_handshake(socket, &config).await? {
+            Some(Answer::Acked(nonce, options)) => return Ok((config.relay, nonce, options)),
+            Some(Answer::Redirected(relay)) => {
+                info!("Relay {} redirected us to {relay}", config.relay);
+                config.relay = relay;
+            }
+            None => debug!("No answer from relay {}, retrying...", config.relay),
+        }
+    }
+}
+
+/// Asks the relay, whose protocol magic is `magic`, for the address it sees
+/// `socket` at, i.e. the public address of this host when behind a NAT. Returns
+/// `None` if the relay does not answer within `timeout`.
+pub async fn reflexive_address(
+    socket: &UdpSocket,
+    relay: SocketAddr,
+    magic: u8,
+    timeout: Duration,
+) -> io::Result<Option<SocketAddr>> {
+    let mut request = Ops::AddressRequest.to_bytes(magic).to_vec();
+    request.resize(ADDRESS_REQUEST_LEN, 0);
+    socket.send_to(&request, relay).await?;
+    let deadline = Instant::now() + timeout;
+    let answer = recv_op(socket, relay, magic, &[Ops::Address], deadline).await?;
+    Ok(answer.and_then(|(_, payload)| parse_addr(&payload)))
+}
+
+/// Sends a [`Ops::Probe`] carrying `id` to the relay, whose protocol magic is
+/// `magic`, and waits up to `timeout` for the matching reply, returning the
+/// round-trip time.
+pub fn probe(
+    socket: &std::net::UdpSocket,
+    relay: SocketAddr,
+    magic: u8,
+    id: u64,
+    timeout: Duration,
+) -> io::Result<Option<Duration>> {
+    let sent = std::time::Instant::now();
+    let deadline = sent + timeout;
+    socket.send_to(&Ops::Probe.message(magic, &id.to_be_bytes()), relay)?;
+    let mut buf = [0u8; 64];
+    loop {
+        let Some(remaining) = deadline.checked_duration_since(std::time::Instant::now()) else {
+            return Ok(None);
+        };
+        socket.set_read_timeout(Some(remaining.max(Duration::from_millis(1))))?;
+        let (n, from) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                return Ok(None)
+            }
+            Err(e) => return Err(e),
+        };
+        match Ops::parse(&buf[..n], magic) {
+            Some((Ops::ProbeReply, payload)) if from == relay && payload == id.to_be_bytes() => {
+                return Ok(Some(sent.elapsed()))
+            }
+            _ => debug!("Ignoring unexpected message from {from}"),
+        }
+    }
+}
+
+/// Pairs with the relay, then bridges datagrams between the local address and
+/// the relay until the relay shuts down or the peer disconnects.
+///
+/// The relay only acknowledges the pairing request; the peer is paired once it
+/// sends its own request with the same secret within the relay's pairing timeout.
+pub async fn run(config: ClientConfig) -> io::Result<()> {
+    run_until(config, future::pending()).await
+}
+
+/// Like [`run`], but also stops once `shutdown` resolves, telling the relay
+/// with [`Ops::Disconnect`] so that the session is torn down right away.
+pub async fn run_until(
+    mut config: ClientConfig,
+    shutdown: impl Future<Output = ()>,
+) -> io::Result<()> {
+    if config.encrypt && config.legacy_handshake {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "encryption cannot be used with the legacy handshake",
+        ));
+    }
+    if config.reliable && (config.legacy_handshake || config.dtls.is_some()) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "reliable delivery needs the v2 handshake, without DTLS",
+        ));
+    }
+    if config.fec.is_some() && (config.reliable || config.legacy_handshake || config.dtls.is_some())
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "forward error correction needs the v2 handshake, without DTLS or reliable delivery",
+        ));
+    }
+    if config
+        .fec
+        .is_some_and(|block_size| !(1..=MAX_BLOCK_SIZE).contains(&block_size))
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("the FEC block size must be between 1 and {MAX_BLOCK_SIZE}"),
+        ));
+    }
+    if config.compress && (config.legacy_handshake || config.dtls.is_some()) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "compression needs the v2 handshake, without DTLS",
+        ));
+    }
+    if config.dtls.is_some() && !cfg!(feature = "dtls") {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "DTLS support is not built in; rebuild with the `dtls` feature",
+        ));
+    }
+    let relay_socket = UdpSocket::bind(match config.relay {
+        SocketAddr::V4(_) => SocketAddr::from(([0, 0, 0, 0], 0)),
+        SocketAddr::V6(_) => SocketAddr::from(([0u16; 8], 0)),
+    })
+    .await?;
+    let local_socket = UdpSocket::bind(config.local).await?;
+
+    tokio::pin!(shutdown);
+    match reflexive_address(
+        &relay_socket,
+        config.relay,
+        config.protocol_magic,
+        config.retry_interval,
+    )
+    .await?
+    {
+        Some(addr) => info!("Relay {} sees this client at {addr}", config.relay),
+        None => debug!("Relay {} did not report our address", config.relay),
+    }
+    let magic = config.protocol_magic;
+    let mut app: Option<SocketAddr> = None;
+    let mut local_buf = vec![0u8; 65535];
+    let mut relay_buf = vec![0u8; 65535];
+    'pairing: loop {
+        info!("Pairing with relay {}...", config.relay);
+        let (relay, nonce, options) = tokio::select! {
+            paired = pair(&relay_socket, &config) => paired?,
+            _ = &mut shutdown => return Ok(()),
+        };
+        config.relay = relay;
+        let mut cipher = config.encrypt.then(|| {
+            SessionCipher::new(
+                config.preshared_key.as_bytes(),
+                &nonce,
+                &config.secret,
+                Role::Peer,
+            )
+        });
+        let mut channel =
+            (options & OPTION_RELIABLE != 0).then(|| Channel::new(DEFAULT_WINDOW, magic));
+        if config.reliable && channel.is_none() {
+            warn!("Relay {} does not offer reliable delivery", config.relay);
+        }
+        let mut fec = config
+            .fec
+            .filter(|_| options & OPTION_FEC != 0)
+            .map(|block_size| Fec::new(block_size, magic));
+        if config.fec.is_some() && fec.is_none() {
+            warn!(
+                "Relay {} does not offer forward error correction",
+                config.relay
+            );
+        }
+        let compressed = options & OPTION_COMPRESSION != 0;
+        if config.compress && !compressed {
+            warn!("Relay {} does not offer compression", config.relay);
+        }
+        info!(
+            "Relay acknowledged; bridging {} to the peer",
+            local_socket.local_addr()?
+        );
+        let mut retransmit = time::interval(DEFAULT_RETRANSMIT_INTERVAL / 2);
+        // the relay always answers echoes
+        let mut rtt = Rtt::default();
+        rtt.answers_echoes = true;
+        let mut echo = time::interval(DEFAULT_RTT_INTERVAL);
+
+        loop {
+            tokio::select! {
+                received = local_socket.recv_from(&mut local_buf) => {
+                    let (n, from) = received?;
+                    if app != Some(from) {
+                        info!("Forwarding datagrams from {from}");
+                        app = Some(from);
+                    }
+                    let sealed = cipher.as_mut().map(|cipher| cipher.seal(&local_buf[..n]));
+                    let datagram = sealed.as_deref().unwrap_or(&local_buf[..n]);
+                    let framed = match &mut channel {
+                        Some(channel) => match channel.send(datagram) {
+                            Some(frame) => Some(frame),
+                            None => {
+                                debug!("Dropping datagram from {from} as the send window is full");
+                                continue;
+                            }
+                        },
+                        None => None,
+                    };
+                    let datagram = framed.as_deref().unwrap_or(datagram);
+                    let (coded, parity) = match &mut fec {
+                        Some(fec) => {
+                            let (frame, parity) = fec.encode(datagram);
+                            (Some(frame), parity)
+                        }
+                        None => (None, None),
+                    };
+                    let datagram = coded.as_deref().unwrap_or(datagram);
+                    relay_socket.send_to(datagram, config.relay).await?;
+                    if let Some(parity) = parity {
+                        relay_socket.send_to(&parity, config.relay).await?;
+                    }
+                }
+                received = relay_socket.recv_from(&mut relay_buf) => {
+                    let (n, from) = received?;
+                    if from != config.relay {
+                        continue;
+                    }
+                    if relay_buf[..n] == Ops::Shutdown.to_bytes(magic) {
+                        info!("Relay is shutting down");
+                        return Ok(());
+                    }
+                    if relay_buf[..n] == Ops::Keepalive.to_bytes(magic) {
+                        trace!("Keepalive from relay");
+                        continue;
+                    }
+                    if let Some((Ops::SessionToken, token)) = Ops::parse(&relay_buf[..n], magic) {
+                        if token.len() == RESUME_TOKEN_LEN {
+                            trace!("Session token from relay");
+                            continue;
+                        }
+                    }
+                    if relay_buf[..n] == Ops::Disconnect.to_bytes(magic) {
+                        info!("Peer disconnected");
+                        return Ok(());
+                    }
+                    match Ops::parse(&relay_buf[..n], magic) {
+                        Some((Ops::Echo, payload)) => {
+                            let reply = Ops::EchoReply.message(magic, payload);
+                            relay_socket.send_to(&reply, config.relay).await?;
+                            continue;
+                        }
+                        Some((Ops::EchoReply, payload)) => {
+                            if let Some(sample) = rtt.reply(payload) {
+                                debug!(
+                                    "Round-trip time to the relay is {sample:?} (smoothed {:?})",
+                                    rtt.smoothed.unwrap_or(sample)
+                                );
+                            }
+                            continue;
+                        }
+                        Some((Ops::IdleWarning, payload)) => {
+                            if let Ok(left) = <[u8; 2]>::try_from(payload) {
+                                debug!(
+                                    "Session is idle and expires in {} seconds; keeping it up",
+                                    u16::from_be_bytes(left)
+                                );
+                                relay_socket.send_to(&Ops::Echo.to_bytes(magic), config.relay).await?;
+                            }
+                            continue;
+                        }
+                        Some((Ops::TooBig, payload)) => {
+                            if let Ok(max) = <[u8; 2]>::try_from(payload) {
+                                warn!(
+                                    "Relay dropped a datagram over its maximum payload of {} bytes",
+                                    u16::from_be_bytes(max)
+                                );
+                            }
+                            continue;
+                        }
+                        _ => (),
+                    }
+                    if let Some((Ops::Redirect, payload)) = Ops::parse(&relay_buf[..n], magic) {
+                        if let Some(relay) = parse_addr(payload) {
+                            info!("Relay {} redirected us to {relay}", config.relay);
+                            config.relay = relay;
+                            continue 'pairing;
+                        }
+                    }
+                    let delivered = match (&mut channel, Ops::parse(&relay_buf[..n], magic)) {
+                        (Some(channel), Some((Ops::SequenceAck, ack))) => {
+                            channel.acknowledge(ack);
+                            continue;
+                        }
+                        (Some(channel), Some((Ops::Sequenced, frame))) => {
+                            let Some((ack, delivered)) = channel.receive(frame) else {
+                                continue;
+                            };
+                            relay_socket.send_to(&ack, config.relay).await?;
+                            delivered
+                        }
+                        (None, Some((Ops::FecData, frame))) if fec.is_some() => {
+                            match fec.as_mut().and_then(|fec| fec.receive_data(frame)) {
+                                Some(delivered) => delivered,
+                                None => continue,
+                            }
+                        }
+                        (None, Some((Ops::FecParity, frame))) if fec.is_some() => {
+                            match fec.as_mut().and_then(|fec| fec.receive_parity(frame)) {
+                                Some(rebuilt) => {
+                                    debug!("Rebuilt a lost datagram from the parity");
+                                    vec![rebuilt]
+                                }
+                                None => continue,
+                            }
+                        }
+                        _ => vec![relay_buf[..n].to_vec()],
+                    };
+                    for datagram in delivered {
+                        let opened = match &mut cipher {
+                            Some(cipher) => match cipher.open(&datagram) {
+                                Some(payload) => Some(payload),
+                                None => {
+                                    debug!("Dropping undecryptable datagram from the relay");
+                                    continue;
+                                }
+                            },
+                            None => None,
+                        };
+                        let payload = opened.as_deref().unwrap_or(&datagram);
+                        let decompressed = match compressed {
+                            true => match compression::decompress(payload) {
+                                Some(payload) => Some(payload),
+                                None => {
+                                    debug!("Dropping malformed compressed datagram from the relay");
+                                    continue;
+                                }
+                            },
+                            false => None,
+                        };
+                        let payload = decompressed.as_deref().unwrap_or(payload);
+                        match app {
+                            Some(app) => {
+                                local_socket.send_to(payload, app).await?;
+                            }
+                            None => warn!("Dropping datagram from the peer, as no local application sent anything yet"),
+                        }
+                    }
+                }
+                _ = echo.tick() => {
+                    if let Some(echo) = rtt.echo(magic) {
+                        relay_socket.send_to(&echo, config.relay).await?;
+                    }
+                }
+                _ = retransmit.tick(), if channel.is_some() => {
+                    let due = channel
+                        .as_mut()
+                        .map(|channel| channel.due(DEFAULT_RETRANSMIT_INTERVAL))
+                        .unwrap_or_default();
+                    for frame in due {
+                        relay_socket.send_to(&frame, config.relay).await?;
+                    }
+                }
+                _ = &mut shutdown => {
+                    info!("Disconnecting from the peer");
+                    relay_socket.send_to(&Ops::Disconnect.to_bytes(magic), config.relay).await?;
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,214 @@
+//! Cluster mode: relay instances sharing a name (e.g. behind DNS round-robin)
+//! tell each other which secrets their peers wait on, so that a peer landing on
+//! another instance than its counterpart is sent there with [`Ops::Redirect`].
+//!
+//! An instance sends [`Ops::ClusterAnnounce`] to every other instance when a
+//! peer starts waiting on a secret or creates a group, and again at every
+//! housekeeping pass while it does, then [`Ops::ClusterWithdraw`] once the peer
+//! is paired or its request expires. Announcements not repeated are forgotten
+//! after [`Config::timeout_pairing`](crate::Config::timeout_pairing). Secrets
+//! never leave the instance: they are identified by the digest
+//! `HMAC-SHA256(cluster_key, "udprelay cluster" || secret)`.
+//!
+//! ```text
+//! +---------+-----------+----------+----------------+----------+
+//! | Command | Unix time | Digest   | Relay address  | MAC      |
+//! | 2 bytes | 8 bytes   | 32 bytes | (encode_addr)  | 32 bytes |
+//! +---------+-----------+----------+----------------+----------+
+//! ```
+//!
+//! The relay address is the one the announcing instance advertises to peers,
+//! and the MAC is `HMAC-SHA256(cluster_key, everything before it)`. Messages
+//! from addresses outside `cluster_peers`, failing authentication or older than
+//! [`CHALLENGE_LIFETIME`](crate::auth::CHALLENGE_LIFETIME) are dropped.
+//!
+//! A peer registering a secret announced by another instance is redirected
+//! instead of acknowledged. When two instances announce the same secret, e.g.
+//! as both peers arrived at once, the one advertising the higher address
+//! redirects its own peers to the other.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+
+use tokio::net::UdpSocket;
+use tracing::{debug, trace};
+
+use crate::auth::{self, MAC_LEN};
+use crate::peer::ExpiringTimer;
+use crate::protocol::{encode_addr, parse_addr, Ops};
+use crate::relay::{Config, Registry, SharedConfig};
+
+const DIGEST_LABEL: &[u8] = b"udprelay cluster";
+const TIME_LEN: usize = 8;
+
+type Digest = [u8; MAC_LEN];
+
+/// A secret another instance announced.
+#[derive(Debug)]
+struct RemoteSecret {
+    /// Relay address of that instance.
+    instance: SocketAddr,
+    timer: ExpiringTimer,
+}
+
+#[derive(Debug)]
+pub(crate) struct Cluster {
+    /// Non-blocking socket exchanging announcements with the other instances.
+    socket: std::net::UdpSocket,
+    remote: HashMap<Digest, RemoteSecret>,
+}
+
+fn cluster_key(config: &Config) -> &[u8] {
+    config
+        .cluster_key
+        .as_deref()
+        .map_or(&[][..], |key| key.as_bytes())
+}
+
+fn digest(config: &Config, secret: &[u8]) -> Digest {
+    auth::sign(cluster_key(config), DIGEST_LABEL, secret)
+}
+
+fn seal(config: &Config, op: Ops, digest: &Digest, instance: &SocketAddr) -> Vec<u8> {
+    let payload = [
+        &auth::unix_time().to_be_bytes()[..],
+        digest,
+        &encode_addr(instance),
+    ]
+    .concat();
+    let mut message = op.message(config.protocol_magic, &payload);
+    let mac = auth::sign(cluster_key(config), &message, &[]);
+    message.extend_from_slice(&mac);
+    message
+}
+
+/// Authenticates a cluster message, returning its command, digest and relay address.
+fn open(config: &Config, message: &[u8]) -> Option<(Ops, Digest, SocketAddr)> {
+    let (signed, mac) = message.split_at_checked(message.len().checked_sub(MAC_LEN)?)?;
+    if !auth::verify(cluster_key(config), signed, &[], mac) {
+        return None;
+    }
+    let (op, payload) = Ops::parse(signed, config.protocol_magic)?;
+    let issued = u64::from_be_bytes(payload.get(..TIME_LEN)?.try_into().ok()?);
+    if auth::is_stale(issued) {
+        return None;
+    }
+    let digest = payload.get(TIME_LEN..TIME_LEN + MAC_LEN)?.try_into().ok()?;
+    let instance = parse_addr(&payload[TIME_LEN + MAC_LEN..])?;
+    Some((op, digest, instance))
+}
+
+impl Cluster {
+    pub(crate) fn new(socket: std::net::UdpSocket) -> io::Result<Cluster> {
+        socket.set_nonblocking(true)?;
+        Ok(Cluster {
+            socket,
+            remote: HashMap::new(),
+        })
+    }
+
+    /// Number of secrets other instances currently announce.
+    pub(crate) fn remote_secrets(&self) -> usize {
+        self.remote.len()
+    }
+
+    /// Relay address of the instance another peer waits on `secret` at, if any.
+    pub(crate) fn locate(&self, config: &Config, secret: &[u8]) -> Option<SocketAddr> {
+        self.remote
+            .get(&digest(config, secret))
+            .filter(|remote| !remote.timer.is_expired(config.timeout_pairing))
+            .map(|remote| remote.instance)
+    }
+
+    /// Tells the other instances a peer of this one waits on `secret`.
+    pub(crate) fn announce(&self, config: &Config, secret: &[u8]) {
+        self.broadcast(config, Ops::ClusterAnnounce, secret);
+    }
+
+    /// Tells the other instances no peer of this one waits on `secret` anymore.
+    pub(crate) fn withdraw(&self, config: &Config, secret: &[u8]) {
+        self.broadcast(config, Ops::ClusterWithdraw, secret);
+    }
+
+    fn broadcast(&self, config: &Config, op: Ops, secret: &[u8]) {
+        let Some(advertise) = config.cluster_advertise else {
+            return;
+        };
+        let message = seal(config, op, &digest(config, secret), &advertise);
+        for peer in &config.cluster_peers {
+            match self.socket.send_to(&message, peer) {
+                Ok(_) => (),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                Err(e) => debug!("Cannot reach cluster peer {peer}: {e}"),
+            }
+        }
+    }
+
+    /// Forgets announcements that were not repeated in time.
+    pub(crate) fn prune(&mut self, config: &Config) {
+        self.remote
+            .retain(|_, remote| !remote.timer.is_expired(config.timeout_pairing));
+    }
+}
+
+/// Receives the announcements of the other instances, redirecting local peers
+/// when an instance with a lower address announces the same secret.
+pub(crate) async fn serve(config: SharedConfig, registry: Registry, socket: UdpSocket) {
+    let mut buf = [0u8; 128];
+    loop {
+        let (n, from) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(e) => {
+                debug!("Cannot receive cluster message: {e}");
+                continue;
+            }
+        };
+        let config = config.borrow().clone();
+        if !config
+            .cluster_peers
+            .iter()
+            .any(|peer| peer.ip().to_canonical() == from.ip().to_canonical())
+        {
+            trace!("Dropping cluster message from unknown instance {from}");
+            continue;
+        }
+        let Some((op, digest, instance)) = open(&config, &buf[..n]) else {
+            debug!("Dropping unauthentic cluster message from {from}");
+            continue;
+        };
+        let mut service = registry.lock().expect("Registry lock poisoned");
+        let Some(cluster) = &mut service.cluster else {
+            return;
+        };
+        match op {
+            Ops::ClusterAnnounce => {
+                trace!("Instance {instance} announced a secret");
+                cluster.remote.insert(
+                    digest,
+                    RemoteSecret {
+                        instance,
+                        timer: ExpiringTimer::new(),
+                    },
+                );
+                if config.cluster_advertise.is_some_and(|own| instance < own) {
+                    service.yield_secret(
+                        &config,
+                        |secret| self::digest(&config, secret) == digest,
+                        instance,
+                    );
+                }
+            }
+            Ops::ClusterWithdraw
+                if cluster
+                    .remote
+                    .get(&digest)
+                    .is_some_and(|remote| remote.instance == instance) =>
+            {
+                trace!("Instance {instance} withdrew a secret");
+                cluster.remote.remove(&digest);
+            }
+            _ => (),
+        }
+    }
+}
@@ -0,0 +1,104 @@
+//! Optional compression of the datagrams the relay sends to a peer, for slow
+//! last-mile links.
+//!
+//! A peer asks for it by setting [`OPTION_COMPRESSION`] in its
+//! [`PairingResponse`](crate::protocol::PairingResponse), and a relay running
+//! with `compression` agrees by echoing the bit in its
+//! [`Ops::Ack`](crate::protocol::Ops::Ack), as for
+//! [reliable delivery](crate::reliable), which it can be combined with. Every
+//! datagram relayed to that peer then starts with a flag byte:
+//!
+//! ```text
+//! +--------+---------+    +--------+-------------------+--------------+
+//! | 00     | Payload |    | 01     | Uncompressed size | LZ4 block    |
+//! | 1 byte |         |    | 1 byte | 2 bytes           |              |
+//! +--------+---------+    +--------+-------------------+--------------+
+//! ```
+//!
+//! Payloads that do not get smaller, e.g. already compressed or encrypted by
+//! the application, pass through untouched behind [`RAW`]; the others are sent
+//! as an LZ4 block behind [`LZ4`] and their size, big endian.
+//!
+//! Only the relay compresses, what the peer sends being relayed as is. With
+//! encryption, payloads are compressed before being sealed; with reliable
+//! delivery or forward error correction, the flagged datagram is what gets
+//! framed. Control messages (e.g. [`Ops::Disconnect`](crate::protocol::Ops::Disconnect))
+//! are not flagged.
+
+/// Option bit of a [`PairingResponse`](crate::protocol::PairingResponse)
+/// asking for compression of the datagrams relayed to the peer.
+pub const OPTION_COMPRESSION: u8 = 0x04;
+
+/// Flag of a payload sent as is.
+pub const RAW: u8 = 0x00;
+/// Flag of a payload sent as an LZ4 block.
+pub const LZ4: u8 = 0x01;
+
+const SIZE_LEN: usize = 2;
+
+/// Flags `payload` for a peer with compression, compressing it if that makes
+/// it smaller.
+pub fn compress(payload: &[u8]) -> Vec<u8> {
+    if let Ok(size) = u16::try_from(payload.len()) {
+        let block = lz4_flex::compress(payload);
+        if 1 + SIZE_LEN + block.len() < 1 + payload.len() {
+            return [&[LZ4][..], &size.to_be_bytes(), &block].concat();
+        }
+    }
+    [&[RAW][..], payload].concat()
+}
+
+/// Restores the payload of a datagram flagged by [`compress`]. Returns `None`
+/// if it is malformed.
+pub fn decompress(datagram: &[u8]) -> Option<Vec<u8>> {
+    match datagram.split_first()? {
+        (&RAW, payload) => Some(payload.to_vec()),
+        (&LZ4, rest) => {
+            let size = usize::from(u16::from_be_bytes(rest.get(..SIZE_LEN)?.try_into().ok()?));
+            let payload = lz4_flex::decompress(&rest[SIZE_LEN..], size).ok()?;
+            (payload.len() == size).then_some(payload)
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compressible_payload_round_trips_as_lz4() {
+        let payload = b"hello hello hello hello hello hello hello hello".repeat(8);
+        let datagram = compress(&payload);
+        assert_eq!(datagram[0], LZ4);
+        assert!(datagram.len() < payload.len());
+        assert_eq!(decompress(&datagram), Some(payload));
+    }
+
+    #[test]
+    fn incompressible_payload_passes_through_raw() {
+        let payload: Vec<u8> = (0..64).map(|_| rand::random()).collect();
+        for payload in [&payload[..], b"", b"x"] {
+            let datagram = compress(payload);
+            assert_eq!(datagram, [&[RAW][..], payload].concat());
+            assert_eq!(decompress(&datagram).as_deref(), Some(payload));
+        }
+    }
+
+    #[test]
+    fn malformed_datagrams_are_refused() {
+        let payload = b"abcabcabcabcabcabcabcabcabcabcabcabc".to_vec();
+        let datagram = compress(&payload);
+        assert_eq!(datagram[0], LZ4);
+        assert_eq!(decompress(b""), None);
+        assert_eq!(decompress(&[0x02, 1, 2, 3]), None);
+        assert_eq!(decompress(&[LZ4, 0]), None);
+        assert_eq!(decompress(&datagram[..datagram.len() - 1]), None);
+        let mut oversized = datagram.clone();
+        oversized[2] += 1;
+        assert_eq!(decompress(&oversized), None);
+        let mut undersized = datagram;
+        undersized[2] -= 1;
+        assert_eq!(decompress(&undersized), None);
+    }
+}
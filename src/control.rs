@@ -0,0 +1,306 @@
+//! Control socket for administering a running relay.
+//!
+//! A client connects to the Unix socket, writes a single command line and
+//! reads back one JSON document before the relay closes the connection.
+//!
+//! | Command | Response |
+//! |---|---|
+//! | `sessions` | active sessions with their key name and age, and for each of their peers (two, or every member of a group) its address, seconds since its last activity, the packets and bytes relayed from it and, for paired peers answering echoes, its smoothed round-trip time in milliseconds and, for peers using [channels](crate::channels), the activity of each channel |
+//! | `pending` | pairing requests waiting for their counterpart |
+//! | `kick <addr or secret>` | tears down matching sessions and pending pairings |
+//! | `stats` | cumulative counters (see the metrics endpoint) |
+//! | `config` | the settings `set` can change |
+//! | `set <setting> <value>` | changes one of those settings, as a reload would, and answers like `config` |
+//!
+//! `set` takes seconds for the timeouts, and a number or `none` for the limits.
+//! The change lasts until the next reload, which applies the settings of the
+//! command line and config file again.
+
+use std::io::{self, Read, Write};
+use std::os::unix::net;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::time;
+use tracing::{debug, info, warn};
+
+use crate::channels::Channels;
+use crate::peer::Side;
+use crate::relay::{Config, ConfigHandle};
+use crate::service::RelayService;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Sends `command` to the control socket at `path` and returns the JSON response.
+pub fn request(path: &Path, command: &str) -> io::Result<String> {
+    let mut stream = net::UnixStream::connect(path)?;
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    stream.write_all(command.as_bytes())?;
+    stream.write_all(b"\n")?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    Ok(response)
+}
+
+pub(crate) async fn serve(
+    listener: UnixListener,
+    registry: Arc<Mutex<RelayService>>,
+    config: ConfigHandle,
+) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                warn!("Cannot accept control connection: {e}");
+                continue;
+            }
+        };
+        let registry = registry.clone();
+        let config = config.clone();
+        tokio::spawn(async move {
+            match time::timeout(REQUEST_TIMEOUT, respond(stream, &registry, &config)).await {
+                Ok(Ok(())) => (),
+                Ok(Err(e)) => debug!("Control request failed: {e}"),
+                Err(_) => debug!("Control request timed out"),
+            }
+        });
+    }
+}
+
+async fn respond(
+    stream: UnixStream,
+    registry: &Mutex<RelayService>,
+    config: &ConfigHandle,
+) -> io::Result<()> {
+    let mut stream = BufReader::new(stream);
+    let mut line = String::new();
+    stream.read_line(&mut line).await?;
+    debug!("Control command: {}", line.trim());
+
+    let response = {
+        let mut registry = registry.lock().expect("Registry lock poisoned");
+        execute(&mut registry, config, line.trim())
+    };
+    let mut response = response.to_string();
+    response.push('\n');
+    stream.get_mut().write_all(response.as_bytes()).await?;
+    stream.get_mut().shutdown().await
+}
+
+fn execute(registry: &mut RelayService, config: &ConfigHandle, command: &str) -> Value {
+    let (command, argument) = command
+        .split_once(' ')
+        .map_or((command, ""), |(c, a)| (c, a.trim()));
+    match (command, argument) {
+        ("sessions", "") => sessions(registry),
+        ("pending", "") => pending(registry),
+        ("kick", target) if !target.is_empty() => json!({ "kicked": registry.kick(target) }),
+        ("stats", "") => stats(registry),
+        ("config", "") => settings(&config.get()),
+        ("set", setting) if !setting.is_empty() => set(config, setting),
+        _ => json!({ "error": format!("unknown command: {command:?}") }),
+    }
+}
+
+/// Settings of `config` the `set` command can change.
+fn settings(config: &Config) -> Value {
+    json!({
+        "timeout_pairing": config.timeout_pairing.as_secs(),
+        "timeout_connection_inactivities": config.timeout_connection_inactivities.as_secs(),
+        "timeout_no_connections": config.timeout_no_connections.as_secs(),
+        "max_sessions": config.max_sessions,
+        "max_pending_pairings": config.max_pending_pairings,
+        "max_sessions_per_ip": config.max_sessions_per_ip,
+    })
+}
+
+/// Changes the setting named by the first word of `argument` to the second.
+fn set(handle: &ConfigHandle, argument: &str) -> Value {
+    let Some((name, value)) = argument.split_once(' ') else {
+        return json!({ "error": "expected a setting and a value" });
+    };
+    let value = value.trim();
+    let secs = || {
+        value
+            .parse()
+            .map(Duration::from_secs)
+            .map_err(|_| format!("expected seconds, got {value:?}"))
+    };
+    let limit = || match value {
+        "none" => Ok(None),
+        _ => value
+            .parse()
+            .map(Some)
+            .map_err(|_| format!("expected a number or none, got {value:?}")),
+    };
+    let mut config = Config::clone(&handle.get());
+    let changed = match name {
+        "timeout_pairing" => secs().map(|timeout| config.timeout_pairing = timeout),
+        "timeout_connection_inactivities" => {
+            secs().map(|timeout| config.timeout_connection_inactivities = timeout)
+        }
+        "timeout_no_connections" => secs().map(|timeout| config.timeout_no_connections = timeout),
+        "max_sessions" => limit().map(|max| config.max_sessions = max),
+        "max_pending_pairings" => limit().map(|max| config.max_pending_pairings = max),
+        "max_sessions_per_ip" => limit().map(|max| config.max_sessions_per_ip = max),
+        _ => Err(format!("unknown setting: {name:?}")),
+    };
+    match changed.and_then(|()| handle.reload(config).map_err(|e| e.to_string())) {
+        Ok(()) => {
+            info!("Set {name} to {value} through the control socket");
+            settings(&handle.get())
+        }
+        Err(e) => json!({ "error": e }),
+    }
+}
+
+fn secret_to_string(secret: &[u8]) -> String {
+    String::from_utf8_lossy(secret).into_owned()
+}
+
+fn sessions(registry: &RelayService) -> Value {
+    let groups = registry.groups.iter().map(|(secret, group)| {
+        let members: Vec<Value> = group
+            .members
+            .iter()
+            .map(|(addr, member)| {
+                json!({
+                    "addr": addr.to_string(),
+                    "idle_secs": member.last_accessed.elapsed().as_secs(),
+                    "packets": member.packets,
+                    "bytes": member.bytes,
+                })
+            })
+            .collect();
+        json!({
+            "secret": secret_to_string(secret),
+            "key": group.key,
+            "group": true,
+            "age_secs": group.started.elapsed().as_secs(),
+            "peers": members,
+        })
+    });
+    let sessions: Vec<Value> = registry
+        .pairing
+        .values()
+        .filter_map(|peer_rc| {
+            let mut peer = peer_rc.lock().expect("Peer lock poisoned");
+            // list every pair once, from its first peer
+            if peer.side != Side::First {
+                return None;
+            }
+            let opponent_rc = peer.get_opponent();
+            let opponent = opponent_rc.lock().expect("Peer lock poisoned");
+            Some(json!({
+                "secret": secret_to_string(&peer.secret),
+                "key": peer.key,
+                "age_secs": peer.started.elapsed().as_secs(),
+                "peers": [
+                    {
+                        "addr": peer.recipient.addr.to_string(),
+                        "idle_secs": peer.last_accessed.elapsed().as_secs(),
+                        "packets": peer.packets,
+                        "bytes": peer.bytes,
+                        "rtt_ms": peer.rtt.smoothed.map(|rtt| rtt.as_millis() as u64),
+                        "channels": peer.channels.as_ref().map(channels),
+                    },
+                    {
+                        "addr": opponent.recipient.addr.to_string(),
+                        "idle_secs": opponent.last_accessed.elapsed().as_secs(),
+                        "packets": opponent.packets,
+                        "bytes": opponent.bytes,
+                        "rtt_ms": opponent.rtt.smoothed.map(|rtt| rtt.as_millis() as u64),
+                        "channels": opponent.channels.as_ref().map(channels),
+                    },
+                ],
+            }))
+        })
+        .chain(groups)
+        .collect();
+    Value::Array(sessions)
+}
+
+/// Activity of the logical channels of a peer using them.
+fn channels(channels: &Channels) -> Value {
+    channels
+        .iter()
+        .map(|(id, stats)| {
+            json!({
+                "id": id,
+                "idle_secs": stats.idle().as_secs(),
+                "packets": stats.packets,
+                "bytes": stats.bytes,
+            })
+        })
+        .collect()
+}
+
+fn pending(registry: &RelayService) -> Value {
+    registry
+        .waiters()
+        .map(|(secret, pending)| {
+            json!({
+                "secret": secret_to_string(secret),
+                "key": pending.key,
+                "addr": pending.addr.to_string(),
+                "waiting_secs": pending.timer.elapsed().as_secs(),
+            })
+        })
+        .collect()
+}
+
+fn stats(registry: &RelayService) -> Value {
+    let counters = &registry.counters;
+    json!({
+        "active_pairs": registry.pairing.len() / 2,
+        "pending_pairings": registry.pending_pairings(),
+        "active_groups": registry.groups.len(),
+        "group_members": registry.group_members.len(),
+        "active_forwards": registry.forwards.len(),
+        "relayed_packets": {
+            "first_to_second": counters.relayed_packets[Side::First as usize],
+            "second_to_first": counters.relayed_packets[Side::Second as usize],
+        },
+        "relayed_bytes": {
+            "first_to_second": counters.relayed_bytes[Side::First as usize],
+            "second_to_first": counters.relayed_bytes[Side::Second as usize],
+        },
+        "pairing_failures": {
+            "bad_psk": counters.rejected_bad_psk,
+            "short_packet": counters.rejected_short_packet,
+            "bad_challenge": counters.rejected_bad_challenge,
+            "replayed": counters.rejected_replayed,
+            "network": counters.rejected_network,
+            "key_mismatch": counters.rejected_key_mismatch,
+            "session_limit": counters.rejected_session_limit,
+            "same_port": counters.rejected_same_port,
+            "group_full": counters.rejected_group_full,
+            "bad_token": counters.rejected_bad_token,
+            "bad_code": counters.rejected_bad_code,
+            "max_sessions": counters.rejected_max_sessions,
+            "max_pending": counters.rejected_max_pending,
+            "ip_limit": counters.rejected_ip_limit,
+            "secret_in_use": counters.rejected_secret_in_use,
+            "max_total_sessions": counters.rejected_max_total_sessions,
+            "magic": counters.rejected_magic,
+        },
+        "pairings_by_key": counters.pairings_by_key,
+        "rate_limited": counters.rate_limited,
+        "undecryptable_packets": counters.undecryptable_packets,
+        "throttled_packets": counters.throttled_packets,
+        "delayed_packets": counters.delayed_packets,
+        "oversized_packets": counters.oversized_packets,
+        "banned_sources": registry.rate_limiter.banned(),
+        "expired_sessions": counters.expired_sessions,
+        "disconnected_sessions": counters.disconnected_sessions,
+        "keepalives": counters.keepalives,
+        "idle_warnings": counters.idle_warnings,
+        "resumed_sessions": counters.resumed_sessions,
+        "taken_over_sessions": counters.taken_over_sessions,
+        "expired_pairings": counters.expired_pairings,
+    })
+}
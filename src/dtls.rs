@@ -0,0 +1,388 @@
+//! Pairing handshake over DTLS, for deployments mandating standard
+//! cryptography; only built with the `dtls` feature.
+//!
+//! The relay accepts DTLS on a port of its own. Once the DTLS handshake is
+//! complete, a peer sends a legacy [`PairingRequest`] over it (the pre-shared
+//! key being protected by DTLS), and the relay answers with an [`Ops::Ticket`].
+//! The peer then sends that ticket in an [`Ops::Redeem`] to the relay port,
+//! from the socket it relays through, and is registered as after any other
+//! handshake. Relayed datagrams stay plain UDP, or use [`crate::encryption`]
+//! with the ticket in place of the handshake nonce.
+//!
+//! The relay authenticates with a certificate when configured with one.
+//! Otherwise both ends authenticate with DTLS-PSK, the identity being the name
+//! of the key ([`DEFAULT_KEY_NAME`](crate::DEFAULT_KEY_NAME) for the main one).
+//!
+//! Handshake datagrams are not retransmitted: a peer whose handshake stalls
+//! starts over after its retry interval.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::str;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+
+use openssl::error::ErrorStack;
+use openssl::ssl::{
+    Ssl, SslContext, SslContextBuilder, SslFiletype, SslMethod, SslOptions, SslVerifyMode,
+};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::time::{self, Instant};
+use tokio_openssl::SslStream;
+use tracing::{debug, trace, warn};
+
+use crate::client::{ClientConfig, DtlsOptions};
+use crate::protocol::{Ops, PairingRequest, RESUME_TOKEN_LEN};
+use crate::relay::{bind_socket, Config, Registry, SharedConfig};
+
+/// Path MTU assumed for handshake datagrams.
+const DTLS_MTU: u32 = 1200;
+/// How long the relay waits for a DTLS handshake and the pairing request following it.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Handshake datagrams queued per peer while its handshake is processed.
+const QUEUE_LEN: usize = 16;
+/// Cipher suites offered without a certificate.
+const PSK_CIPHERS: &str = "PSK-AES256-GCM-SHA384:PSK-CHACHA20-POLY1305:PSK-AES128-GCM-SHA256";
+
+/// Where the datagrams of a [`PeerDatagrams`] come from.
+enum Incoming {
+    /// Dispatched by the listener, which shares its socket between peers.
+    Queue(mpsc::Receiver<Vec<u8>>),
+    /// Read from the socket, dropping datagrams from anyone but the peer.
+    Socket,
+}
+
+/// The datagrams exchanged with one peer, as the byte stream OpenSSL works on:
+/// every read yields one datagram, and every write sends one.
+struct PeerDatagrams {
+    socket: Arc<UdpSocket>,
+    peer: SocketAddr,
+    incoming: Incoming,
+}
+
+impl AsyncRead for PeerDatagrams {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        match &mut this.incoming {
+            Incoming::Queue(queue) => {
+                // a closed queue reads as the end of the stream
+                if let Some(datagram) = ready!(queue.poll_recv(cx)) {
+                    let n = datagram.len().min(buf.remaining());
+                    buf.put_slice(&datagram[..n]);
+                }
+                Poll::Ready(Ok(()))
+            }
+            Incoming::Socket => loop {
+                let start = buf.filled().len();
+                let from = ready!(this.socket.poll_recv_from(cx, buf))?;
+                if from == this.peer {
+                    return Poll::Ready(Ok(()));
+                }
+                trace!("Ignoring unexpected datagram from {from}");
+                buf.set_filled(start);
+            },
+        }
+    }
+}
+
+impl AsyncWrite for PeerDatagrams {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.socket.poll_send_to(cx, buf, self.peer)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Copies `key` into the buffer OpenSSL gives a PSK callback; an unknown key
+/// (length 0) fails the handshake.
+fn copy_psk(key: Option<&[u8]>, buf: &mut [u8]) -> usize {
+    match key {
+        Some(key) if key.len() <= buf.len() => {
+            buf[..key.len()].copy_from_slice(key);
+            key.len()
+        }
+        _ => 0,
+    }
+}
+
+/// DTLS settings for [`PeerDatagrams`], which cannot tell OpenSSL its MTU.
+fn context_builder() -> Result<SslContextBuilder, ErrorStack> {
+    let mut builder = SslContextBuilder::new(SslMethod::dtls())?;
+    builder.set_options(SslOptions::NO_QUERY_MTU);
+    Ok(builder)
+}
+
+fn dtls_session(context: &SslContext) -> Result<Ssl, ErrorStack> {
+    let mut ssl = Ssl::new(context)?;
+    ssl.set_mtu(DTLS_MTU)?;
+    Ok(ssl)
+}
+
+fn server_context(config: &Config, shared: SharedConfig) -> Result<SslContext, ErrorStack> {
+    let mut builder = context_builder()?;
+    match (&config.dtls_certificate, &config.dtls_private_key) {
+        (Some(certificate), Some(private_key)) => {
+            builder.set_certificate_chain_file(certificate)?;
+            builder.set_private_key_file(private_key, SslFiletype::PEM)?;
+            builder.check_private_key()?;
+        }
+        _ => {
+            builder.set_cipher_list(PSK_CIPHERS)?;
+            // keys are looked up at each handshake, so that reloads apply
+            builder.set_psk_server_callback(move |_, identity, psk| {
+                let config = shared.borrow();
+                let key = identity
+                    .and_then(|identity| str::from_utf8(identity).ok())
+                    .and_then(|name| config.psk(name));
+                Ok(copy_psk(key.map(str::as_bytes), psk))
+            });
+        }
+    }
+    Ok(builder.build())
+}
+
+fn client_context(config: &ClientConfig, options: &DtlsOptions) -> Result<SslContext, ErrorStack> {
+    let mut builder = context_builder()?;
+    match &options.ca_file {
+        Some(ca_file) => {
+            builder.set_ca_file(ca_file)?;
+            builder.set_verify(SslVerifyMode::PEER);
+        }
+        None => {
+            builder.set_cipher_list(PSK_CIPHERS)?;
+            let identity = options.identity.clone();
+            let psk = config.preshared_key.clone();
+            builder.set_psk_client_callback(move |_, _, identity_buf, psk_buf| {
+                // the identity is NUL-terminated
+                let identity = [identity.as_bytes(), &[0]].concat();
+                if copy_psk(Some(&identity), identity_buf) == 0 {
+                    return Ok(0);
+                }
+                Ok(copy_psk(Some(psk.as_bytes()), psk_buf))
+            });
+        }
+    }
+    Ok(builder.build())
+}
+
+/// The relay's DTLS socket and the settings of its handshakes.
+#[derive(Debug)]
+pub(crate) struct DtlsListener {
+    socket: std::net::UdpSocket,
+    context: SslContext,
+}
+
+impl DtlsListener {
+    /// Binds `addr` and loads the certificate of `config`, if any; without one,
+    /// PSK identities are looked up in `shared`.
+    pub(crate) fn bind(
+        addr: SocketAddr,
+        config: &Config,
+        shared: SharedConfig,
+    ) -> io::Result<DtlsListener> {
+        let context = server_context(config, shared).map_err(io::Error::other)?;
+        Ok(DtlsListener {
+            socket: bind_socket(addr, config.interface.as_deref())?,
+            context,
+        })
+    }
+
+    /// Dispatches the datagrams received on the listener to one task per peer,
+    /// each answering a single pairing request with a ticket.
+    pub(crate) fn serve(
+        self,
+        config: SharedConfig,
+        registry: Registry,
+    ) -> io::Result<impl std::future::Future<Output = ()>> {
+        let socket = Arc::new(UdpSocket::from_std(self.socket)?);
+        let context = self.context;
+        Ok(async move {
+            let mut peers: HashMap<SocketAddr, mpsc::Sender<Vec<u8>>> = HashMap::new();
+            let mut buf = vec![0u8; 65535];
+            loop {
+                let (n, from) = match socket.recv_from(&mut buf).await {
+                    Ok(received) => received,
+                    Err(e) => {
+                        warn!("Unexpected error: {e}");
+                        continue;
+                    }
+                };
+                let current = config.borrow().clone();
+                if !current.is_peer_allowed(from.ip()) {
+                    trace!("Dropping datagram from disallowed peer {from}");
+                    continue;
+                }
+                if let Some(queue) = peers.get(&from).filter(|queue| !queue.is_closed()) {
+                    // datagrams beyond the queue are dropped, as the network would
+                    let _ = queue.try_send(buf[..n].to_vec());
+                    continue;
+                }
+                {
+                    let mut registry = registry.lock().expect("Registry lock poisoned");
+                    if !registry.rate_limiter.check(&current, from.ip()) {
+                        registry.counters.rate_limited += 1;
+                        continue;
+                    }
+                }
+                peers.retain(|_, queue| !queue.is_closed());
+                let (queue, incoming) = mpsc::channel(QUEUE_LEN);
+                let _ = queue.try_send(buf[..n].to_vec());
+                peers.insert(from, queue);
+
+                let stream = PeerDatagrams {
+                    socket: socket.clone(),
+                    peer: from,
+                    incoming: Incoming::Queue(incoming),
+                };
+                let (context, config, registry) =
+                    (context.clone(), config.clone(), registry.clone());
+                tokio::spawn(async move {
+                    let answered = time::timeout(
+                        HANDSHAKE_TIMEOUT,
+                        answer_pairing_request(&context, stream, &config, &registry),
+                    )
+                    .await;
+                    match answered {
+                        Ok(Ok(())) => (),
+                        Ok(Err(e)) => debug!("DTLS handshake with {from} failed: {e}"),
+                        Err(_) => debug!("DTLS handshake with {from} timed out"),
+                    }
+                });
+            }
+        })
+    }
+}
+
+/// Accepts a DTLS handshake, then answers the pairing request sent over it with
+/// a ticket.
+async fn answer_pairing_request(
+    context: &SslContext,
+    stream: PeerDatagrams,
+    config: &SharedConfig,
+    registry: &Registry,
+) -> io::Result<()> {
+    let peer = stream.peer;
+    let ssl = dtls_session(context).map_err(io::Error::other)?;
+    let mut stream = SslStream::new(ssl, stream).map_err(io::Error::other)?;
+    Pin::new(&mut stream)
+        .accept()
+        .await
+        .map_err(io::Error::other)?;
+    debug!("DTLS handshake with {peer} completed");
+
+    let mut buf = vec![0u8; 1024];
+    let n = stream.read(&mut buf).await?;
+    let config = config.borrow().clone();
+    let ticket = {
+        let mut registry = registry.lock().expect("Registry lock poisoned");
+        let request = match Ops::parse(&buf[..n], config.protocol_magic) {
+            Some((Ops::EstablishConnection, payload)) => PairingRequest::parse(payload),
+            _ => None,
+        };
+        let Some(request) = request else {
+            registry.counters.rejected_short_packet += 1;
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a pairing request",
+            ));
+        };
+        let Some(key) = config.find_presented_key(request.psk) else {
+            registry.counters.rejected_bad_psk += 1;
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "psk does not match",
+            ));
+        };
+        debug!("Issuing ticket to {peer} (key '{key}')");
+        registry.issue_ticket(request.secret, key)
+    };
+    stream
+        .write_all(&Ops::Ticket.message(config.protocol_magic, &ticket))
+        .await?;
+    let _ = stream.shutdown().await;
+    Ok(())
+}
+
+/// Performs a DTLS handshake with the relay's listener, then sends the pairing
+/// request of `config` over it. Returns the ticket the relay answered with, or
+/// `None` if it did not answer by `deadline`.
+pub(crate) async fn request_ticket(
+    config: &ClientConfig,
+    options: &DtlsOptions,
+    deadline: Instant,
+) -> io::Result<Option<[u8; RESUME_TOKEN_LEN]>> {
+    let context = client_context(config, options).map_err(io::Error::other)?;
+    let mut ssl = dtls_session(&context).map_err(io::Error::other)?;
+    if options.ca_file.is_some() {
+        let param = ssl.param_mut();
+        match &options.server_name {
+            Some(name) => param.set_host(name),
+            None => param.set_ip(config.relay.ip()),
+        }
+        .map_err(io::Error::other)?;
+        if let Some(name) = &options.server_name {
+            ssl.set_hostname(name).map_err(io::Error::other)?;
+        }
+    }
+
+    let listener = SocketAddr::new(config.relay.ip(), options.port);
+    let socket = UdpSocket::bind(match listener {
+        SocketAddr::V4(_) => SocketAddr::from(([0, 0, 0, 0], 0)),
+        SocketAddr::V6(_) => SocketAddr::from(([0u16; 8], 0)),
+    })
+    .await?;
+    let stream = PeerDatagrams {
+        socket: Arc::new(socket),
+        peer: listener,
+        incoming: Incoming::Socket,
+    };
+    let mut stream = SslStream::new(ssl, stream).map_err(io::Error::other)?;
+
+    let exchange = async {
+        Pin::new(&mut stream)
+            .connect()
+            .await
+            .map_err(io::Error::other)?;
+        let request = PairingRequest {
+            psk: config.preshared_key.as_bytes(),
+            secret: &config.secret,
+        };
+        stream
+            .write_all(&request.encode(config.protocol_magic))
+            .await?;
+        let mut buf = [0u8; 64];
+        loop {
+            let n = stream.read(&mut buf).await?;
+            if n == 0 {
+                return Ok(None);
+            }
+            match Ops::parse(&buf[..n], config.protocol_magic) {
+                Some((Ops::Ticket, ticket)) => return Ok(ticket.try_into().ok()),
+                _ => debug!("Ignoring unexpected message over DTLS"),
+            }
+        }
+    };
+    match time::timeout_at(deadline, exchange).await {
+        Ok(ticket) => ticket,
+        Err(_) => Ok(None),
+    }
+}
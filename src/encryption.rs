@@ -0,0 +1,251 @@
+//! Encryption of the traffic between a peer and the relay.
+//!
+//! When the relay runs with `encryption`, every datagram a paired peer sends
+//! or receives is sealed with ChaCha20-Poly1305, so that plaintext application
+//! protocols are not exposed on the way to the relay. Control messages (e.g.
+//! [`Ops::Disconnect`](crate::protocol::Ops)) stay in cleartext.
+//!
+//! Each direction has its own key, derived from the pre-shared key, the nonce
+//! of the v2 handshake and the session secret as
+//! `HMAC-SHA256(psk, label || nonce || secret)`, the label being
+//! `udprelay peer to relay` or `udprelay relay to peer`. Every handshake thus
+//! yields fresh keys.
+//!
+//! ```text
+//! +-----------+-------------------------+
+//! |  Counter  | Ciphertext and tag      |
+//! | (8 bytes) | (payload + 16 bytes)    |
+//! +-----------+-------------------------+
+//! ```
+//!
+//! The counter, big endian, starts at 0 and grows with every datagram; the
+//! AEAD nonce is four zero bytes followed by it. Receivers drop datagrams
+//! failing authentication and counters already seen, remembering the last
+//! [`REPLAY_WINDOW`] counters so that reordered datagrams still get through.
+
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Nonce};
+use zeroize::Zeroizing;
+
+use crate::auth;
+
+/// Length of the counter prefixing every sealed datagram.
+pub const COUNTER_LEN: usize = 8;
+/// Length of the authentication tag following the ciphertext.
+pub const TAG_LEN: usize = 16;
+/// Number of counters below the highest one received that are still accepted once.
+pub const REPLAY_WINDOW: u64 = 64;
+
+const PEER_TO_RELAY: &[u8] = b"udprelay peer to relay";
+const RELAY_TO_PEER: &[u8] = b"udprelay relay to peer";
+
+/// Which end of the link a [`SessionCipher`] is used at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Peer,
+    Relay,
+}
+
+/// Keys and counters of one peer's link with the relay.
+pub struct SessionCipher {
+    seal: ChaCha20Poly1305,
+    open: ChaCha20Poly1305,
+    next_counter: u64,
+    /// Highest counter received so far, and which of the [`REPLAY_WINDOW`]
+    /// counters below it were received (bit `n` for `highest - n`).
+    highest: Option<u64>,
+    seen: u64,
+}
+
+impl std::fmt::Debug for SessionCipher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SessionCipher")
+            .field("next_counter", &self.next_counter)
+            .field("highest", &self.highest)
+            .finish_non_exhaustive()
+    }
+}
+
+fn derive_cipher(psk: &[u8], label: &[u8], nonce: &[u8], secret: &[u8]) -> ChaCha20Poly1305 {
+    let key = Zeroizing::new(auth::sign(psk, &[label, nonce].concat(), secret));
+    ChaCha20Poly1305::new_from_slice(&*key).expect("ChaCha20-Poly1305 takes 32-byte keys")
+}
+
+fn aead_nonce(counter: u64) -> Nonce {
+    let mut nonce = [0u8; 12];
+    nonce[4..].copy_from_slice(&counter.to_be_bytes());
+    nonce.into()
+}
+
+impl SessionCipher {
+    /// Derives the keys of a session paired with `nonce` and `secret`.
+    pub fn new(psk: &[u8], nonce: &[u8], secret: &[u8], role: Role) -> SessionCipher {
+        let (seal, open) = match role {
+            Role::Peer => (PEER_TO_RELAY, RELAY_TO_PEER),
+            Role::Relay => (RELAY_TO_PEER, PEER_TO_RELAY),
+        };
+        SessionCipher {
+            seal: derive_cipher(psk, seal, nonce, secret),
+            open: derive_cipher(psk, open, nonce, secret),
+            next_counter: 0,
+            highest: None,
+            seen: 0,
+        }
+    }
+
+    /// Seals `payload` into a datagram for the other end.
+    pub fn seal(&mut self, payload: &[u8]) -> Vec<u8> {
+        let counter = self.next_counter;
+        self.next_counter += 1;
+        let ciphertext = self
+            .seal
+            .encrypt(&aead_nonce(counter), payload)
+            .expect("ChaCha20-Poly1305 can seal any datagram");
+        [&counter.to_be_bytes()[..], &ciphertext].concat()
+    }
+
+    /// Opens a datagram from the other end, or returns `None` if it is not
+    /// authentic or was already received.
+    pub fn open(&mut self, datagram: &[u8]) -> Option<Vec<u8>> {
+        if datagram.len() < COUNTER_LEN + TAG_LEN {
+            return None;
+        }
+        let (counter, ciphertext) = datagram.split_at(COUNTER_LEN);
+        let counter = u64::from_be_bytes(counter.try_into().expect("split at COUNTER_LEN"));
+        if self.is_replayed(counter) {
+            return None;
+        }
+        let payload = self.open.decrypt(&aead_nonce(counter), ciphertext).ok()?;
+        self.mark_received(counter);
+        Some(payload)
+    }
+
+    fn is_replayed(&self, counter: u64) -> bool {
+        match self.highest {
+            Some(highest) if counter <= highest => {
+                let age = highest - counter;
+                age >= REPLAY_WINDOW || self.seen & (1 << age) != 0
+            }
+            _ => false,
+        }
+    }
+
+    fn mark_received(&mut self, counter: u64) {
+        match self.highest {
+            Some(highest) if counter <= highest => self.seen |= 1 << (highest - counter),
+            Some(highest) => {
+                let shift = counter - highest;
+                self.seen = if shift >= REPLAY_WINDOW {
+                    0
+                } else {
+                    self.seen << shift
+                } | 1;
+                self.highest = Some(counter);
+            }
+            None => {
+                self.seen = 1;
+                self.highest = Some(counter);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PSK: &[u8] = b"uNYDA5QRcvYgp2gfS5v5";
+    const NONCE: [u8; auth::NONCE_LEN] = [7; auth::NONCE_LEN];
+
+    fn ciphers() -> (SessionCipher, SessionCipher) {
+        (
+            SessionCipher::new(PSK, &NONCE, b"secret", Role::Peer),
+            SessionCipher::new(PSK, &NONCE, b"secret", Role::Relay),
+        )
+    }
+
+    fn receiver() -> SessionCipher {
+        ciphers().1
+    }
+
+    #[test]
+    fn sealed_datagram_opens_once() {
+        let (mut peer, mut relay) = ciphers();
+        let datagram = peer.seal(b"hello");
+        assert_eq!(relay.open(&datagram).as_deref(), Some(&b"hello"[..]));
+        assert_eq!(relay.open(&datagram), None);
+        let reply = relay.seal(b"world");
+        assert_eq!(peer.open(&reply).as_deref(), Some(&b"world"[..]));
+    }
+
+    #[test]
+    fn forged_datagram_is_not_marked_received() {
+        let (mut peer, mut relay) = ciphers();
+        let datagram = peer.seal(b"hello");
+        let mut forged = datagram.clone();
+        *forged.last_mut().expect("sealed datagrams carry a tag") ^= 1;
+        assert_eq!(relay.open(&forged), None);
+        assert_eq!(relay.open(&datagram).as_deref(), Some(&b"hello"[..]));
+    }
+
+    #[test]
+    fn duplicate_counter_is_replayed() {
+        let mut cipher = receiver();
+        assert!(!cipher.is_replayed(0));
+        cipher.mark_received(0);
+        assert!(cipher.is_replayed(0));
+        cipher.mark_received(1);
+        assert!(cipher.is_replayed(0));
+        assert!(cipher.is_replayed(1));
+    }
+
+    #[test]
+    fn late_counter_within_window_is_accepted_once() {
+        let mut cipher = receiver();
+        cipher.mark_received(10);
+        assert!(!cipher.is_replayed(5));
+        cipher.mark_received(5);
+        assert!(cipher.is_replayed(5));
+        assert!(!cipher.is_replayed(6));
+    }
+
+    #[test]
+    fn counter_out_of_window_is_replayed() {
+        let mut cipher = receiver();
+        cipher.mark_received(100);
+        assert!(cipher.is_replayed(100 - REPLAY_WINDOW));
+        assert!(cipher.is_replayed(0));
+        assert!(!cipher.is_replayed(100 - REPLAY_WINDOW + 1));
+    }
+
+    #[test]
+    fn counter_jump_slides_window() {
+        let mut cipher = receiver();
+        cipher.mark_received(5);
+        cipher.mark_received(5 + REPLAY_WINDOW - 1);
+        assert!(cipher.is_replayed(5));
+        assert!(!cipher.is_replayed(6));
+
+        let mut cipher = receiver();
+        cipher.mark_received(5);
+        cipher.mark_received(5 + REPLAY_WINDOW);
+        assert!(cipher.is_replayed(5));
+        assert!(!cipher.is_replayed(6));
+        assert!(cipher.is_replayed(5 + REPLAY_WINDOW));
+    }
+
+    #[test]
+    fn counter_jump_past_window_clears_it() {
+        let mut cipher = receiver();
+        for counter in 0..REPLAY_WINDOW {
+            cipher.mark_received(counter);
+        }
+        let jump = 3 * REPLAY_WINDOW;
+        cipher.mark_received(jump);
+        assert!(cipher.is_replayed(jump));
+        assert!(cipher.is_replayed(jump - REPLAY_WINDOW));
+        for counter in jump - REPLAY_WINDOW + 1..jump {
+            assert!(!cipher.is_replayed(counter));
+        }
+    }
+}
@@ -0,0 +1,293 @@
+//! Optional forward error correction between a peer and the relay, for lossy
+//! last-mile links.
+//!
+//! A peer asks for it by setting [`OPTION_FEC`] in its
+//! [`PairingResponse`](crate::protocol::PairingResponse), and a relay running
+//! with `fec` agrees by appending the options byte to the secret in its
+//! [`Ops::Ack`], as for [reliable delivery](crate::reliable). From then on,
+//! both ends of that link send datagrams in blocks of [`Ops::FecData`] frames,
+//! each block followed by an [`Ops::FecParity`] frame:
+//!
+//! ```text
+//! +---------+----------+---------+---------+
+//! | ff 29   | Block    | Index   | Payload |
+//! | 2 bytes | 4 bytes  | 1 byte  |         |
+//! +---------+----------+---------+---------+
+//! +---------+----------+---------+---------+
+//! | ff 2a   | Block    | Count   | Parity  |
+//! | 2 bytes | 4 bytes  | 1 byte  |         |
+//! +---------+----------+---------+---------+
+//! ```
+//!
+//! Block numbers, big endian, start at 0 and wrap around; indices count the
+//! frames of a block from 0. The parity is the XOR of every payload of the
+//! block, each prefixed by its length as two big-endian bytes and padded with
+//! zeros to the longest. A receiver missing a single frame of a block rebuilds
+//! it from the parity and the other frames, so that one loss in every
+//! `block size + 1` datagrams is recovered without waiting for a retransmission.
+//! Frames are delivered as they arrive; a rebuilt one once it is.
+//!
+//! Like reliable delivery, the layer works hop by hop, and payloads are sealed
+//! before being framed when encryption is on. A link cannot have both.
+
+use std::collections::HashMap;
+
+use crate::protocol::Ops;
+
+/// Option bit of a [`PairingResponse`](crate::protocol::PairingResponse)
+/// asking for forward error correction.
+pub const OPTION_FEC: u8 = 0x02;
+
+/// Data frames per block, and thus per parity frame, by default.
+pub const DEFAULT_BLOCK_SIZE: u8 = 4;
+
+/// Largest block size, as received frames are tracked in a bitmap.
+pub const MAX_BLOCK_SIZE: u8 = 64;
+
+/// Blocks before the latest one received that can still be completed.
+const KEPT_BLOCKS: i32 = 16;
+
+const HEADER_LEN: usize = 5;
+
+/// XORs `payload`, prefixed by its length, into `parity`, growing it as needed.
+fn fold(parity: &mut Vec<u8>, payload: &[u8]) {
+    let len = u16::try_from(payload.len())
+        .unwrap_or(u16::MAX)
+        .to_be_bytes();
+    if parity.len() < 2 + payload.len() {
+        parity.resize(2 + payload.len(), 0);
+    }
+    for (byte, x) in parity.iter_mut().zip(len.iter().chain(payload)) {
+        *byte ^= x;
+    }
+}
+
+fn header(block: u32, index: u8) -> [u8; HEADER_LEN] {
+    let mut header = [0u8; HEADER_LEN];
+    header[..4].copy_from_slice(&block.to_be_bytes());
+    header[4] = index;
+    header
+}
+
+fn parse_header(frame: &[u8]) -> Option<(u32, u8, &[u8])> {
+    let block = u32::from_be_bytes(frame.get(..4)?.try_into().ok()?);
+    Some((block, *frame.get(4)?, &frame[HEADER_LEN..]))
+}
+
+/// What a receiver knows of a block.
+#[derive(Debug, Default)]
+struct Block {
+    /// Indices of the data frames received, as a bitmap.
+    received: u64,
+    /// XOR of the data frames received, see [`fold`].
+    folded: Vec<u8>,
+    /// Number of data frames and parity, once the parity frame arrived.
+    parity: Option<(u8, Vec<u8>)>,
+    /// Whether the missing data frame was rebuilt, so that it is not delivered
+    /// again if it arrives after all.
+    complete: bool,
+}
+
+impl Block {
+    /// Rebuilds the single data frame missing, if that is the case.
+    fn recover(&mut self) -> Option<Vec<u8>> {
+        let (count, parity) = self.parity.as_ref()?;
+        if self.complete || self.received.count_ones() + 1 != u32::from(*count) {
+            return None;
+        }
+        self.complete = true;
+        let mut rebuilt = parity.clone();
+        for (byte, x) in rebuilt.iter_mut().zip(&self.folded) {
+            *byte ^= x;
+        }
+        let len = usize::from(u16::from_be_bytes(rebuilt.get(..2)?.try_into().ok()?));
+        rebuilt.get(2..2 + len).map(<[u8]>::to_vec)
+    }
+}
+
+/// Forward error correction state of one end of a link, for both directions.
+#[derive(Debug)]
+pub struct Fec {
+    block_size: u8,
+    /// Protocol magic of the frames.
+    magic: u8,
+    /// Block being sent, the next index in it and the parity so far.
+    block: u32,
+    index: u8,
+    parity: Vec<u8>,
+    /// Latest block received.
+    latest: Option<u32>,
+    blocks: HashMap<u32, Block>,
+}
+
+impl Fec {
+    /// Creates the state of a new link, sending a parity frame after every
+    /// `block_size` data frames (between 1 and [`MAX_BLOCK_SIZE`]), under the
+    /// protocol `magic`.
+    pub fn new(block_size: u8, magic: u8) -> Fec {
+        Fec {
+            block_size: block_size.clamp(1, MAX_BLOCK_SIZE),
+            magic,
+            block: 0,
+            index: 0,
+            parity: Vec::new(),
+            latest: None,
+            blocks: HashMap::new(),
+        }
+    }
+
+    /// Frames `payload` for the other end, also returning the parity frame to
+    /// send after it when it completes a block.
+    pub fn encode(&mut self, payload: &[u8]) -> (Vec<u8>, Option<Vec<u8>>) {
+        let frame = Ops::FecData.message(
+            self.magic,
+            &[&header(self.block, self.index)[..], payload].concat(),
+        );
+        fold(&mut self.parity, payload);
+        self.index += 1;
+        if self.index < self.block_size {
+            return (frame, None);
+        }
+        let parity = Ops::FecParity.message(
+            self.magic,
+            &[
+                &header(self.block, self.index)[..],
+                &std::mem::take(&mut self.parity),
+            ]
+            .concat(),
+        );
+        self.block = self.block.wrapping_add(1);
+        self.index = 0;
+        (frame, Some(parity))
+    }
+
+    /// Tracks `block`, forgetting the blocks too old to be completed. Returns
+    /// `None` if `block` itself is.
+    fn block(&mut self, block: u32) -> Option<&mut Block> {
+        match self.latest {
+            Some(latest) if (block.wrapping_sub(latest) as i32) < -KEPT_BLOCKS => return None,
+            Some(latest) if (block.wrapping_sub(latest) as i32) <= 0 => (),
+            _ => {
+                self.latest = Some(block);
+                self.blocks
+                    .retain(|&kept, _| (block.wrapping_sub(kept) as i32) <= KEPT_BLOCKS);
+            }
+        }
+        Some(self.blocks.entry(block).or_default())
+    }
+
+    /// Takes the payload of an [`Ops::FecData`] frame from the other end,
+    /// returning the payloads to deliver: its own, unless it was already
+    /// received or rebuilt, and the one it allowed to rebuild, if any. Returns
+    /// `None` if the frame is malformed.
+    pub fn receive_data(&mut self, frame: &[u8]) -> Option<Vec<Vec<u8>>> {
+        let (block, index, payload) = parse_header(frame)?;
+        if index >= MAX_BLOCK_SIZE {
+            return None;
+        }
+        let Some(state) = self.block(block) else {
+            // too late to help rebuild anything, but still worth delivering
+            return Some(vec![payload.to_vec()]);
+        };
+        if state.complete || state.received & 1 << index != 0 {
+            return Some(Vec::new());
+        }
+        state.received |= 1 << index;
+        fold(&mut state.folded, payload);
+        let mut delivered = vec![payload.to_vec()];
+        delivered.extend(state.recover());
+        Some(delivered)
+    }
+
+    /// Takes the payload of an [`Ops::FecParity`] frame from the other end,
+    /// returning the payload it allowed to rebuild, if any.
+    pub fn receive_parity(&mut self, frame: &[u8]) -> Option<Vec<u8>> {
+        let (block, count, parity) = parse_header(frame)?;
+        if count == 0 || count > MAX_BLOCK_SIZE {
+            return None;
+        }
+        let state = self.block(block)?;
+        if state.parity.is_none() {
+            state.parity = Some((count, parity.to_vec()));
+        }
+        state.recover()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::DEFAULT_MAGIC;
+
+    /// Data frames and the parity frame of one block of `payloads`, as bodies.
+    fn encode_block(fec: &mut Fec, payloads: &[&[u8]]) -> (Vec<Vec<u8>>, Vec<u8>) {
+        let mut frames = Vec::new();
+        let mut parity = None;
+        for payload in payloads {
+            let (frame, completed) = fec.encode(payload);
+            frames.push(body(&frame, Ops::FecData));
+            parity = completed.map(|frame| body(&frame, Ops::FecParity));
+        }
+        (frames, parity.expect("payloads fill a block"))
+    }
+
+    fn body(frame: &[u8], op: Ops) -> Vec<u8> {
+        match Ops::parse(frame, DEFAULT_MAGIC) {
+            Some((parsed, body)) if parsed == op => body.to_vec(),
+            _ => panic!("not a {op:?} frame"),
+        }
+    }
+
+    #[test]
+    fn rebuilds_a_single_loss_from_parity() {
+        let payloads: [&[u8]; 4] = [b"first", b"the longest one", b"", b"4"];
+        let mut sender = Fec::new(4, DEFAULT_MAGIC);
+        let mut receiver = Fec::new(4, DEFAULT_MAGIC);
+        let (frames, parity) = encode_block(&mut sender, &payloads);
+        for index in [0, 2, 3] {
+            let delivered = receiver
+                .receive_data(&frames[index])
+                .expect("well-formed frame");
+            assert_eq!(delivered, [payloads[index].to_vec()]);
+        }
+        assert_eq!(receiver.receive_parity(&parity), Some(payloads[1].to_vec()));
+        // arriving after all, the lost frame is not delivered twice
+        assert_eq!(receiver.receive_data(&frames[1]), Some(Vec::new()));
+    }
+
+    #[test]
+    fn rebuilds_once_parity_arrived_before_the_last_frame() {
+        let payloads: [&[u8]; 3] = [b"lost", b"b", b"c"];
+        let mut sender = Fec::new(3, DEFAULT_MAGIC);
+        let mut receiver = Fec::new(3, DEFAULT_MAGIC);
+        let (frames, parity) = encode_block(&mut sender, &payloads);
+        assert!(receiver.receive_data(&frames[1]).is_some());
+        assert_eq!(receiver.receive_parity(&parity), None);
+        let delivered = receiver
+            .receive_data(&frames[2])
+            .expect("well-formed frame");
+        assert_eq!(delivered, [b"c".to_vec(), b"lost".to_vec()]);
+    }
+
+    #[test]
+    fn cannot_rebuild_two_losses() {
+        let mut sender = Fec::new(4, DEFAULT_MAGIC);
+        let mut receiver = Fec::new(4, DEFAULT_MAGIC);
+        let (frames, parity) = encode_block(&mut sender, &[b"a", b"b", b"c", b"d"]);
+        for frame in &frames[2..] {
+            assert!(receiver.receive_data(frame).is_some());
+        }
+        assert_eq!(receiver.receive_parity(&parity), None);
+    }
+
+    #[test]
+    fn malformed_frames_are_refused() {
+        let mut receiver = Fec::new(4, DEFAULT_MAGIC);
+        assert!(receiver.receive_data(&[0, 0, 0, 0]).is_none());
+        assert!(receiver.receive_data(&header(0, MAX_BLOCK_SIZE)).is_none());
+        assert!(receiver.receive_parity(&header(0, 0)).is_none());
+        assert!(receiver
+            .receive_parity(&header(0, MAX_BLOCK_SIZE + 1))
+            .is_none());
+    }
+}
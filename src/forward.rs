@@ -0,0 +1,146 @@
+//! Static forwarding mode: every datagram is forwarded to a fixed target,
+//! without any handshake, and replies are routed back to the original sender.
+//!
+//! Each sender gets its own upstream socket connected to the target, so the
+//! target sees one source port per sender. Senders expire like pairs, after
+//! [`Config::timeout_connection_inactivities`](crate::Config).
+
+use std::collections::hash_map::Entry;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+
+use socket2::SockRef;
+use tokio::net::UdpSocket;
+use tokio::task::AbortHandle;
+use tracing::{debug, info, trace, warn};
+
+use crate::peer::{send_to, ExpiringTimer, Side};
+use crate::relay::{Registry, SharedConfig};
+
+#[derive(Debug)]
+pub(crate) struct ForwardSession {
+    upstream: Arc<UdpSocket>,
+    /// Target the sender was first forwarded to, kept across reloads.
+    target: SocketAddr,
+    pub(crate) last_accessed: ExpiringTimer,
+    reply_task: AbortHandle,
+}
+
+impl Drop for ForwardSession {
+    fn drop(&mut self) {
+        self.reply_task.abort();
+    }
+}
+
+fn connect_upstream(target: SocketAddr) -> io::Result<UdpSocket> {
+    let unspecified: IpAddr = match target {
+        SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    };
+    let socket = std::net::UdpSocket::bind((unspecified, 0))?;
+    socket.connect(target)?;
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket)
+}
+
+/// Receives datagrams and forwards them to the current `forward_to` target,
+/// opening an upstream socket for every new sender.
+pub(crate) async fn forward_packets(
+    config: SharedConfig,
+    registry: Registry,
+    socket: Arc<UdpSocket>,
+) {
+    let buf_size = config.borrow().recv_buffer_size;
+    let mut buf = vec![0u8; buf_size];
+    loop {
+        let (n, from) = match socket.recv_from(&mut buf).await {
+            Ok((n, from)) if n > 0 => (n, from),
+            Ok(_) => continue,
+            Err(e) => {
+                warn!("Unexpected error: {e}");
+                continue;
+            }
+        };
+        let (allowed, target) = {
+            let config = config.borrow();
+            (config.is_peer_allowed(from.ip()), config.forward_to)
+        };
+        if !allowed {
+            trace!("Dropping datagram from disallowed peer {from}");
+            continue;
+        }
+        let Some(target) = target else {
+            continue;
+        };
+
+        let mut service = registry.lock().expect("Registry lock poisoned");
+        let service = &mut *service;
+        let session = match service.forwards.entry(from) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let upstream = match connect_upstream(target) {
+                    Ok(upstream) => Arc::new(upstream),
+                    Err(e) => {
+                        warn!("Cannot open upstream socket for {from}: {e}");
+                        continue;
+                    }
+                };
+                info!("Forwarding {from} to {target}");
+                let reply_task = tokio::spawn(forward_replies(
+                    registry.clone(),
+                    socket.clone(),
+                    upstream.clone(),
+                    from,
+                    buf_size,
+                ));
+                entry.insert(ForwardSession {
+                    upstream,
+                    target,
+                    last_accessed: ExpiringTimer::new(),
+                    reply_task: reply_task.abort_handle(),
+                })
+            }
+        };
+        session.last_accessed.access();
+        // Tokio only learns a fresh socket is writable once the reactor polls it,
+        // so send directly rather than dropping the first datagram of a sender.
+        match SockRef::from(&*session.upstream).send(&buf[..n]) {
+            Ok(_) => (),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => (),
+            Err(e) => debug!("Cannot forward datagram from {from}: {e}"),
+        }
+        service.counters.relayed_packets[Side::First as usize] += 1;
+        service.counters.relayed_bytes[Side::First as usize] += n as u64;
+        trace!("Forwarding message {from} => {}", session.target);
+    }
+}
+
+/// Routes replies arriving on `upstream` back to `client`.
+async fn forward_replies(
+    registry: Registry,
+    socket: Arc<UdpSocket>,
+    upstream: Arc<UdpSocket>,
+    client: SocketAddr,
+    buf_size: usize,
+) {
+    let mut buf = vec![0u8; buf_size];
+    loop {
+        let n = match upstream.recv(&mut buf).await {
+            Ok(n) => n,
+            Err(e) => {
+                // e.g. the target refusing the previous datagram
+                debug!("Cannot receive reply for {client}: {e}");
+                continue;
+            }
+        };
+        let mut service = registry.lock().expect("Registry lock poisoned");
+        if let Some(session) = service.forwards.get_mut(&client) {
+            session.last_accessed.access();
+        }
+        service.counters.relayed_packets[Side::Second as usize] += 1;
+        service.counters.relayed_bytes[Side::Second as usize] += n as u64;
+        send_to(&socket, &buf[..n], &client);
+        trace!("Forwarding reply => {client}");
+    }
+}
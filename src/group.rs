@@ -0,0 +1,129 @@
+//! Group sessions: in group mode, every peer presenting the same secret joins
+//! one group instead of forming a strict pair, and each datagram is relayed to
+//! every other member of its group.
+//!
+//! Members leave a group after
+//! [`Config::timeout_connection_inactivities`](crate::Config) without sending
+//! anything; the group goes away with its last member.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::str;
+use std::sync::Arc;
+use std::time::Instant;
+
+use tokio::net::UdpSocket;
+use tracing::trace;
+
+use crate::batch::Outbox;
+use crate::encryption::SessionCipher;
+use crate::metrics::Counters;
+use crate::peer::{ExpiringTimer, Side};
+
+#[derive(Debug)]
+pub(crate) struct Member {
+    pub(crate) socket: Arc<UdpSocket>,
+    /// The member that created the group is on the first side, every other one
+    /// on the second.
+    pub(crate) side: Side,
+    pub(crate) last_accessed: ExpiringTimer,
+    /// Datagrams relayed from this member.
+    pub(crate) packets: u64,
+    pub(crate) bytes: u64,
+    pub(crate) joined: Instant,
+    /// Keys of the member's link with the relay, when it is encrypted.
+    pub(crate) cipher: Option<SessionCipher>,
+    /// Datagrams to this member that could not be sent since it was last heard
+    /// from, see [`Config::max_send_failures`](crate::Config::max_send_failures).
+    pub(crate) send_failures: u32,
+}
+
+#[derive(Debug)]
+pub(crate) struct Group {
+    /// Name of the key every member authenticated with.
+    pub(crate) key: String,
+    pub(crate) members: HashMap<SocketAddr, Member>,
+    /// When the group was created.
+    pub(crate) started: Instant,
+}
+
+impl Group {
+    pub(crate) fn new(key: &str) -> Group {
+        Group {
+            key: key.to_owned(),
+            members: HashMap::new(),
+            started: Instant::now(),
+        }
+    }
+
+    pub(crate) fn join(
+        &mut self,
+        addr: SocketAddr,
+        socket: &Arc<UdpSocket>,
+        cipher: Option<SessionCipher>,
+    ) {
+        let side = if self.members.is_empty() {
+            Side::First
+        } else {
+            Side::Second
+        };
+        self.members.insert(
+            addr,
+            Member {
+                socket: socket.clone(),
+                side,
+                last_accessed: ExpiringTimer::new(),
+                packets: 0,
+                bytes: 0,
+                joined: Instant::now(),
+                cipher,
+                send_failures: 0,
+            },
+        );
+    }
+
+    /// Relays `buffer` from the member at `from` to every other member.
+    pub(crate) fn relay(
+        &mut self,
+        counters: &mut Counters,
+        buffer: &[u8],
+        from: &SocketAddr,
+        outbox: &mut Outbox,
+    ) {
+        let Some(sender) = self.members.get_mut(from) else {
+            return;
+        };
+        sender.last_accessed.access();
+        sender.send_failures = 0;
+        let opened = match &mut sender.cipher {
+            Some(cipher) => {
+                let Some(payload) = cipher.open(buffer) else {
+                    trace!("Dropping undecryptable datagram from {from}");
+                    counters.undecryptable_packets += 1;
+                    return;
+                };
+                Some(payload)
+            }
+            None => None,
+        };
+        let buffer = opened.as_deref().unwrap_or(buffer);
+        sender.packets += 1;
+        sender.bytes += buffer.len() as u64;
+        counters.relayed_packets[sender.side as usize] += 1;
+        counters.relayed_bytes[sender.side as usize] += buffer.len() as u64;
+        for (addr, member) in &mut self.members {
+            if addr == from {
+                continue;
+            }
+            match &mut member.cipher {
+                Some(cipher) => outbox.push(&member.socket, *addr, &cipher.seal(buffer)),
+                None => outbox.push(&member.socket, *addr, buffer),
+            }
+        }
+        trace!(
+            "Relaying message {from} => {} => {} other member(s)",
+            str::from_utf8(buffer).unwrap_or("[some bytes]").trim(),
+            self.members.len() - 1
+        );
+    }
+}
@@ -0,0 +1,110 @@
+//! Hooks fired when a pair is created or torn down, so that external systems
+//! can react to peers connecting, e.g. by opening firewall pinholes, starting a
+//! recording or alerting.
+//!
+//! `on_pair` and `on_teardown` are shell commands, run with the details of the
+//! session in their environment:
+//!
+//! | Variable | Value |
+//! |---|---|
+//! | `UDPRELAY_EVENT` | `paired` or `closed` |
+//! | `UDPRELAY_SECRET` | session secret |
+//! | `UDPRELAY_KEY` | name of the key the peers authenticated with |
+//! | `UDPRELAY_PEERS` | addresses of both peers, separated by a comma |
+//! | `UDPRELAY_REASON` | why the pair was torn down (`closed` only) |
+//! | `UDPRELAY_EVENT_JSON` | the whole event, as written to the [accounting log](crate::accounting) |
+//!
+//! The webhook receives the same JSON object in an HTTP `POST` for both
+//! events. Hooks run in the background: failures are logged, and never hold up
+//! relaying.
+
+use std::process::Stdio;
+
+use serde_json::Value;
+use tokio::process::Command;
+use tracing::{debug, warn};
+
+use crate::http;
+
+/// Commands and webhook of a relay, see [`crate::hooks`].
+#[derive(Debug)]
+pub(crate) struct Hooks {
+    pub(crate) on_pair: Option<String>,
+    pub(crate) on_teardown: Option<String>,
+    pub(crate) webhook_url: Option<String>,
+}
+
+impl Hooks {
+    /// Fires the hooks of a `paired` event.
+    pub(crate) fn paired(&self, event: &Value) {
+        self.fire(self.on_pair.as_deref(), event);
+    }
+
+    /// Fires the hooks of a `closed` event.
+    pub(crate) fn closed(&self, event: &Value) {
+        self.fire(self.on_teardown.as_deref(), event);
+    }
+
+    fn fire(&self, command: Option<&str>, event: &Value) {
+        if let Some(command) = command {
+            run(command, event);
+        }
+        if let Some(url) = &self.webhook_url {
+            let url = url.clone();
+            let body = event.to_string();
+            tokio::spawn(async move {
+                match http::post(&url, &body).await {
+                    Ok(200..=299) => (),
+                    Ok(status) => warn!("Webhook {url} answered with status {status}"),
+                    Err(e) => warn!("Cannot call webhook {url}: {e}"),
+                }
+            });
+        }
+    }
+}
+
+/// Starts `command` with the details of `event` in its environment, and
+/// waits for it in the background.
+fn run(command: &str, event: &Value) {
+    let field = |name: &str| event[name].as_str().unwrap_or_default().to_owned();
+    let peers = event["peers"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|peer| peer.as_str().or_else(|| peer["addr"].as_str()))
+        .collect::<Vec<_>>()
+        .join(",");
+    #[cfg(unix)]
+    let mut shell = Command::new("sh");
+    #[cfg(unix)]
+    shell.arg("-c");
+    #[cfg(not(unix))]
+    let mut shell = Command::new("cmd");
+    #[cfg(not(unix))]
+    shell.arg("/C");
+    let child = shell
+        .arg(command)
+        .env("UDPRELAY_EVENT", field("event"))
+        .env("UDPRELAY_SECRET", field("secret"))
+        .env("UDPRELAY_KEY", field("key"))
+        .env("UDPRELAY_PEERS", peers)
+        .env("UDPRELAY_REASON", field("reason"))
+        .env("UDPRELAY_EVENT_JSON", event.to_string())
+        .stdin(Stdio::null())
+        .spawn();
+    let mut child = match child {
+        Ok(child) => child,
+        Err(e) => {
+            warn!("Cannot run hook '{command}': {e}");
+            return;
+        }
+    };
+    let command = command.to_owned();
+    tokio::spawn(async move {
+        match child.wait().await {
+            Ok(status) if status.success() => debug!("Hook '{command}' succeeded"),
+            Ok(status) => warn!("Hook '{command}' failed: {status}"),
+            Err(e) => warn!("Cannot wait for hook '{command}': {e}"),
+        }
+    });
+}
@@ -0,0 +1,128 @@
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time;
+use tracing::{debug, warn};
+
+/// Response of a route: status code, content type and body.
+pub(crate) type Response = (u16, &'static str, String);
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Minimal HTTP server answering `GET` requests, one request per connection.
+/// `route` maps a path to a response, or `None` for a 404.
+pub(crate) async fn serve<F>(listener: TcpListener, route: F)
+where
+    F: Fn(&str) -> Option<Response> + Send + Sync + 'static,
+{
+    let route = Arc::new(route);
+    loop {
+        let (stream, from) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!("Cannot accept HTTP connection: {e}");
+                continue;
+            }
+        };
+        let route = route.clone();
+        tokio::spawn(async move {
+            match time::timeout(REQUEST_TIMEOUT, respond(stream, route.as_ref())).await {
+                Ok(Ok(())) => (),
+                Ok(Err(e)) => debug!("HTTP request from {from} failed: {e}"),
+                Err(_) => debug!("HTTP request from {from} timed out"),
+            }
+        });
+    }
+}
+
+async fn respond<F>(mut stream: TcpStream, route: &F) -> io::Result<()>
+where
+    F: Fn(&str) -> Option<Response>,
+{
+    // only the request line matters; headers and bodies are ignored
+    let mut buf = [0u8; 2048];
+    let mut n = 0;
+    while n < buf.len() && !buf[..n].windows(2).any(|w| w == b"\r\n") {
+        match stream.read(&mut buf[n..]).await? {
+            0 => break,
+            read => n += read,
+        }
+    }
+    let request = String::from_utf8_lossy(&buf[..n]);
+    let mut request_line = request.lines().next().unwrap_or_default().split(' ');
+
+    let (status, content_type, body) = match (request_line.next(), request_line.next()) {
+        (Some("GET"), Some(path)) => {
+            route(path).unwrap_or_else(|| (404, "text/plain", "not found\n".to_owned()))
+        }
+        _ => (405, "text/plain", "method not allowed\n".to_owned()),
+    };
+    let reason = match status {
+        200 => "OK",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        503 => "Service Unavailable",
+        _ => "",
+    };
+    let header = format!(
+        "HTTP/1.1 {status} {reason}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    );
+    stream.write_all(header.as_bytes()).await?;
+    stream.write_all(body.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// Host (with its port, 80 by default) and path of an `http://` URL; `None`
+/// for other URLs, as TLS is not supported.
+pub(crate) fn split_url(url: &str) -> Option<(String, &str)> {
+    let rest = url.strip_prefix("http://")?;
+    let (authority, path) = match rest.find('/') {
+        Some(slash) => rest.split_at(slash),
+        None => (rest, "/"),
+    };
+    if authority.is_empty() {
+        return None;
+    }
+    // a port follows the last colon, unless it closes an IPv6 address
+    match authority.rsplit_once(':') {
+        Some((_, port)) if !port.ends_with(']') => Some((authority.to_owned(), path)),
+        _ => Some((format!("{authority}:80"), path)),
+    }
+}
+
+/// Posts `body` as JSON to the `http://` `url`, returning the status code of
+/// the response.
+pub(crate) async fn post(url: &str, body: &str) -> io::Result<u16> {
+    let (authority, path) = split_url(url)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not an http:// URL"))?;
+    let exchange = async {
+        let mut stream = TcpStream::connect(&authority).await?;
+        let host = authority.trim_end_matches(":80");
+        let request = format!(
+            "POST {path} HTTP/1.1\r\nHost: {host}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        );
+        stream.write_all(request.as_bytes()).await?;
+        // only the status line matters
+        let mut buf = [0u8; 256];
+        let mut n = 0;
+        while n < buf.len() && !buf[..n].windows(2).any(|w| w == b"\r\n") {
+            match stream.read(&mut buf[n..]).await? {
+                0 => break,
+                read => n += read,
+            }
+        }
+        String::from_utf8_lossy(&buf[..n])
+            .split(' ')
+            .nth(1)
+            .and_then(|status| status.parse().ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "malformed HTTP response"))
+    };
+    time::timeout(REQUEST_TIMEOUT, exchange)
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "no response in time"))?
+}
@@ -0,0 +1,48 @@
+//! UDP relay pairing peers by a shared session secret.
+//!
+//! Peers authenticate by answering a challenge with an HMAC keyed by a
+//! pre-shared key (see [`auth`]) and present a session secret; the
+//! first two peers presenting the same secret are paired, after which every
+//! datagram from one peer is relayed to the other. See [`Relay`] for embedding
+//! the relay, and [`protocol`] for the wire format.
+//!
+//! Events are reported through [`tracing`]: pairing lifecycle at `info`,
+//! handshake details at `debug` and every relayed datagram at `trace`.
+
+mod accounting;
+pub mod auth;
+mod batch;
+mod capture;
+pub mod channels;
+pub mod client;
+mod cluster;
+pub mod compression;
+#[cfg(unix)]
+pub mod control;
+#[cfg(feature = "dtls")]
+mod dtls;
+pub mod encryption;
+pub mod fec;
+mod forward;
+mod group;
+mod hooks;
+mod http;
+pub mod logfile;
+mod metrics;
+mod peer;
+pub mod protocol;
+mod ratelimit;
+mod relay;
+pub mod reliable;
+mod service;
+pub mod settings;
+#[cfg(unix)]
+pub mod systemd;
+mod turn;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+mod uring;
+
+pub use relay::{
+    bind_socket, Config, ConfigHandle, NamedKey, Relay, RelayBuilder, SecretCollision,
+    StatusHandle, DEFAULT_KEY_NAME, DEFAULT_RTT_INTERVAL, MAX_THROTTLE_DELAY,
+};
@@ -0,0 +1,280 @@
+//! Log file with size- or time-based rotation.
+//!
+//! Once the file grows past its size limit, or at the start of every hour or
+//! day, `path` is renamed to `path.1`, an existing `path.1` to `path.2` and so
+//! on, the oldest of the kept files being removed, and a new `path` is started.
+
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{self, Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Deserialize;
+use tracing_subscriber::fmt::MakeWriter;
+
+/// When to start a new log file regardless of its size. Periods are aligned on
+/// UTC.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Rotation {
+    #[default]
+    Never,
+    Hourly,
+    Daily,
+}
+
+impl Rotation {
+    /// Index of the period `time` falls in.
+    fn period(self, time: SystemTime) -> u64 {
+        let secs = time
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs());
+        match self {
+            Rotation::Never => 0,
+            Rotation::Hourly => secs / 3600,
+            Rotation::Daily => secs / 86400,
+        }
+    }
+}
+
+impl FromStr for Rotation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Rotation, String> {
+        match s {
+            "never" => Ok(Rotation::Never),
+            "hourly" => Ok(Rotation::Hourly),
+            "daily" => Ok(Rotation::Daily),
+            _ => Err(format!(
+                "unknown rotation '{s}', expected never, hourly or daily"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Rotation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Rotation::Never => "never",
+            Rotation::Hourly => "hourly",
+            Rotation::Daily => "daily",
+        })
+    }
+}
+
+#[derive(Debug)]
+struct Inner {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    /// Period the current file was started in.
+    period: u64,
+    max_size: Option<u64>,
+    rotation: Rotation,
+    keep: usize,
+}
+
+/// Appends to a log file, rotating it as configured. Clones share the file, so
+/// it can be handed to a `tracing` subscriber and written to directly.
+#[derive(Debug, Clone)]
+pub struct LogFile {
+    inner: Arc<Mutex<Inner>>,
+}
+
+fn open(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+fn rotated(path: &Path, index: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{index}"));
+    PathBuf::from(name)
+}
+
+impl LogFile {
+    /// Opens `path` for appending, rotating it once it exceeds `max_size` bytes
+    /// (if given) or when the `rotation` period changes, and keeping `keep`
+    /// rotated files besides the current one. A relative `path` is made absolute,
+    /// as daemonizing changes the working directory.
+    pub fn open(
+        path: impl Into<PathBuf>,
+        max_size: Option<u64>,
+        rotation: Rotation,
+        keep: usize,
+    ) -> io::Result<LogFile> {
+        let path = path::absolute(path.into())?;
+        let file = open(&path)?;
+        let metadata = file.metadata()?;
+        // a file left by a previous run belongs to the period it was last written in
+        let period = rotation.period(metadata.modified().unwrap_or_else(|_| SystemTime::now()));
+        Ok(LogFile {
+            inner: Arc::new(Mutex::new(Inner {
+                path,
+                file,
+                size: metadata.len(),
+                period,
+                max_size,
+                rotation,
+                keep,
+            })),
+        })
+    }
+}
+
+impl Inner {
+    fn should_rotate(&self, len: usize) -> bool {
+        let oversized = self
+            .max_size
+            .is_some_and(|max| self.size > 0 && self.size + len as u64 > max);
+        oversized || self.rotation.period(SystemTime::now()) != self.period
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for index in (1..self.keep).rev() {
+                let from = rotated(&self.path, index);
+                if from.exists() {
+                    fs::rename(&from, rotated(&self.path, index + 1))?;
+                }
+            }
+            fs::rename(&self.path, rotated(&self.path, 1))?;
+        }
+        self.file = open(&self.path)?;
+        self.size = 0;
+        self.period = self.rotation.period(SystemTime::now());
+        Ok(())
+    }
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.should_rotate(buf.len()) {
+            if let Err(e) = self.rotate() {
+                // keep writing to the current file rather than losing the logs,
+                // and retry once it grew by the size limit again or in the next period
+                self.size = 0;
+                self.period = self.rotation.period(SystemTime::now());
+                let _ = writeln!(self.file, "Cannot rotate {}: {e}", self.path.display());
+            }
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+}
+
+impl Write for &LogFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner
+            .lock()
+            .expect("Log file lock poisoned")
+            .write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner
+            .lock()
+            .expect("Log file lock poisoned")
+            .file
+            .flush()
+    }
+}
+
+impl Write for LogFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&*self).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&*self).flush()
+    }
+}
+
+impl<'a> MakeWriter<'a> for LogFile {
+    type Writer = &'a LogFile;
+
+    fn make_writer(&'a self) -> &'a LogFile {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Empty directory of its own for each test.
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("udprelay-logfile-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).expect("temporary directory is writable");
+        dir
+    }
+
+    fn read(path: &Path) -> String {
+        fs::read_to_string(path).expect("log file exists")
+    }
+
+    #[test]
+    fn rotates_past_max_size() {
+        let dir = scratch_dir("size");
+        let path = dir.join("relay.log");
+        let mut log = LogFile::open(&path, Some(12), Rotation::Never, 3).expect("log opens");
+        log.write_all(b"first\n").expect("log writes");
+        log.write_all(b"more\n").expect("log writes");
+        log.write_all(b"second\n").expect("log writes");
+        assert_eq!(read(&path), "second\n");
+        assert_eq!(read(&rotated(&path, 1)), "first\nmore\n");
+        assert!(!rotated(&path, 2).exists());
+        fs::remove_dir_all(dir).expect("temporary directory is removable");
+    }
+
+    #[test]
+    fn keeps_only_keep_rotated_files() {
+        let dir = scratch_dir("keep");
+        let path = dir.join("relay.log");
+        let mut log = LogFile::open(&path, Some(1), Rotation::Never, 2).expect("log opens");
+        for line in ["a\n", "b\n", "c\n", "d\n"] {
+            log.write_all(line.as_bytes()).expect("log writes");
+        }
+        assert_eq!(read(&path), "d\n");
+        assert_eq!(read(&rotated(&path, 1)), "c\n");
+        assert_eq!(read(&rotated(&path, 2)), "b\n");
+        assert!(!rotated(&path, 3).exists());
+        fs::remove_dir_all(dir).expect("temporary directory is removable");
+    }
+
+    #[test]
+    fn keeping_none_starts_over() {
+        let dir = scratch_dir("none");
+        let path = dir.join("relay.log");
+        let mut log = LogFile::open(&path, Some(1), Rotation::Never, 0).expect("log opens");
+        log.write_all(b"a\n").expect("log writes");
+        log.write_all(b"b\n").expect("log writes");
+        assert_eq!(read(&path), "b\n");
+        assert!(!rotated(&path, 1).exists());
+        fs::remove_dir_all(dir).expect("temporary directory is removable");
+    }
+
+    #[test]
+    fn keeps_relative_path_absolute() {
+        let relative = PathBuf::from(format!("udprelay-logfile-{}.log", std::process::id()));
+        let log = LogFile::open(&relative, None, Rotation::Never, 1).expect("log opens");
+        let path = log
+            .inner
+            .lock()
+            .expect("Log file lock poisoned")
+            .path
+            .clone();
+        assert_eq!(
+            path,
+            std::env::current_dir()
+                .expect("working directory exists")
+                .join(&relative)
+        );
+        fs::remove_file(path).expect("log file is removable");
+    }
+}
@@ -1,453 +1,1497 @@
-use std::borrow::BorrowMut;
-use std::cell::RefCell;
-use std::collections::{HashMap, HashSet};
+use std::collections::BTreeSet;
+use std::fs;
+use std::future;
 use std::io;
-use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+#[cfg(unix)]
+use std::io::Write;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::path::{self, PathBuf};
 use std::process::{exit, ExitCode};
-use std::rc::{Rc, Weak};
-use std::str;
 use std::time::Duration;
-use std::time::SystemTime;
 
-use clap::Parser;
+use clap::builder::BoolishValueParser;
+use clap::{ArgAction, Parser, Subcommand};
+#[cfg(unix)]
 use daemonize_me::Daemon;
+use ipnet::IpNet;
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
+#[cfg(unix)]
+use tracing::info;
+use tracing::{error, warn};
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::EnvFilter;
+use udprelay_rust::client::{self, ClientConfig, DtlsOptions};
+use udprelay_rust::logfile::{LogFile, Rotation};
+use udprelay_rust::settings::{
+    self, read_key_file, FileConfig, IpFamily, PortRange, SettingsError,
+};
+#[cfg(unix)]
+use udprelay_rust::{auth, control, fec, protocol, systemd, ConfigHandle, StatusHandle};
+use udprelay_rust::{Config, NamedKey, RelayBuilder, SecretCollision, DEFAULT_KEY_NAME};
+use zeroize::Zeroizing;
 
-const OPS_ACK: [u8; 2] = [0xff, 0x12];
-const OPS_PING: [u8; 2] = [0xff, 0x15];
-const OPS_PONG: [u8; 2] = [0xff, 0x16];
-const OPS_CONN_REQ: [u8; 2] = [0xff, 0x05];
+const DEFAULT_PID_FILE: &str = "/tmp/udprelay-rs.pid";
+#[cfg(unix)]
+const DEFAULT_CONTROL_SOCKET: &str = "/tmp/udprelay-rs.sock";
+const DEFAULT_LOG_KEEP: usize = 5;
+
+/// Stand-ins for the systemd integration on other platforms, where there is neither
+/// a socket to take over nor a service manager to notify.
+#[cfg(not(unix))]
+mod systemd {
+    use std::io;
+    use std::net::UdpSocket;
+    use std::time::Duration;
+
+    pub fn activated_socket() -> io::Result<Option<UdpSocket>> {
+        Ok(None)
+    }
+
+    pub fn notify(_state: &str) -> io::Result<()> {
+        Ok(())
+    }
+
+    pub fn watchdog_interval() -> Option<Duration> {
+        None
+    }
+}
 
 /// Simple program to greet a person
+///
+/// Options may also be given through `UDPRELAY_*` environment variables or a TOML config
+/// file; the command line takes precedence over the environment, which takes precedence
+/// over the config file.
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
-struct Args {
-    /// UDP Port for peer connection
-    udp_port: u16,
-
-    /// The ip to binds
-    #[clap(default_value = "0.0.0.0")]
-    bind_ip: Ipv4Addr,
-
-    /// Verbose output
-    #[arg(short, long)]
-    verbose: bool,
-
-    /// Daemonize the process
-    #[arg(short, long)]
-    daemonize: bool,
-
-    /// Number of seconds before timing out the socket wait. This defines how often would
-    /// the relay check for inactivities, and hence, terminates the connection.
-    #[arg(short, long, default_value_t = 25)]
-    timeout_socket_wait: u64,
-
-    /// Number of seconds before timing out with no connections
-    #[arg(long, default_value_t = 300)]
-    timeout_no_connections: u64,
-
-    /// Number of seconds before timing out the peer pairing
-    #[arg(long, default_value_t = 90)]
-    timeout_pairing: u64,
-
-    /// Number of seconds before timing out connection with no activities
-    #[arg(long, default_value_t = 180)]
-    timeout_connection_inactivities: u64,
-
-    /// Pre-shared key
-    #[arg(long, default_value = "uNYDA5QRcvYgp2gfS5v5")]
-    preshared_key: String,
+#[command(
+    version,
+    about,
+    long_about = None,
+    subcommand_negates_reqs = true
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// `udprelay <port>`, kept as a shorthand for `udprelay serve <port>`
+    #[command(flatten)]
+    serve: ServeArgs,
 }
 
-macro_rules! println_if_verbose {
-    ($verbose:expr, $($arg:tt)*) => {
-        if $verbose {
-            eprintln!($($arg)*);
-        }
-    };
+/// Options of every subcommand, which may also be given before it.
+#[derive(clap::Args, Debug, Clone)]
+struct GlobalArgs {
+    /// TOML config file
+    #[arg(short, long, env = "UDPRELAY_CONFIG", global = true)]
+    config: Option<PathBuf>,
+
+    /// Verbose output; repeat for more details (-v: pairing events, -vv: handshakes,
+    /// -vvv: every relayed packet)
+    #[arg(short, long, action = ArgAction::Count, conflicts_with = "quiet", global = true)]
+    verbose: u8,
+
+    /// Only report errors
+    #[arg(short, long, global = true)]
+    quiet: bool,
 }
 
-#[derive(Debug)]
-struct ExpiringTimer(SystemTime);
+#[derive(clap::Args, Debug, Clone)]
+struct ServeArgs {
+    #[command(flatten)]
+    global: GlobalArgs,
 
-impl ExpiringTimer {
-    fn access(&mut self) {
-        self.0 = SystemTime::now();
-    }
+    /// UDP Port for peer connection; optional when systemd passes the socket. Port 0 binds
+    /// an ephemeral port, announced on stdout (or in --port-file) as `port=<port>
+    /// address=<ip:port>`
+    #[arg(env = "UDPRELAY_PORT")]
+    udp_port: Option<u16>,
 
-    fn is_expired(&self, timeout: u64) -> bool {
-        let elapsed = match SystemTime::now().duration_since(self.0) {
-            Ok(v) => v,
-            Err(e) => {
-                eprintln!(
-                    "Error in getting time elapsed: {}. Defaulting to timeout.",
-                    e
-                );
-                Duration::new(timeout, 0)
-            }
-        };
-        elapsed.as_secs() >= timeout
-    }
+    /// Also listen on this port or range of ports (e.g. 5000-5010), on the same ip, sharing
+    /// sessions across all of them; may be repeated. Without <UDP_PORT>, the first of
+    /// them is the main port
+    #[arg(long = "port", env = "UDPRELAY_PORTS", value_delimiter = ',')]
+    ports: Vec<PortRange>,
 
-    fn new() -> ExpiringTimer {
-        ExpiringTimer(SystemTime::now())
-    }
+    /// The ip or host name to binds. Either IPv4 or IPv6; binding to `::` accepts both
+    /// IPv4 and IPv6 peers where the OS supports dual-stack sockets [default: 0.0.0.0]
+    #[arg(env = "UDPRELAY_BIND_IP")]
+    bind_ip: Option<String>,
+
+    /// Address family to prefer when a host name, of the bind ip or of another address
+    /// setting, resolves to both (ipv4 or ipv6) [default: the first address found]
+    #[arg(long, env = "UDPRELAY_PREFER_FAMILY")]
+    prefer_family: Option<IpFamily>,
+
+    /// Also listen on this port, on the same ip, and only pair a peer of one port
+    /// with a peer of the other
+    #[arg(long, env = "UDPRELAY_SECOND_PORT")]
+    second_port: Option<u16>,
+
+    /// Also accept pairing requests over DTLS on this port, on the same ip (needs
+    /// the `dtls` feature)
+    #[arg(long, env = "UDPRELAY_DTLS_PORT")]
+    dtls_port: Option<u16>,
+
+    /// PEM certificate chain of the DTLS listener; without it, peers authenticate
+    /// with DTLS-PSK
+    #[arg(long, env = "UDPRELAY_DTLS_CERTIFICATE", requires = "dtls_private_key")]
+    dtls_certificate: Option<PathBuf>,
+
+    /// PEM private key of the DTLS certificate
+    #[arg(long, env = "UDPRELAY_DTLS_PRIVATE_KEY", requires = "dtls_certificate")]
+    dtls_private_key: Option<PathBuf>,
+
+    /// Join a cluster of relay instances, exchanging announcements of pending secrets
+    /// on this port, on the same ip
+    #[arg(long, env = "UDPRELAY_CLUSTER_PORT")]
+    cluster_port: Option<u16>,
+
+    /// Cluster address (host:port) of another instance; may be repeated
+    #[arg(long, env = "UDPRELAY_CLUSTER_PEER", value_delimiter = ',')]
+    cluster_peer: Vec<String>,
+
+    /// Address (host:port) peers reach this instance at, which other instances
+    /// redirect peers to
+    #[arg(long, env = "UDPRELAY_CLUSTER_ADVERTISE")]
+    cluster_advertise: Option<String>,
+
+    /// Key authenticating the announcements, shared by every instance
+    #[arg(long, env = "UDPRELAY_CLUSTER_KEY", hide_env_values = true)]
+    cluster_key: Option<String>,
+
+    /// Log filter in `tracing` directive syntax (e.g. `info,udprelay_rust::service=trace`),
+    /// overriding -v/-q
+    #[arg(long, env = "UDPRELAY_LOG")]
+    log_filter: Option<String>,
+
+    /// Write logs to this file instead of stderr, also when daemonized
+    #[arg(long, env = "UDPRELAY_LOG_FILE")]
+    log_file: Option<PathBuf>,
+
+    /// Rotate the log file once it grows past this many bytes
+    #[arg(long, env = "UDPRELAY_LOG_MAX_SIZE")]
+    log_max_size: Option<u64>,
+
+    /// Rotate the log file every hour or day (never, hourly or daily) [default: never]
+    #[arg(long, env = "UDPRELAY_LOG_ROTATION")]
+    log_rotation: Option<Rotation>,
+
+    /// Number of rotated log files kept besides the current one [default: 5]
+    #[arg(long, env = "UDPRELAY_LOG_KEEP")]
+    log_keep: Option<usize>,
+
+    /// Daemonize the process (Unix only)
+    #[arg(short, long, env = "UDPRELAY_DAEMONIZE", num_args = 0..=1, require_equals = true, default_missing_value = "true", value_parser = BoolishValueParser::new())]
+    daemonize: Option<bool>,
+
+    /// PID file written when daemonized [default: /tmp/udprelay-rs.pid], or in the
+    /// foreground when given
+    #[arg(long, env = "UDPRELAY_PID_FILE")]
+    pid_file: Option<PathBuf>,
+
+    /// Write the bound port and address to this file, as `port=<port> address=<ip:port>`,
+    /// instead of announcing an ephemeral port on stdout; removed on exit
+    #[arg(long, env = "UDPRELAY_PORT_FILE")]
+    port_file: Option<PathBuf>,
+
+    /// Number of seconds between housekeeping passes. This defines how often would
+    /// the relay check for inactivities, and hence, terminates the connection [default: 25]
+    #[arg(
+        short = 't',
+        long,
+        alias = "timeout-socket-wait",
+        env = "UDPRELAY_HOUSEKEEPING_INTERVAL"
+    )]
+    housekeeping_interval: Option<u64>,
+
+    /// Number of seconds before timing out with no connections [default: 300]
+    #[arg(long, env = "UDPRELAY_TIMEOUT_NO_CONNECTIONS")]
+    timeout_no_connections: Option<u64>,
+
+    /// Number of seconds before timing out the peer pairing [default: 90]
+    #[arg(long, env = "UDPRELAY_TIMEOUT_PAIRING")]
+    timeout_pairing: Option<u64>,
+
+    /// Number of seconds before timing out connection with no activities [default: 180]
+    #[arg(long, env = "UDPRELAY_TIMEOUT_CONNECTION_INACTIVITIES")]
+    timeout_connection_inactivities: Option<u64>,
+
+    /// Pre-shared key peers must present. Prefer the environment or a key file, as the
+    /// command line is visible to other users through `ps`
+    #[arg(long, env = "UDPRELAY_PSK", hide_env_values = true)]
+    preshared_key: Option<String>,
+
+    /// Read the pre-shared key from this file
+    #[arg(long, env = "UDPRELAY_PSK_FILE", conflicts_with = "preshared_key")]
+    preshared_key_file: Option<PathBuf>,
+
+    /// SHA-256 digest of the pre-shared key, in hexadecimal, instead of the key; only
+    /// authenticates the legacy handshake
+    #[arg(long, env = "UDPRELAY_PSK_HASH", conflicts_with_all = ["preshared_key", "preshared_key_file"])]
+    preshared_key_hash: Option<String>,
+
+    /// Start without a pre-shared key, accepting every peer, or with a weak key
+    #[arg(long, env = "UDPRELAY_INSECURE_OPEN", num_args = 0..=1, require_equals = true, default_missing_value = "true", value_parser = BoolishValueParser::new())]
+    insecure_open: Option<bool>,
+
+    /// Also accept the legacy handshake sending the pre-shared key in cleartext
+    #[arg(long, env = "UDPRELAY_LEGACY_HANDSHAKE", num_args = 0..=1, require_equals = true, default_missing_value = "true", value_parser = BoolishValueParser::new())]
+    legacy_handshake: Option<bool>,
+
+    /// Refuse peers speaking an older protocol version: 1 is the legacy handshake,
+    /// 2 the challenge handshake without version negotiation, 3 with it [default: 1]
+    #[arg(long, env = "UDPRELAY_MIN_PROTOCOL_VERSION")]
+    min_protocol_version: Option<u8>,
+
+    /// First byte of every control message, e.g. 0xfe, instead of 0xff; peers must use
+    /// the same [default: 0xff]
+    #[arg(long, env = "UDPRELAY_PROTOCOL_MAGIC", value_parser = parse_magic)]
+    protocol_magic: Option<u8>,
+
+    /// Encrypt the traffic of paired peers with the relay (ChaCha20-Poly1305, keys
+    /// derived from the pre-shared key and the handshake); needs clients run with --encrypt
+    #[arg(long, env = "UDPRELAY_ENCRYPTION", num_args = 0..=1, require_equals = true, default_missing_value = "true", value_parser = BoolishValueParser::new())]
+    encryption: Option<bool>,
+
+    /// Named keys, only configurable through the config file
+    #[arg(skip)]
+    keys: Vec<NamedKey>,
+
+    /// Only serve peers within this network (e.g. 10.0.0.0/8); may be repeated
+    #[arg(long, env = "UDPRELAY_ALLOW_CIDR", value_delimiter = ',')]
+    allow_cidr: Vec<IpNet>,
+
+    /// Ignore peers within this network, even if allowed; may be repeated
+    #[arg(long, env = "UDPRELAY_DENY_CIDR", value_delimiter = ',')]
+    deny_cidr: Vec<IpNet>,
+
+    /// Give paired peers a token to resume their session after their address changed
+    /// (e.g. roaming or NAT rebinding)
+    #[arg(long, env = "UDPRELAY_SESSION_RESUMPTION", num_args = 0..=1, require_equals = true, default_missing_value = "true", value_parser = BoolishValueParser::new())]
+    session_resumption: Option<bool>,
+
+    /// Let paired peers move their session to a new address by proving knowledge of
+    /// its secret from there
+    #[arg(long, env = "UDPRELAY_SESSION_REBIND", num_args = 0..=1, require_equals = true, default_missing_value = "true", value_parser = BoolishValueParser::new())]
+    session_rebind: Option<bool>,
+
+    /// Only accept as session secret the pairing code of the current 30-second window,
+    /// derived from the pre-shared key (see the code subcommand)
+    #[arg(long, env = "UDPRELAY_PAIRING_CODES", num_args = 0..=1, require_equals = true, default_missing_value = "true", value_parser = BoolishValueParser::new())]
+    pairing_codes: Option<bool>,
+
+    /// Retransmit lost datagrams between the relay and the peers asking for it
+    #[arg(long, env = "UDPRELAY_RELIABLE", num_args = 0..=1, require_equals = true, default_missing_value = "true", value_parser = BoolishValueParser::new())]
+    reliable: Option<bool>,
+
+    /// Number of datagrams in flight to, and held back from, each peer with reliable
+    /// delivery [default: 64]
+    #[arg(long, env = "UDPRELAY_RELIABLE_WINDOW")]
+    reliable_window: Option<u32>,
+
+    /// Number of milliseconds a datagram to a peer with reliable delivery waits for
+    /// its acknowledgement before being sent again [default: 200]
+    #[arg(long, env = "UDPRELAY_RETRANSMIT_INTERVAL")]
+    retransmit_interval: Option<u64>,
+
+    /// Send parity datagrams to the peers asking for it, from which they rebuild a
+    /// lost datagram
+    #[arg(long, env = "UDPRELAY_FEC", num_args = 0..=1, require_equals = true, default_missing_value = "true", value_parser = BoolishValueParser::new())]
+    fec: Option<bool>,
+
+    /// Number of datagrams to a peer with forward error correction per parity
+    /// datagram, between 1 and 64 [default: 4]
+    #[arg(long, env = "UDPRELAY_FEC_BLOCK_SIZE")]
+    fec_block_size: Option<u8>,
+
+    /// Compress the datagrams relayed to the peers asking for it with LZ4
+    #[arg(long, env = "UDPRELAY_COMPRESSION", num_args = 0..=1, require_equals = true, default_missing_value = "true", value_parser = BoolishValueParser::new())]
+    compression: Option<bool>,
+
+    /// Let the peers asking for it multiplex logical channels within their session,
+    /// each datagram starting with a channel ID
+    #[arg(long, env = "UDPRELAY_CHANNELS", num_args = 0..=1, require_equals = true, default_missing_value = "true", value_parser = BoolishValueParser::new())]
+    channels: Option<bool>,
+
+    /// Let every peer presenting the same secret join a group, relaying each datagram
+    /// to all other members, instead of pairing peers two by two
+    #[arg(long, env = "UDPRELAY_GROUP", num_args = 0..=1, require_equals = true, default_missing_value = "true", value_parser = BoolishValueParser::new())]
+    group: Option<bool>,
+
+    /// Most peers a group may hold [default: 8]
+    #[arg(long, env = "UDPRELAY_MAX_GROUP_MEMBERS")]
+    max_group_members: Option<usize>,
+
+    /// Also serve TURN clients (allocations over UDP), authenticating with the name of
+    /// a key as username and its pre-shared key as password
+    #[arg(long, env = "UDPRELAY_TURN", num_args = 0..=1, require_equals = true, default_missing_value = "true", value_parser = BoolishValueParser::new())]
+    turn: Option<bool>,
+
+    /// Realm of the TURN credentials [default: udprelay]
+    #[arg(long, env = "UDPRELAY_TURN_REALM")]
+    turn_realm: Option<String>,
+
+    /// Bandwidth of each paired peer, in kilobits per second; 0 is unlimited [default: 0]
+    #[arg(long, env = "UDPRELAY_RATE_LIMIT_KBPS")]
+    rate_limit_kbps: Option<u32>,
+
+    /// Hold back datagrams over --rate-limit-kbps for up to half a second instead of
+    /// dropping them
+    #[arg(long, env = "UDPRELAY_RATE_LIMIT_DELAY", num_args = 0..=1, require_equals = true, default_missing_value = "true", value_parser = BoolishValueParser::new())]
+    rate_limit_delay: Option<bool>,
+
+    /// Drop this percentage of the datagrams relayed to paired peers on purpose, to
+    /// test tunneled applications [default: 0]
+    #[arg(long, env = "UDPRELAY_CHAOS_LOSS")]
+    chaos_loss: Option<f64>,
+
+    /// Number of milliseconds datagrams relayed to paired peers are held back on
+    /// purpose [default: 0]
+    #[arg(long, env = "UDPRELAY_CHAOS_DELAY_MS")]
+    chaos_delay_ms: Option<u64>,
+
+    /// Number of milliseconds by which each datagram may be held back more or less
+    /// than --chaos-delay-ms, picked at random [default: 0]
+    #[arg(long, env = "UDPRELAY_CHAOS_JITTER_MS")]
+    chaos_jitter_ms: Option<u64>,
+
+    /// Most sessions (pairs, groups and TURN allocations) at once; requests for further ones are refused
+    #[arg(long, env = "UDPRELAY_MAX_SESSIONS")]
+    max_sessions: Option<usize>,
+
+    /// Most pairing requests waiting for their counterpart at once; further ones are refused
+    #[arg(long, env = "UDPRELAY_MAX_PENDING_PAIRINGS")]
+    max_pending_pairings: Option<usize>,
+
+    /// Most paired peers, group members and pending pairing requests a single IP may hold at once;
+    /// further pairing requests from it are refused with a "limit exceeded" message
+    #[arg(long, env = "UDPRELAY_MAX_SESSIONS_PER_IP")]
+    max_sessions_per_ip: Option<usize>,
+
+    /// What to do with a pairing request presenting a secret that is already paired, or
+    /// only waited on by peers it cannot be paired with: queue it behind them, reject it
+    /// with a "secret in use" message, or let it take over from the same IP (queue,
+    /// reject or takeover) [default: queue]
+    #[arg(long, env = "UDPRELAY_SECRET_COLLISION")]
+    secret_collision: Option<SecretCollision>,
+
+    /// Answer pairing requests refused by --max-sessions, --max-pending-pairings or
+    /// --max-total-sessions with a "busy" message instead of dropping them silently
+    #[arg(long, env = "UDPRELAY_REPLY_BUSY", num_args = 0..=1, require_equals = true, default_missing_value = "true", value_parser = BoolishValueParser::new())]
+    reply_busy: Option<bool>,
+
+    /// Serve this many pairs and groups, refusing requests for further ones, and exit
+    /// once the last of them is closed [default: unlimited]
+    #[arg(long, env = "UDPRELAY_MAX_TOTAL_SESSIONS")]
+    max_total_sessions: Option<u64>,
+
+    /// Exit once the first pair or group is closed; same as --max-total-sessions 1
+    #[arg(long, env = "UDPRELAY_ONE_SHOT", conflicts_with = "max_total_sessions", num_args = 0..=1, require_equals = true, default_missing_value = "true", value_parser = BoolishValueParser::new())]
+    one_shot: Option<bool>,
+
+    /// Drop datagrams of paired peers and group members with a payload over this many
+    /// bytes, e.g. 1400 to avoid IP fragmentation
+    #[arg(long, env = "UDPRELAY_MAX_PAYLOAD")]
+    max_payload: Option<usize>,
+
+    /// Answer datagrams dropped by --max-payload with a "too big" message carrying the
+    /// maximum payload instead of dropping them silently
+    #[arg(long, env = "UDPRELAY_REPLY_TOO_BIG", num_args = 0..=1, require_equals = true, default_missing_value = "true", value_parser = BoolishValueParser::new())]
+    reply_too_big: Option<bool>,
+
+    /// Handshake messages accepted per second from a single IP; 0 disables rate
+    /// limiting [default: 5]
+    #[arg(long, env = "UDPRELAY_PAIRING_RATE")]
+    pairing_rate: Option<u32>,
+
+    /// Handshake messages a single IP may send in a burst [default: 10]
+    #[arg(long, env = "UDPRELAY_PAIRING_BURST")]
+    pairing_burst: Option<u32>,
+
+    /// Ban a source after this many rate-limited messages; 0 never bans [default: 50]
+    #[arg(long, env = "UDPRELAY_BAN_AFTER")]
+    ban_after: Option<u32>,
+
+    /// Number of seconds a banned source is ignored [default: 300]
+    #[arg(long, env = "UDPRELAY_BAN_DURATION")]
+    ban_duration: Option<u64>,
+
+    /// Number of seconds between keepalives sent to both peers of an idle pair, keeping
+    /// NAT mappings open; 0 disables keepalives [default: 0]
+    #[arg(long, env = "UDPRELAY_KEEPALIVE_INTERVAL")]
+    keepalive_interval: Option<u64>,
+
+    /// Number of seconds before an idle pair is torn down at which both peers are sent an
+    /// "idle warning" message, checked at every housekeeping pass; 0 disables warnings
+    /// [default: 0]
+    #[arg(long, env = "UDPRELAY_IDLE_WARNING")]
+    idle_warning: Option<u64>,
+
+    /// Number of seconds between round-trip time measurements of the paired peers
+    /// answering echoes; 0 disables measurements [default: 10]
+    #[arg(long, env = "UDPRELAY_RTT_INTERVAL")]
+    rtt_interval: Option<u64>,
+
+    /// Tear down the session of a peer once this many sends to it failed in a row (e.g.
+    /// as it became unreachable); 0 never does [default: 8]
+    #[arg(long, env = "UDPRELAY_MAX_SEND_FAILURES")]
+    max_send_failures: Option<u32>,
+
+    /// Forward every datagram to this host:port instead of pairing peers, routing
+    /// replies back to their sender
+    #[arg(long, env = "UDPRELAY_FORWARD_TO")]
+    forward_to: Option<String>,
+
+    /// Serve Prometheus metrics over HTTP on this address (e.g. 127.0.0.1:9100)
+    #[arg(long, env = "UDPRELAY_METRICS_LISTEN")]
+    metrics_listen: Option<SocketAddr>,
+
+    /// Serve liveness (/healthz) and readiness (/readyz) probes over HTTP on this address
+    /// (e.g. 0.0.0.0:8080)
+    #[arg(long, env = "UDPRELAY_HEALTH_LISTEN")]
+    health_listen: Option<SocketAddr>,
+
+    /// Number of seconds to spend notifying peers when shutting down on SIGTERM/SIGINT
+    /// [default: 2]
+    #[arg(long, env = "UDPRELAY_DRAIN_TIMEOUT")]
+    drain_timeout: Option<u64>,
+
+    /// Number of sockets bound to the port with SO_REUSEPORT, each served by its own
+    /// task, so that the kernel spreads peers across cores [default: 1]
+    #[arg(long, env = "UDPRELAY_WORKERS")]
+    workers: Option<usize>,
+
+    /// Relay datagrams on io_uring instead of recvmmsg/sendmmsg; needs Linux 6.0 or
+    /// later and a build with the `io-uring` feature
+    #[arg(long, env = "UDPRELAY_IO_URING", num_args = 0..=1, require_equals = true, default_missing_value = "true", value_parser = BoolishValueParser::new())]
+    io_uring: Option<bool>,
+
+    /// Kernel receive buffer of the relay sockets (SO_RCVBUF), in bytes [default: system]
+    #[arg(long, env = "UDPRELAY_SO_RCVBUF")]
+    so_rcvbuf: Option<usize>,
+
+    /// Kernel send buffer of the relay sockets (SO_SNDBUF), in bytes [default: system]
+    #[arg(long, env = "UDPRELAY_SO_SNDBUF")]
+    so_sndbuf: Option<usize>,
+
+    /// Size of each buffer datagrams are received into, in bytes; longer datagrams are
+    /// dropped [default: 65535]
+    #[arg(long, env = "UDPRELAY_RECV_BUFFER_SIZE")]
+    recv_buffer_size: Option<usize>,
+
+    /// Mark the datagrams sent by the relay sockets with this DSCP value (0 to 63), e.g. 46
+    /// (expedited forwarding) for VoIP [default: system]
+    #[arg(long, env = "UDPRELAY_DSCP")]
+    dscp: Option<u8>,
+
+    /// Pin the relay sockets to this network interface (SO_BINDTODEVICE on Linux, its
+    /// address elsewhere) [default: any]
+    #[arg(long, env = "UDPRELAY_INTERFACE")]
+    interface: Option<String>,
+
+    /// Accept administration commands (see the `ctl` subcommand) on this Unix socket
+    #[arg(long, env = "UDPRELAY_CONTROL_SOCKET")]
+    control_socket: Option<PathBuf>,
+
+    /// Append one JSON object per session event (pairing, teardown...) to this file
+    #[arg(long, env = "UDPRELAY_ACCOUNTING_LOG")]
+    accounting_log: Option<PathBuf>,
+
+    /// Run this shell command whenever two peers are paired, with the session details in
+    /// UDPRELAY_* environment variables
+    #[arg(long, env = "UDPRELAY_ON_PAIR")]
+    on_pair: Option<String>,
+
+    /// Run this shell command whenever a pair is torn down, with the session details in
+    /// UDPRELAY_* environment variables
+    #[arg(long, env = "UDPRELAY_ON_TEARDOWN")]
+    on_teardown: Option<String>,
+
+    /// POST every pairing and teardown, as JSON, to this http:// URL
+    #[arg(long, env = "UDPRELAY_WEBHOOK_URL")]
+    webhook_url: Option<String>,
+
+    /// Capture every datagram received and sent by the relay to this pcap file, e.g.
+    /// to inspect pairing failures in Wireshark
+    #[arg(long, env = "UDPRELAY_PCAP")]
+    pcap: Option<PathBuf>,
+
+    /// Only capture handshake messages to --pcap
+    #[arg(long, env = "UDPRELAY_PCAP_HANDSHAKE_ONLY", num_args = 0..=1, require_equals = true, default_missing_value = "true", value_parser = BoolishValueParser::new())]
+    pcap_handshake_only: Option<bool>,
 }
 
-#[derive(Debug)]
-struct Recipient<'a> {
-    socket: &'a UdpSocket,
-    addr: SocketAddr,
+#[derive(Subcommand, Debug, Clone)]
+enum Command {
+    /// Pair peers and relay their datagrams (the default, when given a port)
+    Serve(ServeArgs),
+    /// Forward every datagram to a fixed target instead of pairing peers
+    Forward(ForwardArgs),
+    /// Pair through a relay and bridge a local UDP port to the paired peer
+    Client(ClientArgs),
+    /// Check that a relay answers and measure its round-trip time
+    Ping(PingArgs),
+    /// Print the pairing code of the current time window, for relays running with
+    /// --pairing-codes
+    Code(CodeArgs),
+    /// Administer a running relay through its control socket
+    #[cfg(unix)]
+    Ctl(CtlArgs),
 }
 
-impl Recipient<'_> {
-    fn send_message(&self, message: &[u8]) {
-        self.socket
-            .send_to(message, self.addr)
-            .expect("Error in sending message");
-    }
+#[derive(clap::Args, Debug, Clone)]
+struct ForwardArgs {
+    /// Target every datagram is forwarded to, as host:port; replies are routed back to
+    /// their sender
+    #[arg(long, env = "UDPRELAY_FORWARD_TO")]
+    to: String,
+
+    #[command(flatten)]
+    serve: ServeArgs,
 }
 
-#[derive(Debug)]
-struct RecipientData<'a> {
-    recipient: Recipient<'a>,
-    last_accessed: ExpiringTimer,
-    opponent: Option<Weak<RefCell<RecipientData<'a>>>>,
+#[derive(clap::Args, Debug, Clone)]
+struct PingArgs {
+    /// Relay to probe, as host:port
+    relay: String,
+
+    /// Address family to prefer when the relay's host name resolves to both (ipv4 or
+    /// ipv6) [default: the first address found]
+    #[arg(long, env = "UDPRELAY_PREFER_FAMILY")]
+    prefer_family: Option<IpFamily>,
+
+    /// Number of probes to send
+    #[arg(short = 'n', long, default_value_t = 3)]
+    count: u64,
+
+    /// Number of seconds to wait for each reply
+    #[arg(short = 'W', long, default_value_t = 1.0)]
+    timeout: f64,
+
+    /// Number of seconds between probes
+    #[arg(short, long, default_value_t = 1.0)]
+    interval: f64,
+
+    /// First byte of the relay's control messages [default: 0xff]
+    #[arg(long, env = "UDPRELAY_PROTOCOL_MAGIC", value_parser = parse_magic)]
+    protocol_magic: Option<u8>,
 }
 
-impl<'a> RecipientData<'a> {
-    fn get_opponent(&mut self) -> Rc<RefCell<RecipientData<'a>>> {
-        self.opponent
-            .as_mut()
-            .expect("Option is empty. Bugs in setting up opponent?")
-            .upgrade()
-            .expect("Cannot upgrade to strong reference")
+/// Parses a byte given in decimal or, behind `0x`, in hexadecimal.
+fn parse_magic(value: &str) -> Result<u8, String> {
+    match value.strip_prefix("0x") {
+        Some(hex) => u8::from_str_radix(hex, 16),
+        None => value.parse(),
     }
+    .map_err(|e| e.to_string())
 }
 
-fn build_paired_peers<'a>(
-    addr_1: &SocketAddr,
-    udp_1: &'a UdpSocket,
-    addr_2: &SocketAddr,
-    udp_2: &'a UdpSocket,
-) -> (
-    Rc<RefCell<RecipientData<'a>>>,
-    Rc<RefCell<RecipientData<'a>>>,
-) {
-    let peer1 = Rc::new(RefCell::new(RecipientData {
-        recipient: Recipient {
-            socket: udp_1,
-            addr: *addr_1,
-        },
-        last_accessed: ExpiringTimer::new(),
-        opponent: None,
-    }));
-    let peer2 = Rc::new(RefCell::new(RecipientData {
-        recipient: Recipient {
-            socket: udp_2,
-            addr: *addr_2,
-        },
-        last_accessed: ExpiringTimer::new(),
-        opponent: None,
-    }));
-    // assign the opposing reference as weak pointer
+#[derive(clap::Args, Debug, Clone)]
+struct CodeArgs {
+    /// Pre-shared key of the relay
+    #[arg(long, env = "UDPRELAY_PSK", hide_env_values = true)]
+    preshared_key: Option<String>,
 
-    // peer1.borrow_mut().get_mut().op;
-
-    peer1
-        .as_ref()
-        .borrow_mut()
-        .opponent
-        .replace(Rc::downgrade(&peer2));
-    peer2
-        .as_ref()
-        .borrow_mut()
-        .opponent
-        .replace(Rc::downgrade(&peer1));
-    (peer1, peer2)
+    /// Read the pre-shared key from this file
+    #[arg(long, env = "UDPRELAY_PSK_FILE", conflicts_with = "preshared_key")]
+    preshared_key_file: Option<PathBuf>,
 }
 
-fn bind_socket(ip: Ipv4Addr, port: u16, args: &Args) -> Result<UdpSocket, io::Error> {
-    UdpSocket::bind((ip, port)).map(|socket| {
-        socket
-            .set_read_timeout(Some(Duration::new(args.timeout_socket_wait, 0)))
-            .ok();
-        socket
-    })
+#[derive(clap::Args, Debug, Clone)]
+struct ClientArgs {
+    /// Relay to pair through, as host:port
+    #[arg(long, env = "UDPRELAY_RELAY")]
+    relay: String,
+
+    /// Address family to prefer when the relay's host name resolves to both (ipv4 or
+    /// ipv6) [default: the first address found]
+    #[arg(long, env = "UDPRELAY_PREFER_FAMILY")]
+    prefer_family: Option<IpFamily>,
+
+    /// Session secret shared with the peer
+    #[arg(long, env = "UDPRELAY_SECRET", hide_env_values = true)]
+    secret: String,
+
+    /// Local UDP port applications send to, to reach the peer
+    #[arg(long)]
+    local_port: u16,
+
+    /// Local IP to bind the local port to
+    #[arg(long, default_value = "127.0.0.1")]
+    local_ip: IpAddr,
+
+    /// Pre-shared key of the relay; may be omitted for relays started with --insecure-open
+    #[arg(long, env = "UDPRELAY_PSK", hide_env_values = true)]
+    preshared_key: Option<String>,
+
+    /// Read the pre-shared key from this file
+    #[arg(long, env = "UDPRELAY_PSK_FILE", conflicts_with = "preshared_key")]
+    preshared_key_file: Option<PathBuf>,
+
+    /// Pair with the legacy handshake sending the pre-shared key in cleartext
+    #[arg(long)]
+    legacy_handshake: bool,
+
+    /// Encrypt the traffic with the relay, which must run with --encryption
+    #[arg(long, env = "UDPRELAY_ENCRYPT", conflicts_with = "legacy_handshake")]
+    encrypt: bool,
+
+    /// Ask the relay to retransmit lost datagrams between this client and the relay,
+    /// which must run with --reliable
+    #[arg(long, env = "UDPRELAY_RELIABLE", conflicts_with_all = ["legacy_handshake", "dtls_port"])]
+    reliable: bool,
+
+    /// Ask the relay for parity datagrams between this client and the relay, from which
+    /// a lost datagram is rebuilt; the relay must run with --fec
+    #[arg(long, env = "UDPRELAY_FEC", conflicts_with_all = ["legacy_handshake", "dtls_port", "reliable"])]
+    fec: bool,
+
+    /// Number of datagrams to the relay per parity datagram, between 1 and 64
+    /// [default: 4]
+    #[arg(long, env = "UDPRELAY_FEC_BLOCK_SIZE", requires = "fec")]
+    fec_block_size: Option<u8>,
+
+    /// Ask the relay to compress the datagrams it relays to this client, which it
+    /// must run with --compression
+    #[arg(long, env = "UDPRELAY_COMPRESS", conflicts_with_all = ["legacy_handshake", "dtls_port"])]
+    compress: bool,
+
+    /// Send the pairing request over DTLS to the relay's listener on this port
+    #[arg(long, env = "UDPRELAY_DTLS_PORT", conflicts_with = "legacy_handshake")]
+    dtls_port: Option<u16>,
+
+    /// Certificate authority the relay's DTLS certificate must be issued by, which
+    /// is also checked against the host of --relay; without it, authenticate with
+    /// DTLS-PSK
+    #[arg(long, env = "UDPRELAY_DTLS_CA", requires = "dtls_port")]
+    dtls_ca: Option<PathBuf>,
+
+    /// Name of the relay key, as DTLS-PSK identity
+    #[arg(long, default_value = DEFAULT_KEY_NAME, requires = "dtls_port")]
+    dtls_identity: String,
+
+    /// Number of seconds to wait for the relay before retrying the handshake
+    #[arg(long, default_value_t = 1)]
+    retry_interval: u64,
+
+    /// First byte of the relay's control messages [default: 0xff]
+    #[arg(long, env = "UDPRELAY_PROTOCOL_MAGIC", value_parser = parse_magic)]
+    protocol_magic: Option<u8>,
 }
 
-fn concat_arrays<T: Copy>(known_array: &[T], borrowed_slice: &[T]) -> Vec<T> {
-    let mut combined_array = Vec::with_capacity(known_array.len() + borrowed_slice.len());
+#[cfg(unix)]
+#[derive(clap::Args, Debug, Clone)]
+struct CtlArgs {
+    /// Control socket of the relay, as given to its `--control-socket`
+    #[arg(short, long, env = "UDPRELAY_CONTROL_SOCKET", default_value = DEFAULT_CONTROL_SOCKET)]
+    socket: PathBuf,
 
-    combined_array.extend_from_slice(known_array);
-    combined_array.extend_from_slice(borrowed_slice);
+    #[command(subcommand)]
+    command: CtlCommand,
+}
 
-    combined_array
+#[cfg(unix)]
+#[derive(Subcommand, Debug, Clone)]
+enum CtlCommand {
+    /// List active sessions with their peer addresses and seconds since last activity
+    Sessions,
+    /// List pairing requests waiting for their counterpart
+    Pending,
+    /// Tear down sessions and pending pairings by peer address or session secret
+    Kick { target: String },
+    /// Dump cumulative counters as JSON
+    Stats,
+    /// Show the timeouts and limits that `set` can change
+    Config,
+    /// Change a timeout (in seconds) or a limit (a number or `none`) until the next reload
+    Set { setting: String, value: String },
 }
 
-fn process_relay_service(args: &Args, buffer: &[u8], sender: &Rc<RefCell<RecipientData>>) {
-    let mut sender = sender.as_ref().borrow_mut();
-    sender.last_accessed.access();
-    let receiver = sender.get_opponent();
-    let receiver = receiver.as_ref().borrow_mut();
-    receiver.recipient.send_message(buffer);
-    println_if_verbose!(
-        args.verbose,
-        "> Relaying message {} => {} => {}: ",
-        sender.recipient.addr,
-        str::from_utf8(buffer).unwrap_or("[some bytes]").trim(),
-        receiver.recipient.addr
-    );
+#[cfg(unix)]
+impl CtlCommand {
+    fn to_line(&self) -> String {
+        match self {
+            CtlCommand::Sessions => "sessions".to_owned(),
+            CtlCommand::Pending => "pending".to_owned(),
+            CtlCommand::Kick { target } => format!("kick {target}"),
+            CtlCommand::Stats => "stats".to_owned(),
+            CtlCommand::Config => "config".to_owned(),
+            CtlCommand::Set { setting, value } => format!("set {setting} {value}"),
+        }
+    }
 }
 
-fn process_maybe_request(
-    args: &Args,
-    registry: &mut RelayService,
-    buffer: &[u8],
-    from: &SocketAddr,
-) {
-    if buffer.len() < 2 {
-        return;
+impl ServeArgs {
+    /// Fills in whatever was not given on the command line (or environment) from the
+    /// config file.
+    fn or_file(mut self, file: FileConfig) -> ServeArgs {
+        self.udp_port = self.udp_port.or(file.port);
+        if self.ports.is_empty() {
+            self.ports = file.ports.unwrap_or_default();
+        }
+        self.bind_ip = self.bind_ip.or(file.bind_ip);
+        self.prefer_family = self.prefer_family.or(file.prefer_family);
+        self.second_port = self.second_port.or(file.second_port);
+        self.dtls_port = self.dtls_port.or(file.dtls_port);
+        self.dtls_certificate = self.dtls_certificate.or(file.dtls_certificate);
+        self.dtls_private_key = self.dtls_private_key.or(file.dtls_private_key);
+        self.cluster_port = self.cluster_port.or(file.cluster_port);
+        if self.cluster_peer.is_empty() {
+            self.cluster_peer = file.cluster_peer.unwrap_or_default();
+        }
+        self.cluster_advertise = self.cluster_advertise.or(file.cluster_advertise);
+        self.cluster_key = self.cluster_key.or(file.cluster_key);
+        if self.global.verbose == 0 && !self.global.quiet {
+            self.log_filter = self.log_filter.or(file.log_filter);
+        }
+        self.log_file = self.log_file.or(file.log_file);
+        self.log_max_size = self.log_max_size.or(file.log_max_size);
+        self.log_rotation = self.log_rotation.or(file.log_rotation);
+        self.log_keep = self.log_keep.or(file.log_keep);
+        self.daemonize = self.daemonize.or(file.daemonize);
+        self.pid_file = self.pid_file.or(file.pid_file);
+        self.port_file = self.port_file.or(file.port_file);
+        self.housekeeping_interval = self.housekeeping_interval.or(file.housekeeping_interval);
+        self.timeout_no_connections = self.timeout_no_connections.or(file.timeout_no_connections);
+        self.timeout_pairing = self.timeout_pairing.or(file.timeout_pairing);
+        self.timeout_connection_inactivities = self
+            .timeout_connection_inactivities
+            .or(file.timeout_connection_inactivities);
+        if self.preshared_key.is_none()
+            && self.preshared_key_file.is_none()
+            && self.preshared_key_hash.is_none()
+        {
+            self.preshared_key = file.preshared_key;
+            self.preshared_key_file = file.preshared_key_file;
+            self.preshared_key_hash = file.preshared_key_hash;
+        }
+        self.insecure_open = self.insecure_open.or(file.insecure_open);
+        self.legacy_handshake = self.legacy_handshake.or(file.legacy_handshake);
+        self.min_protocol_version = self.min_protocol_version.or(file.min_protocol_version);
+        self.protocol_magic = self.protocol_magic.or(file.protocol_magic);
+        self.encryption = self.encryption.or(file.encryption);
+        self.keys = file.keys.into_iter().map(NamedKey::from).collect();
+        if self.allow_cidr.is_empty() {
+            self.allow_cidr = file.allow_cidr.unwrap_or_default();
+        }
+        if self.deny_cidr.is_empty() {
+            self.deny_cidr = file.deny_cidr.unwrap_or_default();
+        }
+        self.session_resumption = self.session_resumption.or(file.session_resumption);
+        self.session_rebind = self.session_rebind.or(file.session_rebind);
+        self.pairing_codes = self.pairing_codes.or(file.pairing_codes);
+        self.reliable = self.reliable.or(file.reliable);
+        self.reliable_window = self.reliable_window.or(file.reliable_window);
+        self.retransmit_interval = self.retransmit_interval.or(file.retransmit_interval);
+        self.fec = self.fec.or(file.fec);
+        self.fec_block_size = self.fec_block_size.or(file.fec_block_size);
+        self.compression = self.compression.or(file.compression);
+        self.channels = self.channels.or(file.channels);
+        self.group = self.group.or(file.group);
+        self.max_group_members = self.max_group_members.or(file.max_group_members);
+        self.turn = self.turn.or(file.turn);
+        self.turn_realm = self.turn_realm.or(file.turn_realm);
+        self.rate_limit_kbps = self.rate_limit_kbps.or(file.rate_limit_kbps);
+        self.rate_limit_delay = self.rate_limit_delay.or(file.rate_limit_delay);
+        self.chaos_loss = self.chaos_loss.or(file.chaos_loss);
+        self.chaos_delay_ms = self.chaos_delay_ms.or(file.chaos_delay_ms);
+        self.chaos_jitter_ms = self.chaos_jitter_ms.or(file.chaos_jitter_ms);
+        self.max_sessions = self.max_sessions.or(file.max_sessions);
+        self.max_pending_pairings = self.max_pending_pairings.or(file.max_pending_pairings);
+        self.max_sessions_per_ip = self.max_sessions_per_ip.or(file.max_sessions_per_ip);
+        self.secret_collision = self.secret_collision.or(file.secret_collision);
+        self.max_total_sessions = self.max_total_sessions.or(file.max_total_sessions);
+        self.one_shot = self.one_shot.or(file.one_shot);
+        self.reply_busy = self.reply_busy.or(file.reply_busy);
+        self.max_payload = self.max_payload.or(file.max_payload);
+        self.reply_too_big = self.reply_too_big.or(file.reply_too_big);
+        self.pairing_rate = self.pairing_rate.or(file.pairing_rate);
+        self.pairing_burst = self.pairing_burst.or(file.pairing_burst);
+        self.ban_after = self.ban_after.or(file.ban_after);
+        self.ban_duration = self.ban_duration.or(file.ban_duration);
+        self.keepalive_interval = self.keepalive_interval.or(file.keepalive_interval);
+        self.idle_warning = self.idle_warning.or(file.idle_warning);
+        self.rtt_interval = self.rtt_interval.or(file.rtt_interval);
+        self.max_send_failures = self.max_send_failures.or(file.max_send_failures);
+        self.metrics_listen = self.metrics_listen.or(file.metrics_listen);
+        self.health_listen = self.health_listen.or(file.health_listen);
+        self.forward_to = self.forward_to.or(file.forward_to);
+        self.control_socket = self.control_socket.or(file.control_socket);
+        self.accounting_log = self.accounting_log.or(file.accounting_log);
+        self.on_pair = self.on_pair.or(file.on_pair);
+        self.on_teardown = self.on_teardown.or(file.on_teardown);
+        self.webhook_url = self.webhook_url.or(file.webhook_url);
+        self.pcap = self.pcap.or(file.pcap);
+        self.pcap_handshake_only = self.pcap_handshake_only.or(file.pcap_handshake_only);
+        self.drain_timeout = self.drain_timeout.or(file.drain_timeout);
+        self.workers = self.workers.or(file.workers);
+        self.io_uring = self.io_uring.or(file.io_uring);
+        self.so_rcvbuf = self.so_rcvbuf.or(file.so_rcvbuf);
+        self.so_sndbuf = self.so_sndbuf.or(file.so_sndbuf);
+        self.recv_buffer_size = self.recv_buffer_size.or(file.recv_buffer_size);
+        self.interface = self.interface.or(file.interface);
+        self.dscp = self.dscp.or(file.dscp);
+        self
     }
-    if let Ok(token) = TryInto::<&[u8; 2]>::try_into(&buffer[0..2]) {
-        match *token {
-            OPS_PING => {
-                registry
-                    .socket
-                    .send_to(&OPS_PONG, from)
-                    .expect("Error in sending message");
-            }
-            OPS_CONN_REQ => process_pairing_request(args, registry, buffer, from),
-            _ => (),
+
+    fn log_filter(&self) -> EnvFilter {
+        if let Some(directives) = &self.log_filter {
+            return EnvFilter::new(directives);
         }
+        let level = match (self.global.quiet, self.global.verbose) {
+            (true, _) => LevelFilter::ERROR,
+            (false, 0) => LevelFilter::WARN,
+            (false, 1) => LevelFilter::INFO,
+            (false, 2) => LevelFilter::DEBUG,
+            (false, _) => LevelFilter::TRACE,
+        };
+        EnvFilter::new(level.to_string())
+    }
+
+    fn relay_config(&self, udp_port: u16) -> Result<Config, SettingsError> {
+        let defaults = Config::default();
+        let secs = |value: Option<u64>, default| value.map(Duration::from_secs).unwrap_or(default);
+        let preshared_key = match &self.preshared_key_file {
+            Some(path) => Some(read_key_file(path)?),
+            None => self.preshared_key.clone(),
+        };
+        let preshared_key_hash = self
+            .preshared_key_hash
+            .as_deref()
+            .map(|hash| {
+                auth::parse_psk_hash(hash).ok_or_else(|| SettingsError::PskHash(hash.to_owned()))
+            })
+            .transpose()?;
+        let bind_ip = match &self.bind_ip {
+            Some(host) => settings::resolve_ip(host, self.prefer_family)?,
+            None => Ipv4Addr::UNSPECIFIED.into(),
+        };
+        Ok(Config {
+            bind: SocketAddr::new(bind_ip, udp_port),
+            second_bind: self.second_port.map(|port| SocketAddr::new(bind_ip, port)),
+            extra_binds: self
+                .ports
+                .iter()
+                .flat_map(|range| range.ports())
+                .filter(|&port| port != udp_port)
+                .collect::<BTreeSet<u16>>()
+                .into_iter()
+                .map(|port| SocketAddr::new(bind_ip, port))
+                .collect(),
+            dtls_bind: self.dtls_port.map(|port| SocketAddr::new(bind_ip, port)),
+            dtls_certificate: self.dtls_certificate.clone(),
+            dtls_private_key: self.dtls_private_key.clone(),
+            cluster_bind: self.cluster_port.map(|port| SocketAddr::new(bind_ip, port)),
+            cluster_peers: self
+                .cluster_peer
+                .iter()
+                .map(|peer| settings::resolve(peer, self.prefer_family))
+                .collect::<Result<_, _>>()?,
+            cluster_advertise: self
+                .cluster_advertise
+                .as_deref()
+                .map(|addr| settings::resolve(addr, self.prefer_family))
+                .transpose()?,
+            cluster_key: self.cluster_key.clone().map(Zeroizing::new),
+            preshared_key: preshared_key.map(Zeroizing::new),
+            preshared_key_hash,
+            keys: self.keys.clone(),
+            insecure_open: self.insecure_open.unwrap_or(false),
+            legacy_handshake: self.legacy_handshake.unwrap_or(false),
+            min_protocol_version: self
+                .min_protocol_version
+                .unwrap_or(defaults.min_protocol_version),
+            protocol_magic: self.protocol_magic.unwrap_or(defaults.protocol_magic),
+            encryption: self.encryption.unwrap_or(false),
+            allow_cidrs: self.allow_cidr.clone(),
+            deny_cidrs: self.deny_cidr.clone(),
+            session_resumption: self.session_resumption.unwrap_or(false),
+            session_rebind: self.session_rebind.unwrap_or(false),
+            pairing_codes: self.pairing_codes.unwrap_or(false),
+            reliable: self.reliable.unwrap_or(false),
+            reliable_window: self.reliable_window.unwrap_or(defaults.reliable_window),
+            retransmit_interval: self
+                .retransmit_interval
+                .map_or(defaults.retransmit_interval, Duration::from_millis),
+            fec: self.fec.unwrap_or(false),
+            fec_block_size: self.fec_block_size.unwrap_or(defaults.fec_block_size),
+            compression: self.compression.unwrap_or(false),
+            channels: self.channels.unwrap_or(false),
+            group: self.group.unwrap_or(false),
+            max_group_members: self.max_group_members.unwrap_or(defaults.max_group_members),
+            turn: self.turn.unwrap_or(false),
+            turn_realm: self.turn_realm.clone().unwrap_or(defaults.turn_realm),
+            rate_limit_kbps: self.rate_limit_kbps.filter(|&kbps| kbps > 0),
+            rate_limit_delay: self.rate_limit_delay.unwrap_or(false),
+            chaos_loss: self.chaos_loss.unwrap_or(defaults.chaos_loss),
+            chaos_delay: self
+                .chaos_delay_ms
+                .map_or(defaults.chaos_delay, Duration::from_millis),
+            chaos_jitter: self
+                .chaos_jitter_ms
+                .map_or(defaults.chaos_jitter, Duration::from_millis),
+            max_sessions: self.max_sessions,
+            max_pending_pairings: self.max_pending_pairings,
+            max_sessions_per_ip: self.max_sessions_per_ip,
+            secret_collision: self.secret_collision.unwrap_or_default(),
+            max_total_sessions: if self.one_shot.unwrap_or(false) {
+                Some(1)
+            } else {
+                self.max_total_sessions
+            },
+            reply_busy: self.reply_busy.unwrap_or(false),
+            max_payload: self.max_payload,
+            reply_too_big: self.reply_too_big.unwrap_or(false),
+            pairing_rate: self.pairing_rate.unwrap_or(defaults.pairing_rate),
+            pairing_burst: self.pairing_burst.unwrap_or(defaults.pairing_burst),
+            ban_after: self.ban_after.unwrap_or(defaults.ban_after),
+            ban_duration: secs(self.ban_duration, defaults.ban_duration),
+            housekeeping_interval: secs(self.housekeeping_interval, defaults.housekeeping_interval),
+            timeout_no_connections: secs(
+                self.timeout_no_connections,
+                defaults.timeout_no_connections,
+            ),
+            timeout_pairing: secs(self.timeout_pairing, defaults.timeout_pairing),
+            timeout_connection_inactivities: secs(
+                self.timeout_connection_inactivities,
+                defaults.timeout_connection_inactivities,
+            ),
+            keepalive_interval: self
+                .keepalive_interval
+                .filter(|&secs| secs > 0)
+                .map(Duration::from_secs),
+            idle_warning: self
+                .idle_warning
+                .filter(|&secs| secs > 0)
+                .map(Duration::from_secs),
+            rtt_interval: match self.rtt_interval {
+                Some(0) => None,
+                Some(secs) => Some(Duration::from_secs(secs)),
+                None => defaults.rtt_interval,
+            },
+            max_send_failures: self.max_send_failures.unwrap_or(defaults.max_send_failures),
+            metrics_listen: self.metrics_listen,
+            health_listen: self.health_listen,
+            forward_to: self
+                .forward_to
+                .as_deref()
+                .map(|target| settings::resolve(target, self.prefer_family))
+                .transpose()?,
+            control_socket: self.control_socket.clone(),
+            accounting_log: self.accounting_log.clone(),
+            on_pair: self.on_pair.clone(),
+            on_teardown: self.on_teardown.clone(),
+            webhook_url: self.webhook_url.clone(),
+            pcap: self.pcap.clone(),
+            pcap_handshake_only: self.pcap_handshake_only.unwrap_or(false),
+            drain_timeout: secs(self.drain_timeout, defaults.drain_timeout),
+            workers: self.workers.unwrap_or(defaults.workers),
+            io_uring: self.io_uring.unwrap_or(false),
+            so_rcvbuf: self.so_rcvbuf,
+            so_sndbuf: self.so_sndbuf,
+            recv_buffer_size: self.recv_buffer_size.unwrap_or(defaults.recv_buffer_size),
+            interface: self.interface.clone(),
+            dscp: self.dscp,
+        })
     }
 }
 
-fn process_pairing_request(
-    args: &Args,
-    registry: &mut RelayService,
-    buffer: &[u8],
-    from: &SocketAddr,
-) {
-    let psk_bytes = args.preshared_key.as_bytes();
-    // [**xyPPPPP...PPPPPSSSSS....SSSS]
-    // *: command
-    // x: denote number of bytes (after the first 4 bytes) for PSK
-    // y: denote number of bytes (after the first 4 + x bytes) for secret key
-    // P: pre-shared key (where len = x)
-    // S: Secret key (where len = y)
-    if buffer.len() > (2 + args.preshared_key.as_bytes().len()) {
-        // check at least it has the minimum number of bytes needed
-        println_if_verbose!(args.verbose, "> Got establish connection token from {from}");
-
-        let n_psk: usize = buffer[2].into();
-        let psk_end = 4 + n_psk;
-        let n_secret: usize = buffer[3].into();
-        if buffer.len() < n_psk + n_secret {
-            println_if_verbose!(
-                args.verbose,
-                "> Aborting as there aren't enough message length than needed"
-            );
+#[cfg(unix)]
+/// Re-reads the config file on every SIGHUP and swaps in the resulting settings,
+/// with the command line and environment still taking precedence.
+async fn reload_on_sighup(cli_args: ServeArgs, udp_port: u16, handle: ConfigHandle) {
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            error!("Cannot listen for SIGHUP: {e}");
             return;
         }
-
-        let peer_secret = &buffer[psk_end..(psk_end + n_secret)];
-
-        if &buffer[4..psk_end] == psk_bytes {
-            // send ack
-            println_if_verbose!(
-                args.verbose,
-                "> Authenticated. Peer secret: {:?}",
-                str::from_utf8(peer_secret).unwrap_or("[some bytes]")
-            );
-            match registry.pending_pairing.get_mut(peer_secret) {
-                Some((other_peer, timer)) if other_peer == from => {
-                    println_if_verbose!(
-                        args.verbose,
-                        "> Found existing pairing request from same address/ip/secret. Ignoring..."
-                    );
-                    timer.access();
-                }
-                Some((_, _)) => {
-                    let (other_peer, _) = registry
-                        .pending_pairing
-                        .remove(peer_secret)
-                        .expect("This should exists, as it just were");
-                    let (peer1, peer2) =
-                        build_paired_peers(&other_peer, registry.socket, from, registry.socket);
-                    println_if_verbose!(
-                        args.verbose,
-                        "> Found other peer with same secret. Connecting {} to {}.",
-                        peer1.borrow().recipient.addr,
-                        peer2.borrow().recipient.addr,
-                    );
-                    registry.pairing.insert(other_peer, peer1);
-                    registry.pairing.insert(*from, peer2);
-                }
-                None => {
-                    let message = concat_arrays(&OPS_ACK, peer_secret);
-                    registry
-                        .socket
-                        .send_to(&message, from)
-                        .expect("Error in sending message");
-
-                    registry
-                        .pending_pairing
-                        .borrow_mut()
-                        .insert(peer_secret.to_owned(), (*from, ExpiringTimer::new()));
+    };
+    while hangup.recv().await.is_some() {
+        let Some(path) = &cli_args.global.config else {
+            warn!("Received SIGHUP, but there is no config file to reload");
+            continue;
+        };
+        let config = FileConfig::load(path)
+            .and_then(|file| cli_args.clone().or_file(file).relay_config(udp_port));
+        match config {
+            Ok(config) => {
+                if let Err(e) = handle.reload(config) {
+                    error!("Refusing to reload settings: {e}");
                 }
             }
-        } else {
-            println_if_verbose!(args.verbose, "> Aborting as psk does not match");
+            Err(e) => error!("Cannot reload config file: {e}"),
         }
     }
 }
 
-struct RelayService<'a> {
-    pairing: HashMap<SocketAddr, Rc<RefCell<RecipientData<'a>>>>,
-    pending_pairing: HashMap<Vec<u8>, (SocketAddr, ExpiringTimer)>,
-    socket: &'a UdpSocket,
+/// Prints a summary of every session to stderr, next to the logs, on every SIGUSR1.
+#[cfg(unix)]
+async fn report_on_sigusr1(status: StatusHandle, log_file: Option<LogFile>) {
+    let mut user_signal = match signal(SignalKind::user_defined1()) {
+        Ok(user_signal) => user_signal,
+        Err(e) => {
+            error!("Cannot listen for SIGUSR1: {e}");
+            return;
+        }
+    };
+    while user_signal.recv().await.is_some() {
+        match log_file.as_ref() {
+            Some(mut log_file) => {
+                let _ = log_file.write_all(status.report().as_bytes());
+            }
+            None => eprint!("{}", status.report()),
+        }
+    }
 }
 
-impl RelayService<'_> {
-    fn is_empty(&self) -> bool {
-        self.pairing.len() == 0 && self.pending_pairing.len() == 0
+/// Tells the systemd watchdog the relay is alive every `interval`.
+async fn ping_watchdog(interval: Duration) {
+    loop {
+        if let Err(e) = systemd::notify("WATCHDOG=1") {
+            warn!("Cannot ping the systemd watchdog: {e}");
+        }
+        tokio::time::sleep(interval).await;
     }
+}
 
-    fn remove_inactive_connections(&mut self, args: &Args) {
-        if self.pairing.is_empty() {
-            return;
+/// Resolves on the first SIGTERM or SIGINT.
+#[cfg(unix)]
+async fn shutdown_signal() {
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(terminate) => terminate,
+        Err(e) => {
+            eprintln!("Cannot listen for SIGTERM: {}", e);
+            return future::pending().await;
         }
-        // keep track of the pairs of addr to remove.
-        let mut to_remove = HashSet::new();
-        for peer_a_rc in self.pairing.values() {
-            let mut peer_a_guard = peer_a_rc.as_ref().borrow_mut();
-            let peer_b_rc = peer_a_guard.get_opponent();
-            let peer_b_guard = peer_b_rc.as_ref().borrow_mut();
+    };
+    tokio::select! {
+        _ = terminate.recv() => (),
+        _ = tokio::signal::ctrl_c() => (),
+    }
+}
 
-            let last_access_a = &peer_a_guard.last_accessed;
-            let last_access_b = &peer_b_guard.last_accessed;
+/// Resolves on the first Ctrl-C or Ctrl-Break, or when the console is closed or
+/// the system shuts down, as when a service wrapper stops the relay.
+#[cfg(windows)]
+async fn shutdown_signal() {
+    use tokio::signal::windows;
 
-            if last_access_a.is_expired(args.timeout_connection_inactivities)
-                && last_access_b.is_expired(args.timeout_connection_inactivities)
-            {
-                println_if_verbose!(args.verbose, "> Connection between '{addr1}' and '{addr2} has no activities after {timeout} seconds. Removing them...",
-                        addr1=peer_a_guard.recipient.addr,
-                        addr2=peer_b_guard.recipient.addr,
-                        timeout=args.timeout_connection_inactivities
-                    );
-                to_remove.insert(peer_a_guard.recipient.addr);
-                to_remove.insert(peer_b_guard.recipient.addr);
-            };
+    let events = (
+        windows::ctrl_break(),
+        windows::ctrl_close(),
+        windows::ctrl_shutdown(),
+    );
+    let (mut ctrl_break, mut close, mut shutdown) = match events {
+        (Ok(ctrl_break), Ok(close), Ok(shutdown)) => (ctrl_break, close, shutdown),
+        (Err(e), _, _) | (_, Err(e), _) | (_, _, Err(e)) => {
+            eprintln!("Cannot listen for console events: {}", e);
+            return future::pending().await;
         }
+    };
+    tokio::select! {
+        _ = ctrl_break.recv() => (),
+        _ = close.recv() => (),
+        _ = shutdown.recv() => (),
+        _ = tokio::signal::ctrl_c() => (),
+    }
+}
 
-        for k in to_remove {
-            self.pairing.remove(&k).expect("unable to remvoe key");
-        }
+#[cfg(unix)]
+fn post_fork_parent(_ppid: i32, cpid: i32) -> ! {
+    eprintln!("Daeminized process started; pid: {}.", cpid);
+    exit(0)
+}
+
+fn resolve(relay: &str, prefer: Option<IpFamily>) -> Result<SocketAddr, String> {
+    match relay
+        .to_socket_addrs()
+        .map(|addrs| settings::pick(addrs, prefer))
+    {
+        Ok(Some(addr)) => Ok(addr),
+        Ok(None) => Err(format!("No address found for relay {relay}")),
+        Err(e) => Err(format!("Cannot resolve relay {relay}: {e}")),
     }
+}
+
+/// Host name of a `host:port` relay address; `None` for an IP address.
+fn relay_host(relay: &str) -> Option<String> {
+    let (host, _) = relay.rsplit_once(':')?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    host.parse::<IpAddr>().is_err().then(|| host.to_owned())
+}
+
+fn ping(args: PingArgs) -> ExitCode {
+    let relay = match resolve(&args.relay, args.prefer_family) {
+        Ok(relay) => relay,
+        Err(e) => {
+            eprintln!("{e}");
+            return ExitCode::from(2);
+        }
+    };
+    let (Ok(timeout), Ok(interval)) = (
+        Duration::try_from_secs_f64(args.timeout),
+        Duration::try_from_secs_f64(args.interval),
+    ) else {
+        eprintln!("Invalid timeout or interval");
+        return ExitCode::from(2);
+    };
+    let magic = args.protocol_magic.unwrap_or(protocol::DEFAULT_MAGIC);
+    let unspecified: IpAddr = match relay {
+        SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    };
+    let socket = match UdpSocket::bind((unspecified, 0)) {
+        Ok(socket) => socket,
+        Err(e) => {
+            eprintln!("Cannot bind socket: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
 
-    fn remove_expired_pairing_request(&mut self, args: &Args) {
-        self.pending_pairing.retain(|_, (v, pending_timer)| {
-            if pending_timer.is_expired(args.timeout_pairing) {
-                println_if_verbose!(
-                    args.verbose,
-                    "> Pending pairing from '{v}' is expired after {} seconds",
-                    args.timeout_pairing
+    let mut rtts = Vec::new();
+    for seq in 1..=args.count {
+        if seq > 1 {
+            std::thread::sleep(interval);
+        }
+        match client::probe(&socket, relay, magic, seq, timeout) {
+            Ok(Some(rtt)) => {
+                println!(
+                    "reply from {relay}: seq={seq} time={:.3} ms",
+                    rtt.as_secs_f64() * 1000.0
                 );
-                return false;
+                rtts.push(rtt);
             }
-            true
-        });
+            Ok(None) => println!("no reply from {relay}: seq={seq}"),
+            Err(e) => println!("cannot probe {relay}: seq={seq}: {e}"),
+        }
     }
-}
 
-fn start_relay_service(args: &Args, socket: UdpSocket) {
-    let mut registry = RelayService {
-        pairing: HashMap::new(),
-        pending_pairing: HashMap::new(),
-        socket: &socket,
+    println!(
+        "{} probes sent, {} replies received",
+        args.count,
+        rtts.len()
+    );
+    let (Some(min), Some(max)) = (rtts.iter().min(), rtts.iter().max()) else {
+        return ExitCode::FAILURE;
     };
+    let avg = rtts.iter().sum::<Duration>() / rtts.len() as u32;
+    println!(
+        "rtt min/avg/max = {:.3}/{:.3}/{:.3} ms",
+        min.as_secs_f64() * 1000.0,
+        avg.as_secs_f64() * 1000.0,
+        max.as_secs_f64() * 1000.0
+    );
+    ExitCode::SUCCESS
+}
 
-    // loop untils some value is returned by the functor
-    let mut buf = [0u8; 65535];
-    let mut no_connection_since: Option<ExpiringTimer> = None;
+fn client(args: ClientArgs, global: &GlobalArgs) -> ExitCode {
+    let level = match (global.quiet, global.verbose) {
+        (true, _) => LevelFilter::ERROR,
+        (false, 0) => LevelFilter::INFO,
+        (false, 1) => LevelFilter::DEBUG,
+        (false, _) => LevelFilter::TRACE,
+    };
+    tracing_subscriber::fmt()
+        .with_max_level(level)
+        .with_writer(std::io::stderr)
+        .init();
 
-    // let psk_bytes = args.preshared_key.as_bytes();
-    loop {
-        match registry.socket.recv_from(&mut buf) {
-            Ok((n, from)) if n > 0 => match registry.pairing.get(&from) {
-                Some(sender) => process_relay_service(args, &buf[..n], sender),
-                None => process_maybe_request(args, &mut registry, &buf[..n], &from),
-            },
+    let relay = match resolve(&args.relay, args.prefer_family) {
+        Ok(relay) => relay,
+        Err(e) => {
+            eprintln!("{e}");
+            return ExitCode::from(2);
+        }
+    };
+    let preshared_key = match &args.preshared_key_file {
+        Some(path) => match read_key_file(path) {
+            Ok(psk) => psk,
+            Err(e) => {
+                eprintln!("Cannot read pre-shared key: {}", e);
+                return ExitCode::from(2);
+            }
+        },
+        None => args.preshared_key.unwrap_or_default(),
+    };
+    let config = ClientConfig {
+        relay,
+        secret: args.secret.into_bytes(),
+        preshared_key: Zeroizing::new(preshared_key),
+        legacy_handshake: args.legacy_handshake,
+        encrypt: args.encrypt,
+        reliable: args.reliable,
+        fec: args
+            .fec
+            .then(|| args.fec_block_size.unwrap_or(fec::DEFAULT_BLOCK_SIZE)),
+        compress: args.compress,
+        dtls: args.dtls_port.map(|port| DtlsOptions {
+            port,
+            ca_file: args.dtls_ca,
+            server_name: relay_host(&args.relay),
+            identity: args.dtls_identity,
+        }),
+        local: SocketAddr::new(args.local_ip, args.local_port),
+        retry_interval: Duration::from_secs(args.retry_interval),
+        protocol_magic: args.protocol_magic.unwrap_or(protocol::DEFAULT_MAGIC),
+    };
 
-            // when this socket timeout, do some processing in the following.
-            Err(e) if e.kind() == io::ErrorKind::WouldBlock => (),
-            Err(e) => eprintln!("Unexpected error: {e}"),
-            _ => (),
-        };
+    let runtime = match tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+    {
+        Ok(runtime) => runtime,
+        Err(e) => {
+            eprintln!("Cannot start async runtime: {}", e);
+            return ExitCode::from(128);
+        }
+    };
+    let result = runtime.block_on(async { client::run_until(config, shutdown_signal()).await });
+    if let Err(e) = result {
+        eprintln!("Client failed: {}", e);
+        return ExitCode::FAILURE;
+    }
+    ExitCode::SUCCESS
+}
 
-        // stop this process when it has no activities after the given time
-        match (&no_connection_since, registry.is_empty()) {
-            (Some(timer), true) => {
-                if timer.is_expired(args.timeout_no_connections) {
-                    println_if_verbose!(
-                        args.verbose,
-                        "> No connections for {} seconds. Quitting...",
-                        args.timeout_no_connections
-                    );
-                    break;
-                }
+fn code(args: CodeArgs) -> ExitCode {
+    let preshared_key = match &args.preshared_key_file {
+        Some(path) => match read_key_file(path) {
+            Ok(psk) => psk,
+            Err(e) => {
+                eprintln!("Cannot read pre-shared key: {}", e);
+                return ExitCode::from(2);
             }
-            // remove timer as there's pending connections
-            (Some(_), false) => no_connection_since = None,
-            // add a pending timer
-            (None, true) => no_connection_since = Some(ExpiringTimer::new()),
-            (None, false) => (), // all is good
-        };
+        },
+        None => args.preshared_key.unwrap_or_default(),
+    };
+    let step = auth::CODE_STEP.as_secs();
+    let accepted_for = step - auth::unix_time() % step + auth::CODE_SKEW_STEPS * step;
+    println!("{}", auth::current_pairing_code(preshared_key.as_bytes()));
+    eprintln!("Accepted for {accepted_for} more seconds");
+    ExitCode::SUCCESS
+}
 
-        registry.remove_expired_pairing_request(args);
-        registry.remove_inactive_connections(args);
+#[cfg(unix)]
+fn ctl(args: CtlArgs) -> ExitCode {
+    match control::request(&args.socket, &args.command.to_line()) {
+        Ok(response) => {
+            print!("{response}");
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!(
+                "Cannot reach control socket {}: {}",
+                args.socket.display(),
+                e
+            );
+            ExitCode::FAILURE
+        }
     }
 }
 
-fn post_fork_parent(_ppid: i32, cpid: i32) -> ! {
-    eprintln!("Daeminized process started; pid: {}.", cpid);
-    exit(0)
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    match cli.command {
+        Some(Command::Serve(serve_args)) => serve(serve_args),
+        Some(Command::Forward(forward_args)) => serve(ServeArgs {
+            forward_to: Some(forward_args.to),
+            ..forward_args.serve
+        }),
+        Some(Command::Client(client_args)) => client(client_args, &cli.serve.global),
+        Some(Command::Ping(ping_args)) => ping(ping_args),
+        Some(Command::Code(code_args)) => code(code_args),
+        #[cfg(unix)]
+        Some(Command::Ctl(ctl_args)) => ctl(ctl_args),
+        None => serve(cli.serve),
+    }
 }
 
-fn main() -> ExitCode {
-    let args = Args::parse();
+fn serve(args: ServeArgs) -> ExitCode {
+    #[cfg(unix)]
+    let cli_args = args.clone();
+    let args = match &args.global.config {
+        Some(path) => match FileConfig::load(path) {
+            Ok(file) => args.or_file(file),
+            Err(e) => {
+                eprintln!("Cannot load config file: {}", e);
+                return ExitCode::from(2);
+            }
+        },
+        None => args,
+    };
+    let log_file = match &args.log_file {
+        Some(path) => match LogFile::open(
+            path,
+            args.log_max_size,
+            args.log_rotation.unwrap_or_default(),
+            args.log_keep.unwrap_or(DEFAULT_LOG_KEEP),
+        ) {
+            Ok(log_file) => Some(log_file),
+            Err(e) => {
+                eprintln!("Cannot open log file {}: {}", path.display(), e);
+                return ExitCode::from(2);
+            }
+        },
+        None => None,
+    };
+    let subscriber = tracing_subscriber::fmt().with_env_filter(args.log_filter());
+    match log_file.clone() {
+        Some(log_file) => subscriber.with_writer(log_file).with_ansi(false).init(),
+        None => subscriber.with_writer(std::io::stderr).init(),
+    }
+
+    let activated = match systemd::activated_socket() {
+        Ok(activated) => activated,
+        Err(e) => {
+            error!("Cannot use the socket passed by systemd: {}", e);
+            return ExitCode::from(2);
+        }
+    };
+    let activated_port = activated
+        .as_ref()
+        .and_then(|socket| socket.local_addr().ok())
+        .map(|addr| addr.port());
+    let first_port = args.ports.first().map(|range| *range.ports().start());
+    let Some(udp_port) = activated_port.or(args.udp_port).or(first_port) else {
+        error!("No UDP port given on the command line, environment, nor config file");
+        return ExitCode::from(2);
+    };
+
+    let config = match args.relay_config(udp_port) {
+        Ok(config) => config,
+        Err(e) => {
+            error!("Invalid settings: {}", e);
+            return ExitCode::from(2);
+        }
+    };
 
     // Create UDP sockets for listening port
-    let socket = match bind_socket(args.bind_ip, args.udp_port, &args) {
-        Ok(socket) => socket,
+    let mut builder = RelayBuilder::from(config);
+    if let Some(socket) = activated {
+        builder = builder.socket(socket);
+    }
+    let relay = match builder.build() {
+        Ok(relay) => relay,
+        Err(e) if e.kind() == io::ErrorKind::InvalidInput => {
+            error!("Invalid settings: {}", e);
+            return ExitCode::from(2);
+        }
         Err(e) => {
-            eprintln!("Cannot binds socket: {}", e);
+            error!("Cannot binds socket: {}", e);
             exit(49)
         }
     };
 
-    if args.daemonize {
-        // let stdout = File::create("/tmp/daemon.out").unwrap();
-        // let stderr = File::create("/tmp/daemon.err").unwrap();
+    // kept absolute so that it is removed from where it was written, as
+    // daemonizing changes the working directory
+    let port_file = match args.port_file.as_deref().map(path::absolute).transpose() {
+        Ok(port_file) => port_file,
+        Err(e) => {
+            error!("Invalid port file: {}", e);
+            return ExitCode::from(2);
+        }
+    };
+    // with port 0, scripts learn the port the OS picked from this line
+    if udp_port == 0 || port_file.is_some() {
+        let addr = match relay.local_addr() {
+            Ok(addr) => addr,
+            Err(e) => {
+                error!("Cannot get the relay address: {}", e);
+                return ExitCode::FAILURE;
+            }
+        };
+        let announcement = format!("port={} address={addr}\n", addr.port());
+        match &port_file {
+            Some(port_file) => {
+                if let Err(e) = fs::write(port_file, announcement) {
+                    error!("Cannot write port file {}: {}", port_file.display(), e);
+                    return ExitCode::from(128);
+                }
+            }
+            None => print!("{announcement}"),
+        }
+    }
 
+    let daemonize = args.daemonize.unwrap_or(false);
+    let pid_file = match &args.pid_file {
+        Some(path) => Some(path.clone()),
+        None if daemonize => Some(PathBuf::from(DEFAULT_PID_FILE)),
+        None => None,
+    };
+    #[cfg(unix)]
+    if let (true, Some(pid_file)) = (daemonize, &pid_file) {
         let daemon = Daemon::new()
-            .pid_file("/tmp/udprelay-rs.pid", Some(false))
+            .pid_file(pid_file, Some(false))
             .umask(0o000)
             .work_dir("/tmp")
-            // .stdout(stdout)
-            // .stderr(stderr)
             // Hooks are optional
             .setup_post_fork_parent_hook(post_fork_parent);
 
         match daemon.start() {
-            Ok(_) => eprintln!("Success, daemonized"),
+            Ok(_) => info!("Success, daemonized"),
             Err(e) => {
-                eprintln!("Error: {}", e);
+                error!("Cannot daemonize: {}", e);
                 return ExitCode::from(128);
             }
         }
     }
-    start_relay_service(&args, socket);
+    if daemonize && cfg!(not(unix)) {
+        error!("Cannot daemonize on this platform; run the relay as a service, with --log-file");
+        return ExitCode::from(2);
+    }
+    if let (false, Some(pid_file)) = (daemonize, &pid_file) {
+        if let Err(e) = fs::write(pid_file, format!("{}\n", std::process::id())) {
+            error!("Cannot write PID file {}: {}", pid_file.display(), e);
+            return ExitCode::from(128);
+        }
+    }
+
+    // the runtime is only started after daemonizing, as forking does not carry threads over
+    let runtime = match tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+    {
+        Ok(runtime) => runtime,
+        Err(e) => {
+            error!("Cannot start async runtime: {}", e);
+            return ExitCode::from(128);
+        }
+    };
+    let result = runtime.block_on(async {
+        #[cfg(unix)]
+        tokio::spawn(reload_on_sighup(cli_args, udp_port, relay.config_handle()));
+        #[cfg(unix)]
+        tokio::spawn(report_on_sigusr1(relay.status_handle(), log_file));
+        if let Some(interval) = systemd::watchdog_interval() {
+            tokio::spawn(ping_watchdog(interval));
+        }
+        if let Err(e) = systemd::notify("READY=1") {
+            warn!("Cannot notify systemd: {e}");
+        }
+        relay
+            .run_until(async {
+                shutdown_signal().await;
+                if let Err(e) = systemd::notify("STOPPING=1") {
+                    warn!("Cannot notify systemd: {e}");
+                }
+            })
+            .await
+    });
+    if let Some(pid_file) = &pid_file {
+        if let Err(e) = fs::remove_file(pid_file) {
+            error!("Cannot remove PID file {}: {}", pid_file.display(), e);
+        }
+    }
+    if let Some(port_file) = &port_file {
+        if let Err(e) = fs::remove_file(port_file) {
+            error!("Cannot remove port file {}: {}", port_file.display(), e);
+        }
+    }
+    if let Err(e) = result {
+        error!("Relay service failed: {}", e);
+        return ExitCode::FAILURE;
+    }
 
     ExitCode::SUCCESS
 }
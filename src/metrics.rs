@@ -0,0 +1,581 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use crate::peer::Side;
+use crate::service::RelayService;
+
+/// Cumulative counters of a relay since it started.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct Counters {
+    /// Indexed by the [`Side`] of the sending peer.
+    pub(crate) relayed_packets: [u64; 2],
+    pub(crate) relayed_bytes: [u64; 2],
+    pub(crate) rejected_bad_psk: u64,
+    pub(crate) rejected_short_packet: u64,
+    pub(crate) rejected_bad_challenge: u64,
+    pub(crate) rejected_replayed: u64,
+    pub(crate) rejected_network: u64,
+    pub(crate) rejected_key_mismatch: u64,
+    pub(crate) rejected_session_limit: u64,
+    /// Peers registering on the port their counterpart is already waiting on,
+    /// in dual-port mode.
+    pub(crate) rejected_same_port: u64,
+    pub(crate) rejected_group_full: u64,
+    /// Resume requests carrying an unknown token, and rebind requests naming an
+    /// unknown session or failing authentication.
+    pub(crate) rejected_bad_token: u64,
+    /// Secrets other than a current pairing code, with
+    /// [`Config::pairing_codes`](crate::Config::pairing_codes).
+    pub(crate) rejected_bad_code: u64,
+    /// Pairing requests refused by [`Config::max_sessions`](crate::Config::max_sessions).
+    pub(crate) rejected_max_sessions: u64,
+    /// Pairing requests refused by
+    /// [`Config::max_pending_pairings`](crate::Config::max_pending_pairings).
+    pub(crate) rejected_max_pending: u64,
+    /// Pairing requests refused by
+    /// [`Config::max_sessions_per_ip`](crate::Config::max_sessions_per_ip).
+    pub(crate) rejected_ip_limit: u64,
+    /// Pairing requests refused by
+    /// [`Config::secret_collision`](crate::Config::secret_collision).
+    pub(crate) rejected_secret_in_use: u64,
+    /// Pairing requests refused once
+    /// [`Config::max_total_sessions`](crate::Config::max_total_sessions) were paired.
+    pub(crate) rejected_max_total_sessions: u64,
+    /// Pairing requests starting with another protocol magic than
+    /// [`Config::protocol_magic`](crate::Config::protocol_magic).
+    pub(crate) rejected_magic: u64,
+    /// Handshake messages of a protocol version older than
+    /// [`Config::min_protocol_version`](crate::Config::min_protocol_version).
+    pub(crate) rejected_version: u64,
+    /// Sessions rebound to a new address of one of their peers.
+    pub(crate) resumed_sessions: u64,
+    /// Waiting or paired peers replaced under [`SecretCollision::Takeover`](crate::SecretCollision).
+    pub(crate) taken_over_sessions: u64,
+    /// Sessions paired so far, by the name of the key they authenticated with.
+    pub(crate) pairings_by_key: HashMap<String, u64>,
+    /// Handshake messages dropped by the per-source rate limiter.
+    pub(crate) rate_limited: u64,
+    pub(crate) expired_sessions: u64,
+    /// Sessions torn down by a peer sending [`Ops::Disconnect`](crate::protocol::Ops).
+    pub(crate) disconnected_sessions: u64,
+    /// Sessions torn down by one of their peers starting a new handshake.
+    pub(crate) restarted_sessions: u64,
+    /// Datagrams of encrypted sessions failing authentication or replayed.
+    pub(crate) undecryptable_packets: u64,
+    /// Datagrams of paired peers dropped for exceeding their bandwidth.
+    pub(crate) throttled_packets: u64,
+    /// Datagrams of paired peers and group members dropped as their payload
+    /// was over [`Config::max_payload`](crate::Config::max_payload).
+    pub(crate) oversized_packets: u64,
+    /// Datagrams to paired peers dropped on purpose, see
+    /// [`Config::chaos_loss`](crate::Config::chaos_loss).
+    pub(crate) chaos_dropped_packets: u64,
+    /// Datagrams of paired peers held back to stay within their bandwidth.
+    pub(crate) delayed_packets: u64,
+    /// Peers sent to another instance of the cluster.
+    pub(crate) redirected: u64,
+    /// Datagrams of paired peers and group members that could not be sent.
+    pub(crate) send_failures: u64,
+    /// Sessions torn down as sends to one of their peers kept failing.
+    pub(crate) unreachable_sessions: u64,
+    /// Frames sent again to peers with reliable delivery, see [`crate::reliable`].
+    pub(crate) retransmitted_packets: u64,
+    /// Datagrams to peers with reliable delivery dropped as their send window was full.
+    pub(crate) window_overflows: u64,
+    /// Bytes saved by compressing the datagrams relayed to peers, see
+    /// [`crate::compression`].
+    pub(crate) compression_saved_bytes: u64,
+    /// Datagrams rebuilt by forward error correction, see [`crate::fec`].
+    pub(crate) fec_recovered_packets: u64,
+    /// Keepalives sent to the peers of idle sessions.
+    pub(crate) keepalives: u64,
+    /// Warnings sent to the peers of pairs about to expire, see
+    /// [`Config::idle_warning`](crate::Config::idle_warning).
+    pub(crate) idle_warnings: u64,
+    pub(crate) expired_pairings: u64,
+}
+
+impl Counters {
+    /// Adds the counts of `other`, e.g. gathered while relaying a batch without
+    /// holding the registry.
+    pub(crate) fn add(&mut self, other: Counters) {
+        let Counters {
+            relayed_packets,
+            relayed_bytes,
+            rejected_bad_psk,
+            rejected_short_packet,
+            rejected_bad_challenge,
+            rejected_replayed,
+            rejected_network,
+            rejected_key_mismatch,
+            rejected_session_limit,
+            rejected_same_port,
+            rejected_group_full,
+            rejected_bad_token,
+            rejected_bad_code,
+            rejected_max_sessions,
+            rejected_max_pending,
+            rejected_ip_limit,
+            rejected_secret_in_use,
+            rejected_max_total_sessions,
+            rejected_magic,
+            rejected_version,
+            resumed_sessions,
+            taken_over_sessions,
+            pairings_by_key,
+            rate_limited,
+            expired_sessions,
+            disconnected_sessions,
+            restarted_sessions,
+            undecryptable_packets,
+            throttled_packets,
+            oversized_packets,
+            chaos_dropped_packets,
+            delayed_packets,
+            redirected,
+            send_failures,
+            unreachable_sessions,
+            retransmitted_packets,
+            window_overflows,
+            compression_saved_bytes,
+            fec_recovered_packets,
+            keepalives,
+            idle_warnings,
+            expired_pairings,
+        } = other;
+        for (total, count) in self.relayed_packets.iter_mut().zip(relayed_packets) {
+            *total += count;
+        }
+        for (total, count) in self.relayed_bytes.iter_mut().zip(relayed_bytes) {
+            *total += count;
+        }
+        self.rejected_bad_psk += rejected_bad_psk;
+        self.rejected_short_packet += rejected_short_packet;
+        self.rejected_bad_challenge += rejected_bad_challenge;
+        self.rejected_replayed += rejected_replayed;
+        self.rejected_network += rejected_network;
+        self.rejected_key_mismatch += rejected_key_mismatch;
+        self.rejected_session_limit += rejected_session_limit;
+        self.rejected_same_port += rejected_same_port;
+        self.rejected_group_full += rejected_group_full;
+        self.rejected_bad_token += rejected_bad_token;
+        self.rejected_bad_code += rejected_bad_code;
+        self.rejected_max_sessions += rejected_max_sessions;
+        self.rejected_max_pending += rejected_max_pending;
+        self.rejected_ip_limit += rejected_ip_limit;
+        self.rejected_secret_in_use += rejected_secret_in_use;
+        self.rejected_max_total_sessions += rejected_max_total_sessions;
+        self.rejected_magic += rejected_magic;
+        self.rejected_version += rejected_version;
+        self.resumed_sessions += resumed_sessions;
+        self.taken_over_sessions += taken_over_sessions;
+        for (key, count) in pairings_by_key {
+            *self.pairings_by_key.entry(key).or_default() += count;
+        }
+        self.rate_limited += rate_limited;
+        self.expired_sessions += expired_sessions;
+        self.disconnected_sessions += disconnected_sessions;
+        self.restarted_sessions += restarted_sessions;
+        self.undecryptable_packets += undecryptable_packets;
+        self.throttled_packets += throttled_packets;
+        self.oversized_packets += oversized_packets;
+        self.chaos_dropped_packets += chaos_dropped_packets;
+        self.delayed_packets += delayed_packets;
+        self.redirected += redirected;
+        self.send_failures += send_failures;
+        self.unreachable_sessions += unreachable_sessions;
+        self.retransmitted_packets += retransmitted_packets;
+        self.window_overflows += window_overflows;
+        self.compression_saved_bytes += compression_saved_bytes;
+        self.fec_recovered_packets += fec_recovered_packets;
+        self.keepalives += keepalives;
+        self.idle_warnings += idle_warnings;
+        self.expired_pairings += expired_pairings;
+    }
+}
+
+const FIRST_TO_SECOND: &str = "{direction=\"first_to_second\"}";
+const SECOND_TO_FIRST: &str = "{direction=\"second_to_first\"}";
+
+fn as_samples(samples: &[(String, u64)]) -> Vec<(&str, u64)> {
+    samples
+        .iter()
+        .map(|(labels, value)| (labels.as_str(), *value))
+        .collect()
+}
+
+/// Renders the relay state in the Prometheus text exposition format.
+pub(crate) fn render(registry: &RelayService) -> String {
+    let counters = &registry.counters;
+    let mut out = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, samples: &[(&str, u64)]| {
+        let _ = writeln!(out, "# HELP {name} {help}");
+        let _ = writeln!(out, "# TYPE {name} {kind}");
+        for (labels, value) in samples {
+            let _ = writeln!(out, "{name}{labels} {value}");
+        }
+    };
+
+    metric(
+        "udprelay_active_pairs",
+        "gauge",
+        "Number of paired sessions.",
+        &[("", registry.pairing.len() as u64 / 2)],
+    );
+    metric(
+        "udprelay_pending_pairings",
+        "gauge",
+        "Number of pairing requests waiting for their counterpart.",
+        &[("", registry.pending_pairings() as u64)],
+    );
+    metric(
+        "udprelay_active_groups",
+        "gauge",
+        "Number of groups in group mode.",
+        &[("", registry.groups.len() as u64)],
+    );
+    metric(
+        "udprelay_group_members",
+        "gauge",
+        "Number of peers in a group.",
+        &[("", registry.group_members.len() as u64)],
+    );
+    metric(
+        "udprelay_active_forwards",
+        "gauge",
+        "Number of senders forwarded to the static target.",
+        &[("", registry.forwards.len() as u64)],
+    );
+    metric(
+        "udprelay_turn_allocations",
+        "gauge",
+        "Number of TURN allocations.",
+        &[("", registry.allocations.len() as u64)],
+    );
+    let mut active_by_key = HashMap::<String, u64>::new();
+    for peer in registry.pairing.values() {
+        let peer = peer.lock().expect("Peer lock poisoned");
+        if peer.side == Side::First {
+            *active_by_key.entry(peer.key.clone()).or_default() += 1;
+        }
+    }
+    let by_key = |values: &HashMap<String, u64>| -> Vec<(String, u64)> {
+        values
+            .iter()
+            .map(|(key, value)| (format!("{{key=\"{}\"}}", key.escape_default()), *value))
+            .collect()
+    };
+    metric(
+        "udprelay_active_pairs_by_key",
+        "gauge",
+        "Number of paired sessions, by the name of the key they authenticated with.",
+        &as_samples(&by_key(&active_by_key)),
+    );
+    metric(
+        "udprelay_pairings_total",
+        "counter",
+        "Sessions paired, by the name of the key they authenticated with.",
+        &as_samples(&by_key(&counters.pairings_by_key)),
+    );
+    let by_direction = |values: &[u64; 2]| {
+        [
+            (FIRST_TO_SECOND, values[Side::First as usize]),
+            (SECOND_TO_FIRST, values[Side::Second as usize]),
+        ]
+    };
+    metric(
+        "udprelay_relayed_packets_total",
+        "counter",
+        "Datagrams relayed, by direction relative to the peer that registered first.",
+        &by_direction(&counters.relayed_packets),
+    );
+    metric(
+        "udprelay_relayed_bytes_total",
+        "counter",
+        "Payload bytes relayed, by direction relative to the peer that registered first.",
+        &by_direction(&counters.relayed_bytes),
+    );
+    metric(
+        "udprelay_pairing_failures_total",
+        "counter",
+        "Rejected pairing requests, by reason.",
+        &[
+            ("{reason=\"bad_psk\"}", counters.rejected_bad_psk),
+            ("{reason=\"short_packet\"}", counters.rejected_short_packet),
+            (
+                "{reason=\"bad_challenge\"}",
+                counters.rejected_bad_challenge,
+            ),
+            ("{reason=\"replayed\"}", counters.rejected_replayed),
+            ("{reason=\"network\"}", counters.rejected_network),
+            ("{reason=\"key_mismatch\"}", counters.rejected_key_mismatch),
+            (
+                "{reason=\"session_limit\"}",
+                counters.rejected_session_limit,
+            ),
+            ("{reason=\"same_port\"}", counters.rejected_same_port),
+            ("{reason=\"group_full\"}", counters.rejected_group_full),
+            ("{reason=\"bad_token\"}", counters.rejected_bad_token),
+            ("{reason=\"bad_code\"}", counters.rejected_bad_code),
+            ("{reason=\"max_sessions\"}", counters.rejected_max_sessions),
+            ("{reason=\"max_pending\"}", counters.rejected_max_pending),
+            ("{reason=\"ip_limit\"}", counters.rejected_ip_limit),
+            (
+                "{reason=\"secret_in_use\"}",
+                counters.rejected_secret_in_use,
+            ),
+            (
+                "{reason=\"max_total_sessions\"}",
+                counters.rejected_max_total_sessions,
+            ),
+            ("{reason=\"version\"}", counters.rejected_version),
+            ("{reason=\"magic\"}", counters.rejected_magic),
+        ],
+    );
+    metric(
+        "udprelay_rate_limited_total",
+        "counter",
+        "Handshake messages dropped by the per-source rate limiter.",
+        &[("", counters.rate_limited)],
+    );
+    metric(
+        "udprelay_undecryptable_packets_total",
+        "counter",
+        "Datagrams of encrypted sessions dropped as not authentic or replayed.",
+        &[("", counters.undecryptable_packets)],
+    );
+    metric(
+        "udprelay_throttled_packets_total",
+        "counter",
+        "Datagrams of paired peers dropped for exceeding their bandwidth limit.",
+        &[("", counters.throttled_packets)],
+    );
+    metric(
+        "udprelay_delayed_packets_total",
+        "counter",
+        "Datagrams of paired peers held back to stay within their bandwidth limit.",
+        &[("", counters.delayed_packets)],
+    );
+    metric(
+        "udprelay_oversized_packets_total",
+        "counter",
+        "Datagrams of paired peers and group members dropped as their payload was over --max-payload.",
+        &[("", counters.oversized_packets)],
+    );
+    metric(
+        "udprelay_chaos_dropped_packets_total",
+        "counter",
+        "Datagrams to paired peers dropped on purpose by --chaos-loss.",
+        &[("", counters.chaos_dropped_packets)],
+    );
+    metric(
+        "udprelay_banned_sources",
+        "gauge",
+        "Source addresses currently banned for exceeding the pairing rate.",
+        &[("", registry.rate_limiter.banned() as u64)],
+    );
+    metric(
+        "udprelay_expired_sessions_total",
+        "counter",
+        "Paired sessions torn down after inactivity.",
+        &[("", counters.expired_sessions)],
+    );
+    metric(
+        "udprelay_send_failures_total",
+        "counter",
+        "Datagrams to paired peers and group members that could not be sent.",
+        &[("", counters.send_failures)],
+    );
+    metric(
+        "udprelay_unreachable_sessions_total",
+        "counter",
+        "Sessions torn down as sends to one of their peers kept failing.",
+        &[("", counters.unreachable_sessions)],
+    );
+    metric(
+        "udprelay_retransmitted_packets_total",
+        "counter",
+        "Datagrams sent again to peers with reliable delivery as they were not acknowledged.",
+        &[("", counters.retransmitted_packets)],
+    );
+    metric(
+        "udprelay_window_overflows_total",
+        "counter",
+        "Datagrams to peers with reliable delivery dropped as their send window was full.",
+        &[("", counters.window_overflows)],
+    );
+    metric(
+        "udprelay_compression_saved_bytes_total",
+        "counter",
+        "Bytes saved by compressing the datagrams relayed to peers with compression.",
+        &[("", counters.compression_saved_bytes)],
+    );
+    metric(
+        "udprelay_fec_recovered_packets_total",
+        "counter",
+        "Datagrams of peers with forward error correction rebuilt from parity.",
+        &[("", counters.fec_recovered_packets)],
+    );
+    metric(
+        "udprelay_resumed_sessions_total",
+        "counter",
+        "Sessions rebound to a new address of one of their peers.",
+        &[("", counters.resumed_sessions)],
+    );
+    metric(
+        "udprelay_taken_over_sessions_total",
+        "counter",
+        "Waiting or paired peers replaced by a peer presenting their secret from the same IP.",
+        &[("", counters.taken_over_sessions)],
+    );
+    metric(
+        "udprelay_keepalives_total",
+        "counter",
+        "Keepalives sent to the peers of idle sessions.",
+        &[("", counters.keepalives)],
+    );
+    metric(
+        "udprelay_idle_warnings_total",
+        "counter",
+        "Warnings sent to the peers of pairs about to be torn down for inactivity.",
+        &[("", counters.idle_warnings)],
+    );
+    metric(
+        "udprelay_disconnected_sessions_total",
+        "counter",
+        "Sessions torn down on request of one of their peers.",
+        &[("", counters.disconnected_sessions)],
+    );
+    metric(
+        "udprelay_restarted_sessions_total",
+        "counter",
+        "Sessions torn down by one of their peers starting a new handshake from its address.",
+        &[("", counters.restarted_sessions)],
+    );
+    metric(
+        "udprelay_redirected_peers_total",
+        "counter",
+        "Peers sent to another instance of the cluster, where their counterpart waits.",
+        &[("", counters.redirected)],
+    );
+    metric(
+        "udprelay_cluster_remote_secrets",
+        "gauge",
+        "Secrets other instances of the cluster announced.",
+        &[(
+            "",
+            registry
+                .cluster
+                .as_ref()
+                .map_or(0, |cluster| cluster.remote_secrets() as u64),
+        )],
+    );
+    let rtts: Vec<u64> = registry
+        .pairing
+        .values()
+        .filter_map(|peer| {
+            let peer = peer.lock().expect("Peer lock poisoned");
+            peer.rtt.smoothed.map(|rtt| rtt.as_millis() as u64)
+        })
+        .collect();
+    metric(
+        "udprelay_peer_rtt_milliseconds",
+        "gauge",
+        "Smoothed round-trip time of the paired peers answering echoes, on average and at most.",
+        &[
+            (
+                "{stat=\"average\"}",
+                rtts.iter().sum::<u64>() / (rtts.len() as u64).max(1),
+            ),
+            ("{stat=\"max\"}", rtts.iter().copied().max().unwrap_or(0)),
+        ],
+    );
+    metric(
+        "udprelay_expired_pairings_total",
+        "counter",
+        "Pairing requests expired before a counterpart arrived.",
+        &[("", counters.expired_pairings)],
+    );
+    out
+}
+
+fn write_peer(
+    out: &mut String,
+    addr: &SocketAddr,
+    idle: Duration,
+    packets: u64,
+    bytes: u64,
+    rtt: Option<Duration>,
+) {
+    let _ = write!(
+        out,
+        "  {addr}: {packets} packets, {bytes} bytes sent, idle {}s",
+        idle.as_secs()
+    );
+    match rtt {
+        Some(rtt) => {
+            let _ = writeln!(out, ", rtt {}ms", rtt.as_millis());
+        }
+        None => out.push('\n'),
+    }
+}
+
+/// Renders a human-readable summary of every session, as dumped on `SIGUSR1`.
+pub(crate) fn report(registry: &RelayService) -> String {
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        "{} session(s), {} group(s), {} pending pairing(s)",
+        registry.pairing.len() / 2,
+        registry.groups.len(),
+        registry.pending_pairings()
+    );
+    for peer in registry.pairing.values() {
+        let mut peer = peer.lock().expect("Peer lock poisoned");
+        // list every pair once, from its first peer
+        if peer.side != Side::First {
+            continue;
+        }
+        let opponent = peer.get_opponent();
+        let opponent = opponent.lock().expect("Peer lock poisoned");
+        let _ = writeln!(
+            out,
+            "session '{}' (key '{}', up {}s)",
+            String::from_utf8_lossy(&peer.secret),
+            peer.key,
+            peer.started.elapsed().as_secs()
+        );
+        for peer in [&*peer, &*opponent] {
+            write_peer(
+                &mut out,
+                &peer.recipient.addr,
+                peer.last_accessed.elapsed(),
+                peer.packets,
+                peer.bytes,
+                peer.rtt.smoothed,
+            );
+        }
+    }
+    for (secret, group) in &registry.groups {
+        let _ = writeln!(
+            out,
+            "group '{}' (key '{}', up {}s)",
+            String::from_utf8_lossy(secret),
+            group.key,
+            group.started.elapsed().as_secs()
+        );
+        for (addr, member) in &group.members {
+            write_peer(
+                &mut out,
+                addr,
+                member.last_accessed.elapsed(),
+                member.packets,
+                member.bytes,
+                None,
+            );
+        }
+    }
+    out
+}
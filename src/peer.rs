@@ -0,0 +1,252 @@
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant, SystemTime};
+
+use tokio::net::UdpSocket;
+use tracing::{debug, warn};
+
+use crate::capture;
+use crate::channels::Channels;
+use crate::encryption::SessionCipher;
+use crate::fec::Fec;
+use crate::protocol::{Ops, RESUME_TOKEN_LEN};
+use crate::ratelimit::Throttle;
+use crate::reliable::Channel;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct ExpiringTimer(SystemTime);
+
+impl ExpiringTimer {
+    pub(crate) fn access(&mut self) {
+        self.0 = SystemTime::now();
+    }
+
+    pub(crate) fn is_expired(&self, timeout: Duration) -> bool {
+        let elapsed = match SystemTime::now().duration_since(self.0) {
+            Ok(v) => v,
+            Err(e) => {
+                warn!(
+                    "Error in getting time elapsed: {}. Defaulting to timeout.",
+                    e
+                );
+                timeout
+            }
+        };
+        elapsed >= timeout
+    }
+
+    /// Time since the last access; zero if the clock went backwards.
+    pub(crate) fn elapsed(&self) -> Duration {
+        SystemTime::now()
+            .duration_since(self.0)
+            .unwrap_or(Duration::ZERO)
+    }
+
+    pub(crate) fn new() -> ExpiringTimer {
+        ExpiringTimer(SystemTime::now())
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Recipient {
+    pub(crate) socket: Arc<UdpSocket>,
+    pub(crate) addr: SocketAddr,
+}
+
+impl Recipient {
+    pub(crate) fn send_message(&self, message: &[u8]) {
+        send_to(&self.socket, message, &self.addr);
+    }
+}
+
+/// Sends without awaiting; a full send buffer drops the datagram as the network
+/// would. Other errors, e.g. for a peer reported unreachable, are returned.
+pub(crate) fn try_send(socket: &UdpSocket, message: &[u8], to: &SocketAddr) -> io::Result<()> {
+    capture::sent(socket, to, message);
+    match socket.try_send_to(message, *to) {
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Like [`try_send`], for messages whose loss nothing depends on (e.g. replies
+/// to handshake messages): errors are only logged.
+pub(crate) fn send_to(socket: &UdpSocket, message: &[u8], to: &SocketAddr) {
+    if let Err(e) = try_send(socket, message, to) {
+        debug!("Cannot send to {to}: {e}");
+    }
+}
+
+/// Round-trip time between the relay and a paired peer, measured with
+/// [`Ops::Echo`] once the peer showed it answers them by sending one.
+#[derive(Debug, Default)]
+pub(crate) struct Rtt {
+    /// Whether the peer sent an [`Ops::Echo`], and so answers the relay's.
+    pub(crate) answers_echoes: bool,
+    /// Identifier and sending time of the echo waiting for its reply.
+    outstanding: Option<(u64, Instant)>,
+    /// Smoothed round-trip time, as for TCP (RFC 6298).
+    pub(crate) smoothed: Option<Duration>,
+}
+
+impl Rtt {
+    /// Builds an echo to send the peer under the protocol `magic`, if it
+    /// answers them. An echo still unanswered is forgotten, as lost.
+    pub(crate) fn echo(&mut self, magic: u8) -> Option<Vec<u8>> {
+        if !self.answers_echoes {
+            return None;
+        }
+        let id: u64 = rand::random();
+        self.outstanding = Some((id, Instant::now()));
+        Some(Ops::Echo.message(magic, &id.to_be_bytes()))
+    }
+
+    /// Takes the payload of an [`Ops::EchoReply`] from the peer, returning the
+    /// round-trip time measured if it answers the outstanding echo.
+    pub(crate) fn reply(&mut self, payload: &[u8]) -> Option<Duration> {
+        match self.outstanding {
+            Some((id, sent)) if payload == id.to_be_bytes() => {
+                self.outstanding = None;
+                let sample = sent.elapsed();
+                self.smoothed = Some(match self.smoothed {
+                    Some(smoothed) => smoothed * 7 / 8 + sample / 8,
+                    None => sample,
+                });
+                Some(sample)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Which side of a pair a peer is on; the first one registered its pairing request first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Side {
+    First = 0,
+    Second = 1,
+}
+
+#[derive(Debug)]
+pub(crate) struct RecipientData {
+    pub(crate) recipient: Recipient,
+    pub(crate) side: Side,
+    /// Session secret both peers paired with.
+    pub(crate) secret: Vec<u8>,
+    /// Name of the key both peers authenticated with.
+    pub(crate) key: String,
+    pub(crate) last_accessed: ExpiringTimer,
+    /// Token the peer resumes the session with after changing address, see
+    /// [`Ops::Resume`](crate::protocol::Ops).
+    pub(crate) resume_token: [u8; RESUME_TOKEN_LEN],
+    /// Datagrams relayed from this peer.
+    pub(crate) packets: u64,
+    pub(crate) bytes: u64,
+    /// When the pair was formed.
+    pub(crate) started: Instant,
+    /// Keys of the peer's link with the relay, when it is encrypted.
+    pub(crate) cipher: Option<SessionCipher>,
+    /// Sequencing state of the peer's link with the relay, when it has reliable
+    /// delivery.
+    pub(crate) reliable: Option<Channel>,
+    /// Forward error correction state of the peer's link with the relay, when it
+    /// has it.
+    pub(crate) fec: Option<Fec>,
+    /// Whether datagrams relayed to the peer are compressed, see
+    /// [`crate::compression`].
+    pub(crate) compress: bool,
+    /// Activity of the logical channels of the peer, when it uses them, see
+    /// [`crate::channels`].
+    pub(crate) channels: Option<Channels>,
+    /// Limits the bandwidth of this peer, see [`Config::rate_limit_kbps`](crate::Config).
+    pub(crate) throttle: Throttle,
+    pub(crate) rtt: Rtt,
+    /// Datagrams to this peer that could not be sent since it was last heard
+    /// from, see [`Config::max_send_failures`](crate::Config::max_send_failures).
+    pub(crate) send_failures: u32,
+    /// Latest activity of the pair when the peer was last sent an
+    /// [`Ops::IdleWarning`], so that it is warned once per idle period.
+    pub(crate) idle_warned: Option<ExpiringTimer>,
+    pub(crate) opponent: Option<Weak<Mutex<RecipientData>>>,
+}
+
+impl RecipientData {
+    pub(crate) fn get_opponent(&mut self) -> Arc<Mutex<RecipientData>> {
+        self.opponent
+            .as_mut()
+            .expect("Option is empty. Bugs in setting up opponent?")
+            .upgrade()
+            .expect("Cannot upgrade to strong reference")
+    }
+}
+
+pub(crate) fn build_paired_peers(
+    secret: &[u8],
+    key: &str,
+    addr_1: &SocketAddr,
+    udp_1: &Arc<UdpSocket>,
+    addr_2: &SocketAddr,
+    udp_2: &Arc<UdpSocket>,
+) -> (Arc<Mutex<RecipientData>>, Arc<Mutex<RecipientData>>) {
+    let peer1 = Arc::new(Mutex::new(RecipientData {
+        recipient: Recipient {
+            socket: udp_1.clone(),
+            addr: *addr_1,
+        },
+        side: Side::First,
+        secret: secret.to_owned(),
+        key: key.to_owned(),
+        last_accessed: ExpiringTimer::new(),
+        resume_token: rand::random(),
+        packets: 0,
+        bytes: 0,
+        started: Instant::now(),
+        cipher: None,
+        reliable: None,
+        fec: None,
+        compress: false,
+        channels: None,
+        throttle: Throttle::new(),
+        rtt: Rtt::default(),
+        send_failures: 0,
+        idle_warned: None,
+        opponent: None,
+    }));
+    let peer2 = Arc::new(Mutex::new(RecipientData {
+        recipient: Recipient {
+            socket: udp_2.clone(),
+            addr: *addr_2,
+        },
+        side: Side::Second,
+        secret: secret.to_owned(),
+        key: key.to_owned(),
+        last_accessed: ExpiringTimer::new(),
+        resume_token: rand::random(),
+        packets: 0,
+        bytes: 0,
+        started: Instant::now(),
+        cipher: None,
+        reliable: None,
+        fec: None,
+        compress: false,
+        channels: None,
+        throttle: Throttle::new(),
+        rtt: Rtt::default(),
+        send_failures: 0,
+        idle_warned: None,
+        opponent: None,
+    }));
+    // assign the opposing reference as weak pointer
+    peer1
+        .lock()
+        .expect("Peer lock poisoned")
+        .opponent
+        .replace(Arc::downgrade(&peer2));
+    peer2
+        .lock()
+        .expect("Peer lock poisoned")
+        .opponent
+        .replace(Arc::downgrade(&peer1));
+    (peer1, peer2)
+}
@@ -0,0 +1,473 @@
+//! Wire format of the relay's control messages.
+//!
+//! Every control message starts with two command bytes (see [`Ops`]). Datagrams
+//! from peers that are already paired are relayed verbatim, except for a bare
+//! [`Ops::Disconnect`], a bare [`Ops::ChallengeRequest`], [`Ops::Echo`] and
+//! [`Ops::EchoReply`], and handshake messages that authenticate, which a peer
+//! restarted on the same address sends.
+//!
+//! Peers pair with the v2 handshake: [`Ops::ChallengeRequest`], answered by an
+//! [`Ops::Challenge`] carrying a nonce, and finally a [`PairingResponse`]
+//! proving knowledge of the pre-shared key (see [`crate::auth`]). The legacy
+//! [`PairingRequest`] sends the key in cleartext and is only accepted when the
+//! relay runs with `legacy_handshake`. Relays built with the `dtls` feature can
+//! also take the pairing request over DTLS, see [`Ops::Ticket`].
+//!
+//! Peers and relays agree on a protocol version while requesting the challenge,
+//! see [`Ops::ChallengeRequest`]: version 1 is the legacy handshake, version 2
+//! the v2 handshake without negotiation and version 3 the v2 handshake with it.
+//!
+//! The first command byte, the protocol magic, is [`DEFAULT_MAGIC`] unless
+//! configured otherwise, e.g. when it collides with the first payload byte of an
+//! application or to make the relay harder to fingerprint. Messages are built
+//! and parsed with the magic of the relay or client at hand; relays and their
+//! peers must use the same one.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use crate::auth::{MAC_LEN, NONCE_LEN};
+
+/// Length of the token carried by [`Ops::SessionToken`] and [`Ops::Resume`], and
+/// of the ticket carried by [`Ops::Ticket`] and [`Ops::Redeem`].
+pub const RESUME_TOKEN_LEN: usize = 16;
+
+/// Highest protocol version this crate speaks.
+pub const PROTOCOL_VERSION: u8 = 3;
+
+/// Lowest protocol version this crate speaks.
+pub const MIN_PROTOCOL_VERSION: u8 = 1;
+
+/// Protocol version of peers requesting a challenge without saying which
+/// version they speak.
+pub const UNNEGOTIATED_VERSION: u8 = 2;
+
+/// Protocol version of peers sending a [`PairingRequest`].
+pub const LEGACY_VERSION: u8 = 1;
+
+/// Shortest [`Ops::AddressRequest`] answered, the length of the longest
+/// [`Ops::Address`] message (for an IPv6 address).
+pub const ADDRESS_REQUEST_LEN: usize = 2 + 1 + 16 + 2;
+
+/// Protocol magic used unless configured otherwise.
+pub const DEFAULT_MAGIC: u8 = 0xff;
+
+/// The magic of `buffer` if it would be a pairing request under a magic other
+/// than `magic`, i.e. comes from a peer configured differently.
+pub fn foreign_magic(buffer: &[u8], magic: u8) -> Option<u8> {
+    match *buffer.get(..2)? {
+        [first, opcode] if first != magic => Ops::from_opcode(opcode)
+            .filter(|op| {
+                matches!(
+                    op,
+                    Ops::EstablishConnection | Ops::ChallengeRequest | Ops::ChallengeResponse
+                )
+            })
+            .map(|_| first),
+        _ => None,
+    }
+}
+
+/// Command bytes prefixing every control message: the magic byte and an
+/// opcode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ops {
+    /// Legacy pairing request carrying the pre-shared key and the session secret.
+    EstablishConnection,
+    /// Asks the relay for a challenge; followed by one byte, the highest protocol
+    /// version the peer speaks. Peers leaving it out speak
+    /// [`UNNEGOTIATED_VERSION`].
+    ChallengeRequest,
+    /// Relay's answer to [`Ops::ChallengeRequest`]; followed by the nonce and,
+    /// if the request carried a version, by one byte, the highest version both
+    /// the peer and the relay speak.
+    Challenge,
+    /// Pairing request answering a challenge, see [`PairingResponse`].
+    ChallengeResponse,
+    /// Acknowledges a pairing request; followed by the session secret and, if
+    /// the request asked for options the relay agreed to, by one byte, those
+    /// options.
+    Ack,
+    /// Answers a pairing request the relay has no room for, when it runs with
+    /// `reply_busy`; has no payload.
+    Busy,
+    /// Answers a pairing request from a host already holding the relay's
+    /// `max_sessions_per_ip`; has no payload.
+    LimitExceeded,
+    /// Answers a pairing request presenting a secret already taken, when the
+    /// relay's `secret_collision` policy refuses it; has no payload.
+    SecretInUse,
+    /// Answers a datagram over the relay's `max_payload`, which it dropped, when
+    /// it runs with `reply_too_big`; followed by that maximum as two big-endian
+    /// bytes.
+    TooBig,
+    /// Answers a handshake message of a protocol version the relay does not
+    /// accept; followed by two bytes, the lowest and highest versions it does.
+    UnsupportedVersion,
+    /// Liveness probe, answered with [`Ops::Pong`].
+    Ping,
+    Pong,
+    /// Health probe; its payload is echoed back in an [`Ops::ProbeReply`].
+    Probe,
+    ProbeReply,
+    /// Round-trip time probe between a paired peer and the relay, sent by
+    /// either; its payload, of up to 64 bytes, is echoed back in an
+    /// [`Ops::EchoReply`] instead of being relayed. The relay only sends it to
+    /// peers that sent one first, and so answer it.
+    Echo,
+    EchoReply,
+    /// Asks the relay for the address it sees the sender at. The relay only
+    /// answers requests at least [`ADDRESS_REQUEST_LEN`] bytes long, so that
+    /// the answer is never larger than the request.
+    AddressRequest,
+    /// Answer to [`Ops::AddressRequest`]; followed by an [`encode_addr`] payload.
+    Address,
+    /// Sent by the relay to every peer when it shuts down.
+    Shutdown,
+    /// Sent by the relay, without payload, to both peers of an idle pair to keep
+    /// NAT mappings open; peers should drop it.
+    Keepalive,
+    /// Sent by the relay to both peers of a pair about to be torn down for
+    /// inactivity, when it runs with `idle_warning`; followed by the seconds
+    /// left as two big-endian bytes. Any datagram of either peer, e.g. an
+    /// [`Ops::Echo`], keeps the pair up.
+    IdleWarning,
+    /// Sent by the relay to each peer of a new pair when session resumption is
+    /// enabled; followed by a [`RESUME_TOKEN_LEN`]-byte token.
+    SessionToken,
+    /// Rebinds a session to the sender's address after it changed; followed by
+    /// the latest token received in an [`Ops::SessionToken`]. Answered by an
+    /// [`Ops::Ack`] and a fresh token.
+    Resume,
+    /// Rebinds a session to the sender's address after it changed, proving
+    /// knowledge of the session secret instead of presenting a token, see
+    /// [`RebindRequest`]. Answered by an [`Ops::Ack`].
+    Rebind,
+    /// Datagram of a link with reliable delivery; followed by a sequence number
+    /// and the payload, see [`crate::reliable`].
+    Sequenced,
+    /// Acknowledges [`Ops::Sequenced`] frames, see [`crate::reliable`].
+    SequenceAck,
+    /// Datagram of a link with forward error correction; followed by a block
+    /// number, an index and the payload, see [`crate::fec`].
+    FecData,
+    /// Parity of a block of [`Ops::FecData`] frames, see [`crate::fec`].
+    FecParity,
+    /// Sent by the relay over DTLS in answer to a [`PairingRequest`]; followed
+    /// by a [`RESUME_TOKEN_LEN`]-byte single-use ticket.
+    Ticket,
+    /// Registers the sender as if it had just authenticated, with the secret and
+    /// key of the pairing request a [`Ops::Ticket`] answered; followed by that
+    /// ticket. Answered like a pairing request.
+    Redeem,
+    /// Sent by a paired peer, without payload, to tear down its session; the
+    /// relay forwards it to the opponent.
+    Disconnect,
+    /// Tells the other instances of a cluster a peer waits on a secret, see
+    /// [`crate::cluster`].
+    ClusterAnnounce,
+    /// Tells the other instances of a cluster no peer waits on a secret anymore.
+    ClusterWithdraw,
+    /// Sent by a relay in cluster mode instead of [`Ops::Ack`] when the peer's
+    /// counterpart waits at another instance, or later if both registered at
+    /// once; followed by an [`encode_addr`] payload, the address of that
+    /// instance. The peer should pair with it again.
+    Redirect,
+}
+
+impl Ops {
+    /// Second byte of the command, following the magic byte.
+    pub const fn opcode(self) -> u8 {
+        match self {
+            Ops::EstablishConnection => 0x05,
+            Ops::ChallengeRequest => 0x06,
+            Ops::Challenge => 0x07,
+            Ops::ChallengeResponse => 0x08,
+            Ops::Ack => 0x12,
+            Ops::Busy => 0x13,
+            Ops::Ping => 0x15,
+            Ops::Pong => 0x16,
+            Ops::Probe => 0x17,
+            Ops::ProbeReply => 0x18,
+            Ops::AddressRequest => 0x19,
+            Ops::Address => 0x1a,
+            Ops::Keepalive => 0x1b,
+            Ops::SessionToken => 0x1c,
+            Ops::Resume => 0x1d,
+            Ops::Ticket => 0x1e,
+            Ops::Redeem => 0x1f,
+            Ops::Shutdown => 0x20,
+            Ops::Disconnect => 0x21,
+            Ops::ClusterAnnounce => 0x22,
+            Ops::ClusterWithdraw => 0x23,
+            Ops::Redirect => 0x24,
+            Ops::UnsupportedVersion => 0x25,
+            Ops::Rebind => 0x26,
+            Ops::Sequenced => 0x27,
+            Ops::SequenceAck => 0x28,
+            Ops::FecData => 0x29,
+            Ops::FecParity => 0x2a,
+            Ops::Echo => 0x2b,
+            Ops::EchoReply => 0x2c,
+            Ops::TooBig => 0x2d,
+            Ops::LimitExceeded => 0x2e,
+            Ops::SecretInUse => 0x2f,
+            Ops::IdleWarning => 0x30,
+        }
+    }
+
+    pub fn to_bytes(self, magic: u8) -> [u8; 2] {
+        [magic, self.opcode()]
+    }
+
+    pub fn from_bytes(token: [u8; 2], magic: u8) -> Option<Ops> {
+        match token {
+            [first, opcode] if first == magic => Ops::from_opcode(opcode),
+            _ => None,
+        }
+    }
+
+    fn from_opcode(opcode: u8) -> Option<Ops> {
+        match opcode {
+            0x05 => Some(Ops::EstablishConnection),
+            0x06 => Some(Ops::ChallengeRequest),
+            0x07 => Some(Ops::Challenge),
+            0x08 => Some(Ops::ChallengeResponse),
+            0x12 => Some(Ops::Ack),
+            0x13 => Some(Ops::Busy),
+            0x15 => Some(Ops::Ping),
+            0x16 => Some(Ops::Pong),
+            0x17 => Some(Ops::Probe),
+            0x18 => Some(Ops::ProbeReply),
+            0x19 => Some(Ops::AddressRequest),
+            0x1a => Some(Ops::Address),
+            0x1b => Some(Ops::Keepalive),
+            0x1c => Some(Ops::SessionToken),
+            0x1d => Some(Ops::Resume),
+            0x1e => Some(Ops::Ticket),
+            0x1f => Some(Ops::Redeem),
+            0x20 => Some(Ops::Shutdown),
+            0x21 => Some(Ops::Disconnect),
+            0x22 => Some(Ops::ClusterAnnounce),
+            0x23 => Some(Ops::ClusterWithdraw),
+            0x24 => Some(Ops::Redirect),
+            0x25 => Some(Ops::UnsupportedVersion),
+            0x26 => Some(Ops::Rebind),
+            0x27 => Some(Ops::Sequenced),
+            0x28 => Some(Ops::SequenceAck),
+            0x29 => Some(Ops::FecData),
+            0x2a => Some(Ops::FecParity),
+            0x2b => Some(Ops::Echo),
+            0x2c => Some(Ops::EchoReply),
+            0x2d => Some(Ops::TooBig),
+            0x2e => Some(Ops::LimitExceeded),
+            0x2f => Some(Ops::SecretInUse),
+            0x30 => Some(Ops::IdleWarning),
+            _ => None,
+        }
+    }
+
+    /// Splits a datagram starting with `magic` into its command and the
+    /// remaining payload.
+    pub fn parse(buffer: &[u8], magic: u8) -> Option<(Ops, &[u8])> {
+        let token: [u8; 2] = buffer.get(0..2)?.try_into().ok()?;
+        Some((Ops::from_bytes(token, magic)?, &buffer[2..]))
+    }
+
+    /// Whether this command is part of pairing a peer, or of rebinding its
+    /// session to a new address.
+    pub fn is_handshake(self) -> bool {
+        matches!(
+            self,
+            Ops::EstablishConnection
+                | Ops::ChallengeRequest
+                | Ops::Challenge
+                | Ops::ChallengeResponse
+                | Ops::Ack
+                | Ops::Busy
+                | Ops::LimitExceeded
+                | Ops::SecretInUse
+                | Ops::SessionToken
+                | Ops::Resume
+                | Ops::Ticket
+                | Ops::Redeem
+                | Ops::Redirect
+                | Ops::UnsupportedVersion
+                | Ops::Rebind
+        )
+    }
+
+    /// Builds a message consisting of this command, under `magic`, followed by
+    /// `payload`.
+    pub fn message(self, magic: u8, payload: &[u8]) -> Vec<u8> {
+        let mut message = Vec::with_capacity(2 + payload.len());
+        message.extend_from_slice(&self.to_bytes(magic));
+        message.extend_from_slice(payload);
+        message
+    }
+}
+
+/// Payload of an [`Ops::EstablishConnection`] message.
+///
+/// ```text
+/// [**xyPPPPP...PPPPPSSSSS....SSSS]
+/// *: command
+/// x: denote number of bytes (after the first 4 bytes) for PSK
+/// y: denote number of bytes (after the first 4 + x bytes) for secret key
+/// P: pre-shared key (where len = x)
+/// S: Secret key (where len = y)
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PairingRequest<'a> {
+    pub psk: &'a [u8],
+    pub secret: &'a [u8],
+}
+
+impl<'a> PairingRequest<'a> {
+    /// Parses the payload following the command bytes. Returns `None` when the
+    /// message is shorter than its length fields claim.
+    pub fn parse(payload: &'a [u8]) -> Option<PairingRequest<'a>> {
+        let n_psk: usize = (*payload.first()?).into();
+        let n_secret: usize = (*payload.get(1)?).into();
+        let psk_end = 2 + n_psk;
+        Some(PairingRequest {
+            psk: payload.get(2..psk_end)?,
+            secret: payload.get(psk_end..psk_end + n_secret)?,
+        })
+    }
+
+    /// Encodes the full message, including the command bytes under `magic`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if either the key or the secret is longer than 255 bytes.
+    pub fn encode(&self, magic: u8) -> Vec<u8> {
+        let n_psk = u8::try_from(self.psk.len()).expect("PSK longer than 255 bytes");
+        let n_secret = u8::try_from(self.secret.len()).expect("Secret longer than 255 bytes");
+        let mut payload = vec![n_psk, n_secret];
+        payload.extend_from_slice(self.psk);
+        payload.extend_from_slice(self.secret);
+        Ops::EstablishConnection.message(magic, &payload)
+    }
+}
+
+/// Payload of an [`Ops::ChallengeResponse`] message.
+///
+/// ```text
+/// [**yNNNN...NNNNSSSSS....SSSSMMMM...MMMMo]
+/// *: command
+/// y: denote number of bytes for secret key
+/// N: nonce received in the challenge (32 bytes)
+/// S: Secret key (where len = y)
+/// M: HMAC-SHA256(psk, nonce || secret) (32 bytes)
+/// o: options asked for, e.g. crate::reliable::OPTION_RELIABLE (optional)
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PairingResponse<'a> {
+    pub nonce: &'a [u8],
+    pub secret: &'a [u8],
+    pub mac: &'a [u8],
+    /// Options the peer asks for; `0` when the message leaves them out.
+    pub options: u8,
+}
+
+impl<'a> PairingResponse<'a> {
+    /// Parses the payload following the command bytes. Returns `None` when the
+    /// message is shorter than its length field claims.
+    pub fn parse(payload: &'a [u8]) -> Option<PairingResponse<'a>> {
+        let n_secret: usize = (*payload.first()?).into();
+        let secret_start = 1 + NONCE_LEN;
+        let secret_end = secret_start + n_secret;
+        Some(PairingResponse {
+            nonce: payload.get(1..secret_start)?,
+            secret: payload.get(secret_start..secret_end)?,
+            mac: payload.get(secret_end..secret_end + MAC_LEN)?,
+            options: payload.get(secret_end + MAC_LEN).copied().unwrap_or(0),
+        })
+    }
+
+    /// Encodes the full message, including the command bytes under `magic`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the secret is longer than 255 bytes.
+    pub fn encode(&self, magic: u8) -> Vec<u8> {
+        let n_secret = u8::try_from(self.secret.len()).expect("Secret longer than 255 bytes");
+        let mut payload = vec![n_secret];
+        payload.extend_from_slice(self.nonce);
+        payload.extend_from_slice(self.secret);
+        payload.extend_from_slice(self.mac);
+        if self.options != 0 {
+            payload.push(self.options);
+        }
+        Ops::ChallengeResponse.message(magic, &payload)
+    }
+}
+
+/// Payload of an [`Ops::Rebind`] message, sent from the new address of the peer
+/// after requesting a challenge there.
+///
+/// ```text
+/// [**NNNN...NNNNMMMM...MMMMAAAA...AAAA]
+/// *: command
+/// N: nonce received in the challenge (32 bytes)
+/// M: HMAC-SHA256(secret, nonce || A) (32 bytes)
+/// A: address the relay saw the peer at so far, see encode_addr
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RebindRequest<'a> {
+    pub nonce: &'a [u8],
+    pub mac: &'a [u8],
+    pub old_addr: SocketAddr,
+}
+
+impl<'a> RebindRequest<'a> {
+    /// Parses the payload following the command bytes. Returns `None` when the
+    /// message is too short or the address malformed.
+    pub fn parse(payload: &'a [u8]) -> Option<RebindRequest<'a>> {
+        Some(RebindRequest {
+            nonce: payload.get(..NONCE_LEN)?,
+            mac: payload.get(NONCE_LEN..NONCE_LEN + MAC_LEN)?,
+            old_addr: parse_addr(payload.get(NONCE_LEN + MAC_LEN..)?)?,
+        })
+    }
+
+    /// Encodes the full message, including the command bytes under `magic`.
+    pub fn encode(&self, magic: u8) -> Vec<u8> {
+        let payload = [self.nonce, self.mac, &encode_addr(&self.old_addr)].concat();
+        Ops::Rebind.message(magic, &payload)
+    }
+}
+
+/// Encodes the payload of an [`Ops::Address`] message: the address family (`4`
+/// or `6`), the IP address and the port in network byte order. IPv4-mapped IPv6
+/// addresses are reported as IPv4.
+///
+/// ```text
+/// [F AAAA PP] or [F AAAAAAAAAAAAAAAA PP]
+/// ```
+pub fn encode_addr(addr: &SocketAddr) -> Vec<u8> {
+    let mut payload = match addr.ip().to_canonical() {
+        IpAddr::V4(ip) => [&[4][..], &ip.octets()].concat(),
+        IpAddr::V6(ip) => [&[6][..], &ip.octets()].concat(),
+    };
+    payload.extend_from_slice(&addr.port().to_be_bytes());
+    payload
+}
+
+/// Parses the payload of an [`Ops::Address`] or [`Ops::Redirect`] message, see
+/// [`encode_addr`].
+pub fn parse_addr(payload: &[u8]) -> Option<SocketAddr> {
+    let (ip, rest): (IpAddr, _) = match payload.split_first()? {
+        (4, rest) => {
+            let octets: [u8; 4] = rest.get(..4)?.try_into().ok()?;
+            (Ipv4Addr::from(octets).into(), &rest[4..])
+        }
+        (6, rest) => {
+            let octets: [u8; 16] = rest.get(..16)?.try_into().ok()?;
+            (Ipv6Addr::from(octets).into(), &rest[16..])
+        }
+        _ => return None,
+    };
+    let port: [u8; 2] = rest.try_into().ok()?;
+    Some(SocketAddr::new(ip, u16::from_be_bytes(port)))
+}
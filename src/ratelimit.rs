@@ -0,0 +1,136 @@
+//! Per-source-IP token buckets throttling pairing attempts.
+//!
+//! Every handshake message costs a token; buckets refill at `pairing_rate`
+//! tokens per second up to `pairing_burst`. A source that keeps sending with an
+//! empty bucket is banned for `ban_duration` after `ban_after` dropped messages.
+//!
+//! Paired peers get a bucket of their own limiting their bandwidth, see [`Throttle`].
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use tracing::{info, trace};
+
+use crate::relay::Config;
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    refilled: Instant,
+    /// Messages dropped since the bucket was last full.
+    violations: u32,
+    banned_until: Option<Instant>,
+}
+
+impl Bucket {
+    fn refill(&mut self, config: &Config, now: Instant) {
+        let burst = f64::from(config.pairing_burst);
+        let elapsed = now.duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * f64::from(config.pairing_rate)).min(burst);
+        self.refilled = now;
+        if self.tokens >= burst {
+            self.violations = 0;
+        }
+    }
+}
+
+/// Token bucket of a paired peer, in bytes, holding up to one second of traffic.
+#[derive(Debug)]
+pub(crate) struct Throttle {
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl Throttle {
+    /// A bucket that starts full, whatever the rate.
+    pub(crate) fn new() -> Throttle {
+        Throttle {
+            tokens: f64::MAX,
+            refilled: Instant::now(),
+        }
+    }
+
+    /// Takes tokens for a datagram of `len` bytes at `rate` bytes per second,
+    /// returning how long to hold it back, or `None` if that would exceed
+    /// `max_delay` and it must be dropped. Datagrams larger than the bucket
+    /// pass once it is full.
+    pub(crate) fn take(&mut self, rate: f64, len: usize, max_delay: Duration) -> Option<Duration> {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(rate);
+        self.refilled = now;
+        let needed = (len as f64).min(rate);
+        let delay = Duration::from_secs_f64(((needed - self.tokens) / rate).max(0.0));
+        if delay > max_delay {
+            return None;
+        }
+        self.tokens -= len as f64;
+        Some(delay)
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct RateLimiter {
+    buckets: HashMap<IpAddr, Bucket>,
+}
+
+impl RateLimiter {
+    /// Takes a token for a message from `ip`, returning `false` when it must be dropped.
+    pub(crate) fn check(&mut self, config: &Config, ip: IpAddr) -> bool {
+        if config.pairing_rate == 0 {
+            return true;
+        }
+        let now = Instant::now();
+        let bucket = self.buckets.entry(ip).or_insert_with(|| Bucket {
+            tokens: config.pairing_burst.into(),
+            refilled: now,
+            violations: 0,
+            banned_until: None,
+        });
+        match bucket.banned_until {
+            Some(until) if until > now => return false,
+            Some(_) => bucket.banned_until = None,
+            None => (),
+        }
+        bucket.refill(config, now);
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return true;
+        }
+
+        bucket.violations += 1;
+        trace!("Rate limiting pairing attempts from {ip}");
+        if config.ban_after > 0 && bucket.violations >= config.ban_after {
+            info!(
+                "Banning {ip} for {} seconds after {} rate-limited pairing attempts",
+                config.ban_duration.as_secs(),
+                bucket.violations
+            );
+            bucket.banned_until = Some(now + config.ban_duration);
+            bucket.violations = 0;
+        }
+        false
+    }
+
+    /// Number of sources currently banned.
+    pub(crate) fn banned(&self) -> usize {
+        let now = Instant::now();
+        self.buckets
+            .values()
+            .filter(|bucket| bucket.banned_until.is_some_and(|until| until > now))
+            .count()
+    }
+
+    /// Forgets sources that are neither banned nor short of tokens.
+    pub(crate) fn prune(&mut self, config: &Config) {
+        let now = Instant::now();
+        self.buckets.retain(|_, bucket| {
+            if bucket.banned_until.is_some_and(|until| until > now) {
+                return true;
+            }
+            bucket.refill(config, now);
+            bucket.tokens < f64::from(config.pairing_burst)
+        });
+    }
+}
@@ -0,0 +1,2083 @@
+use std::fmt;
+use std::fs;
+use std::future::{self, Future};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+#[cfg(unix)]
+use std::path::Path;
+use std::path::{self, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use ipnet::IpNet;
+use serde::Deserialize;
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::net::{TcpListener, UdpSocket};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio::time;
+use tracing::{debug, info, trace, warn};
+use zeroize::Zeroizing;
+
+use crate::accounting::AccountingLog;
+use crate::auth::PSK_HASH_LEN;
+use crate::batch::{Outbox, RecvBatch};
+use crate::cluster::{self, Cluster};
+#[cfg(unix)]
+use crate::control;
+#[cfg(feature = "dtls")]
+use crate::dtls::DtlsListener;
+use crate::hooks::Hooks;
+use crate::metrics::Counters;
+use crate::peer::ExpiringTimer;
+use crate::protocol::{Ops, DEFAULT_MAGIC, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
+use crate::service::{self, RelayService};
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+use crate::uring::RelayRing;
+use crate::{auth, capture, fec, forward, http, metrics, reliable};
+
+/// Largest [`Config::dscp`], as the field has six bits.
+pub const MAX_DSCP: u8 = 63;
+
+/// Smallest [`Config::recv_buffer_size`], enough for any handshake message.
+pub const MIN_RECV_BUFFER_SIZE: usize = 1024;
+
+/// Longest a datagram is held back by [`Config::rate_limit_delay`]; datagrams
+/// that would wait longer are dropped.
+pub const MAX_THROTTLE_DELAY: Duration = Duration::from_millis(500);
+
+/// How often the round-trip time of the paired peers is measured by default,
+/// see [`Config::rtt_interval`].
+pub const DEFAULT_RTT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// What the relay does with a pairing request whose secret is taken: by an
+/// established pair, or by peers waiting on it that it cannot be paired with,
+/// i.e. on the same port in dual-port mode.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SecretCollision {
+    /// The peer waits for the next peer presenting the secret, after those
+    /// already waiting on it.
+    #[default]
+    Queue,
+    /// The peer is answered [`Ops::SecretInUse`].
+    Reject,
+    /// The peer replaces the waiting or paired peer holding the secret from the
+    /// same IP, with the same key, e.g. after restarting; it is rejected if
+    /// there is none.
+    Takeover,
+}
+
+impl FromStr for SecretCollision {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<SecretCollision, String> {
+        match s {
+            "queue" => Ok(SecretCollision::Queue),
+            "reject" => Ok(SecretCollision::Reject),
+            "takeover" => Ok(SecretCollision::Takeover),
+            _ => Err(format!(
+                "unknown collision policy '{s}', expected queue, reject or takeover"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for SecretCollision {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            SecretCollision::Queue => "queue",
+            SecretCollision::Reject => "reject",
+            SecretCollision::Takeover => "takeover",
+        })
+    }
+}
+
+/// Name under which [`Config::preshared_key`] appears in logs, metrics and the
+/// control socket.
+pub const DEFAULT_KEY_NAME: &str = "default";
+
+/// A named pre-shared key of the keyring, with its own policies. Policies left
+/// unset fall back to the relay-wide settings.
+#[derive(Debug, Clone)]
+pub struct NamedKey {
+    pub name: String,
+    pub preshared_key: Zeroizing<String>,
+    /// Maximum number of sessions paired with this key at once.
+    pub max_sessions: Option<usize>,
+    pub timeout_pairing: Option<Duration>,
+    pub timeout_connection_inactivities: Option<Duration>,
+    /// Networks peers using this key must belong to; empty allows every network.
+    pub allow_cidrs: Vec<IpNet>,
+    /// Bandwidth of each peer paired with this key, in kilobits per second;
+    /// `Some(0)` lifts the relay-wide limit.
+    pub rate_limit_kbps: Option<u32>,
+    pub secret_collision: Option<SecretCollision>,
+}
+
+/// Runtime settings of a [`Relay`].
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Address the relay socket is bound to.
+    pub bind: SocketAddr,
+    /// Address of a second relay socket. When set, peers are only paired across
+    /// the two sockets: one registers on `bind`, its counterpart on `second_bind`.
+    pub second_bind: Option<SocketAddr>,
+    /// Further addresses the relay listens on, e.g. a range of ports, served by
+    /// the same event loop and sharing the sessions of `bind`: peers are paired
+    /// whichever of them they registered on.
+    pub extra_binds: Vec<SocketAddr>,
+    /// Forwards every datagram to this address instead of pairing peers, see
+    /// [`crate::forward`].
+    pub forward_to: Option<SocketAddr>,
+    /// Address accepting pairing requests over DTLS, if any (see `crate::dtls`,
+    /// which needs the `dtls` feature).
+    pub dtls_bind: Option<SocketAddr>,
+    /// PEM certificate chain the DTLS listener authenticates with; without it,
+    /// peers authenticate with DTLS-PSK, using the name of a key as identity.
+    pub dtls_certificate: Option<PathBuf>,
+    /// PEM private key of `dtls_certificate`.
+    pub dtls_private_key: Option<PathBuf>,
+    /// Address exchanging announcements with the other instances of a cluster,
+    /// if any (see [`crate::cluster`]).
+    pub cluster_bind: Option<SocketAddr>,
+    /// Cluster addresses of the other instances.
+    pub cluster_peers: Vec<SocketAddr>,
+    /// Address peers reach this instance at, which other instances redirect to.
+    pub cluster_advertise: Option<SocketAddr>,
+    /// Key authenticating the announcements, shared by every instance.
+    pub cluster_key: Option<Zeroizing<String>>,
+    /// Pre-shared key peers must present to be paired.
+    pub preshared_key: Option<Zeroizing<String>>,
+    /// SHA-256 digest of the pre-shared key, for relays whose settings must not
+    /// hold the key itself. Only the legacy handshake, which carries the key,
+    /// can be checked against it.
+    pub preshared_key_hash: Option<[u8; PSK_HASH_LEN]>,
+    /// Additional named keys, e.g. one per team sharing the relay.
+    pub keys: Vec<NamedKey>,
+    /// Allows running without any key (accepting every peer) or with weak keys.
+    pub insecure_open: bool,
+    /// Whether to also accept the legacy handshake carrying the pre-shared key
+    /// in cleartext (see [`crate::protocol::PairingRequest`]).
+    pub legacy_handshake: bool,
+    /// Lowest protocol version peers must speak (see [`crate::protocol`]); older
+    /// peers are answered [`Ops::UnsupportedVersion`].
+    pub min_protocol_version: u8,
+    /// First command byte of every control message, see [`crate::protocol`].
+    pub protocol_magic: u8,
+    /// Encrypts the traffic of paired peers with the relay, see [`crate::encryption`].
+    /// Needs a pre-shared key and the v2 handshake.
+    pub encryption: bool,
+    /// Networks peers must belong to; empty allows every network.
+    pub allow_cidrs: Vec<IpNet>,
+    /// Networks whose peers are ignored, even when allowed by `allow_cidrs`.
+    pub deny_cidrs: Vec<IpNet>,
+    /// Whether paired peers are given a token to resume their session from a new
+    /// address, see [`crate::protocol::Ops::Resume`].
+    pub session_resumption: bool,
+    /// Whether paired peers may rebind their session to a new address by proving
+    /// knowledge of its secret, see [`crate::protocol::Ops::Rebind`].
+    pub session_rebind: bool,
+    /// Whether peers asking for it get reliable delivery of the datagrams between
+    /// them and the relay, see [`crate::reliable`].
+    pub reliable: bool,
+    /// Frames in flight to, and held back from, each peer with reliable delivery.
+    pub reliable_window: u32,
+    /// How long a frame to a peer with reliable delivery waits for its
+    /// acknowledgement before being sent again.
+    pub retransmit_interval: Duration,
+    /// Whether peers asking for it get forward error correction of the datagrams
+    /// between them and the relay, see [`crate::fec`].
+    pub fec: bool,
+    /// Datagrams to a peer with forward error correction per parity datagram.
+    pub fec_block_size: u8,
+    /// Whether peers asking for it get the datagrams relayed to them compressed,
+    /// see [`crate::compression`].
+    pub compression: bool,
+    /// Whether peers asking for it may multiplex logical channels within their
+    /// session, see [`crate::channels`].
+    pub channels: bool,
+    /// Whether session secrets must be the pairing code of the current time
+    /// window, derived from the key the peer authenticated with, see
+    /// [`crate::auth::pairing_code`].
+    pub pairing_codes: bool,
+    /// Whether peers presenting the same secret join a group instead of forming
+    /// a pair, see [`crate::group`].
+    pub group: bool,
+    /// Most peers a group may hold.
+    pub max_group_members: usize,
+    /// Also serves TURN clients on the relay port, see [`crate::turn`]. Needs a
+    /// pre-shared key, the TURN password.
+    pub turn: bool,
+    /// Realm of the TURN long-term credentials.
+    pub turn_realm: String,
+    /// Bandwidth of each paired peer, in kilobits per second; `None` is unlimited.
+    /// Group members and static forwarding are not limited.
+    pub rate_limit_kbps: Option<u32>,
+    /// Holds back datagrams over `rate_limit_kbps` for up to
+    /// [`MAX_THROTTLE_DELAY`] instead of dropping them.
+    pub rate_limit_delay: bool,
+    /// Percentage of the datagrams relayed to paired peers dropped on purpose,
+    /// to test tunneled applications against a lossy network.
+    pub chaos_loss: f64,
+    /// How long datagrams relayed to paired peers are held back on purpose.
+    pub chaos_delay: Duration,
+    /// Up to how much longer or shorter than `chaos_delay` each datagram is held
+    /// back, picked at random, which also reorders them.
+    pub chaos_jitter: Duration,
+    /// Largest payload relayed from a paired peer or group member; larger ones,
+    /// which would fragment, are dropped. `None` relays everything received.
+    pub max_payload: Option<usize>,
+    /// Answers payloads over `max_payload` with [`Ops::TooBig`] instead of
+    /// dropping them silently.
+    pub reply_too_big: bool,
+    /// Most sessions (pairs, groups and TURN allocations) at once, whatever their key.
+    pub max_sessions: Option<usize>,
+    /// Most pairing requests waiting for their counterpart at once.
+    pub max_pending_pairings: Option<usize>,
+    /// Most paired peers, group members and pending pairing requests a single IP
+    /// may hold at once; further pairing requests from it are answered
+    /// [`Ops::LimitExceeded`].
+    pub max_sessions_per_ip: Option<usize>,
+    /// What to do with pairing requests presenting a secret already taken.
+    pub secret_collision: SecretCollision,
+    /// Pairs and groups the relay serves over its lifetime: once that many were
+    /// created, requests for new ones are refused, and the relay stops when the
+    /// last of them is closed. TURN allocations do not count. `None` never stops.
+    pub max_total_sessions: Option<u64>,
+    /// Answers pairing requests refused by `max_sessions`,
+    /// `max_pending_pairings` or `max_total_sessions` with [`Ops::Busy`] instead
+    /// of dropping them.
+    pub reply_busy: bool,
+    /// Handshake messages accepted per second from a single IP; `0` disables rate limiting.
+    pub pairing_rate: u32,
+    /// Handshake messages a single IP may send in a burst.
+    pub pairing_burst: u32,
+    /// Rate-limited messages after which a source is banned; `0` never bans.
+    pub ban_after: u32,
+    /// How long a banned source is ignored.
+    pub ban_duration: Duration,
+    /// How often expired pairings and inactive connections are swept.
+    pub housekeeping_interval: Duration,
+    /// How long the relay keeps running without any pairs (nor pending pairings).
+    pub timeout_no_connections: Duration,
+    /// How long a pairing request waits for its counterpart.
+    pub timeout_pairing: Duration,
+    /// How long a pair may stay silent before being torn down.
+    pub timeout_connection_inactivities: Duration,
+    /// Sends keepalives at this interval to both peers of pairs idle for at least
+    /// as long, so that NAT mappings do not expire; `None` disables keepalives.
+    pub keepalive_interval: Option<Duration>,
+    /// Sends [`Ops::IdleWarning`] to both peers of a pair this long before it
+    /// is torn down for inactivity, as seen at a housekeeping pass; `None`
+    /// disables warnings.
+    pub idle_warning: Option<Duration>,
+    /// Measures the round-trip time of the paired peers answering
+    /// [`Ops::Echo`] at this interval; `None` disables measurements.
+    pub rtt_interval: Option<Duration>,
+    /// Consecutive failures to send to a peer, without hearing from it in between,
+    /// after which its session is torn down (e.g. as ICMP reports it unreachable);
+    /// `0` never tears sessions down for failed sends.
+    pub max_send_failures: u32,
+    /// Address to serve Prometheus metrics on (at `/metrics`), if any.
+    pub metrics_listen: Option<SocketAddr>,
+    /// Address to serve the liveness (`/healthz`) and readiness (`/readyz`)
+    /// probes on, if any.
+    pub health_listen: Option<SocketAddr>,
+    /// Unix socket accepting administration commands, if any (see [`crate::control`]);
+    /// only supported on Unix.
+    pub control_socket: Option<PathBuf>,
+    /// File to append session lifecycle events to, as JSON Lines (see
+    /// [`crate::accounting`]), if any.
+    pub accounting_log: Option<PathBuf>,
+    /// Shell command run whenever two peers are paired (see [`crate::hooks`]), if any.
+    pub on_pair: Option<String>,
+    /// Shell command run whenever a pair is torn down, if any.
+    pub on_teardown: Option<String>,
+    /// `http://` URL session lifecycle events are posted to, if any.
+    pub webhook_url: Option<String>,
+    /// File to capture the relay's traffic to, in pcap format (see
+    /// [`crate::capture`]), if any.
+    pub pcap: Option<PathBuf>,
+    /// Whether only handshake messages are captured to `pcap`.
+    pub pcap_handshake_only: bool,
+    /// How long shutting down may take to notify peers.
+    pub drain_timeout: Duration,
+    /// Kernel receive buffer of the relay sockets (`SO_RCVBUF`), in bytes;
+    /// `None` keeps the system default.
+    pub so_rcvbuf: Option<usize>,
+    /// Kernel send buffer of the relay sockets (`SO_SNDBUF`), in bytes;
+    /// `None` keeps the system default.
+    pub so_sndbuf: Option<usize>,
+    /// Size of each buffer datagrams are received into; longer datagrams are dropped.
+    pub recv_buffer_size: usize,
+    /// DSCP value (0 to 63) the relay sockets mark the datagrams they send with,
+    /// in the IPv4 TOS or IPv6 traffic class field; `None` keeps the system default.
+    pub dscp: Option<u8>,
+    /// Network interface the relay sockets are pinned to, with `SO_BINDTODEVICE`
+    /// on Linux; elsewhere, sockets bound to an unspecified address are bound to
+    /// the first address of the interface instead.
+    pub interface: Option<String>,
+    /// Number of sockets bound to each address with `SO_REUSEPORT`, each served
+    /// by its own task, so that the kernel spreads peers across cores. Sessions
+    /// are shared between all of them.
+    pub workers: usize,
+    /// Relays the datagrams of each socket on an io_uring of its own instead of
+    /// `recvmmsg`/`sendmmsg`, with a multishot receive into provided buffers
+    /// (see `crate::uring`); needs Linux 6.0 or later and the `io-uring`
+    /// feature, and falls back to the portable loop otherwise.
+    pub io_uring: bool,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            bind: (Ipv4Addr::UNSPECIFIED, 0).into(),
+            second_bind: None,
+            extra_binds: Vec::new(),
+            forward_to: None,
+            dtls_bind: None,
+            dtls_certificate: None,
+            dtls_private_key: None,
+            cluster_bind: None,
+            cluster_peers: Vec::new(),
+            cluster_advertise: None,
+            cluster_key: None,
+            preshared_key: None,
+            preshared_key_hash: None,
+            keys: Vec::new(),
+            insecure_open: false,
+            legacy_handshake: false,
+            min_protocol_version: MIN_PROTOCOL_VERSION,
+            protocol_magic: DEFAULT_MAGIC,
+            encryption: false,
+            allow_cidrs: Vec::new(),
+            deny_cidrs: Vec::new(),
+            session_resumption: false,
+            session_rebind: false,
+            pairing_codes: false,
+            reliable: false,
+            reliable_window: reliable::DEFAULT_WINDOW,
+            retransmit_interval: reliable::DEFAULT_RETRANSMIT_INTERVAL,
+            fec: false,
+            fec_block_size: fec::DEFAULT_BLOCK_SIZE,
+            compression: false,
+            channels: false,
+            group: false,
+            max_group_members: 8,
+            turn: false,
+            turn_realm: "udprelay".to_owned(),
+            rate_limit_kbps: None,
+            rate_limit_delay: false,
+            chaos_loss: 0.0,
+            chaos_delay: Duration::ZERO,
+            chaos_jitter: Duration::ZERO,
+            max_payload: None,
+            reply_too_big: false,
+            max_sessions: None,
+            max_total_sessions: None,
+            max_pending_pairings: None,
+            max_sessions_per_ip: None,
+            secret_collision: SecretCollision::Queue,
+            reply_busy: false,
+            pairing_rate: 5,
+            pairing_burst: 10,
+            ban_after: 50,
+            ban_duration: Duration::from_secs(300),
+            housekeeping_interval: Duration::from_secs(25),
+            timeout_no_connections: Duration::from_secs(300),
+            timeout_pairing: Duration::from_secs(90),
+            timeout_connection_inactivities: Duration::from_secs(180),
+            keepalive_interval: None,
+            idle_warning: None,
+            rtt_interval: Some(DEFAULT_RTT_INTERVAL),
+            max_send_failures: 8,
+            metrics_listen: None,
+            health_listen: None,
+            control_socket: None,
+            accounting_log: None,
+            on_pair: None,
+            on_teardown: None,
+            webhook_url: None,
+            pcap: None,
+            pcap_handshake_only: false,
+            drain_timeout: Duration::from_secs(2),
+            so_rcvbuf: None,
+            so_sndbuf: None,
+            recv_buffer_size: 65535,
+            dscp: None,
+            interface: None,
+            workers: 1,
+            io_uring: false,
+        }
+    }
+}
+
+impl Config {
+    /// Whether datagrams from `ip` may be processed according to the allow and deny lists.
+    pub fn is_peer_allowed(&self, ip: IpAddr) -> bool {
+        // dual-stack sockets report IPv4 peers as IPv4-mapped IPv6 addresses
+        let ip = ip.to_canonical();
+        (self.allow_cidrs.is_empty() || self.allow_cidrs.iter().any(|net| net.contains(&ip)))
+            && !self.deny_cidrs.iter().any(|net| net.contains(&ip))
+    }
+
+    /// Checks that the relay has a strong enough key unless
+    /// [`Config::insecure_open`] is set. Static forwarding needs no key.
+    pub fn validate(&self) -> io::Result<()> {
+        let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidInput, message);
+        if self.second_bind.is_some() && self.forward_to.is_some() {
+            return Err(invalid(
+                "a second port cannot be used with static forwarding".to_owned(),
+            ));
+        }
+        if !self.extra_binds.is_empty() && self.second_bind.is_some() {
+            return Err(invalid(
+                "a second port cannot be used with further ports".to_owned(),
+            ));
+        }
+        if self.group && self.second_bind.is_some() {
+            return Err(invalid(
+                "a second port cannot be used with group mode".to_owned(),
+            ));
+        }
+        if self.keepalive_interval == Some(Duration::ZERO) {
+            return Err(invalid("the keepalive interval cannot be zero".to_owned()));
+        }
+        if self.idle_warning == Some(Duration::ZERO) {
+            return Err(invalid("the idle warning cannot be zero".to_owned()));
+        }
+        if self
+            .idle_warning
+            .is_some_and(|warning| warning >= self.timeout_connection_inactivities)
+        {
+            return Err(invalid(
+                "the idle warning must be shorter than the inactivity timeout".to_owned(),
+            ));
+        }
+        if self.rtt_interval == Some(Duration::ZERO) {
+            return Err(invalid(
+                "the round-trip time interval cannot be zero".to_owned(),
+            ));
+        }
+        if self.recv_buffer_size < MIN_RECV_BUFFER_SIZE {
+            return Err(invalid(format!(
+                "the receive buffer must hold at least {MIN_RECV_BUFFER_SIZE} bytes"
+            )));
+        }
+        if self.dscp.is_some_and(|dscp| dscp > MAX_DSCP) {
+            return Err(invalid(format!(
+                "the DSCP value must be between 0 and {MAX_DSCP}"
+            )));
+        }
+        if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&self.min_protocol_version) {
+            return Err(invalid(format!(
+                "the minimum protocol version must be between {MIN_PROTOCOL_VERSION} and {PROTOCOL_VERSION}"
+            )));
+        }
+        if self.encryption && (self.legacy_handshake || self.forward_to.is_some()) {
+            return Err(invalid(
+                "encryption cannot be used with the legacy handshake nor static forwarding"
+                    .to_owned(),
+            ));
+        }
+        if self.reliable_window == 0 || self.retransmit_interval.is_zero() {
+            return Err(invalid(
+                "the reliable window and the retransmit interval cannot be zero".to_owned(),
+            ));
+        }
+        if !(1..=fec::MAX_BLOCK_SIZE).contains(&self.fec_block_size) {
+            return Err(invalid(format!(
+                "the FEC block size must be between 1 and {}",
+                fec::MAX_BLOCK_SIZE
+            )));
+        }
+        if self.preshared_key_hash.is_some() {
+            if self.default_key().is_some() || !self.legacy_handshake {
+                return Err(invalid(
+                    "a hashed pre-shared key replaces the key, and only authenticates the legacy handshake"
+                        .to_owned(),
+                ));
+            }
+            if self.encryption || self.turn || self.pairing_codes || self.dtls_bind.is_some() {
+                return Err(invalid(
+                    "encryption, TURN, pairing codes and DTLS need the pre-shared key, not its hash"
+                        .to_owned(),
+                ));
+            }
+        }
+        if self.encryption && self.is_open() {
+            return Err(invalid("encryption needs a pre-shared key".to_owned()));
+        }
+        if self.turn && (self.is_open() || self.forward_to.is_some()) {
+            return Err(invalid(
+                "TURN needs a pre-shared key and cannot be used with static forwarding".to_owned(),
+            ));
+        }
+        if self.dtls_bind.is_some() && !cfg!(feature = "dtls") {
+            return Err(invalid(
+                "DTLS support is not built in; rebuild with the `dtls` feature".to_owned(),
+            ));
+        }
+        if self.io_uring && !cfg!(all(target_os = "linux", feature = "io-uring")) {
+            return Err(invalid(
+                "io_uring support is not built in; rebuild with the `io-uring` feature on Linux"
+                    .to_owned(),
+            ));
+        }
+        if self.dtls_certificate.is_some() != self.dtls_private_key.is_some() {
+            return Err(invalid(
+                "a DTLS certificate needs its private key, and conversely".to_owned(),
+            ));
+        }
+        if self.dtls_bind.is_some() && self.forward_to.is_some() {
+            return Err(invalid(
+                "DTLS cannot be used with static forwarding".to_owned(),
+            ));
+        }
+        if self.dtls_bind.is_some() && self.dtls_certificate.is_none() && self.is_open() {
+            return Err(invalid(
+                "DTLS without a certificate needs a pre-shared key".to_owned(),
+            ));
+        }
+        if self.cluster_bind.is_some() {
+            if self.cluster_advertise.is_none() {
+                return Err(invalid(
+                    "a cluster needs the address to advertise to peers".to_owned(),
+                ));
+            }
+            if self.forward_to.is_some() || self.second_bind.is_some() {
+                return Err(invalid(
+                    "a cluster cannot be used with static forwarding nor a second port".to_owned(),
+                ));
+            }
+            match self
+                .cluster_key
+                .as_deref()
+                .map(|key| auth::check_psk_strength(key))
+            {
+                None => return Err(invalid("a cluster needs a cluster key".to_owned())),
+                Some(Err(e)) if !self.insecure_open => {
+                    return Err(invalid(format!("cluster key: {e}")))
+                }
+                Some(Err(e)) => warn!("Cluster key: {e}"),
+                Some(Ok(())) => (),
+            }
+        } else if !self.cluster_peers.is_empty() {
+            return Err(invalid("cluster peers need a cluster address".to_owned()));
+        }
+        if cfg!(not(unix)) && self.control_socket.is_some() {
+            return Err(invalid(
+                "the control socket is only supported on Unix".to_owned(),
+            ));
+        }
+        if self.workers == 0 {
+            return Err(invalid("at least one worker is needed".to_owned()));
+        }
+        if self.rate_limit_kbps == Some(0) {
+            return Err(invalid("the rate limit cannot be zero".to_owned()));
+        }
+        if self
+            .webhook_url
+            .as_deref()
+            .is_some_and(|url| http::split_url(url).is_none())
+        {
+            return Err(invalid("the webhook URL must be an http:// URL".to_owned()));
+        }
+        if self.max_total_sessions == Some(0) {
+            return Err(invalid(
+                "the total number of sessions cannot be zero".to_owned(),
+            ));
+        }
+        if self.max_sessions_per_ip == Some(0) {
+            return Err(invalid(
+                "the number of sessions per IP cannot be zero".to_owned(),
+            ));
+        }
+        if self.max_payload == Some(0) {
+            return Err(invalid("the maximum payload cannot be zero".to_owned()));
+        }
+        if !(0.0..=100.0).contains(&self.chaos_loss) {
+            return Err(invalid(
+                "the chaos loss must be a percentage between 0 and 100".to_owned(),
+            ));
+        }
+        if self.group && self.max_group_members < 2 {
+            return Err(invalid("groups must allow at least 2 members".to_owned()));
+        }
+        if self.is_open() || self.forward_to.is_some() {
+            return Ok(());
+        }
+        if self.default_key().is_none() && self.preshared_key_hash.is_none() && self.keys.is_empty()
+        {
+            return Err(invalid(
+                "no pre-shared key configured; enable insecure open mode to accept every peer"
+                    .to_owned(),
+            ));
+        }
+        let keys = self
+            .default_key()
+            .map(|psk| (DEFAULT_KEY_NAME, psk))
+            .into_iter()
+            .chain(
+                self.keys
+                    .iter()
+                    .map(|key| (key.name.as_str(), key.preshared_key.as_str())),
+            );
+        for (name, psk) in keys {
+            match auth::check_psk_strength(psk) {
+                Err(e) if !self.insecure_open => return Err(invalid(format!("key '{name}': {e}"))),
+                Err(e) => warn!("Key '{name}': {e}"),
+                Ok(()) => (),
+            }
+        }
+        Ok(())
+    }
+
+    fn default_key(&self) -> Option<&str> {
+        self.preshared_key
+            .as_deref()
+            .map(String::as_str)
+            .filter(|psk| !psk.is_empty())
+    }
+
+    /// Whether every peer is accepted, as no key is configured.
+    pub(crate) fn is_open(&self) -> bool {
+        self.insecure_open
+            && self.default_key().is_none()
+            && self.preshared_key_hash.is_none()
+            && self.keys.is_empty()
+    }
+
+    /// Name of the key `presented` in cleartext, comparing SHA-256 digests in
+    /// constant time, so that neither the content nor the length of a key leaks.
+    pub(crate) fn find_presented_key(&self, presented: &[u8]) -> Option<&str> {
+        let digest = auth::hash_psk(presented);
+        if self
+            .preshared_key_hash
+            .is_some_and(|hash| auth::constant_time_eq(&hash, &digest))
+        {
+            return Some(DEFAULT_KEY_NAME);
+        }
+        self.find_key(|psk| auth::constant_time_eq(&auth::hash_psk(psk), &digest))
+    }
+
+    /// Name of the first key for which `matches` holds, trying
+    /// [`Config::preshared_key`] (named [`DEFAULT_KEY_NAME`]) before the keyring.
+    /// Every peer authenticates as [`DEFAULT_KEY_NAME`] when the relay is open.
+    pub(crate) fn find_key(&self, matches: impl Fn(&[u8]) -> bool) -> Option<&str> {
+        if self.is_open()
+            || self
+                .default_key()
+                .is_some_and(|psk| matches(psk.as_bytes()))
+        {
+            return Some(DEFAULT_KEY_NAME);
+        }
+        self.keys
+            .iter()
+            .find(|key| matches(key.preshared_key.as_bytes()))
+            .map(|key| key.name.as_str())
+    }
+
+    /// The pre-shared key named `name`, [`DEFAULT_KEY_NAME`] being
+    /// [`Config::preshared_key`].
+    pub(crate) fn psk(&self, name: &str) -> Option<&str> {
+        self.default_key()
+            .filter(|_| name == DEFAULT_KEY_NAME)
+            .or_else(|| self.key(name).map(|key| key.preshared_key.as_str()))
+    }
+
+    /// The keyring entry named `name`; `None` for the default key (or a key
+    /// removed by a reload).
+    pub(crate) fn key(&self, name: &str) -> Option<&NamedKey> {
+        self.keys.iter().find(|key| key.name == name)
+    }
+
+    pub(crate) fn timeout_pairing_for(&self, key: &str) -> Duration {
+        self.key(key)
+            .and_then(|key| key.timeout_pairing)
+            .unwrap_or(self.timeout_pairing)
+    }
+
+    pub(crate) fn secret_collision_for(&self, key: &str) -> SecretCollision {
+        self.key(key)
+            .and_then(|key| key.secret_collision)
+            .unwrap_or(self.secret_collision)
+    }
+
+    pub(crate) fn timeout_connection_inactivities_for(&self, key: &str) -> Duration {
+        self.key(key)
+            .and_then(|key| key.timeout_connection_inactivities)
+            .unwrap_or(self.timeout_connection_inactivities)
+    }
+
+    /// Bandwidth of each peer paired with `key`, in bytes per second, if limited.
+    pub(crate) fn rate_limit_for(&self, key: &str) -> Option<f64> {
+        self.key(key)
+            .and_then(|key| key.rate_limit_kbps)
+            .or(self.rate_limit_kbps)
+            .filter(|&kbps| kbps > 0)
+            .map(|kbps| f64::from(kbps) * 1000.0 / 8.0)
+    }
+}
+
+/// Binds a non-blocking UDP socket, pinned to `interface` if given (see
+/// [`Config::interface`]). Binding to the unspecified IPv6 address also accepts
+/// IPv4 peers where the OS supports dual-stack sockets.
+pub fn bind_socket(addr: SocketAddr, interface: Option<&str>) -> io::Result<std::net::UdpSocket> {
+    bind_udp_socket(addr, false, interface)
+}
+
+pub(crate) fn bind_udp_socket(
+    addr: SocketAddr,
+    reuse_port: bool,
+    interface: Option<&str>,
+) -> io::Result<std::net::UdpSocket> {
+    let addr = match interface {
+        Some(name) => bind_to_interface_addr(addr, name)?,
+        None => addr,
+    };
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+    if let Some(name) = interface {
+        socket.bind_device(Some(name.as_bytes())).map_err(|e| {
+            io::Error::new(e.kind(), format!("cannot bind to interface {name}: {e}"))
+        })?;
+    }
+    if reuse_port {
+        #[cfg(unix)]
+        socket.set_reuse_port(true)?;
+    }
+    if addr.is_ipv6() {
+        // best effort: some platforms only support v6-only sockets
+        if let Err(e) = socket.set_only_v6(false) {
+            warn!("Cannot enable dual-stack socket, serving IPv6 only: {}", e);
+        }
+    }
+    socket.bind(&addr.into())?;
+    // the socket is handed over to tokio once the runtime is up (i.e. after daemonizing)
+    socket.set_nonblocking(true)?;
+    if let Some(name) = interface {
+        info!(
+            "Bound {} to interface {name}",
+            socket.local_addr()?.as_socket().unwrap_or(addr)
+        );
+    }
+    Ok(socket.into())
+}
+
+/// Address to bind to `name` with: `addr` itself where sockets can be pinned to
+/// an interface.
+#[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+fn bind_to_interface_addr(addr: SocketAddr, _name: &str) -> io::Result<SocketAddr> {
+    Ok(addr)
+}
+
+/// Address to bind to `name` with: the first address of the interface in the
+/// family of `addr` if it is unspecified, as sockets cannot be pinned to an
+/// interface here.
+#[cfg(all(
+    unix,
+    not(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))
+))]
+fn bind_to_interface_addr(addr: SocketAddr, name: &str) -> io::Result<SocketAddr> {
+    use std::ffi::CStr;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+    use std::ptr;
+
+    if !addr.ip().is_unspecified() {
+        return Ok(addr);
+    }
+    let mut addrs = ptr::null_mut();
+    // SAFETY: on success, getifaddrs stores a list freed below
+    if unsafe { libc::getifaddrs(&mut addrs) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut found = None;
+    let mut next = addrs;
+    while found.is_none() && !next.is_null() {
+        // SAFETY: every entry of the list, and what it points to, is valid until freed
+        let entry = unsafe { &*next };
+        next = entry.ifa_next;
+        if entry.ifa_addr.is_null()
+            || unsafe { CStr::from_ptr(entry.ifa_name) }.to_bytes() != name.as_bytes()
+        {
+            continue;
+        }
+        found = match (i32::from(unsafe { (*entry.ifa_addr).sa_family }), addr) {
+            (libc::AF_INET, SocketAddr::V4(_)) => {
+                let sin = unsafe { &*entry.ifa_addr.cast::<libc::sockaddr_in>() };
+                Some(Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr)).into())
+            }
+            (libc::AF_INET6, SocketAddr::V6(_)) => {
+                let sin6 = unsafe { &*entry.ifa_addr.cast::<libc::sockaddr_in6>() };
+                Some(Ipv6Addr::from(sin6.sin6_addr.s6_addr).into())
+            }
+            _ => None,
+        };
+    }
+    // SAFETY: the list came from getifaddrs and is no longer borrowed
+    unsafe { libc::freeifaddrs(addrs) };
+    match found {
+        Some(ip) => Ok(SocketAddr::new(ip, addr.port())),
+        None => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("interface {name} has no address to bind {addr} with"),
+        )),
+    }
+}
+
+#[cfg(not(unix))]
+fn bind_to_interface_addr(_addr: SocketAddr, _name: &str) -> io::Result<SocketAddr> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "binding to an interface is not supported on this platform",
+    ))
+}
+
+/// Binds the sockets of [`Config::workers`] workers to `addr`, starting with
+/// `first` if given, and sizes their kernel buffers. With several workers, the
+/// first socket decides the port (e.g. when binding port 0) and must have
+/// `SO_REUSEPORT` set. Without `SO_REUSEPORT` (e.g. on Windows), the workers
+/// share the first socket.
+fn bind_worker_sockets(
+    config: &Config,
+    first: Option<std::net::UdpSocket>,
+    addr: SocketAddr,
+) -> io::Result<Vec<std::net::UdpSocket>> {
+    let first = match first {
+        Some(socket) => socket,
+        None => bind_udp_socket(addr, config.workers > 1, config.interface.as_deref())?,
+    };
+    let addr = first.local_addr()?;
+    let mut sockets = vec![first];
+    for _ in 1..config.workers {
+        #[cfg(unix)]
+        sockets.push(bind_udp_socket(addr, true, config.interface.as_deref())?);
+        #[cfg(not(unix))]
+        sockets.push(sockets[0].try_clone()?);
+    }
+    for socket in &sockets {
+        let socket = SockRef::from(socket);
+        if let Some(size) = config.so_rcvbuf {
+            socket.set_recv_buffer_size(size)?;
+        }
+        if let Some(size) = config.so_sndbuf {
+            socket.set_send_buffer_size(size)?;
+        }
+        if let Some(dscp) = config.dscp {
+            set_dscp(&socket, addr, dscp)?;
+        }
+    }
+    // the kernel may adjust the requested sizes, e.g. Linux doubles them and caps
+    // them at net.core.rmem_max / wmem_max
+    let socket = SockRef::from(&sockets[0]);
+    info!(
+        "Socket buffers of {addr}: {} bytes to receive, {} bytes to send",
+        socket.recv_buffer_size()?,
+        socket.send_buffer_size()?
+    );
+    Ok(sockets)
+}
+
+/// Marks the datagrams sent by `socket`, bound to `addr`, with `dscp`, the
+/// upper six bits of the TOS or traffic class byte.
+fn set_dscp(socket: &SockRef, addr: SocketAddr, dscp: u8) -> io::Result<()> {
+    let tos = u32::from(dscp) << 2;
+    if addr.is_ipv4() {
+        return socket.set_tos_v4(tos);
+    }
+    #[cfg(unix)]
+    socket.set_tclass_v6(tos)?;
+    // best effort, for IPv4 peers of a dual-stack socket
+    if let Err(e) = socket.set_tos_v4(tos) {
+        debug!("Cannot mark IPv4 datagrams of dual-stack socket {addr}: {e}");
+    }
+    Ok(())
+}
+
+fn bind_tcp_listener(addr: SocketAddr) -> io::Result<std::net::TcpListener> {
+    let listener = std::net::TcpListener::bind(addr)?;
+    listener.set_nonblocking(true)?;
+    Ok(listener)
+}
+
+/// Binds the control socket, replacing a stale socket left by a previous run.
+/// Any other file at `path` is left alone and fails the bind.
+#[cfg(unix)]
+fn bind_control_socket(path: &Path) -> io::Result<std::os::unix::net::UnixListener> {
+    use std::os::unix::fs::FileTypeExt;
+
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => fs::remove_file(path)?,
+        Ok(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} exists and is not a socket", path.display()),
+            ))
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+        Err(e) => return Err(e),
+    }
+    let listener = std::os::unix::net::UnixListener::bind(path)?;
+    listener.set_nonblocking(true)?;
+    Ok(listener)
+}
+
+/// Builder for a [`Relay`], see [`Relay::builder`].
+#[derive(Debug, Default)]
+pub struct RelayBuilder {
+    config: Config,
+    socket: Option<std::net::UdpSocket>,
+}
+
+impl From<Config> for RelayBuilder {
+    fn from(config: Config) -> RelayBuilder {
+        RelayBuilder {
+            config,
+            socket: None,
+        }
+    }
+}
+
+impl RelayBuilder {
+    /// Address to bind the relay socket to.
+    pub fn bind(mut self, addr: impl Into<SocketAddr>) -> RelayBuilder {
+        self.config.bind = addr.into();
+        self
+    }
+
+    /// Also listens on `addr`, pairing peers of one socket with peers of the other.
+    pub fn second_bind(mut self, addr: impl Into<SocketAddr>) -> RelayBuilder {
+        self.config.second_bind = Some(addr.into());
+        self
+    }
+
+    /// Also listens on `addr`, sharing the sessions of the other sockets; may be
+    /// given several times.
+    pub fn extra_bind(mut self, addr: impl Into<SocketAddr>) -> RelayBuilder {
+        self.config.extra_binds.push(addr.into());
+        self
+    }
+
+    /// Forwards every datagram to `target` instead of pairing peers.
+    pub fn forward_to(mut self, target: impl Into<SocketAddr>) -> RelayBuilder {
+        self.config.forward_to = Some(target.into());
+        self
+    }
+
+    /// Also accepts pairing requests over DTLS on `addr`; needs the `dtls` feature.
+    pub fn dtls(mut self, addr: impl Into<SocketAddr>) -> RelayBuilder {
+        self.config.dtls_bind = Some(addr.into());
+        self
+    }
+
+    /// Authenticates the DTLS listener with a certificate instead of DTLS-PSK.
+    pub fn dtls_certificate(
+        mut self,
+        certificate: impl Into<PathBuf>,
+        private_key: impl Into<PathBuf>,
+    ) -> RelayBuilder {
+        self.config.dtls_certificate = Some(certificate.into());
+        self.config.dtls_private_key = Some(private_key.into());
+        self
+    }
+
+    /// Joins a cluster of relay instances, exchanging announcements on `bind`
+    /// authenticated with `key`, and advertising `advertise` to the peers
+    /// redirected here. See [`RelayBuilder::cluster_peer`].
+    pub fn cluster(
+        mut self,
+        bind: impl Into<SocketAddr>,
+        advertise: impl Into<SocketAddr>,
+        key: impl Into<String>,
+    ) -> RelayBuilder {
+        self.config.cluster_bind = Some(bind.into());
+        self.config.cluster_advertise = Some(advertise.into());
+        self.config.cluster_key = Some(Zeroizing::new(key.into()));
+        self
+    }
+
+    /// Cluster address of another instance; may be given several times.
+    pub fn cluster_peer(mut self, addr: impl Into<SocketAddr>) -> RelayBuilder {
+        self.config.cluster_peers.push(addr.into());
+        self
+    }
+
+    /// Uses an already bound socket instead of binding [`RelayBuilder::bind`].
+    /// With several [`RelayBuilder::workers`], it must have `SO_REUSEPORT` set.
+    pub fn socket(mut self, socket: std::net::UdpSocket) -> RelayBuilder {
+        self.socket = Some(socket);
+        self
+    }
+
+    pub fn psk(mut self, preshared_key: impl Into<String>) -> RelayBuilder {
+        self.config.preshared_key = Some(Zeroizing::new(preshared_key.into()));
+        self
+    }
+
+    /// Sets the SHA-256 digest of the pre-shared key instead of the key, see
+    /// [`Config::preshared_key_hash`].
+    pub fn psk_hash(mut self, hash: [u8; PSK_HASH_LEN]) -> RelayBuilder {
+        self.config.preshared_key_hash = Some(hash);
+        self
+    }
+
+    /// Allows running without any key, accepting every peer, or with weak keys.
+    pub fn insecure_open(mut self, enabled: bool) -> RelayBuilder {
+        self.config.insecure_open = enabled;
+        self
+    }
+
+    /// Adds a named key to the keyring.
+    pub fn key(mut self, key: NamedKey) -> RelayBuilder {
+        self.config.keys.push(key);
+        self
+    }
+
+    /// Also accepts the legacy handshake sending the pre-shared key in cleartext.
+    pub fn legacy_handshake(mut self, enabled: bool) -> RelayBuilder {
+        self.config.legacy_handshake = enabled;
+        self
+    }
+
+    /// Refuses peers speaking a protocol version older than `version`.
+    pub fn min_protocol_version(mut self, version: u8) -> RelayBuilder {
+        self.config.min_protocol_version = version;
+        self
+    }
+
+    /// Changes the first command byte of every control message from `0xff`;
+    /// peers must use the same.
+    pub fn protocol_magic(mut self, magic: u8) -> RelayBuilder {
+        self.config.protocol_magic = magic;
+        self
+    }
+
+    /// Encrypts the traffic of paired peers with the relay.
+    pub fn encryption(mut self, enabled: bool) -> RelayBuilder {
+        self.config.encryption = enabled;
+        self
+    }
+
+    /// Only serves peers within `net`; may be given several times.
+    pub fn allow_cidr(mut self, net: IpNet) -> RelayBuilder {
+        self.config.allow_cidrs.push(net);
+        self
+    }
+
+    /// Ignores peers within `net`; may be given several times.
+    pub fn deny_cidr(mut self, net: IpNet) -> RelayBuilder {
+        self.config.deny_cidrs.push(net);
+        self
+    }
+
+    /// Gives paired peers a token to resume their session from a new address.
+    pub fn session_resumption(mut self, enabled: bool) -> RelayBuilder {
+        self.config.session_resumption = enabled;
+        self
+    }
+
+    /// Lets paired peers rebind their session to a new address with its secret.
+    pub fn session_rebind(mut self, enabled: bool) -> RelayBuilder {
+        self.config.session_rebind = enabled;
+        self
+    }
+
+    /// Only accepts the pairing code of the current time window as session secret.
+    pub fn pairing_codes(mut self, enabled: bool) -> RelayBuilder {
+        self.config.pairing_codes = enabled;
+        self
+    }
+
+    /// Offers reliable delivery to the peers asking for it, with at most `window`
+    /// frames in flight to each of them.
+    pub fn reliable(mut self, window: u32) -> RelayBuilder {
+        self.config.reliable = true;
+        self.config.reliable_window = window;
+        self
+    }
+
+    /// Offers forward error correction to the peers asking for it, sending them
+    /// a parity datagram after every `block_size` datagrams.
+    pub fn fec(mut self, block_size: u8) -> RelayBuilder {
+        self.config.fec = true;
+        self.config.fec_block_size = block_size;
+        self
+    }
+
+    /// Compresses the datagrams relayed to the peers asking for it.
+    pub fn compression(mut self) -> RelayBuilder {
+        self.config.compression = true;
+        self
+    }
+
+    /// Lets the peers asking for it multiplex logical channels within their session.
+    pub fn channels(mut self) -> RelayBuilder {
+        self.config.channels = true;
+        self
+    }
+
+    /// Sends frames to peers with reliable delivery again when they are not
+    /// acknowledged within `interval`.
+    pub fn retransmit_interval(mut self, interval: Duration) -> RelayBuilder {
+        self.config.retransmit_interval = interval;
+        self
+    }
+
+    /// Lets peers sharing a secret join a group of up to `max_members` peers
+    /// instead of forming pairs.
+    pub fn group(mut self, max_members: usize) -> RelayBuilder {
+        self.config.group = true;
+        self.config.max_group_members = max_members;
+        self
+    }
+
+    /// Also serves TURN clients, authenticating them in `realm`.
+    pub fn turn(mut self, realm: impl Into<String>) -> RelayBuilder {
+        self.config.turn = true;
+        self.config.turn_realm = realm.into();
+        self
+    }
+
+    /// Limits the bandwidth of each paired peer to `kbps` kilobits per second,
+    /// holding back datagrams over the limit if `delay`, dropping them otherwise.
+    pub fn rate_limit_kbps(mut self, kbps: u32, delay: bool) -> RelayBuilder {
+        self.config.rate_limit_kbps = Some(kbps);
+        self.config.rate_limit_delay = delay;
+        self
+    }
+
+    /// Impairs the relay on purpose, as a network simulator: drops `loss_percent`
+    /// percent of the datagrams relayed to paired peers, and holds back the others
+    /// for `delay`, give or take up to `jitter`.
+    pub fn chaos(mut self, loss_percent: f64, delay: Duration, jitter: Duration) -> RelayBuilder {
+        self.config.chaos_loss = loss_percent;
+        self.config.chaos_delay = delay;
+        self.config.chaos_jitter = jitter;
+        self
+    }
+
+    /// Refuses new sessions once `max` pairs, groups and TURN allocations are established.
+    pub fn max_sessions(mut self, max: usize) -> RelayBuilder {
+        self.config.max_sessions = Some(max);
+        self
+    }
+
+    /// Serves `max` pairs, then stops once the last of them is closed, see
+    /// [`Config::max_total_sessions`].
+    pub fn max_total_sessions(mut self, max: u64) -> RelayBuilder {
+        self.config.max_total_sessions = Some(max);
+        self
+    }
+
+    /// Refuses new pairing requests once `max` wait for their counterpart.
+    pub fn max_pending_pairings(mut self, max: usize) -> RelayBuilder {
+        self.config.max_pending_pairings = Some(max);
+        self
+    }
+
+    /// Refuses pairing requests from an IP once it holds `max` paired peers and
+    /// pending pairing requests.
+    pub fn max_sessions_per_ip(mut self, max: usize) -> RelayBuilder {
+        self.config.max_sessions_per_ip = Some(max);
+        self
+    }
+
+    /// Sets what to do with pairing requests presenting a secret already taken.
+    pub fn secret_collision(mut self, policy: SecretCollision) -> RelayBuilder {
+        self.config.secret_collision = policy;
+        self
+    }
+
+    /// Drops payloads of paired peers and group members over `max` bytes,
+    /// answering them with [`Ops::TooBig`] if `reply`.
+    pub fn max_payload(mut self, max: usize, reply: bool) -> RelayBuilder {
+        self.config.max_payload = Some(max);
+        self.config.reply_too_big = reply;
+        self
+    }
+
+    /// Answers refused pairing requests with [`Ops::Busy`] rather than dropping them.
+    pub fn reply_busy(mut self, enabled: bool) -> RelayBuilder {
+        self.config.reply_busy = enabled;
+        self
+    }
+
+    /// Limits handshake messages from a single IP to `rate` per second with bursts of `burst`.
+    pub fn pairing_rate(mut self, rate: u32, burst: u32) -> RelayBuilder {
+        self.config.pairing_rate = rate;
+        self.config.pairing_burst = burst;
+        self
+    }
+
+    /// Bans a source for `duration` once `after` of its messages were rate limited.
+    pub fn ban(mut self, after: u32, duration: Duration) -> RelayBuilder {
+        self.config.ban_after = after;
+        self.config.ban_duration = duration;
+        self
+    }
+
+    pub fn housekeeping_interval(mut self, interval: Duration) -> RelayBuilder {
+        self.config.housekeeping_interval = interval;
+        self
+    }
+
+    pub fn timeout_no_connections(mut self, timeout: Duration) -> RelayBuilder {
+        self.config.timeout_no_connections = timeout;
+        self
+    }
+
+    pub fn timeout_pairing(mut self, timeout: Duration) -> RelayBuilder {
+        self.config.timeout_pairing = timeout;
+        self
+    }
+
+    pub fn timeout_connection_inactivities(mut self, timeout: Duration) -> RelayBuilder {
+        self.config.timeout_connection_inactivities = timeout;
+        self
+    }
+
+    /// Measures the round-trip time of the paired peers answering
+    /// [`Ops::Echo`] at `interval`, or never with `None`.
+    pub fn rtt_interval(mut self, interval: Option<Duration>) -> RelayBuilder {
+        self.config.rtt_interval = interval;
+        self
+    }
+
+    /// Sends keepalives to the peers of sessions idle for `interval`, at that interval.
+    pub fn keepalive_interval(mut self, interval: Duration) -> RelayBuilder {
+        self.config.keepalive_interval = Some(interval);
+        self
+    }
+
+    /// Warns both peers of a pair `warning` before it is torn down for inactivity.
+    pub fn idle_warning(mut self, warning: Duration) -> RelayBuilder {
+        self.config.idle_warning = Some(warning);
+        self
+    }
+
+    /// Tears down the session of a peer once `failures` sends to it failed in a row.
+    pub fn max_send_failures(mut self, failures: u32) -> RelayBuilder {
+        self.config.max_send_failures = failures;
+        self
+    }
+
+    /// Serves Prometheus metrics over HTTP at `/metrics` on the given address.
+    pub fn metrics_listen(mut self, addr: impl Into<SocketAddr>) -> RelayBuilder {
+        self.config.metrics_listen = Some(addr.into());
+        self
+    }
+
+    /// Serves the liveness and readiness probes over HTTP on the given address,
+    /// see [`Config::health_listen`].
+    pub fn health_listen(mut self, addr: impl Into<SocketAddr>) -> RelayBuilder {
+        self.config.health_listen = Some(addr.into());
+        self
+    }
+
+    pub fn drain_timeout(mut self, timeout: Duration) -> RelayBuilder {
+        self.config.drain_timeout = timeout;
+        self
+    }
+
+    /// Sizes the kernel receive and send buffers of the relay sockets; `None`
+    /// keeps the system default.
+    pub fn socket_buffers(mut self, rcvbuf: Option<usize>, sndbuf: Option<usize>) -> RelayBuilder {
+        self.config.so_rcvbuf = rcvbuf;
+        self.config.so_sndbuf = sndbuf;
+        self
+    }
+
+    /// Receives datagrams into buffers of `size` bytes, dropping longer ones.
+    pub fn recv_buffer_size(mut self, size: usize) -> RelayBuilder {
+        self.config.recv_buffer_size = size;
+        self
+    }
+
+    /// Marks the datagrams sent by the relay sockets with `dscp`, see
+    /// [`Config::dscp`].
+    pub fn dscp(mut self, dscp: u8) -> RelayBuilder {
+        self.config.dscp = Some(dscp);
+        self
+    }
+
+    /// Pins the relay sockets to the network interface `name`, see
+    /// [`Config::interface`].
+    pub fn interface(mut self, name: impl Into<String>) -> RelayBuilder {
+        self.config.interface = Some(name.into());
+        self
+    }
+
+    /// Serves each address with `workers` sockets bound with `SO_REUSEPORT`.
+    pub fn workers(mut self, workers: usize) -> RelayBuilder {
+        self.config.workers = workers;
+        self
+    }
+
+    /// Sends relayed datagrams through io_uring, see [`Config::io_uring`].
+    pub fn io_uring(mut self) -> RelayBuilder {
+        self.config.io_uring = true;
+        self
+    }
+
+    /// Accepts administration commands on a Unix socket at `path`.
+    pub fn control_socket(mut self, path: impl Into<PathBuf>) -> RelayBuilder {
+        self.config.control_socket = Some(path.into());
+        self
+    }
+
+    /// Appends session lifecycle events to the file at `path`.
+    pub fn accounting_log(mut self, path: impl Into<PathBuf>) -> RelayBuilder {
+        self.config.accounting_log = Some(path.into());
+        self
+    }
+
+    /// Runs the shell command `command` whenever two peers are paired, see
+    /// [`crate::hooks`].
+    pub fn on_pair(mut self, command: impl Into<String>) -> RelayBuilder {
+        self.config.on_pair = Some(command.into());
+        self
+    }
+
+    /// Runs the shell command `command` whenever a pair is torn down.
+    pub fn on_teardown(mut self, command: impl Into<String>) -> RelayBuilder {
+        self.config.on_teardown = Some(command.into());
+        self
+    }
+
+    /// Posts session lifecycle events to the `http://` `url`.
+    pub fn webhook(mut self, url: impl Into<String>) -> RelayBuilder {
+        self.config.webhook_url = Some(url.into());
+        self
+    }
+
+    /// Captures the datagrams received and sent by the relay to a pcap file at
+    /// `path`, or only the handshake messages with `handshake_only`.
+    pub fn pcap(mut self, path: impl Into<PathBuf>, handshake_only: bool) -> RelayBuilder {
+        self.config.pcap = Some(path.into());
+        self.config.pcap_handshake_only = handshake_only;
+        self
+    }
+
+    /// Binds the relay sockets (unless one was given), the DTLS listener, the
+    /// metrics and health listeners and the control socket, and opens the
+    /// accounting log and the packet capture, without starting the relay.
+    pub fn build(mut self) -> io::Result<Relay> {
+        self.config.validate()?;
+        let mut sockets = bind_worker_sockets(&self.config, self.socket, self.config.bind)?;
+        for addr in &self.config.extra_binds {
+            sockets.extend(bind_worker_sockets(&self.config, None, *addr)?);
+        }
+        let second_sockets = match self.config.second_bind {
+            Some(addr) => bind_worker_sockets(&self.config, None, addr)?,
+            None => Vec::new(),
+        };
+        let metrics_listener = match self.config.metrics_listen {
+            Some(addr) => Some(bind_tcp_listener(addr)?),
+            None => None,
+        };
+        let health_listener = match self.config.health_listen {
+            Some(addr) => Some(bind_tcp_listener(addr)?),
+            None => None,
+        };
+        // paths are kept absolute, as daemonizing changes the working directory
+        for path in [
+            &mut self.config.control_socket,
+            &mut self.config.accounting_log,
+            &mut self.config.pcap,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            *path = path::absolute(&*path)?;
+        }
+        let cluster_socket = match self.config.cluster_bind {
+            Some(addr) => Some(bind_udp_socket(
+                addr,
+                false,
+                self.config.interface.as_deref(),
+            )?),
+            None => None,
+        };
+        let cluster = match &cluster_socket {
+            Some(socket) => Some(Cluster::new(socket.try_clone()?)?),
+            None => None,
+        };
+        let accounting = match &self.config.accounting_log {
+            Some(path) => Some(AccountingLog::open(path)?),
+            None => None,
+        };
+        let hooks = (self.config.on_pair.is_some()
+            || self.config.on_teardown.is_some()
+            || self.config.webhook_url.is_some())
+        .then(|| Hooks {
+            on_pair: self.config.on_pair.clone(),
+            on_teardown: self.config.on_teardown.clone(),
+            webhook_url: self.config.webhook_url.clone(),
+        });
+        if let Some(path) = &self.config.pcap {
+            capture::start(
+                path,
+                self.config.pcap_handshake_only,
+                self.config.protocol_magic,
+            )?;
+        }
+        #[cfg(unix)]
+        let control_listener = match &self.config.control_socket {
+            Some(path) => Some(bind_control_socket(path)?),
+            None => None,
+        };
+        let config = ConfigHandle::new(self.config);
+        #[cfg(feature = "dtls")]
+        let dtls_listener = match config.get().dtls_bind {
+            Some(addr) => Some(DtlsListener::bind(addr, &config.get(), config.subscribe())?),
+            None => None,
+        };
+        let registry = Arc::new_cyclic(|handle| {
+            Mutex::new(RelayService::new(
+                accounting,
+                hooks,
+                cluster,
+                handle.clone(),
+                config.subscribe(),
+            ))
+        });
+        Ok(Relay {
+            config,
+            registry,
+            sockets,
+            second_sockets,
+            #[cfg(feature = "dtls")]
+            dtls_listener,
+            cluster_socket,
+            metrics_listener,
+            health_listener,
+            #[cfg(unix)]
+            control_listener,
+        })
+    }
+
+    /// Shorthand for [`RelayBuilder::build`] followed by [`Relay::run`].
+    pub async fn run(self) -> io::Result<()> {
+        self.build()?.run().await
+    }
+}
+
+/// A UDP relay pairing peers that present the same session secret.
+///
+/// ```no_run
+/// # async fn example() -> std::io::Result<()> {
+/// use std::net::Ipv4Addr;
+/// use udprelay_rust::Relay;
+///
+/// Relay::builder()
+///     .bind((Ipv4Addr::UNSPECIFIED, 60017))
+///     .psk("my pre-shared key")
+///     .run()
+///     .await
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct Relay {
+    config: ConfigHandle,
+    registry: Registry,
+    /// One socket per worker, all bound to the same address.
+    sockets: Vec<std::net::UdpSocket>,
+    /// Sockets of the second port, if any.
+    second_sockets: Vec<std::net::UdpSocket>,
+    #[cfg(feature = "dtls")]
+    dtls_listener: Option<DtlsListener>,
+    cluster_socket: Option<std::net::UdpSocket>,
+    metrics_listener: Option<std::net::TcpListener>,
+    health_listener: Option<std::net::TcpListener>,
+    #[cfg(unix)]
+    control_listener: Option<std::os::unix::net::UnixListener>,
+}
+
+pub(crate) type Registry = Arc<Mutex<RelayService>>;
+
+/// Current settings of a running relay, updated through its [`ConfigHandle`].
+pub(crate) type SharedConfig = watch::Receiver<Arc<Config>>;
+
+/// Handle to the settings of a [`Relay`], which can be swapped while it runs.
+#[derive(Debug, Clone)]
+pub struct ConfigHandle(Arc<watch::Sender<Arc<Config>>>);
+
+impl ConfigHandle {
+    fn new(config: Config) -> ConfigHandle {
+        ConfigHandle(Arc::new(watch::Sender::new(Arc::new(config))))
+    }
+
+    pub fn get(&self) -> Arc<Config> {
+        self.0.borrow().clone()
+    }
+
+    /// Atomically swaps in new settings; established sessions and pending pairings
+    /// are kept. Sockets cannot be rebound, so `bind`, `second_bind`,
+    /// `extra_binds`, the DTLS settings, `cluster_bind`, `metrics_listen`,
+    /// `health_listen`, `control_socket`, `accounting_log`, `pcap`, `workers`,
+    /// `io_uring` and the buffer sizes keep their current values, as does
+    /// `protocol_magic`, which established peers rely on. `forward_to` may
+    /// change its target, which new senders are forwarded to, but cannot turn
+    /// static forwarding on or off. Settings failing [`Config::validate`] are
+    /// refused.
+    pub fn reload(&self, mut config: Config) -> io::Result<()> {
+        config.validate()?;
+        let current = self.get();
+        if (
+            &config.bind,
+            (&config.second_bind, &config.extra_binds),
+            (
+                &config.dtls_bind,
+                &config.dtls_certificate,
+                &config.dtls_private_key,
+            ),
+            &config.cluster_bind,
+            (&config.metrics_listen, &config.health_listen),
+            &config.control_socket,
+            (&config.accounting_log, &config.pcap),
+            (&config.on_pair, &config.on_teardown, &config.webhook_url),
+            (config.forward_to.is_some(), &config.interface),
+            (config.workers, config.io_uring, config.protocol_magic),
+            (
+                config.so_rcvbuf,
+                config.so_sndbuf,
+                config.recv_buffer_size,
+                config.dscp,
+            ),
+        ) != (
+            &current.bind,
+            (&current.second_bind, &current.extra_binds),
+            (
+                &current.dtls_bind,
+                &current.dtls_certificate,
+                &current.dtls_private_key,
+            ),
+            &current.cluster_bind,
+            (&current.metrics_listen, &current.health_listen),
+            &current.control_socket,
+            (&current.accounting_log, &current.pcap),
+            (&current.on_pair, &current.on_teardown, &current.webhook_url),
+            (current.forward_to.is_some(), &current.interface),
+            (current.workers, current.io_uring, current.protocol_magic),
+            (
+                current.so_rcvbuf,
+                current.so_sndbuf,
+                current.recv_buffer_size,
+                current.dscp,
+            ),
+        ) {
+            warn!("Changing listening addresses (including the cluster address), the interface, sockets, buffers, DSCP marking, DTLS certificates, the accounting log, hooks, the packet capture, the protocol magic or turning static forwarding on or off requires a restart; keeping the current ones");
+        }
+        config.bind = current.bind;
+        config.second_bind = current.second_bind;
+        config.extra_binds = current.extra_binds.clone();
+        config.dtls_bind = current.dtls_bind;
+        config.dtls_certificate = current.dtls_certificate.clone();
+        config.dtls_private_key = current.dtls_private_key.clone();
+        config.cluster_bind = current.cluster_bind;
+        if config.forward_to.is_some() != current.forward_to.is_some() {
+            config.forward_to = current.forward_to;
+        }
+        config.interface = current.interface.clone();
+        config.metrics_listen = current.metrics_listen;
+        config.health_listen = current.health_listen;
+        config.control_socket = current.control_socket.clone();
+        config.accounting_log = current.accounting_log.clone();
+        config.pcap = current.pcap.clone();
+        config.on_pair = current.on_pair.clone();
+        config.on_teardown = current.on_teardown.clone();
+        config.webhook_url = current.webhook_url.clone();
+        config.pcap_handshake_only = current.pcap_handshake_only;
+        config.workers = current.workers;
+        config.io_uring = current.io_uring;
+        config.protocol_magic = current.protocol_magic;
+        config.so_rcvbuf = current.so_rcvbuf;
+        config.so_sndbuf = current.so_sndbuf;
+        config.recv_buffer_size = current.recv_buffer_size;
+        config.dscp = current.dscp;
+        self.0.send_replace(Arc::new(config));
+        info!("Reloaded settings");
+        Ok(())
+    }
+
+    fn subscribe(&self) -> SharedConfig {
+        self.0.subscribe()
+    }
+}
+
+/// Read-only view of the sessions of a [`Relay`], usable while it runs.
+#[derive(Debug, Clone)]
+pub struct StatusHandle(Registry);
+
+impl StatusHandle {
+    /// Human-readable summary of every session: its peers, the packets and
+    /// bytes each of them sent, and how long ago they were last active.
+    pub fn report(&self) -> String {
+        metrics::report(&self.0.lock().expect("Registry lock poisoned"))
+    }
+}
+
+impl Relay {
+    pub fn builder() -> RelayBuilder {
+        RelayBuilder::default()
+    }
+
+    pub fn config(&self) -> Arc<Config> {
+        self.config.get()
+    }
+
+    /// Handle to swap the settings of this relay while it runs.
+    pub fn config_handle(&self) -> ConfigHandle {
+        self.config.clone()
+    }
+
+    /// Handle to inspect the sessions of this relay while it runs.
+    pub fn status_handle(&self) -> StatusHandle {
+        StatusHandle(self.registry.clone())
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.sockets[0].local_addr()
+    }
+
+    /// Address of the second socket, if the relay pairs across two ports.
+    pub fn second_local_addr(&self) -> io::Result<Option<SocketAddr>> {
+        self.second_sockets
+            .first()
+            .map(|socket| socket.local_addr())
+            .transpose()
+    }
+
+    /// Serves peers until there have been no connections for
+    /// [`Config::timeout_no_connections`]. Must be called within a tokio runtime.
+    pub async fn run(self) -> io::Result<()> {
+        self.run_until(future::pending()).await
+    }
+
+    /// Like [`Relay::run`], but also shuts down once `shutdown` resolves, telling
+    /// every peer with [`Ops::Shutdown`].
+    pub async fn run_until(self, shutdown: impl Future<Output = ()>) -> io::Result<()> {
+        let config = self.config.subscribe();
+        let registry = self.registry;
+        let forwarding = self.config.get().forward_to.is_some();
+
+        let workers = self.config.get().workers;
+        if workers > 1 {
+            info!("Serving with {workers} workers");
+        }
+        if self.sockets.len() > workers {
+            info!("Listening on {} ports", self.sockets.len() / workers);
+        }
+        let mut tasks = Vec::new();
+        let sockets = self
+            .sockets
+            .into_iter()
+            .map(|socket| UdpSocket::from_std(socket).map(Arc::new))
+            .collect::<io::Result<Vec<_>>>()?;
+        let second_sockets = self
+            .second_sockets
+            .into_iter()
+            .map(|socket| UdpSocket::from_std(socket).map(Arc::new))
+            .collect::<io::Result<Vec<_>>>()?;
+        let relay_sockets = [&sockets[..], &second_sockets[..]].concat();
+        for socket in sockets {
+            tasks.push(if forwarding {
+                tokio::spawn(forward::forward_packets(
+                    config.clone(),
+                    registry.clone(),
+                    socket,
+                ))
+            } else {
+                spawn_relay(&config, &registry, socket, &relay_sockets)
+            });
+        }
+        for socket in second_sockets {
+            tasks.push(spawn_relay(&config, &registry, socket, &relay_sockets));
+        }
+        #[cfg(feature = "dtls")]
+        if let Some(listener) = self.dtls_listener {
+            tasks.push(tokio::spawn(
+                listener.serve(config.clone(), registry.clone())?,
+            ));
+        }
+        if let Some(socket) = self.cluster_socket {
+            tasks.push(tokio::spawn(cluster::serve(
+                config.clone(),
+                registry.clone(),
+                UdpSocket::from_std(socket)?,
+            )));
+        }
+        tasks.extend([
+            tokio::spawn(expire_pairing_requests(config.clone(), registry.clone())),
+            tokio::spawn(cleanup_inactive_connections(
+                config.clone(),
+                registry.clone(),
+            )),
+            tokio::spawn(send_keepalives(config.clone(), registry.clone())),
+            tokio::spawn(send_echoes(config.clone(), registry.clone())),
+            tokio::spawn(retransmit(config.clone(), registry.clone())),
+        ]);
+        if let Some(listener) = self.metrics_listener {
+            let listener = TcpListener::from_std(listener)?;
+            let registry = registry.clone();
+            tasks.push(tokio::spawn(http::serve(listener, move |path| {
+                (path == "/metrics").then(|| {
+                    let registry = registry.lock().expect("Registry lock poisoned");
+                    (200, "text/plain; version=0.0.4", metrics::render(&registry))
+                })
+            })));
+        }
+        if let Some(listener) = self.health_listener {
+            let listener = TcpListener::from_std(listener)?;
+            let registry = registry.clone();
+            let config = config.clone();
+            tasks.push(tokio::spawn(http::serve(listener, move |path| {
+                match path {
+                    // answering at all shows that the process is alive with its sockets bound
+                    "/healthz" => Some((200, "text/plain", "ok\n".to_owned())),
+                    "/readyz" => {
+                        let config = config.borrow().clone();
+                        let registry = registry.lock().expect("Registry lock poisoned");
+                        Some(match registry.unready_reason(&config) {
+                            None => (200, "text/plain", "ready\n".to_owned()),
+                            Some(reason) => (503, "text/plain", format!("{reason}\n")),
+                        })
+                    }
+                    _ => None,
+                }
+            })));
+        }
+
+        #[cfg(unix)]
+        if let Some(listener) = self.control_listener {
+            let listener = UnixListener::from_std(listener)?;
+            tasks.push(tokio::spawn(control::serve(
+                listener,
+                registry.clone(),
+                self.config.clone(),
+            )));
+        }
+
+        let shutting_down = tokio::select! {
+            _ = wait_for_no_connections(config.clone(), registry.clone()) => false,
+            _ = wait_for_last_session(config.clone(), registry.clone()) => false,
+            _ = shutdown => true,
+        };
+        for task in &tasks {
+            task.abort();
+        }
+        // the threads relaying on io_uring release their sockets as their task ends
+        for task in tasks {
+            let _ = task.await;
+        }
+        let config = self.config.get();
+        if shutting_down {
+            notify_shutdown(&config, &registry).await;
+        }
+        registry.lock().expect("Registry lock poisoned").close_all();
+        if let Some(path) = &config.control_socket {
+            match fs::remove_file(path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Tells every peer that the relay is going away, giving up on peers that
+/// cannot be sent to within the drain timeout.
+async fn notify_shutdown(config: &Config, registry: &Registry) {
+    let peers = registry.lock().expect("Registry lock poisoned").peers();
+    info!("Shutting down, notifying {} peer(s)...", peers.len());
+
+    let message = Ops::Shutdown.to_bytes(config.protocol_magic);
+    let notify_all = async {
+        for (socket, addr) in &peers {
+            capture::sent(socket, addr, &message);
+            if let Err(e) = socket.send_to(&message, addr).await {
+                warn!("Cannot notify '{addr}' of shutdown: {e}");
+            }
+        }
+    };
+    if time::timeout(config.drain_timeout, notify_all)
+        .await
+        .is_err()
+    {
+        warn!(
+            "Timed out notifying peers after {} seconds",
+            config.drain_timeout.as_secs()
+        );
+    }
+}
+
+/// Receives datagrams and either relays them to the paired opponent or treats
+/// them as a possible request.
+async fn relay_packets(config: SharedConfig, registry: Registry, socket: Arc<UdpSocket>) {
+    let mut batch = RecvBatch::new(config.borrow().recv_buffer_size);
+    let mut outbox = Outbox::default();
+    loop {
+        match batch.recv(&socket).await {
+            Ok(()) => (),
+            // ICMP errors for earlier sends, reported on receive by some platforms
+            // (e.g. Windows) without telling which peer; its session expires as usual
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionRefused
+                ) =>
+            {
+                debug!("Ignoring error reported for an earlier send: {e}");
+                continue;
+            }
+            Err(e) => {
+                warn!("Unexpected error: {e}");
+                continue;
+            }
+        }
+        process_batch(
+            &config.borrow(),
+            &registry,
+            &socket,
+            batch.iter(),
+            &mut outbox,
+        );
+        let failed = outbox.flush();
+        if !failed.is_empty() {
+            let mut registry = registry.lock().expect("Registry lock poisoned");
+            registry.record_send_failures(&config.borrow(), &failed);
+        }
+    }
+}
+
+/// Relays each datagram of a batch received on `socket` to the paired
+/// opponent, or treats it as a possible request, queueing what to send in
+/// `outbox`. The registry is locked for each datagram in turn, and not while
+/// datagrams of paired peers are relayed.
+pub(crate) fn process_batch<'a>(
+    config: &Config,
+    registry: &Registry,
+    socket: &Arc<UdpSocket>,
+    batch: impl Iterator<Item = (&'a [u8], SocketAddr)>,
+    outbox: &mut Outbox,
+) {
+    let mut counters = Counters::default();
+    for (buffer, from) in batch {
+        capture::received(socket, &from, buffer);
+        if buffer.is_empty() {
+            continue;
+        }
+        if !config.is_peer_allowed(from.ip()) {
+            trace!("Dropping datagram from disallowed peer {from}");
+            continue;
+        }
+        let sender = registry
+            .lock()
+            .expect("Registry lock poisoned")
+            .process_datagram(config, socket, buffer, &from, outbox);
+        if let Some(sender) = sender {
+            service::process_relay_service(config, &mut counters, buffer, &sender, outbox);
+        }
+    }
+    registry
+        .lock()
+        .expect("Registry lock poisoned")
+        .counters
+        .add(counters);
+}
+
+/// Relays the datagrams of `socket` on `ring`, or with [`relay_packets`]
+/// should the ring fail.
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+async fn relay_packets_uring(
+    config: SharedConfig,
+    registry: Registry,
+    socket: Arc<UdpSocket>,
+    ring: RelayRing,
+) {
+    // the thread stops when the task does
+    let (_thread, failure) = match ring.spawn(config.clone(), registry.clone()) {
+        Ok(spawned) => spawned,
+        Err(e) => {
+            warn!("Cannot start the io_uring thread, relaying without it: {e}");
+            return relay_packets(config, registry, socket).await;
+        }
+    };
+    if let Ok(e) = failure.await {
+        warn!("io_uring failed, relaying without it: {e}");
+        relay_packets(config, registry, socket).await;
+    }
+}
+
+/// Spawns the task relaying the datagrams of `socket`, on io_uring if
+/// [`Config::io_uring`] and the ring can be set up for it and the other relay
+/// `sockets`.
+#[cfg_attr(
+    not(all(target_os = "linux", feature = "io-uring")),
+    allow(unused_variables)
+)]
+fn spawn_relay(
+    config: &SharedConfig,
+    registry: &Registry,
+    socket: Arc<UdpSocket>,
+    sockets: &[Arc<UdpSocket>],
+) -> JoinHandle<()> {
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    if config.borrow().io_uring {
+        let size = config.borrow().recv_buffer_size;
+        match RelayRing::new(&socket, sockets, size) {
+            Ok(ring) => {
+                return tokio::spawn(relay_packets_uring(
+                    config.clone(),
+                    registry.clone(),
+                    socket,
+                    ring,
+                ))
+            }
+            Err(e) => warn!("Cannot set up io_uring, relaying without it: {e}"),
+        }
+    }
+    tokio::spawn(relay_packets(config.clone(), registry.clone(), socket))
+}
+
+/// Sleeps for the current housekeeping interval and returns the settings to
+/// apply, so reloaded settings take effect from the next pass.
+async fn housekeeping_tick(config: &SharedConfig) -> Arc<Config> {
+    let interval = config.borrow().housekeeping_interval;
+    time::sleep(interval).await;
+    config.borrow().clone()
+}
+
+async fn expire_pairing_requests(config: SharedConfig, registry: Registry) {
+    loop {
+        let config = housekeeping_tick(&config).await;
+        registry
+            .lock()
+            .expect("Registry lock poisoned")
+            .remove_expired_pairing_request(&config);
+    }
+}
+
+async fn cleanup_inactive_connections(config: SharedConfig, registry: Registry) {
+    loop {
+        let config = housekeeping_tick(&config).await;
+        registry
+            .lock()
+            .expect("Registry lock poisoned")
+            .remove_inactive_connections(&config);
+    }
+}
+
+/// Sends keepalives to idle sessions, waiting for the housekeeping interval
+/// instead while keepalives are disabled.
+async fn send_keepalives(config: SharedConfig, registry: Registry) {
+    loop {
+        let interval = config.borrow().keepalive_interval;
+        let Some(interval) = interval else {
+            housekeeping_tick(&config).await;
+            continue;
+        };
+        time::sleep(interval).await;
+        let config = config.borrow().clone();
+        registry
+            .lock()
+            .expect("Registry lock poisoned")
+            .send_keepalives(&config, interval);
+    }
+}
+
+/// Measures the round-trip time of the paired peers answering echoes, waiting
+/// for the housekeeping interval instead while measurements are disabled.
+async fn send_echoes(config: SharedConfig, registry: Registry) {
+    loop {
+        let interval = config.borrow().rtt_interval;
+        let Some(interval) = interval else {
+            housekeeping_tick(&config).await;
+            continue;
+        };
+        time::sleep(interval).await;
+        let config = config.borrow().clone();
+        registry
+            .lock()
+            .expect("Registry lock poisoned")
+            .send_echoes(&config);
+    }
+}
+
+/// Sends again the frames to peers with reliable delivery that were not
+/// acknowledged in time, waiting for the housekeeping interval instead while
+/// reliable delivery is disabled.
+async fn retransmit(config: SharedConfig, registry: Registry) {
+    loop {
+        let (reliable, interval) = {
+            let config = config.borrow();
+            (config.reliable, config.retransmit_interval)
+        };
+        if !reliable {
+            housekeeping_tick(&config).await;
+            continue;
+        }
+        time::sleep(interval / 2).await;
+        registry
+            .lock()
+            .expect("Registry lock poisoned")
+            .retransmit(interval);
+    }
+}
+
+/// Resolves once the relay served its [`Config::max_total_sessions`] and all of
+/// them were closed.
+async fn wait_for_last_session(config: SharedConfig, registry: Registry) {
+    let session_closed = registry
+        .lock()
+        .expect("Registry lock poisoned")
+        .session_closed
+        .clone();
+    loop {
+        {
+            let config = config.borrow().clone();
+            let registry = registry.lock().expect("Registry lock poisoned");
+            if registry.is_spent(&config)
+                && registry.pairing.is_empty()
+                && registry.groups.is_empty()
+            {
+                info!("Served the last session. Quitting...");
+                return;
+            }
+        }
+        session_closed.notified().await;
+    }
+}
+
+/// Resolves once the relay has had no pairs (nor pending pairings) for
+/// `timeout_no_connections`.
+async fn wait_for_no_connections(config: SharedConfig, registry: Registry) {
+    let mut no_connection_since: Option<ExpiringTimer> = None;
+    loop {
+        let config = housekeeping_tick(&config).await;
+        let is_empty = registry.lock().expect("Registry lock poisoned").is_empty();
+
+        // stop this process when it has no activities after the given time
+        match (&no_connection_since, is_empty) {
+            (Some(timer), true) => {
+                if timer.is_expired(config.timeout_no_connections) {
+                    info!(
+                        "No connections for {} seconds. Quitting...",
+                        config.timeout_no_connections.as_secs()
+                    );
+                    return;
+                }
+            }
+            // remove timer as there's pending connections
+            (Some(_), false) => no_connection_since = None,
+            // add a pending timer
+            (None, true) => no_connection_since = Some(ExpiringTimer::new()),
+            (None, false) => (), // all is good
+        };
+    }
+}
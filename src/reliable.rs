@@ -0,0 +1,306 @@
+//! Optional reliability layer between a peer and the relay, for tunneled
+//! protocols that cannot tolerate loss.
+//!
+//! A peer asks for it by setting [`OPTION_RELIABLE`] in its
+//! [`PairingResponse`](crate::protocol::PairingResponse), and a relay running
+//! with `reliable` agrees by appending the same options byte to the secret in
+//! its [`Ops::Ack`]. From then on, both ends of that link carry datagrams in
+//! [`Ops::Sequenced`] frames and acknowledge them with [`Ops::SequenceAck`]:
+//!
+//! ```text
+//! +---------+-----------------+---------+
+//! | ff 27   | Sequence number | Payload |
+//! | 2 bytes | 4 bytes         |         |
+//! +---------+-----------------+---------+
+//! +---------+-----------------+---------+
+//! | ff 28   | Next expected   | Bitmap  |
+//! | 2 bytes | 4 bytes         | 4 bytes |
+//! +---------+-----------------+---------+
+//! ```
+//!
+//! Sequence numbers, big endian, start at 0 and wrap around. An acknowledgement
+//! says that every frame before the next expected one arrived, and bit `n` of
+//! its bitmap (least significant first) that frame `next + 1 + n` did too.
+//! Frames not acknowledged within the retransmit interval are sent again until
+//! they are, or the session ends; at most a window of frames is in flight.
+//! Received frames are delivered in order, frames arriving early being held
+//! back as long as they are within the window.
+//!
+//! The layer works hop by hop: the relay acknowledges and reorders what a peer
+//! sends before relaying it, and sequences what it relays to that peer on its
+//! own, so that only one peer of a pair may use it. With encryption, payloads
+//! are sealed before being framed. Control messages (e.g.
+//! [`Ops::Disconnect`]) and datagrams sent without frame are not sequenced.
+
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use crate::protocol::Ops;
+
+/// Option bit of a [`PairingResponse`](crate::protocol::PairingResponse)
+/// asking for reliable delivery.
+pub const OPTION_RELIABLE: u8 = 0x01;
+
+/// Frames in flight, and held back, by default.
+pub const DEFAULT_WINDOW: u32 = 64;
+
+/// How long a frame waits for its acknowledgement by default before being sent again.
+pub const DEFAULT_RETRANSMIT_INTERVAL: Duration = Duration::from_millis(200);
+
+const SEQ_LEN: usize = 4;
+/// Frames after the next expected one an acknowledgement reports.
+const BITMAP_LEN: u32 = 32;
+
+#[derive(Debug)]
+struct InFlight {
+    seq: u32,
+    frame: Vec<u8>,
+    sent: Instant,
+}
+
+/// Sequencing state of one end of a link, for both directions.
+#[derive(Debug)]
+pub struct Channel {
+    window: u32,
+    /// Protocol magic of the frames.
+    magic: u8,
+    next_seq: u32,
+    /// Frames sent and not acknowledged yet, in sequence order.
+    in_flight: VecDeque<InFlight>,
+    /// Sequence number of the next frame to deliver.
+    expected: u32,
+    /// Frames received ahead of `expected`.
+    early: HashMap<u32, Vec<u8>>,
+}
+
+/// How far `seq` is past `base`, negative if it is before.
+fn offset(seq: u32, base: u32) -> i32 {
+    seq.wrapping_sub(base) as i32
+}
+
+impl Channel {
+    /// Creates the state of a new link, keeping at most `window` frames in
+    /// flight and held back, under the protocol `magic`.
+    pub fn new(window: u32, magic: u8) -> Channel {
+        Channel {
+            window,
+            magic,
+            next_seq: 0,
+            in_flight: VecDeque::new(),
+            expected: 0,
+            early: HashMap::new(),
+        }
+    }
+
+    /// Frames `payload` for the other end, keeping the frame until it is
+    /// acknowledged. Returns `None` if the window is full.
+    pub fn send(&mut self, payload: &[u8]) -> Option<Vec<u8>> {
+        if self.in_flight.len() >= self.window as usize {
+            return None;
+        }
+        let seq = self.next_seq;
+        self.next_seq = seq.wrapping_add(1);
+        let frame = Ops::Sequenced.message(self.magic, &[&seq.to_be_bytes()[..], payload].concat());
+        self.in_flight.push_back(InFlight {
+            seq,
+            frame: frame.clone(),
+            sent: Instant::now(),
+        });
+        Some(frame)
+    }
+
+    /// Takes the payload of an [`Ops::Sequenced`] frame from the other end,
+    /// returning the acknowledgement to answer it with and the payloads now
+    /// deliverable in order. Returns `None` if the frame is malformed.
+    pub fn receive(&mut self, frame: &[u8]) -> Option<(Vec<u8>, Vec<Vec<u8>>)> {
+        let seq = u32::from_be_bytes(frame.get(..SEQ_LEN)?.try_into().ok()?);
+        let payload = &frame[SEQ_LEN..];
+        let mut delivered = Vec::new();
+        match offset(seq, self.expected) {
+            0 => {
+                delivered.push(payload.to_vec());
+                self.expected = self.expected.wrapping_add(1);
+                while let Some(payload) = self.early.remove(&self.expected) {
+                    delivered.push(payload);
+                    self.expected = self.expected.wrapping_add(1);
+                }
+            }
+            // beyond the window, the frame is dropped and sent again later
+            ahead if ahead > 0 && (ahead as u32) < self.window => {
+                self.early.entry(seq).or_insert_with(|| payload.to_vec());
+            }
+            // a duplicate, acknowledged again as the first acknowledgement got lost
+            _ => (),
+        }
+        Some((self.acknowledgement(), delivered))
+    }
+
+    fn acknowledgement(&self) -> Vec<u8> {
+        let bitmap = (0..BITMAP_LEN)
+            .filter(|n| {
+                let seq = self.expected.wrapping_add(1 + n);
+                self.early.contains_key(&seq)
+            })
+            .fold(0u32, |bitmap, n| bitmap | 1 << n);
+        Ops::SequenceAck.message(
+            self.magic,
+            &[self.expected.to_be_bytes(), bitmap.to_be_bytes()].concat(),
+        )
+    }
+
+    /// Takes the payload of an [`Ops::SequenceAck`] from the other end,
+    /// forgetting the frames it acknowledges.
+    pub fn acknowledge(&mut self, ack: &[u8]) {
+        let (Some(next), Some(bitmap)) = (ack.get(..SEQ_LEN), ack.get(SEQ_LEN..SEQ_LEN + 4)) else {
+            return;
+        };
+        let next = u32::from_be_bytes(next.try_into().expect("sliced to SEQ_LEN"));
+        let bitmap = u32::from_be_bytes(bitmap.try_into().expect("sliced to 4 bytes"));
+        self.in_flight
+            .retain(|frame| match offset(frame.seq, next) {
+                before if before < 0 => false,
+                0 => true,
+                after => !(after as u32 <= BITMAP_LEN && bitmap & 1 << (after - 1) != 0),
+            });
+    }
+
+    /// Frames sent at least `interval` ago and still not acknowledged, to be
+    /// sent again now.
+    pub fn due(&mut self, interval: Duration) -> Vec<Vec<u8>> {
+        let now = Instant::now();
+        self.in_flight
+            .iter_mut()
+            .filter(|frame| now.duration_since(frame.sent) >= interval)
+            .map(|frame| {
+                frame.sent = now;
+                frame.frame.clone()
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::DEFAULT_MAGIC;
+
+    /// Body of `frame`, which must be an `op` message.
+    fn body(frame: &[u8], op: Ops) -> &[u8] {
+        match Ops::parse(frame, DEFAULT_MAGIC) {
+            Some((parsed, body)) if parsed == op => body,
+            _ => panic!("not a {op:?} frame"),
+        }
+    }
+
+    fn ack(next: u32, bitmap: u32) -> Vec<u8> {
+        Ops::SequenceAck.message(
+            DEFAULT_MAGIC,
+            &[next.to_be_bytes(), bitmap.to_be_bytes()].concat(),
+        )
+    }
+
+    /// Channels whose sequence numbers are about to wrap around.
+    fn wrapping_pair() -> (Channel, Channel) {
+        let mut sender = Channel::new(DEFAULT_WINDOW, DEFAULT_MAGIC);
+        let mut receiver = Channel::new(DEFAULT_WINDOW, DEFAULT_MAGIC);
+        sender.next_seq = u32::MAX - 1;
+        receiver.expected = u32::MAX - 1;
+        (sender, receiver)
+    }
+
+    #[test]
+    fn delivers_in_order_across_wraparound() {
+        let (mut sender, mut receiver) = wrapping_pair();
+        let frames: Vec<_> = [b"a", b"b", b"c", b"d"]
+            .iter()
+            .map(|payload| sender.send(*payload).expect("window has room"))
+            .collect();
+        assert_eq!(
+            &body(&frames[2], Ops::Sequenced)[..SEQ_LEN],
+            0u32.to_be_bytes()
+        );
+
+        let (answer, delivered) = receiver
+            .receive(body(&frames[2], Ops::Sequenced))
+            .expect("well-formed frame");
+        assert!(delivered.is_empty());
+        assert_eq!(answer, ack(u32::MAX - 1, 0b10));
+
+        let (_, delivered) = receiver
+            .receive(body(&frames[0], Ops::Sequenced))
+            .expect("well-formed frame");
+        assert_eq!(delivered, [b"a".to_vec()]);
+        let (answer, delivered) = receiver
+            .receive(body(&frames[1], Ops::Sequenced))
+            .expect("well-formed frame");
+        assert_eq!(delivered, [b"b".to_vec(), b"c".to_vec()]);
+        assert_eq!(answer, ack(1, 0));
+        let (answer, delivered) = receiver
+            .receive(body(&frames[3], Ops::Sequenced))
+            .expect("well-formed frame");
+        assert_eq!(delivered, [b"d".to_vec()]);
+        assert_eq!(answer, ack(2, 0));
+
+        sender.acknowledge(body(&answer, Ops::SequenceAck));
+        assert!(sender.in_flight.is_empty());
+    }
+
+    #[test]
+    fn bitmap_acknowledges_frames_past_a_loss() {
+        let (mut sender, mut receiver) = wrapping_pair();
+        let frames: Vec<_> = (0..5u8)
+            .map(|n| sender.send(&[n]).expect("window has room"))
+            .collect();
+        // the first and third frames get lost
+        let mut answer = Vec::new();
+        for frame in [&frames[1], &frames[3], &frames[4]] {
+            let delivered;
+            (answer, delivered) = receiver
+                .receive(body(frame, Ops::Sequenced))
+                .expect("well-formed frame");
+            assert!(delivered.is_empty());
+        }
+        assert_eq!(answer, ack(u32::MAX - 1, 0b1101));
+
+        sender.acknowledge(body(&answer, Ops::SequenceAck));
+        let pending: Vec<_> = sender.in_flight.iter().map(|frame| frame.seq).collect();
+        assert_eq!(pending, [u32::MAX - 1, 0]);
+        assert_eq!(
+            sender.due(Duration::ZERO),
+            [frames[0].clone(), frames[2].clone()]
+        );
+    }
+
+    #[test]
+    fn duplicates_are_acknowledged_again_but_not_delivered() {
+        let mut sender = Channel::new(DEFAULT_WINDOW, DEFAULT_MAGIC);
+        let mut receiver = Channel::new(DEFAULT_WINDOW, DEFAULT_MAGIC);
+        let frame = sender.send(b"once").expect("window has room");
+        let (first, delivered) = receiver
+            .receive(body(&frame, Ops::Sequenced))
+            .expect("well-formed frame");
+        assert_eq!(delivered, [b"once".to_vec()]);
+        let (again, delivered) = receiver
+            .receive(body(&frame, Ops::Sequenced))
+            .expect("well-formed frame");
+        assert!(delivered.is_empty());
+        assert_eq!(again, first);
+        assert!(receiver.receive(&[0, 0, 0]).is_none());
+    }
+
+    #[test]
+    fn window_bounds_frames_in_flight_and_held_back() {
+        let mut sender = Channel::new(2, DEFAULT_MAGIC);
+        assert!(sender.send(b"1").is_some());
+        assert!(sender.send(b"2").is_some());
+        assert!(sender.send(b"3").is_none());
+        sender.acknowledge(body(&ack(1, 0), Ops::SequenceAck));
+        assert!(sender.send(b"3").is_some());
+
+        let mut receiver = Channel::new(2, DEFAULT_MAGIC);
+        let beyond = [2u32.to_be_bytes().as_slice(), b"late"].concat();
+        let (answer, _) = receiver.receive(&beyond).expect("well-formed frame");
+        assert_eq!(answer, ack(0, 0));
+        assert!(receiver.early.is_empty());
+    }
+}
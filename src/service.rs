@@ -0,0 +1,2076 @@
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::iter;
+use std::net::{IpAddr, SocketAddr};
+use std::str;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+use tokio::net::UdpSocket;
+use tokio::sync::Notify;
+use tokio::time;
+use tracing::{debug, info, trace};
+
+use crate::accounting::{self, AccountingLog, CloseReason};
+use crate::auth::{self, Challenger, ReplayWindow};
+use crate::batch::Outbox;
+use crate::channels::{self, Channels, OPTION_CHANNELS};
+use crate::cluster::Cluster;
+use crate::compression::{self, OPTION_COMPRESSION};
+use crate::encryption::{Role, SessionCipher};
+use crate::fec::{Fec, OPTION_FEC};
+use crate::forward::ForwardSession;
+use crate::group::Group;
+use crate::hooks::Hooks;
+use crate::metrics::Counters;
+use crate::peer::{build_paired_peers, send_to, ExpiringTimer, RecipientData, Side};
+#[cfg(feature = "dtls")]
+use crate::protocol::RESUME_TOKEN_LEN;
+use crate::protocol::{
+    self, encode_addr, Ops, PairingRequest, PairingResponse, RebindRequest, ADDRESS_REQUEST_LEN,
+    LEGACY_VERSION, PROTOCOL_VERSION, UNNEGOTIATED_VERSION,
+};
+use crate::ratelimit::RateLimiter;
+use crate::relay::{Config, SecretCollision, SharedConfig, MAX_THROTTLE_DELAY};
+use crate::reliable::{Channel, OPTION_RELIABLE};
+use crate::turn::{self, Allocation};
+
+/// A peer waiting for its counterpart, queued by session secret in [`RelayService`].
+#[derive(Debug)]
+pub(crate) struct PendingPairing {
+    pub(crate) addr: SocketAddr,
+    /// Socket the request arrived on, which the pair will be relayed through.
+    pub(crate) socket: Arc<UdpSocket>,
+    pub(crate) timer: ExpiringTimer,
+    /// Name of the key the peer authenticated with.
+    pub(crate) key: String,
+    /// Keys of the peer's link with the relay, when it is encrypted.
+    pub(crate) cipher: Option<SessionCipher>,
+    /// Options the peer asked for and the relay agreed to, e.g.
+    /// [`OPTION_RELIABLE`].
+    pub(crate) options: u8,
+}
+
+/// Secret and key of a peer that sent its pairing request over DTLS, until it
+/// redeems its ticket, see [`crate::dtls`].
+#[cfg(feature = "dtls")]
+#[derive(Debug)]
+pub(crate) struct DtlsTicket {
+    secret: Vec<u8>,
+    key: String,
+    timer: ExpiringTimer,
+}
+
+/// Longest probe payload echoed back, so probes cannot be used for amplification
+/// nor to bounce large datagrams.
+const MAX_PROBE_PAYLOAD: usize = 64;
+
+/// Housekeeping passes the relay may miss before it is reported as not ready.
+const MISSED_HOUSEKEEPING_PASSES: u32 = 3;
+
+#[derive(Debug)]
+pub(crate) struct RelayService {
+    pub(crate) pairing: HashMap<SocketAddr, Arc<Mutex<RecipientData>>>,
+    /// Peers waiting on each secret, oldest first; no queue is left empty.
+    pub(crate) pending_pairing: HashMap<Vec<u8>, VecDeque<PendingPairing>>,
+    /// Senders in static forwarding mode, see [`crate::forward`].
+    pub(crate) forwards: HashMap<SocketAddr, ForwardSession>,
+    /// Groups of peers in group mode, keyed by session secret, see [`crate::group`].
+    pub(crate) groups: HashMap<Vec<u8>, Group>,
+    /// Secret of the group every group member belongs to.
+    pub(crate) group_members: HashMap<SocketAddr, Vec<u8>>,
+    /// TURN allocations, keyed by client address, see [`crate::turn`].
+    pub(crate) allocations: HashMap<SocketAddr, Allocation>,
+    /// Tickets issued over DTLS and not redeemed yet.
+    #[cfg(feature = "dtls")]
+    dtls_tickets: HashMap<[u8; RESUME_TOKEN_LEN], DtlsTicket>,
+    pub(crate) counters: Counters,
+    pub(crate) challenger: Challenger,
+    replay_window: ReplayWindow,
+    pub(crate) rate_limiter: RateLimiter,
+    accounting: Option<AccountingLog>,
+    /// Commands and webhook fired when pairs are created or torn down, see
+    /// [`crate::hooks`].
+    hooks: Option<Hooks>,
+    /// Announcements exchanged with the other instances, in cluster mode, see
+    /// [`crate::cluster`].
+    pub(crate) cluster: Option<Cluster>,
+    /// The registry holding this service, for the tasks it spawns.
+    pub(crate) handle: Weak<Mutex<RelayService>>,
+    /// Current settings, for the tasks it spawns.
+    pub(crate) config: SharedConfig,
+    /// Notified whenever a pair or group is closed, for the relay to stop after
+    /// its last session, see [`Config::max_total_sessions`].
+    pub(crate) session_closed: Arc<Notify>,
+    /// Last housekeeping pass, showing that the relay's tasks are responsive.
+    housekeeping: ExpiringTimer,
+}
+
+impl RelayService {
+    pub(crate) fn new(
+        accounting: Option<AccountingLog>,
+        hooks: Option<Hooks>,
+        cluster: Option<Cluster>,
+        handle: Weak<Mutex<RelayService>>,
+        config: SharedConfig,
+    ) -> RelayService {
+        RelayService {
+            pairing: HashMap::new(),
+            pending_pairing: HashMap::new(),
+            forwards: HashMap::new(),
+            groups: HashMap::new(),
+            group_members: HashMap::new(),
+            allocations: HashMap::new(),
+            #[cfg(feature = "dtls")]
+            dtls_tickets: HashMap::new(),
+            counters: Counters::default(),
+            challenger: Challenger::new(),
+            replay_window: ReplayWindow::default(),
+            rate_limiter: RateLimiter::default(),
+            accounting,
+            hooks,
+            cluster,
+            handle,
+            config,
+            session_closed: Arc::new(Notify::new()),
+            housekeeping: ExpiringTimer::new(),
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.pairing.is_empty()
+            && self.pending_pairing.is_empty()
+            && self.forwards.is_empty()
+            && self.groups.is_empty()
+            && self.allocations.is_empty()
+    }
+
+    /// Every paired peer and every peer waiting to be paired, with the socket
+    /// it talks to.
+    pub(crate) fn peers(&self) -> Vec<(Arc<UdpSocket>, SocketAddr)> {
+        let paired = self.pairing.values().map(|peer| {
+            let peer = peer.lock().expect("Peer lock poisoned");
+            (peer.recipient.socket.clone(), peer.recipient.addr)
+        });
+        let pending = self
+            .waiters()
+            .map(|(_, pending)| (pending.socket.clone(), pending.addr));
+        let grouped = self.groups.values().flat_map(|group| {
+            group
+                .members
+                .iter()
+                .map(|(addr, member)| (member.socket.clone(), *addr))
+        });
+        paired.chain(pending).chain(grouped).collect()
+    }
+
+    /// Issues a single-use ticket to a peer that authenticated with `key` over
+    /// DTLS, for it to redeem with [`Ops::Redeem`] within the pairing timeout.
+    #[cfg(feature = "dtls")]
+    pub(crate) fn issue_ticket(&mut self, secret: &[u8], key: &str) -> [u8; RESUME_TOKEN_LEN] {
+        let ticket = rand::random();
+        self.dtls_tickets.insert(
+            ticket,
+            DtlsTicket {
+                secret: secret.to_owned(),
+                key: key.to_owned(),
+                timer: ExpiringTimer::new(),
+            },
+        );
+        ticket
+    }
+
+    /// Whether the relay served as many pairs and groups as
+    /// [`Config::max_total_sessions`] allows, so that it takes no new ones.
+    pub(crate) fn is_spent(&self, config: &Config) -> bool {
+        config
+            .max_total_sessions
+            .is_some_and(|max| self.counters.pairings_by_key.values().sum::<u64>() >= max)
+    }
+
+    /// Why the relay cannot take new sessions, if so, for the readiness probe:
+    /// a session limit is reached or housekeeping missed several passes.
+    pub(crate) fn unready_reason(&self, config: &Config) -> Option<&'static str> {
+        if config
+            .max_sessions
+            .is_some_and(|max| self.sessions() >= max)
+        {
+            Some("at the session limit")
+        } else if config
+            .max_pending_pairings
+            .is_some_and(|max| self.pending_pairings() >= max)
+        {
+            Some("at the pending pairing limit")
+        } else if self.is_spent(config) {
+            Some("served its last session")
+        } else if self
+            .housekeeping
+            .is_expired(config.housekeeping_interval * MISSED_HOUSEKEEPING_PASSES)
+        {
+            Some("housekeeping is stalled")
+        } else {
+            None
+        }
+    }
+
+    /// Number of established sessions: pairs, groups and TURN allocations.
+    pub(crate) fn sessions(&self) -> usize {
+        self.pairing.len() / 2 + self.groups.len() + self.allocations.len()
+    }
+
+    /// Whether the key named `key` may not be used for another session.
+    pub(crate) fn at_session_limit(&self, config: &Config, key: &str) -> bool {
+        config
+            .key(key)
+            .and_then(|policy| policy.max_sessions)
+            .is_some_and(|max| self.sessions_with_key(key) >= max)
+    }
+
+    /// Number of peers waiting to be paired, on any secret.
+    pub(crate) fn pending_pairings(&self) -> usize {
+        self.pending_pairing.values().map(VecDeque::len).sum()
+    }
+
+    /// Every peer waiting to be paired, with the secret it waits on.
+    pub(crate) fn waiters(&self) -> impl Iterator<Item = (&[u8], &PendingPairing)> {
+        self.pending_pairing.iter().flat_map(|(secret, waiters)| {
+            waiters
+                .iter()
+                .map(move |pending| (secret.as_slice(), pending))
+        })
+    }
+
+    /// Whether an established pair was paired on `secret`.
+    pub(crate) fn is_paired_on(&self, secret: &[u8]) -> bool {
+        self.pairing
+            .values()
+            .any(|peer| peer.lock().expect("Peer lock poisoned").secret == secret)
+    }
+
+    /// Number of paired peers, group members and pending pairing requests from
+    /// `ip`.
+    pub(crate) fn sessions_from_ip(&self, ip: IpAddr) -> usize {
+        let ip = ip.to_canonical();
+        self.pairing
+            .keys()
+            .chain(self.group_members.keys())
+            .chain(self.waiters().map(|(_, pending)| &pending.addr))
+            .filter(|addr| addr.ip().to_canonical() == ip)
+            .count()
+    }
+
+    /// Number of active sessions (pairs, groups or TURN allocations) with the
+    /// key named `key`.
+    pub(crate) fn sessions_with_key(&self, key: &str) -> usize {
+        let pairs = self
+            .pairing
+            .values()
+            .filter(|peer| {
+                let peer = peer.lock().expect("Peer lock poisoned");
+                peer.side == Side::First && peer.key == key
+            })
+            .count();
+        pairs
+            + self
+                .groups
+                .values()
+                .filter(|group| group.key == key)
+                .count()
+            + self
+                .allocations
+                .values()
+                .filter(|allocation| allocation.key == key)
+                .count()
+    }
+
+    /// Dispatches a datagram received from `from` on `socket`. Returns the sender
+    /// when it is paired, for its datagram to be relayed with
+    /// [`process_relay_service`] once the registry is unlocked.
+    pub(crate) fn process_datagram(
+        &mut self,
+        config: &Config,
+        socket: &Arc<UdpSocket>,
+        buffer: &[u8],
+        from: &SocketAddr,
+        outbox: &mut Outbox,
+    ) -> Option<Arc<Mutex<RecipientData>>> {
+        if buffer == Ops::Disconnect.to_bytes(config.protocol_magic)
+            && self.disconnect(config, from, outbox)
+        {
+            return None;
+        }
+        if self.pairing.contains_key(from) || self.group_members.contains_key(from) {
+            match Ops::parse(buffer, config.protocol_magic) {
+                // only answered: anyone can send it, so it must not end the session
+                Some((Ops::ChallengeRequest, version)) if version.len() <= 1 => {
+                    process_maybe_request(config, self, socket, buffer, from);
+                    return None;
+                }
+                Some(_) if self.is_authentic_handshake(config, buffer, from) => {
+                    if !self.reacknowledge(config, socket, buffer, from) {
+                        self.close_restarted(config, from, outbox);
+                        process_maybe_request(config, self, socket, buffer, from);
+                    }
+                    return None;
+                }
+                _ => (),
+            }
+        }
+        match self.pairing.get(from) {
+            Some(sender) => {
+                if !same_port(
+                    &sender.lock().expect("Peer lock poisoned").recipient.socket,
+                    socket,
+                ) {
+                    trace!("Dropping datagram from {from}, which is paired on the other port");
+                    return None;
+                }
+                Some(sender.clone())
+            }
+            None => match self.group_members.get(from) {
+                Some(secret) => {
+                    if !fits_max_payload(config, &mut self.counters, buffer, socket, from, outbox) {
+                        return None;
+                    }
+                    self.groups
+                        .get_mut(secret)
+                        .expect("Group members belong to a group")
+                        .relay(&mut self.counters, buffer, from, outbox);
+                    None
+                }
+                None => {
+                    process_maybe_request(config, self, socket, buffer, from);
+                    None
+                }
+            },
+        }
+    }
+
+    pub(crate) fn remove_inactive_connections(&mut self, config: &Config) {
+        self.housekeeping.access();
+        self.forwards.retain(|addr, session| {
+            if session
+                .last_accessed
+                .is_expired(config.timeout_connection_inactivities)
+            {
+                info!(
+                    "Forwarding from '{addr}' has no activities after {} seconds. Removing it...",
+                    config.timeout_connection_inactivities.as_secs()
+                );
+                self.counters.expired_sessions += 1;
+                return false;
+            }
+            true
+        });
+        self.remove_inactive_group_members(config);
+        self.allocations.retain(|addr, allocation| {
+            if allocation.expire() {
+                info!("TURN allocation of '{addr}' expired. Removing it...");
+                self.counters.expired_sessions += 1;
+                return false;
+            }
+            true
+        });
+        if self.pairing.is_empty() {
+            return;
+        }
+        // keep track of the pairs of addr to remove.
+        let mut to_remove = HashSet::new();
+        let mut warned = 0;
+        for peer_a_rc in self.pairing.values() {
+            let mut peer_a_guard = peer_a_rc.lock().expect("Peer lock poisoned");
+            let peer_b_rc = peer_a_guard.get_opponent();
+            let peer_b_guard = peer_b_rc.lock().expect("Peer lock poisoned");
+
+            let last_access_a = peer_a_guard.last_accessed;
+            let last_access_b = peer_b_guard.last_accessed;
+            let timeout = config.timeout_connection_inactivities_for(&peer_a_guard.key);
+
+            if last_access_a.is_expired(timeout) && last_access_b.is_expired(timeout) {
+                info!(
+                    "Connection between '{addr1}' and '{addr2}' (key '{key}') has no activities after {timeout} seconds. Removing them...",
+                    addr1 = peer_a_guard.recipient.addr,
+                    addr2 = peer_b_guard.recipient.addr,
+                    key = peer_a_guard.key,
+                    timeout = timeout.as_secs()
+                );
+                to_remove.insert(peer_a_guard.recipient.addr);
+                to_remove.insert(peer_b_guard.recipient.addr);
+            } else if let Some(warning) = config.idle_warning {
+                // warn once per idle period, which starts at the latest activity of the pair
+                let active = last_access_a.max(last_access_b);
+                let left = timeout.saturating_sub(active.elapsed());
+                if left <= warning && peer_a_guard.idle_warned != Some(active) {
+                    peer_a_guard.idle_warned = Some(active);
+                    let secs = u16::try_from(left.as_secs()).unwrap_or(u16::MAX);
+                    peer_a_guard.recipient.send_message(
+                        &Ops::IdleWarning.message(config.protocol_magic, &secs.to_be_bytes()),
+                    );
+                    warned += 1;
+                }
+            };
+        }
+
+        if warned > 0 {
+            debug!("Warned {warned} idle peer(s)");
+        }
+        self.counters.idle_warnings += warned;
+        self.counters.expired_sessions += to_remove.len() as u64 / 2;
+        for k in to_remove {
+            self.close_pair(&k, CloseReason::Inactivity);
+        }
+    }
+
+    /// Removes the pair of the peer at `addr`, recording why in the accounting
+    /// log and firing the teardown hooks. Returns the opponent, if `addr` was
+    /// paired.
+    fn close_pair(
+        &mut self,
+        addr: &SocketAddr,
+        reason: CloseReason,
+    ) -> Option<Arc<Mutex<RecipientData>>> {
+        let peer_rc = self.pairing.remove(addr)?;
+        let mut peer = peer_rc.lock().expect("Peer lock poisoned");
+        let opponent_rc = peer.get_opponent();
+        {
+            let opponent = opponent_rc.lock().expect("Peer lock poisoned");
+            self.pairing.remove(&opponent.recipient.addr);
+            let event = match peer.side {
+                Side::First => accounting::closed(&peer, &opponent, reason),
+                Side::Second => accounting::closed(&opponent, &peer, reason),
+            };
+            if let Some(log) = &mut self.accounting {
+                log.record(&event);
+            }
+            if let Some(hooks) = &self.hooks {
+                hooks.closed(&event);
+            }
+        }
+        self.session_closed.notify_one();
+        Some(opponent_rc)
+    }
+
+    /// Removes the group member at `addr`, recording why in the accounting log.
+    /// Returns whether its group went away with it.
+    fn leave_group(&mut self, addr: &SocketAddr, reason: CloseReason) -> bool {
+        let Some(secret) = self.group_members.remove(addr) else {
+            return false;
+        };
+        let group = self
+            .groups
+            .get_mut(&secret)
+            .expect("Group members belong to a group");
+        let member = group
+            .members
+            .remove(addr)
+            .expect("Group members belong to their group");
+        if let Some(log) = &mut self.accounting {
+            log.left(&secret, &group.key, addr, &member, reason);
+        }
+        if group.members.is_empty() {
+            self.groups.remove(&secret);
+            self.session_closed.notify_one();
+            return true;
+        }
+        false
+    }
+
+    /// Records every remaining session as closed by a shutdown and forgets it.
+    pub(crate) fn close_all(&mut self) {
+        let paired: Vec<SocketAddr> = self.pairing.keys().copied().collect();
+        for addr in paired {
+            self.close_pair(&addr, CloseReason::Shutdown);
+        }
+        let members: Vec<SocketAddr> = self.group_members.keys().copied().collect();
+        for addr in members {
+            self.leave_group(&addr, CloseReason::Shutdown);
+        }
+    }
+
+    /// Tears down the session of the peer at `from` on its request, telling its
+    /// opponent (after the datagrams relayed before); a group member only leaves
+    /// its group. Returns whether `from` was in a session.
+    fn disconnect(&mut self, config: &Config, from: &SocketAddr, outbox: &mut Outbox) -> bool {
+        if self.group_members.contains_key(from) {
+            info!("Group member '{from}' disconnected");
+            if self.leave_group(from, CloseReason::Disconnect) {
+                self.counters.disconnected_sessions += 1;
+            }
+            return true;
+        }
+        let Some(opponent) = self.close_pair(from, CloseReason::Disconnect) else {
+            return false;
+        };
+        let opponent = opponent.lock().expect("Peer lock poisoned");
+        outbox.push(
+            &opponent.recipient.socket,
+            opponent.recipient.addr,
+            &Ops::Disconnect.to_bytes(config.protocol_magic),
+        );
+        info!(
+            "'{from}' disconnected from '{}' (key '{}')",
+            opponent.recipient.addr, opponent.key
+        );
+        self.counters.disconnected_sessions += 1;
+        true
+    }
+
+    /// Whether `buffer`, from a paired peer or group member, is a handshake
+    /// message that authenticates, i.e. the peer restarted on the same address
+    /// or retried its pairing, rather than data that merely looks like one or a
+    /// replayed response.
+    fn is_authentic_handshake(&self, config: &Config, buffer: &[u8], from: &SocketAddr) -> bool {
+        match Ops::parse(buffer, config.protocol_magic) {
+            Some((Ops::ChallengeResponse, payload)) => {
+                PairingResponse::parse(payload).is_some_and(|response| {
+                    self.challenger.is_valid(response.nonce, from)
+                        && !self.replay_window.contains(response.nonce)
+                        && config
+                            .find_key(|psk| {
+                                auth::verify(psk, response.nonce, response.secret, response.mac)
+                            })
+                            .is_some()
+                })
+            }
+            Some((Ops::EstablishConnection, payload)) if config.legacy_handshake => {
+                PairingRequest::parse(payload)
+                    .is_some_and(|request| config.find_presented_key(request.psk).is_some())
+            }
+            #[cfg(feature = "dtls")]
+            Some((Ops::Redeem, ticket)) => <[u8; RESUME_TOKEN_LEN]>::try_from(ticket)
+                .is_ok_and(|ticket| self.dtls_tickets.contains_key(&ticket)),
+            _ => false,
+        }
+    }
+
+    /// Acknowledges again the session of `from`, whose authentic challenge
+    /// response for the secret and key of that session means it missed the
+    /// acknowledgement and retried, with new keys if encrypted. Returns `false`
+    /// if the response is for another session, i.e. the peer restarted.
+    fn reacknowledge(
+        &mut self,
+        config: &Config,
+        socket: &Arc<UdpSocket>,
+        buffer: &[u8],
+        from: &SocketAddr,
+    ) -> bool {
+        let Some((Ops::ChallengeResponse, payload)) = Ops::parse(buffer, config.protocol_magic)
+        else {
+            return false;
+        };
+        let Some(response) = PairingResponse::parse(payload) else {
+            return false;
+        };
+        let Some(key) =
+            config.find_key(|psk| auth::verify(psk, response.nonce, response.secret, response.mac))
+        else {
+            return false;
+        };
+        let cipher = config.encryption.then(|| {
+            let psk = config.psk(key).unwrap_or_default();
+            SessionCipher::new(psk.as_bytes(), response.nonce, response.secret, Role::Relay)
+        });
+        if let Some(peer) = self.pairing.get(from) {
+            let mut peer = peer.lock().expect("Peer lock poisoned");
+            if peer.secret != response.secret || peer.key != key {
+                return false;
+            }
+            let options = granted_options(config, response.options);
+            grant_options(config, &mut peer, cipher, options);
+            let mut ack = Ops::Ack.message(config.protocol_magic, &peer.secret);
+            if options != 0 {
+                ack.push(options);
+            }
+            send_to(socket, &ack, from);
+            if config.session_resumption {
+                peer.recipient.send_message(
+                    &Ops::SessionToken.message(config.protocol_magic, &peer.resume_token),
+                );
+            }
+        } else if let Some(secret) = self.group_members.get(from) {
+            let group = self
+                .groups
+                .get_mut(secret)
+                .expect("Group members belong to a group");
+            if secret.as_slice() != response.secret || group.key != key {
+                return false;
+            }
+            if let Some(member) = group.members.get_mut(from) {
+                member.cipher = cipher;
+            }
+            send_to(
+                socket,
+                &Ops::Ack.message(config.protocol_magic, secret),
+                from,
+            );
+        } else {
+            return false;
+        }
+        self.replay_window.insert(response.nonce);
+        debug!("Acknowledging '{from}' again, as it retried the pairing of its session");
+        true
+    }
+
+    /// Ends the session of `from`, which restarted its handshake, telling its
+    /// opponent as if it had disconnected.
+    fn close_restarted(&mut self, config: &Config, from: &SocketAddr, outbox: &mut Outbox) {
+        if self.group_members.contains_key(from) {
+            info!("Group member '{from}' restarted its handshake");
+            self.leave_group(from, CloseReason::Restart);
+        } else if let Some(opponent) = self.close_pair(from, CloseReason::Restart) {
+            let opponent = opponent.lock().expect("Peer lock poisoned");
+            outbox.push(
+                &opponent.recipient.socket,
+                opponent.recipient.addr,
+                &Ops::Disconnect.to_bytes(config.protocol_magic),
+            );
+            info!(
+                "'{from}' restarted its handshake, disconnecting '{}' (key '{}')",
+                opponent.recipient.addr, opponent.key
+            );
+        }
+        self.counters.restarted_sessions += 1;
+    }
+
+    /// Sends again the frames to peers with reliable delivery that were not
+    /// acknowledged within `interval`.
+    pub(crate) fn retransmit(&mut self, interval: Duration) {
+        let mut resent = 0;
+        for peer in self.pairing.values() {
+            let mut peer = peer.lock().expect("Peer lock poisoned");
+            let peer = &mut *peer;
+            let Some(channel) = &mut peer.reliable else {
+                continue;
+            };
+            for frame in channel.due(interval) {
+                peer.recipient.send_message(&frame);
+                resent += 1;
+            }
+        }
+        self.counters.retransmitted_packets += resent;
+    }
+
+    /// Sends [`Ops::Echo`] to every paired peer answering them, measuring its
+    /// round-trip time.
+    pub(crate) fn send_echoes(&mut self, config: &Config) {
+        for peer in self.pairing.values() {
+            let mut peer = peer.lock().expect("Peer lock poisoned");
+            if let Some(echo) = peer.rtt.echo(config.protocol_magic) {
+                peer.recipient.send_message(&echo);
+            }
+        }
+    }
+
+    /// Sends [`Ops::Keepalive`] to every peer of the pairs and groups that have
+    /// been silent for at least `idle`.
+    pub(crate) fn send_keepalives(&mut self, config: &Config, idle: Duration) {
+        let message = Ops::Keepalive.to_bytes(config.protocol_magic);
+        let mut sent = 0;
+        for peer in self.pairing.values() {
+            let mut peer = peer.lock().expect("Peer lock poisoned");
+            let opponent = peer.get_opponent();
+            let opponent = opponent.lock().expect("Peer lock poisoned");
+            if peer.last_accessed.is_expired(idle) && opponent.last_accessed.is_expired(idle) {
+                peer.recipient.send_message(&message);
+                sent += 1;
+            }
+        }
+        for group in self.groups.values() {
+            if group
+                .members
+                .values()
+                .all(|member| member.last_accessed.is_expired(idle))
+            {
+                for (addr, member) in &group.members {
+                    send_to(&member.socket, &message, addr);
+                    sent += 1;
+                }
+            }
+        }
+        if sent > 0 {
+            trace!("Sent {sent} keepalive(s)");
+        }
+        self.counters.keepalives += sent;
+    }
+
+    /// Counts the datagrams that could not be sent to the peers at `failed`,
+    /// tearing down the sessions of peers that failed
+    /// [`Config::max_send_failures`] times in a row; a group member only leaves
+    /// its group.
+    pub(crate) fn record_send_failures(&mut self, config: &Config, failed: &[SocketAddr]) {
+        let mut unreachable = Vec::new();
+        for addr in failed {
+            self.counters.send_failures += 1;
+            let failures = if let Some(peer) = self.pairing.get(addr) {
+                let mut peer = peer.lock().expect("Peer lock poisoned");
+                peer.send_failures += 1;
+                peer.send_failures
+            } else if let Some(member) = self
+                .group_members
+                .get(addr)
+                .and_then(|secret| self.groups.get_mut(secret))
+                .and_then(|group| group.members.get_mut(addr))
+            {
+                member.send_failures += 1;
+                member.send_failures
+            } else {
+                continue;
+            };
+            if failures == config.max_send_failures {
+                unreachable.push(*addr);
+            }
+        }
+        for addr in unreachable {
+            if self.group_members.contains_key(&addr) {
+                info!("Group member '{addr}' is unreachable. Removing it...");
+                if self.leave_group(&addr, CloseReason::Unreachable) {
+                    self.counters.unreachable_sessions += 1;
+                }
+                continue;
+            }
+            let Some(opponent) = self.close_pair(&addr, CloseReason::Unreachable) else {
+                continue;
+            };
+            let opponent = opponent.lock().expect("Peer lock poisoned");
+            // let the opponent know, so that it can pair again
+            opponent
+                .recipient
+                .send_message(&Ops::Disconnect.to_bytes(config.protocol_magic));
+            info!(
+                "'{addr}' is unreachable. Tearing down its session with '{}' (key '{}')...",
+                opponent.recipient.addr, opponent.key
+            );
+            self.counters.unreachable_sessions += 1;
+        }
+    }
+
+    fn remove_inactive_group_members(&mut self, config: &Config) {
+        let mut expired = Vec::new();
+        for group in self.groups.values() {
+            let timeout = config.timeout_connection_inactivities_for(&group.key);
+            for (addr, member) in &group.members {
+                if member.last_accessed.is_expired(timeout) {
+                    info!(
+                        "Group member '{addr}' (key '{}') has no activities after {} seconds. Removing it...",
+                        group.key,
+                        timeout.as_secs()
+                    );
+                    expired.push(*addr);
+                }
+            }
+        }
+        for addr in expired {
+            if self.leave_group(&addr, CloseReason::Inactivity) {
+                self.counters.expired_sessions += 1;
+            }
+        }
+    }
+
+    /// Tears down every session, group member and pending pairing matching
+    /// `target`, which is either a peer address or a session secret (removing
+    /// the whole group). Returns the number removed.
+    #[cfg_attr(not(unix), allow(dead_code))]
+    pub(crate) fn kick(&mut self, target: &str) -> usize {
+        let target_addr = target.parse::<SocketAddr>().ok();
+        let matches = |addr: &SocketAddr, secret: &[u8]| {
+            Some(*addr) == target_addr || secret == target.as_bytes()
+        };
+
+        let to_remove: Vec<SocketAddr> = self
+            .pairing
+            .values()
+            .filter_map(|peer_rc| {
+                let peer = peer_rc.lock().expect("Peer lock poisoned");
+                matches(&peer.recipient.addr, &peer.secret).then_some(peer.recipient.addr)
+            })
+            .collect();
+        let mut kicked = 0;
+        for k in &to_remove {
+            if let Some(opponent) = self.close_pair(k, CloseReason::Kick) {
+                let opponent = opponent.lock().expect("Peer lock poisoned");
+                info!("Kicking '{k}' and '{}'", opponent.recipient.addr);
+                kicked += 1;
+            }
+        }
+
+        let members: Vec<SocketAddr> = self
+            .groups
+            .iter()
+            .flat_map(|(secret, group)| {
+                group
+                    .members
+                    .keys()
+                    .filter(move |addr| matches(addr, secret))
+            })
+            .copied()
+            .collect();
+        for addr in &members {
+            info!("Kicking '{addr}'");
+            self.leave_group(addr, CloseReason::Kick);
+        }
+
+        let pending = self.pending_pairings();
+        self.pending_pairing.retain(|secret, waiters| {
+            waiters.retain(|pending| !matches(&pending.addr, secret));
+            !waiters.is_empty()
+        });
+        kicked + members.len() + pending - self.pending_pairings()
+    }
+
+    /// Redirects every pending peer and group member whose secret `matches` to
+    /// `instance`, which announced the same secret in cluster mode.
+    pub(crate) fn yield_secret(
+        &mut self,
+        config: &Config,
+        matches: impl Fn(&[u8]) -> bool,
+        instance: SocketAddr,
+    ) {
+        let mut redirected = Vec::new();
+        self.pending_pairing.retain(|secret, waiters| {
+            if !matches(secret) {
+                return true;
+            }
+            redirected.extend(
+                waiters
+                    .drain(..)
+                    .map(|pending| (pending.socket.clone(), pending.addr)),
+            );
+            false
+        });
+        let members: Vec<(Arc<UdpSocket>, SocketAddr)> = self
+            .groups
+            .iter()
+            .filter(|(secret, _)| matches(secret))
+            .flat_map(|(_, group)| {
+                group
+                    .members
+                    .iter()
+                    .map(|(addr, member)| (member.socket.clone(), *addr))
+            })
+            .collect();
+        for (_, addr) in &members {
+            self.leave_group(addr, CloseReason::Redirect);
+        }
+        for (socket, addr) in redirected.into_iter().chain(members) {
+            redirect(config, &mut self.counters, &socket, &addr, &instance);
+        }
+    }
+
+    pub(crate) fn remove_expired_pairing_request(&mut self, config: &Config) {
+        self.replay_window.prune();
+        self.rate_limiter.prune(config);
+        #[cfg(feature = "dtls")]
+        self.dtls_tickets.retain(|_, ticket| {
+            !ticket
+                .timer
+                .is_expired(config.timeout_pairing_for(&ticket.key))
+        });
+        self.pending_pairing.retain(|secret, waiters| {
+            waiters.retain(|pending| {
+                let timeout = config.timeout_pairing_for(&pending.key);
+                if pending.timer.is_expired(timeout) {
+                    info!(
+                        "Pending pairing from '{}' is expired after {} seconds",
+                        pending.addr,
+                        timeout.as_secs()
+                    );
+                    self.counters.expired_pairings += 1;
+                    return false;
+                }
+                true
+            });
+            if waiters.is_empty() {
+                if let Some(cluster) = &self.cluster {
+                    cluster.withdraw(config, secret);
+                }
+                return false;
+            }
+            true
+        });
+        if let Some(cluster) = &mut self.cluster {
+            cluster.prune(config);
+            for secret in self.pending_pairing.keys().chain(self.groups.keys()) {
+                cluster.announce(config, secret);
+            }
+        }
+    }
+}
+
+/// Whether both sockets serve the same port, i.e. are the same socket or the
+/// sockets of two workers.
+fn same_port(a: &Arc<UdpSocket>, b: &Arc<UdpSocket>) -> bool {
+    Arc::ptr_eq(a, b) || a.local_addr().ok() == b.local_addr().ok()
+}
+
+/// Relays a datagram of the paired `sender` to its opponent, once
+/// [`RelayService::process_datagram`] handed it over. Only the peers of the pair
+/// are locked, so that decrypting, compressing and sealing do not hold up the
+/// rest of the relay; `counters` are added to the relay's afterwards.
+pub(crate) fn process_relay_service(
+    config: &Config,
+    counters: &mut Counters,
+    buffer: &[u8],
+    sender: &Arc<Mutex<RecipientData>>,
+    outbox: &mut Outbox,
+) {
+    // the sender is unlocked before its opponent is locked, as the opponent may
+    // be relaying the other way on another worker at the same time
+    let (payloads, opponent, from, has_channels) = {
+        let mut sender = sender.lock().expect("Peer lock poisoned");
+        let payloads = receive_payloads(config, counters, buffer, &mut sender, outbox);
+        (
+            payloads,
+            sender.opponent.as_ref().and_then(Weak::upgrade),
+            sender.recipient.addr,
+            sender.channels.is_some(),
+        )
+    };
+    // the pair may have been torn down since the registry was unlocked
+    let (false, Some(receiver)) = (payloads.is_empty(), opponent) else {
+        return;
+    };
+    let mut receiver = receiver.lock().expect("Peer lock poisoned");
+    for (payload, delay) in payloads {
+        relay_payload(
+            config,
+            counters,
+            &payload,
+            delay,
+            (&from, has_channels),
+            &mut receiver,
+            outbox,
+        );
+    }
+}
+
+/// Takes the payloads to relay out of a datagram of `sender`, with how long to
+/// hold each back, answering what is meant for the relay itself.
+fn receive_payloads<'a>(
+    config: &Config,
+    counters: &mut Counters,
+    buffer: &'a [u8],
+    sender: &mut RecipientData,
+    outbox: &mut Outbox,
+) -> Vec<(Cow<'a, [u8]>, Duration)> {
+    sender.last_accessed.access();
+    sender.send_failures = 0;
+    match Ops::parse(buffer, config.protocol_magic) {
+        Some((Ops::Echo, payload)) if payload.len() <= MAX_PROBE_PAYLOAD => {
+            sender.rtt.answers_echoes = true;
+            let reply = Ops::EchoReply.message(config.protocol_magic, payload);
+            outbox.push(&sender.recipient.socket, sender.recipient.addr, &reply);
+            return Vec::new();
+        }
+        Some((Ops::EchoReply, payload)) => {
+            if let Some(rtt) = sender.rtt.reply(payload) {
+                trace!("Round-trip time to {} is {rtt:?}", sender.recipient.addr);
+            }
+            return Vec::new();
+        }
+        _ => (),
+    }
+    if let Some(channel) = &mut sender.reliable {
+        match Ops::parse(buffer, config.protocol_magic) {
+            Some((Ops::SequenceAck, ack)) => {
+                channel.acknowledge(ack);
+                return Vec::new();
+            }
+            Some((Ops::Sequenced, frame)) => {
+                let Some((ack, delivered)) = channel.receive(frame) else {
+                    trace!("Dropping malformed frame from {}", sender.recipient.addr);
+                    return Vec::new();
+                };
+                outbox.push(&sender.recipient.socket, sender.recipient.addr, &ack);
+                return delivered
+                    .into_iter()
+                    .filter_map(|payload| {
+                        accept_payload(config, counters, Cow::Owned(payload), sender, outbox)
+                    })
+                    .collect();
+            }
+            _ => (),
+        }
+    }
+    if let Some(fec) = &mut sender.fec {
+        let delivered = match Ops::parse(buffer, config.protocol_magic) {
+            Some((Ops::FecData, frame)) => fec.receive_data(frame),
+            Some((Ops::FecParity, frame)) => fec.receive_parity(frame).map(|rebuilt| {
+                counters.fec_recovered_packets += 1;
+                vec![rebuilt]
+            }),
+            _ => None,
+        };
+        if let Some(delivered) = delivered {
+            return delivered
+                .into_iter()
+                .filter_map(|payload| {
+                    accept_payload(config, counters, Cow::Owned(payload), sender, outbox)
+                })
+                .collect();
+        }
+    }
+    accept_payload(config, counters, Cow::Borrowed(buffer), sender, outbox)
+        .into_iter()
+        .collect()
+}
+
+/// Decrypts a payload of `sender`, after taking it out of its frame with
+/// reliable delivery, and checks it against the limits of the relay. Returns
+/// the payload to relay and how long to hold it back, or `None` if it is
+/// dropped.
+fn accept_payload<'a>(
+    config: &Config,
+    counters: &mut Counters,
+    buffer: Cow<'a, [u8]>,
+    sender: &mut RecipientData,
+    outbox: &mut Outbox,
+) -> Option<(Cow<'a, [u8]>, Duration)> {
+    let buffer = match &mut sender.cipher {
+        Some(cipher) => {
+            let Some(payload) = cipher.open(&buffer) else {
+                trace!(
+                    "Dropping undecryptable datagram from {}",
+                    sender.recipient.addr
+                );
+                counters.undecryptable_packets += 1;
+                return None;
+            };
+            Cow::Owned(payload)
+        }
+        None => buffer,
+    };
+    if let Some(channels) = &mut sender.channels {
+        if !channels.record(&buffer) {
+            trace!(
+                "Dropping datagram without channel from {}",
+                sender.recipient.addr
+            );
+            return None;
+        }
+    }
+    let recipient = &sender.recipient;
+    if !fits_max_payload(
+        config,
+        counters,
+        &buffer,
+        &recipient.socket,
+        &recipient.addr,
+        outbox,
+    ) {
+        return None;
+    }
+    let delay = match config.rate_limit_for(&sender.key) {
+        Some(rate) => {
+            let max_delay = if config.rate_limit_delay {
+                MAX_THROTTLE_DELAY
+            } else {
+                Duration::ZERO
+            };
+            match sender.throttle.take(rate, buffer.len(), max_delay) {
+                Some(delay) => delay,
+                None => {
+                    trace!(
+                        "Dropping datagram from {} over its rate limit",
+                        sender.recipient.addr
+                    );
+                    counters.throttled_packets += 1;
+                    return None;
+                }
+            }
+        }
+        None => Duration::ZERO,
+    };
+    sender.packets += 1;
+    sender.bytes += buffer.len() as u64;
+    counters.relayed_packets[sender.side as usize] += 1;
+    counters.relayed_bytes[sender.side as usize] += buffer.len() as u64;
+    Some((buffer, delay))
+}
+
+/// Relays a payload of the peer at `from`, which uses logical channels or not,
+/// to its opponent `receiver`, holding it back for `delay`.
+fn relay_payload(
+    config: &Config,
+    counters: &mut Counters,
+    buffer: &[u8],
+    delay: Duration,
+    (from, has_channels): (&SocketAddr, bool),
+    receiver: &mut RecipientData,
+    outbox: &mut Outbox,
+) {
+    let buffer = &*channels::reframe(buffer, has_channels, receiver.channels.is_some());
+    let compressed = receiver.compress.then(|| {
+        let flagged = compression::compress(buffer);
+        counters.compression_saved_bytes += (buffer.len() + 1).saturating_sub(flagged.len()) as u64;
+        flagged
+    });
+    let message = compressed.as_deref().unwrap_or(buffer);
+    let sealed = receiver.cipher.as_mut().map(|cipher| cipher.seal(message));
+    let message = sealed.as_deref().unwrap_or(message);
+    let framed = match &mut receiver.reliable {
+        Some(channel) => match channel.send(message) {
+            Some(frame) => Some(frame),
+            None => {
+                trace!(
+                    "Dropping datagram to {} as its send window is full",
+                    receiver.recipient.addr
+                );
+                counters.window_overflows += 1;
+                return;
+            }
+        },
+        None => None,
+    };
+    let message = framed.as_deref().unwrap_or(message);
+    let (coded, parity) = match &mut receiver.fec {
+        Some(fec) => {
+            let (frame, parity) = fec.encode(message);
+            (Some(frame), parity)
+        }
+        None => (None, None),
+    };
+    let message = coded.as_deref().unwrap_or(message);
+    if !delay.is_zero() {
+        counters.delayed_packets += 1;
+    }
+    for datagram in iter::once(message).chain(parity.as_deref()) {
+        let Some(impairment) = impair(config, counters) else {
+            continue;
+        };
+        let delay = delay + impairment;
+        if delay.is_zero() {
+            outbox.push(
+                &receiver.recipient.socket,
+                receiver.recipient.addr,
+                datagram,
+            );
+        } else {
+            let socket = receiver.recipient.socket.clone();
+            let addr = receiver.recipient.addr;
+            let datagram = datagram.to_vec();
+            tokio::spawn(async move {
+                time::sleep(delay).await;
+                send_to(&socket, &datagram, &addr);
+            });
+        }
+    }
+    trace!(
+        "Relaying message {from} => {} => {}",
+        str::from_utf8(buffer).unwrap_or("[some bytes]").trim(),
+        receiver.recipient.addr
+    );
+}
+
+/// Whether `payload`, from `from`, is within [`Config::max_payload`]. Payloads
+/// over it are counted and, with [`Config::reply_too_big`], answered with
+/// [`Ops::TooBig`].
+fn fits_max_payload(
+    config: &Config,
+    counters: &mut Counters,
+    payload: &[u8],
+    socket: &Arc<UdpSocket>,
+    from: &SocketAddr,
+    outbox: &mut Outbox,
+) -> bool {
+    let Some(max) = config.max_payload else {
+        return true;
+    };
+    if payload.len() <= max {
+        return true;
+    }
+    trace!(
+        "Dropping datagram from {from} with a payload of {} bytes, over the maximum",
+        payload.len()
+    );
+    counters.oversized_packets += 1;
+    if config.reply_too_big {
+        let max = u16::try_from(max).unwrap_or(u16::MAX);
+        outbox.push(
+            socket,
+            *from,
+            &Ops::TooBig.message(config.protocol_magic, &max.to_be_bytes()),
+        );
+    }
+    false
+}
+
+/// Drops or holds back a datagram to a paired peer on purpose, as configured
+/// with [`Config::chaos_loss`], [`Config::chaos_delay`] and
+/// [`Config::chaos_jitter`]. Returns how long to hold it back, or `None` if it
+/// is dropped.
+fn impair(config: &Config, counters: &mut Counters) -> Option<Duration> {
+    if config.chaos_loss > 0.0 && rand::random::<f64>() * 100.0 < config.chaos_loss {
+        counters.chaos_dropped_packets += 1;
+        return None;
+    }
+    if config.chaos_jitter.is_zero() {
+        return Some(config.chaos_delay);
+    }
+    let jitter = config.chaos_jitter.mul_f64(2.0 * rand::random::<f64>());
+    Some((config.chaos_delay + jitter).saturating_sub(config.chaos_jitter))
+}
+
+/// Whether peers speaking protocol `version` may pair, answering
+/// [`Ops::UnsupportedVersion`] otherwise.
+fn accepts_version(
+    config: &Config,
+    registry: &mut RelayService,
+    socket: &UdpSocket,
+    version: u8,
+    from: &SocketAddr,
+) -> bool {
+    if version >= config.min_protocol_version {
+        return true;
+    }
+    debug!("Refusing {from}, which speaks protocol version {version}");
+    registry.counters.rejected_version += 1;
+    send_to(
+        socket,
+        &Ops::UnsupportedVersion.message(
+            config.protocol_magic,
+            &[config.min_protocol_version, PROTOCOL_VERSION],
+        ),
+        from,
+    );
+    false
+}
+
+fn process_maybe_request(
+    config: &Config,
+    registry: &mut RelayService,
+    socket: &Arc<UdpSocket>,
+    buffer: &[u8],
+    from: &SocketAddr,
+) {
+    if config.turn && turn::is_stun(buffer) {
+        turn::process(config, registry, socket, buffer, from);
+        return;
+    }
+    let op = Ops::parse(buffer, config.protocol_magic).map(|(op, _)| op);
+    if matches!(
+        op,
+        Some(
+            Ops::ChallengeRequest
+                | Ops::ChallengeResponse
+                | Ops::EstablishConnection
+                | Ops::Resume
+                | Ops::Rebind
+                | Ops::Redeem
+        )
+    ) && !registry.rate_limiter.check(config, from.ip())
+    {
+        registry.counters.rate_limited += 1;
+        return;
+    }
+
+    match Ops::parse(buffer, config.protocol_magic) {
+        Some((Ops::Ping, _)) => send_to(socket, &Ops::Pong.to_bytes(config.protocol_magic), from),
+        Some((Ops::Probe, payload)) if payload.len() <= MAX_PROBE_PAYLOAD => send_to(
+            socket,
+            &Ops::ProbeReply.message(config.protocol_magic, payload),
+            from,
+        ),
+        Some((Ops::AddressRequest, _)) if buffer.len() >= ADDRESS_REQUEST_LEN => send_to(
+            socket,
+            &Ops::Address.message(config.protocol_magic, &encode_addr(from)),
+            from,
+        ),
+        Some((Ops::ChallengeRequest, payload)) => {
+            let version = payload.first().copied().unwrap_or(UNNEGOTIATED_VERSION);
+            if !accepts_version(config, registry, socket, version, from) {
+                return;
+            }
+            debug!("Issuing challenge to {from} (protocol version {version})");
+            let mut challenge =
+                Ops::Challenge.message(config.protocol_magic, &registry.challenger.issue(from));
+            if !payload.is_empty() {
+                challenge.push(version.min(PROTOCOL_VERSION));
+            }
+            send_to(socket, &challenge, from);
+        }
+        Some((Ops::ChallengeResponse, payload)) => {
+            process_pairing_response(config, registry, socket, payload, from)
+        }
+        Some((Ops::EstablishConnection, payload)) if config.legacy_handshake => {
+            process_pairing_request(config, registry, socket, payload, from)
+        }
+        Some((Ops::EstablishConnection, _)) => {
+            debug!("Ignoring legacy handshake from {from} as it is disabled")
+        }
+        Some((Ops::Resume, token)) if config.session_resumption => {
+            process_resume(config, registry, socket, token, from)
+        }
+        Some((Ops::Rebind, payload)) if config.session_rebind => {
+            process_rebind(config, registry, socket, payload, from)
+        }
+        #[cfg(feature = "dtls")]
+        Some((Ops::Redeem, ticket)) => process_redeem(config, registry, socket, ticket, from),
+        None => {
+            if let Some(magic) = protocol::foreign_magic(buffer, config.protocol_magic) {
+                debug!("Ignoring pairing request from {from} with protocol magic {magic:#04x}");
+                registry.counters.rejected_magic += 1;
+            }
+        }
+        _ => (),
+    }
+}
+
+fn process_pairing_request(
+    config: &Config,
+    registry: &mut RelayService,
+    socket: &Arc<UdpSocket>,
+    payload: &[u8],
+    from: &SocketAddr,
+) {
+    debug!("Got establish connection token from {from}");
+    if !accepts_version(config, registry, socket, LEGACY_VERSION, from) {
+        return;
+    }
+
+    let request = match PairingRequest::parse(payload) {
+        Some(request) => request,
+        None => {
+            debug!("Aborting as there aren't enough message length than needed");
+            registry.counters.rejected_short_packet += 1;
+            return;
+        }
+    };
+    let Some(key) = config.find_presented_key(request.psk) else {
+        debug!("Aborting as psk does not match");
+        registry.counters.rejected_bad_psk += 1;
+        return;
+    };
+
+    register_pairing(config, registry, socket, request.secret, key, from, None, 0);
+}
+
+fn process_pairing_response(
+    config: &Config,
+    registry: &mut RelayService,
+    socket: &Arc<UdpSocket>,
+    payload: &[u8],
+    from: &SocketAddr,
+) {
+    debug!("Got challenge response from {from}");
+
+    let response = match PairingResponse::parse(payload) {
+        Some(response) => response,
+        None => {
+            debug!("Aborting as there aren't enough message length than needed");
+            registry.counters.rejected_short_packet += 1;
+            return;
+        }
+    };
+
+    if !registry.challenger.is_valid(response.nonce, from) {
+        debug!("Aborting as the challenge is unknown or expired");
+        registry.counters.rejected_bad_challenge += 1;
+        return;
+    }
+    let Some(key) =
+        config.find_key(|psk| auth::verify(psk, response.nonce, response.secret, response.mac))
+    else {
+        debug!("Aborting as psk does not match");
+        registry.counters.rejected_bad_psk += 1;
+        return;
+    };
+    if !registry.replay_window.insert(response.nonce) {
+        debug!("Aborting as the challenge was already answered");
+        registry.counters.rejected_replayed += 1;
+        return;
+    }
+
+    let cipher = config.encryption.then(|| {
+        let psk = config.psk(key).unwrap_or_default();
+        SessionCipher::new(psk.as_bytes(), response.nonce, response.secret, Role::Relay)
+    });
+    register_pairing(
+        config,
+        registry,
+        socket,
+        response.secret,
+        key,
+        from,
+        cipher,
+        granted_options(config, response.options),
+    );
+}
+
+/// Options of those `requested` the relay agrees to: reliable delivery or, as a
+/// link cannot have both, forward error correction, compression and channels.
+fn granted_options(config: &Config, requested: u8) -> u8 {
+    let delivery = if config.reliable && requested & OPTION_RELIABLE != 0 {
+        OPTION_RELIABLE
+    } else if config.fec && requested & OPTION_FEC != 0 {
+        OPTION_FEC
+    } else {
+        0
+    };
+    let compression = if config.compression {
+        requested & OPTION_COMPRESSION
+    } else {
+        0
+    };
+    let channels = if config.channels {
+        requested & OPTION_CHANNELS
+    } else {
+        0
+    };
+    delivery | compression | channels
+}
+
+/// Rebinds the paired peer holding `token` to `from`, issuing it a new token.
+fn process_resume(
+    config: &Config,
+    registry: &mut RelayService,
+    socket: &Arc<UdpSocket>,
+    token: &[u8],
+    from: &SocketAddr,
+) {
+    debug!("Got resume request from {from}");
+    let old_addr = registry.pairing.iter().find_map(|(addr, peer)| {
+        let peer = peer.lock().expect("Peer lock poisoned");
+        auth::constant_time_eq(&peer.resume_token, token).then_some(*addr)
+    });
+    let Some(old_addr) = old_addr else {
+        debug!("Aborting as the resume token is unknown");
+        registry.counters.rejected_bad_token += 1;
+        return;
+    };
+    rebind(config, registry, socket, old_addr, from, true);
+}
+
+/// Rebinds the paired peer at the address named in the request to `from`, once
+/// it answered a challenge issued there with the secret of its session.
+fn process_rebind(
+    config: &Config,
+    registry: &mut RelayService,
+    socket: &Arc<UdpSocket>,
+    payload: &[u8],
+    from: &SocketAddr,
+) {
+    debug!("Got rebind request from {from}");
+    let Some(request) = RebindRequest::parse(payload) else {
+        debug!("Aborting as the rebind request is malformed");
+        registry.counters.rejected_short_packet += 1;
+        return;
+    };
+    if !registry.challenger.is_valid(request.nonce, from) {
+        debug!("Aborting as the challenge is unknown or expired");
+        registry.counters.rejected_bad_challenge += 1;
+        return;
+    }
+    // peers of a dual-stack socket are known by their IPv4-mapped address
+    let mapped = match request.old_addr.ip() {
+        IpAddr::V4(ip) => SocketAddr::new(ip.to_ipv6_mapped().into(), request.old_addr.port()),
+        IpAddr::V6(_) => request.old_addr,
+    };
+    let old_addr = [request.old_addr, mapped]
+        .into_iter()
+        .find(|addr| registry.pairing.contains_key(addr));
+    let authentic = old_addr.is_some_and(|addr| {
+        let peer = registry.pairing[&addr].lock().expect("Peer lock poisoned");
+        auth::verify(
+            &peer.secret,
+            request.nonce,
+            &encode_addr(&request.old_addr),
+            request.mac,
+        )
+    });
+    let (Some(old_addr), true) = (old_addr, authentic) else {
+        debug!("Aborting as the session is unknown or its secret does not match");
+        registry.counters.rejected_bad_token += 1;
+        return;
+    };
+    if !registry.replay_window.insert(request.nonce) {
+        debug!("Aborting as the challenge was already answered");
+        registry.counters.rejected_replayed += 1;
+        return;
+    }
+    rebind(config, registry, socket, old_addr, from, false);
+}
+
+/// Moves the paired peer at `old_addr` to `from` and acknowledges it there,
+/// issuing it a new resume token if `new_token`.
+fn rebind(
+    config: &Config,
+    registry: &mut RelayService,
+    socket: &Arc<UdpSocket>,
+    old_addr: SocketAddr,
+    from: &SocketAddr,
+    new_token: bool,
+) {
+    let peer_rc = registry
+        .pairing
+        .remove(&old_addr)
+        .expect("This should exists, as it just were");
+    {
+        let mut peer = peer_rc.lock().expect("Peer lock poisoned");
+        peer.recipient.addr = *from;
+        peer.recipient.socket = socket.clone();
+        peer.last_accessed.access();
+        peer.recipient
+            .send_message(&Ops::Ack.message(config.protocol_magic, &peer.secret));
+        if new_token {
+            peer.resume_token = rand::random();
+            peer.recipient.send_message(
+                &Ops::SessionToken.message(config.protocol_magic, &peer.resume_token),
+            );
+        }
+        info!(
+            "Resumed session of '{old_addr}' at '{from}' (key '{}')",
+            peer.key
+        );
+        if let Some(log) = &mut registry.accounting {
+            log.resumed(&peer, &old_addr);
+        }
+    }
+    registry.pairing.insert(*from, peer_rc);
+    registry.counters.resumed_sessions += 1;
+}
+
+/// Registers the peer redeeming a ticket issued over DTLS as if it had just
+/// authenticated, deriving its keys from the ticket when encryption is enabled.
+#[cfg(feature = "dtls")]
+fn process_redeem(
+    config: &Config,
+    registry: &mut RelayService,
+    socket: &Arc<UdpSocket>,
+    ticket: &[u8],
+    from: &SocketAddr,
+) {
+    debug!("Got ticket from {from}");
+    let issued = <[u8; RESUME_TOKEN_LEN]>::try_from(ticket)
+        .ok()
+        .and_then(|ticket| registry.dtls_tickets.remove(&ticket))
+        .filter(|issued| {
+            !issued
+                .timer
+                .is_expired(config.timeout_pairing_for(&issued.key))
+        });
+    let Some(issued) = issued else {
+        debug!("Aborting as the ticket is unknown or expired");
+        registry.counters.rejected_bad_token += 1;
+        return;
+    };
+
+    let cipher = config.encryption.then(|| {
+        let psk = config.psk(&issued.key).unwrap_or_default();
+        SessionCipher::new(psk.as_bytes(), ticket, &issued.secret, Role::Relay)
+    });
+    register_pairing(
+        config,
+        registry,
+        socket,
+        &issued.secret,
+        &issued.key,
+        from,
+        cipher,
+        0,
+    );
+}
+
+/// Pairs an authenticated peer with the oldest one waiting on the same secret,
+/// or queues it as pending, as allowed by the policies of `key`. With a second
+/// port, peers are only paired across the two ports. The `options` granted to
+/// the peer are echoed in the acknowledgement, except to group members, which
+/// get none.
+#[allow(clippy::too_many_arguments)]
+fn register_pairing(
+    config: &Config,
+    registry: &mut RelayService,
+    socket: &Arc<UdpSocket>,
+    peer_secret: &[u8],
+    key: &str,
+    from: &SocketAddr,
+    cipher: Option<SessionCipher>,
+    options: u8,
+) {
+    debug!(
+        "Authenticated with key '{key}'. Peer secret: {:?}",
+        str::from_utf8(peer_secret).unwrap_or("[some bytes]")
+    );
+    if config.pairing_codes
+        && !auth::is_valid_pairing_code(config.psk(key).unwrap_or_default().as_bytes(), peer_secret)
+    {
+        debug!("Aborting as the secret is not a current pairing code");
+        registry.counters.rejected_bad_code += 1;
+        return;
+    }
+    let policy = config.key(key);
+    if let Some(policy) = policy {
+        let ip = from.ip().to_canonical();
+        if !policy.allow_cidrs.is_empty() && !policy.allow_cidrs.iter().any(|net| net.contains(&ip))
+        {
+            debug!("Aborting as {from} is outside the networks allowed for key '{key}'");
+            registry.counters.rejected_network += 1;
+            return;
+        }
+    }
+    if config.group {
+        join_group(config, registry, socket, peer_secret, key, from, cipher);
+        return;
+    }
+    if !registry.pending_pairing.contains_key(peer_secret) {
+        if let Some(instance) = locate(config, registry, peer_secret) {
+            redirect(config, &mut registry.counters, socket, from, &instance);
+            return;
+        }
+    }
+    let spent = registry.is_spent(config);
+    let at_session_limit = registry.at_session_limit(config, key);
+    let at_capacity = config
+        .max_sessions
+        .is_some_and(|max| registry.sessions() >= max);
+    let pending_full = config
+        .max_pending_pairings
+        .is_some_and(|max| registry.pending_pairings() >= max);
+    let at_ip_limit = config
+        .max_sessions_per_ip
+        .is_some_and(|max| registry.sessions_from_ip(from.ip()) >= max);
+    let mut ack = Ops::Ack.message(config.protocol_magic, peer_secret);
+    if options != 0 {
+        ack.push(options);
+    }
+    let collision = config.secret_collision_for(key);
+
+    let waiters = registry.pending_pairing.get(peer_secret);
+    let waiting =
+        waiters.and_then(|waiters| waiters.iter().position(|pending| pending.addr == *from));
+    let other_key = waiters
+        .and_then(VecDeque::front)
+        .filter(|pending| pending.key != key)
+        .map(|pending| pending.key.clone());
+    let partner = waiters.and_then(|waiters| {
+        waiters
+            .iter()
+            .position(|pending| config.second_bind.is_none() || !same_port(&pending.socket, socket))
+    });
+    let waited_on = waiters.is_some();
+
+    match (waiting, other_key, partner) {
+        (Some(index), _, _) => {
+            debug!("Found existing pairing request from same address/ip/secret. Refreshing it...");
+            let pending = &mut registry
+                .pending_pairing
+                .get_mut(peer_secret)
+                .expect("This should exists, as it just were")[index];
+            pending.timer.access();
+            // the peer retried as it missed the acknowledgement, with new keys if encrypted
+            pending.cipher = cipher;
+            pending.options = options;
+            send_to(socket, &ack, from);
+        }
+        (None, Some(other_key), _) => {
+            debug!("Aborting as the pending peer with the same secret used key '{other_key}'");
+            registry.counters.rejected_key_mismatch += 1;
+        }
+        (None, None, Some(_)) if at_session_limit => {
+            debug!("Aborting as key '{key}' reached its session limit");
+            registry.counters.rejected_session_limit += 1;
+        }
+        (None, None, Some(_)) if at_ip_limit => {
+            reject_ip_limit(config, &mut registry.counters, socket, from)
+        }
+        (None, None, Some(_)) if spent => {
+            debug!("Aborting as the relay paired its last session");
+            registry.counters.rejected_max_total_sessions += 1;
+            reply_busy(config, socket, from);
+        }
+        (None, None, Some(_)) if at_capacity => {
+            debug!("Aborting as the relay reached its session limit");
+            registry.counters.rejected_max_sessions += 1;
+            reply_busy(config, socket, from);
+        }
+        (None, None, Some(index)) => {
+            let waiters = registry
+                .pending_pairing
+                .get_mut(peer_secret)
+                .expect("This should exists, as it just were");
+            let pending = waiters
+                .remove(index)
+                .expect("This should exists, as it just were");
+            if waiters.is_empty() {
+                registry.pending_pairing.remove(peer_secret);
+                if let Some(cluster) = &registry.cluster {
+                    cluster.withdraw(config, peer_secret);
+                }
+            }
+            let (peer1, peer2) = build_paired_peers(
+                peer_secret,
+                key,
+                &pending.addr,
+                &pending.socket,
+                from,
+                socket,
+            );
+            for (peer, cipher, options) in [
+                (&peer1, pending.cipher, pending.options),
+                (&peer2, cipher, options),
+            ] {
+                let mut peer = peer.lock().expect("Peer lock poisoned");
+                grant_options(config, &mut peer, cipher, options);
+            }
+            info!(
+                "Found other peer with same secret. Connecting {} to {} (key '{key}').",
+                pending.addr, from,
+            );
+            *registry
+                .counters
+                .pairings_by_key
+                .entry(key.to_owned())
+                .or_default() += 1;
+            send_to(socket, &ack, from);
+            let event = accounting::paired(
+                &peer1.lock().expect("Peer lock poisoned"),
+                &peer2.lock().expect("Peer lock poisoned"),
+            );
+            if let Some(log) = &mut registry.accounting {
+                log.record(&event);
+            }
+            if let Some(hooks) = &registry.hooks {
+                hooks.paired(&event);
+            }
+            if config.session_resumption {
+                for peer in [&peer1, &peer2] {
+                    let peer = peer.lock().expect("Peer lock poisoned");
+                    peer.recipient.send_message(
+                        &Ops::SessionToken.message(config.protocol_magic, &peer.resume_token),
+                    );
+                }
+            }
+            registry.pairing.insert(pending.addr, peer1);
+            registry.pairing.insert(*from, peer2);
+        }
+        (None, None, None)
+            if collision != SecretCollision::Queue
+                && (waited_on || registry.is_paired_on(peer_secret)) =>
+        {
+            collide(
+                config,
+                registry,
+                socket,
+                peer_secret,
+                key,
+                from,
+                &ack,
+                cipher,
+                options,
+                collision,
+            );
+        }
+        (None, None, None) if at_session_limit => {
+            debug!("Aborting as key '{key}' reached its session limit");
+            registry.counters.rejected_session_limit += 1;
+        }
+        (None, None, None) if at_ip_limit => {
+            reject_ip_limit(config, &mut registry.counters, socket, from)
+        }
+        (None, None, None) if spent => {
+            debug!("Aborting as the relay paired its last session");
+            registry.counters.rejected_max_total_sessions += 1;
+            reply_busy(config, socket, from);
+        }
+        (None, None, None) if pending_full => {
+            debug!("Aborting as the relay reached its pending pairing limit");
+            registry.counters.rejected_max_pending += 1;
+            reply_busy(config, socket, from);
+        }
+        (None, None, None) => {
+            send_to(socket, &ack, from);
+            if waited_on {
+                debug!("Queueing behind the peers already waiting on the same secret");
+            } else if let Some(cluster) = &registry.cluster {
+                cluster.announce(config, peer_secret);
+            }
+
+            registry
+                .pending_pairing
+                .entry(peer_secret.to_owned())
+                .or_default()
+                .push_back(PendingPairing {
+                    addr: *from,
+                    socket: socket.clone(),
+                    timer: ExpiringTimer::new(),
+                    key: key.to_owned(),
+                    cipher,
+                    options,
+                });
+        }
+    }
+}
+
+/// Sets up the `options` granted to a paired `peer`, with the keys of its link.
+fn grant_options(
+    config: &Config,
+    peer: &mut RecipientData,
+    cipher: Option<SessionCipher>,
+    options: u8,
+) {
+    peer.cipher = cipher;
+    peer.reliable = (options & OPTION_RELIABLE != 0)
+        .then(|| Channel::new(config.reliable_window, config.protocol_magic));
+    peer.fec =
+        (options & OPTION_FEC != 0).then(|| Fec::new(config.fec_block_size, config.protocol_magic));
+    peer.compress = options & OPTION_COMPRESSION != 0;
+    peer.channels = (options & OPTION_CHANNELS != 0).then(Channels::default);
+}
+
+/// Handles a pairing request presenting a secret already taken, which the
+/// `collision` policy of its key does not queue: with
+/// [`SecretCollision::Takeover`], the peer replaces the waiting or paired peer
+/// holding the secret from the same IP with the same key, if any, and is
+/// otherwise answered [`Ops::SecretInUse`].
+#[allow(clippy::too_many_arguments)]
+fn collide(
+    config: &Config,
+    registry: &mut RelayService,
+    socket: &Arc<UdpSocket>,
+    peer_secret: &[u8],
+    key: &str,
+    from: &SocketAddr,
+    ack: &[u8],
+    cipher: Option<SessionCipher>,
+    options: u8,
+    collision: SecretCollision,
+) {
+    let ip = from.ip().to_canonical();
+    let same_host = |addr: &SocketAddr| addr.ip().to_canonical() == ip;
+    if collision == SecretCollision::Takeover {
+        let waiter = registry
+            .pending_pairing
+            .get_mut(peer_secret)
+            .and_then(|waiters| {
+                waiters
+                    .iter_mut()
+                    .find(|pending| same_host(&pending.addr) && pending.key == key)
+            });
+        if let Some(pending) = waiter {
+            info!(
+                "'{from}' takes over the pending pairing of '{}'",
+                pending.addr
+            );
+            pending.addr = *from;
+            pending.socket = socket.clone();
+            pending.timer.access();
+            pending.cipher = cipher;
+            pending.options = options;
+            send_to(socket, ack, from);
+            registry.counters.taken_over_sessions += 1;
+            return;
+        }
+        let holder = registry
+            .pairing
+            .iter()
+            .find(|(addr, peer)| {
+                let peer = peer.lock().expect("Peer lock poisoned");
+                same_host(addr) && peer.secret == peer_secret && peer.key == key
+            })
+            .map(|(addr, _)| *addr);
+        if let Some(old_addr) = holder {
+            take_over(
+                config, registry, socket, old_addr, from, ack, cipher, options,
+            );
+            return;
+        }
+    }
+    debug!("Aborting as the secret is already in use");
+    if registry.pending_pairing.contains_key(peer_secret) {
+        registry.counters.rejected_same_port += 1;
+    } else {
+        registry.counters.rejected_secret_in_use += 1;
+    }
+    send_to(
+        socket,
+        &Ops::SecretInUse.to_bytes(config.protocol_magic),
+        from,
+    );
+}
+
+/// Moves the paired peer at `old_addr` to `from`, which presented its secret
+/// again from the same IP, with the keys and options of its new handshake.
+#[allow(clippy::too_many_arguments)]
+fn take_over(
+    config: &Config,
+    registry: &mut RelayService,
+    socket: &Arc<UdpSocket>,
+    old_addr: SocketAddr,
+    from: &SocketAddr,
+    ack: &[u8],
+    cipher: Option<SessionCipher>,
+    options: u8,
+) {
+    let peer_rc = registry
+        .pairing
+        .remove(&old_addr)
+        .expect("This should exists, as it just were");
+    {
+        let mut peer = peer_rc.lock().expect("Peer lock poisoned");
+        peer.recipient.addr = *from;
+        peer.recipient.socket = socket.clone();
+        peer.last_accessed.access();
+        grant_options(config, &mut peer, cipher, options);
+        peer.recipient.send_message(ack);
+        if config.session_resumption {
+            peer.resume_token = rand::random();
+            peer.recipient.send_message(
+                &Ops::SessionToken.message(config.protocol_magic, &peer.resume_token),
+            );
+        }
+        info!(
+            "'{from}' took over the session of '{old_addr}' (key '{}')",
+            peer.key
+        );
+        if let Some(log) = &mut registry.accounting {
+            log.resumed(&peer, &old_addr);
+        }
+    }
+    registry.pairing.insert(*from, peer_rc);
+    registry.counters.taken_over_sessions += 1;
+}
+
+/// Relay address of the instance of the cluster another peer waits on
+/// `secret` at, if any.
+fn locate(config: &Config, registry: &RelayService, secret: &[u8]) -> Option<SocketAddr> {
+    registry
+        .cluster
+        .as_ref()
+        .and_then(|cluster| cluster.locate(config, secret))
+}
+
+/// Sends a peer to the instance of the cluster its counterpart waits at.
+fn redirect(
+    config: &Config,
+    counters: &mut Counters,
+    socket: &Arc<UdpSocket>,
+    addr: &SocketAddr,
+    instance: &SocketAddr,
+) {
+    info!("Redirecting {addr} to {instance}, where its counterpart waits");
+    counters.redirected += 1;
+    send_to(
+        socket,
+        &Ops::Redirect.message(config.protocol_magic, &encode_addr(instance)),
+        addr,
+    );
+}
+
+/// Refuses a pairing request from a host holding
+/// [`Config::max_sessions_per_ip`], telling it so.
+fn reject_ip_limit(
+    config: &Config,
+    counters: &mut Counters,
+    socket: &Arc<UdpSocket>,
+    from: &SocketAddr,
+) {
+    debug!("Aborting as {} reached its session limit", from.ip());
+    counters.rejected_ip_limit += 1;
+    send_to(
+        socket,
+        &Ops::LimitExceeded.to_bytes(config.protocol_magic),
+        from,
+    );
+}
+
+/// Tells a peer its pairing request was refused for lack of room, if the relay
+/// is configured to.
+fn reply_busy(config: &Config, socket: &Arc<UdpSocket>, from: &SocketAddr) {
+    if config.reply_busy {
+        send_to(socket, &Ops::Busy.to_bytes(config.protocol_magic), from);
+    }
+}
+
+/// Adds an authenticated peer to the group of `peer_secret`, creating the group
+/// when it is the first member.
+fn join_group(
+    config: &Config,
+    registry: &mut RelayService,
+    socket: &Arc<UdpSocket>,
+    peer_secret: &[u8],
+    key: &str,
+    from: &SocketAddr,
+    cipher: Option<SessionCipher>,
+) {
+    if !registry.groups.contains_key(peer_secret) {
+        if let Some(instance) = locate(config, registry, peer_secret) {
+            redirect(config, &mut registry.counters, socket, from, &instance);
+            return;
+        }
+    }
+    let at_session_limit = registry.at_session_limit(config, key);
+    let at_capacity = config
+        .max_sessions
+        .is_some_and(|max| registry.sessions() >= max);
+    let spent = registry.is_spent(config);
+    // a member retrying its handshake holds no further session
+    let at_ip_limit = !registry.group_members.contains_key(from)
+        && config
+            .max_sessions_per_ip
+            .is_some_and(|max| registry.sessions_from_ip(from.ip()) >= max);
+    match registry.groups.get_mut(peer_secret) {
+        Some(group) if group.key != key => {
+            debug!(
+                "Aborting as the group with the same secret uses key '{}'",
+                group.key
+            );
+            registry.counters.rejected_key_mismatch += 1;
+            return;
+        }
+        Some(group) if group.members.len() >= config.max_group_members => {
+            debug!("Aborting as the group is full");
+            registry.counters.rejected_group_full += 1;
+            return;
+        }
+        _ if at_ip_limit => {
+            reject_ip_limit(config, &mut registry.counters, socket, from);
+            return;
+        }
+        Some(group) => {
+            group.join(*from, socket, cipher);
+            info!(
+                "Peer {from} joined a group (key '{key}'), now {} members.",
+                group.members.len()
+            );
+        }
+        None if at_session_limit => {
+            debug!("Aborting as key '{key}' reached its session limit");
+            registry.counters.rejected_session_limit += 1;
+            return;
+        }
+        None if at_capacity => {
+            debug!("Aborting as the relay reached its session limit");
+            registry.counters.rejected_max_sessions += 1;
+            reply_busy(config, socket, from);
+            return;
+        }
+        None if spent => {
+            debug!("Aborting as the relay served its last session");
+            registry.counters.rejected_max_total_sessions += 1;
+            reply_busy(config, socket, from);
+            return;
+        }
+        None => {
+            let mut group = Group::new(key);
+            group.join(*from, socket, cipher);
+            info!("Peer {from} created a group (key '{key}').");
+            if let Some(cluster) = &registry.cluster {
+                cluster.announce(config, peer_secret);
+            }
+            *registry
+                .counters
+                .pairings_by_key
+                .entry(key.to_owned())
+                .or_default() += 1;
+            registry.groups.insert(peer_secret.to_owned(), group);
+        }
+    }
+    registry.group_members.insert(*from, peer_secret.to_owned());
+    if let Some(log) = &mut registry.accounting {
+        log.joined(peer_secret, key, from);
+    }
+    send_to(
+        socket,
+        &Ops::Ack.message(config.protocol_magic, peer_secret),
+        from,
+    );
+}
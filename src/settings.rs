@@ -0,0 +1,340 @@
+//! Settings loaded from a TOML configuration file.
+//!
+//! ```toml
+//! port = 60017
+//! bind_ip = "::"
+//! preshared_key = "..."
+//! timeout_pairing = 90
+//! daemonize = true
+//! pid_file = "/run/udprelay.pid"
+//!
+//! [[keys]]
+//! name = "team-a"
+//! preshared_key = "..."
+//! max_sessions = 10
+//! ```
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use ipnet::IpNet;
+use serde::Deserialize;
+
+use crate::logfile::Rotation;
+use crate::relay::{NamedKey, SecretCollision};
+
+/// Contents of a configuration file. Every field is optional; whatever is
+/// missing falls back to the command line, environment or built-in defaults.
+/// Timeouts and intervals are given in seconds.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileConfig {
+    pub port: Option<u16>,
+    /// Further ports or ranges of ports, e.g. `["5000-5010"]`, see
+    /// [`Config::extra_binds`](crate::Config::extra_binds).
+    pub ports: Option<Vec<PortRange>>,
+    /// IP address or host name to bind to.
+    pub bind_ip: Option<String>,
+    /// Address family preferred when resolving host names.
+    pub prefer_family: Option<IpFamily>,
+    /// Second port peers are paired across, see [`Config::second_bind`](crate::Config::second_bind).
+    pub second_port: Option<u16>,
+    /// Port of the DTLS listener, see [`Config::dtls_bind`](crate::Config::dtls_bind).
+    pub dtls_port: Option<u16>,
+    pub dtls_certificate: Option<PathBuf>,
+    pub dtls_private_key: Option<PathBuf>,
+    /// Port exchanging announcements with the other instances of a cluster, see
+    /// [`Config::cluster_bind`](crate::Config::cluster_bind).
+    pub cluster_port: Option<u16>,
+    /// Cluster addresses of the other instances, as `host:port`.
+    pub cluster_peer: Option<Vec<String>>,
+    /// Address peers reach this instance at, as `host:port`.
+    pub cluster_advertise: Option<String>,
+    pub cluster_key: Option<String>,
+    /// Log filter, either a level (e.g. `info`) or `tracing` directives.
+    pub log_filter: Option<String>,
+    /// File logs are written to instead of stderr, see [`LogFile`](crate::logfile::LogFile).
+    pub log_file: Option<PathBuf>,
+    /// Size in bytes past which the log file is rotated.
+    pub log_max_size: Option<u64>,
+    pub log_rotation: Option<Rotation>,
+    /// Number of rotated log files kept.
+    pub log_keep: Option<usize>,
+    pub daemonize: Option<bool>,
+    pub pid_file: Option<PathBuf>,
+    pub port_file: Option<PathBuf>,
+    pub housekeeping_interval: Option<u64>,
+    pub timeout_no_connections: Option<u64>,
+    pub timeout_pairing: Option<u64>,
+    pub timeout_connection_inactivities: Option<u64>,
+    /// Target of the static forwarding mode, as `host:port`.
+    pub forward_to: Option<String>,
+    pub preshared_key: Option<String>,
+    /// File holding the pre-shared key, as an alternative to `preshared_key`.
+    pub preshared_key_file: Option<PathBuf>,
+    /// SHA-256 digest of the pre-shared key, as an alternative to `preshared_key`.
+    pub preshared_key_hash: Option<String>,
+    pub insecure_open: Option<bool>,
+    pub legacy_handshake: Option<bool>,
+    pub min_protocol_version: Option<u8>,
+    pub protocol_magic: Option<u8>,
+    pub encryption: Option<bool>,
+    pub allow_cidr: Option<Vec<IpNet>>,
+    pub deny_cidr: Option<Vec<IpNet>>,
+    pub session_resumption: Option<bool>,
+    pub session_rebind: Option<bool>,
+    pub pairing_codes: Option<bool>,
+    pub reliable: Option<bool>,
+    pub reliable_window: Option<u32>,
+    pub retransmit_interval: Option<u64>,
+    pub fec: Option<bool>,
+    pub fec_block_size: Option<u8>,
+    pub compression: Option<bool>,
+    pub channels: Option<bool>,
+    pub group: Option<bool>,
+    pub max_group_members: Option<usize>,
+    pub turn: Option<bool>,
+    pub turn_realm: Option<String>,
+    pub rate_limit_kbps: Option<u32>,
+    pub rate_limit_delay: Option<bool>,
+    pub chaos_loss: Option<f64>,
+    pub chaos_delay_ms: Option<u64>,
+    pub chaos_jitter_ms: Option<u64>,
+    pub max_sessions: Option<usize>,
+    pub max_pending_pairings: Option<usize>,
+    pub max_sessions_per_ip: Option<usize>,
+    pub secret_collision: Option<SecretCollision>,
+    pub max_total_sessions: Option<u64>,
+    pub one_shot: Option<bool>,
+    pub reply_busy: Option<bool>,
+    pub max_payload: Option<usize>,
+    pub reply_too_big: Option<bool>,
+    pub pairing_rate: Option<u32>,
+    pub pairing_burst: Option<u32>,
+    pub ban_after: Option<u32>,
+    pub ban_duration: Option<u64>,
+    pub keepalive_interval: Option<u64>,
+    pub idle_warning: Option<u64>,
+    pub rtt_interval: Option<u64>,
+    pub max_send_failures: Option<u32>,
+    pub metrics_listen: Option<SocketAddr>,
+    pub health_listen: Option<SocketAddr>,
+    pub control_socket: Option<PathBuf>,
+    pub accounting_log: Option<PathBuf>,
+    pub on_pair: Option<String>,
+    pub on_teardown: Option<String>,
+    pub webhook_url: Option<String>,
+    pub pcap: Option<PathBuf>,
+    pub pcap_handshake_only: Option<bool>,
+    pub drain_timeout: Option<u64>,
+    pub workers: Option<usize>,
+    pub io_uring: Option<bool>,
+    pub so_rcvbuf: Option<usize>,
+    pub so_sndbuf: Option<usize>,
+    pub recv_buffer_size: Option<usize>,
+    pub interface: Option<String>,
+    pub dscp: Option<u8>,
+    /// Named pre-shared keys accepted besides `preshared_key`.
+    #[serde(default)]
+    pub keys: Vec<FileKey>,
+}
+
+/// A `[[keys]]` entry of the configuration file, see [`NamedKey`].
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileKey {
+    pub name: String,
+    pub preshared_key: String,
+    pub max_sessions: Option<usize>,
+    pub timeout_pairing: Option<u64>,
+    pub timeout_connection_inactivities: Option<u64>,
+    pub allow_cidr: Option<Vec<IpNet>>,
+    pub rate_limit_kbps: Option<u32>,
+    pub secret_collision: Option<SecretCollision>,
+}
+
+impl From<FileKey> for NamedKey {
+    fn from(key: FileKey) -> NamedKey {
+        NamedKey {
+            name: key.name,
+            preshared_key: key.preshared_key.into(),
+            max_sessions: key.max_sessions,
+            timeout_pairing: key.timeout_pairing.map(Duration::from_secs),
+            timeout_connection_inactivities: key
+                .timeout_connection_inactivities
+                .map(Duration::from_secs),
+            allow_cidrs: key.allow_cidr.unwrap_or_default(),
+            rate_limit_kbps: key.rate_limit_kbps,
+            secret_collision: key.secret_collision,
+        }
+    }
+}
+
+/// Address family preferred when a host name resolves to both.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IpFamily {
+    Ipv4,
+    Ipv6,
+}
+
+impl IpFamily {
+    fn contains(self, addr: &SocketAddr) -> bool {
+        match self {
+            IpFamily::Ipv4 => addr.is_ipv4(),
+            IpFamily::Ipv6 => addr.is_ipv6(),
+        }
+    }
+}
+
+impl FromStr for IpFamily {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<IpFamily, String> {
+        match s {
+            "ipv4" => Ok(IpFamily::Ipv4),
+            "ipv6" => Ok(IpFamily::Ipv6),
+            _ => Err(format!(
+                "unknown address family '{s}', expected ipv4 or ipv6"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for IpFamily {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            IpFamily::Ipv4 => "ipv4",
+            IpFamily::Ipv6 => "ipv6",
+        })
+    }
+}
+
+/// The first of `addrs` of the `prefer`red family, or the first of them if
+/// there is none.
+pub fn pick(
+    addrs: impl IntoIterator<Item = SocketAddr>,
+    prefer: Option<IpFamily>,
+) -> Option<SocketAddr> {
+    let addrs: Vec<SocketAddr> = addrs.into_iter().collect();
+    prefer
+        .and_then(|family| addrs.iter().find(|addr| family.contains(addr)))
+        .or(addrs.first())
+        .copied()
+}
+
+/// A port (`5000`) or an inclusive range of ports (`5000-5010`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct PortRange {
+    first: u16,
+    last: u16,
+}
+
+impl PortRange {
+    pub fn ports(self) -> RangeInclusive<u16> {
+        self.first..=self.last
+    }
+}
+
+impl FromStr for PortRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<PortRange, String> {
+        let (first, last) = s.split_once('-').unwrap_or((s, s));
+        let parse = |port: &str| {
+            port.trim()
+                .parse::<u16>()
+                .ok()
+                .filter(|&port| port != 0)
+                .ok_or_else(|| format!("invalid port '{port}' in '{s}'"))
+        };
+        let (first, last) = (parse(first)?, parse(last)?);
+        if first > last {
+            return Err(format!("empty port range '{s}'"));
+        }
+        Ok(PortRange { first, last })
+    }
+}
+
+impl TryFrom<String> for PortRange {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<PortRange, String> {
+        s.parse()
+    }
+}
+
+#[derive(Debug)]
+pub enum SettingsError {
+    Io(PathBuf, io::Error),
+    Parse(PathBuf, toml::de::Error),
+    /// A `host:port` setting that cannot be resolved.
+    Resolve(String, io::Error),
+    /// A pre-shared key hash that is not a SHA-256 digest.
+    PskHash(String),
+}
+
+impl fmt::Display for SettingsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SettingsError::Io(path, e) => write!(f, "cannot read {}: {}", path.display(), e),
+            SettingsError::Resolve(host, e) => write!(f, "cannot resolve {}: {}", host, e),
+            SettingsError::Parse(path, e) => write!(f, "cannot parse {}: {}", path.display(), e),
+            SettingsError::PskHash(hash) => write!(
+                f,
+                "invalid pre-shared key hash {}: expected 64 hexadecimal digits",
+                hash
+            ),
+        }
+    }
+}
+
+impl std::error::Error for SettingsError {}
+
+/// Resolves a `host:port` setting to its first address, of the `prefer`red
+/// family if any.
+pub fn resolve(host: &str, prefer: Option<IpFamily>) -> Result<SocketAddr, SettingsError> {
+    host.to_socket_addrs()
+        .and_then(|addrs| {
+            pick(addrs, prefer)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no address found"))
+        })
+        .map_err(|e| SettingsError::Resolve(host.to_owned(), e))
+}
+
+/// Resolves an IP address or host name setting, like [`resolve`].
+pub fn resolve_ip(host: &str, prefer: Option<IpFamily>) -> Result<IpAddr, SettingsError> {
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    match host.parse() {
+        Ok(ip) => Ok(ip),
+        Err(_) => (host, 0)
+            .to_socket_addrs()
+            .and_then(|addrs| {
+                pick(addrs, prefer)
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no address found"))
+            })
+            .map(|addr| addr.ip())
+            .map_err(|e| SettingsError::Resolve(host.to_owned(), e)),
+    }
+}
+
+/// Reads a pre-shared key from `path`, ignoring a trailing newline.
+pub fn read_key_file(path: &Path) -> Result<String, SettingsError> {
+    let content = fs::read_to_string(path).map_err(|e| SettingsError::Io(path.to_owned(), e))?;
+    Ok(content.trim_end_matches(['\r', '\n']).to_owned())
+}
+
+impl FileConfig {
+    pub fn load(path: &Path) -> Result<FileConfig, SettingsError> {
+        let content =
+            fs::read_to_string(path).map_err(|e| SettingsError::Io(path.to_owned(), e))?;
+        toml::from_str(&content).map_err(|e| SettingsError::Parse(path.to_owned(), e))
+    }
+}
@@ -0,0 +1,96 @@
+//! Integration with systemd: socket activation and readiness notification.
+//!
+//! When started by a socket unit, the relay serves the datagram socket systemd
+//! passed (see `sd_listen_fds(3)`) instead of binding its own, see
+//! [`activated_socket`]. Under a `Type=notify` service, [`notify`] tells systemd
+//! when the relay is ready or stopping, and [`watchdog_interval`] how often to
+//! ping its watchdog (see `sd_notify(3)`). Outside of systemd, the environment
+//! variables these rely on are unset and every function does nothing.
+
+use std::env;
+use std::io;
+use std::net::UdpSocket;
+use std::os::fd::{FromRawFd, OwnedFd, RawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::UnixDatagram;
+use std::time::Duration;
+
+use socket2::{Socket, Type};
+
+/// First file descriptor passed by systemd.
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Whether a variable naming a PID (e.g. `LISTEN_PID`) is unset or names this process.
+fn is_for_us(pid_var: &str) -> bool {
+    env::var(pid_var).map_or(true, |pid| pid == std::process::id().to_string())
+}
+
+/// Takes the UDP socket systemd passed to this process, if it was socket
+/// activated, and clears the variables announcing it so that they are not
+/// inherited. Only the first passed socket is used; it must be a datagram socket.
+pub fn activated_socket() -> io::Result<Option<UdpSocket>> {
+    let Ok(fds) = env::var("LISTEN_FDS") else {
+        return Ok(None);
+    };
+    if env::var_os("LISTEN_PID").is_none() || !is_for_us("LISTEN_PID") {
+        return Ok(None);
+    }
+    for var in ["LISTEN_FDS", "LISTEN_PID", "LISTEN_FDNAMES"] {
+        env::remove_var(var);
+    }
+    let fds: RawFd = fds
+        .parse()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid LISTEN_FDS"))?;
+    if fds < 1 {
+        return Ok(None);
+    }
+    // SAFETY: systemd hands the descriptors from LISTEN_FDS_START on over to this
+    // process, and nothing else claims them as the variables were just cleared
+    let socket = Socket::from(unsafe { OwnedFd::from_raw_fd(LISTEN_FDS_START) });
+    if socket.r#type()? != Type::DGRAM {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "the socket passed by systemd is not a datagram socket",
+        ));
+    }
+    socket.set_nonblocking(true)?;
+    Ok(Some(socket.into()))
+}
+
+/// Sends `state` (e.g. `READY=1`) to the service manager, if it expects
+/// notifications.
+pub fn notify(state: &str) -> io::Result<()> {
+    let Some(path) = env::var_os("NOTIFY_SOCKET") else {
+        return Ok(());
+    };
+    let socket = UnixDatagram::unbound()?;
+    match path.as_bytes().strip_prefix(b"@") {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            socket.send_to_addr(state.as_bytes(), &addr)?;
+        }
+        #[cfg(not(target_os = "linux"))]
+        Some(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "abstract notification sockets are only supported on Linux",
+            ))
+        }
+        None => {
+            socket.send_to(state.as_bytes(), &path)?;
+        }
+    }
+    Ok(())
+}
+
+/// How often to send `WATCHDOG=1`, half the watchdog timeout of the service,
+/// if it has one.
+pub fn watchdog_interval() -> Option<Duration> {
+    if !is_for_us("WATCHDOG_PID") {
+        return None;
+    }
+    let usec: u64 = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    (usec > 0).then(|| Duration::from_micros(usec / 2))
+}
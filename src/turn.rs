@@ -0,0 +1,669 @@
+//! TURN-lite: a minimal subset of TURN (RFC 8656) over UDP, so that
+//! WebRTC-style clients can use the relay as a lightweight TURN server.
+//!
+//! STUN messages are served on the relay port, told apart from the relay's own
+//! messages by their magic cookie. Binding requests, Allocate, Refresh and
+//! CreatePermission requests, and Send and Data indications are supported;
+//! channels, TCP and IPv6 allocations, and FINGERPRINT checks are not.
+//!
+//! Requests other than Binding authenticate with long-term credentials: the
+//! username is the name of a key ([`DEFAULT_KEY_NAME`](crate::DEFAULT_KEY_NAME)
+//! for the main one), the password is the pre-shared key, and the realm is
+//! [`Config::turn_realm`](crate::Config::turn_realm). Nonces are issued like
+//! handshake challenges, and go stale after
+//! [`CHALLENGE_LIFETIME`](crate::auth::CHALLENGE_LIFETIME).
+//!
+//! Each allocation gets its own UDP socket, the relayed address. Allocations
+//! count as sessions against `max_sessions` and the limits of their key, and
+//! are swept by the housekeeping once their lifetime runs out.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+use hmac::{Hmac, KeyInit, Mac};
+use md5::{Digest, Md5};
+use sha1::Sha1;
+use socket2::SockRef;
+use tokio::net::UdpSocket;
+use tokio::task::AbortHandle;
+use tracing::{debug, info, trace, warn};
+
+use crate::peer::{send_to, ExpiringTimer, Side};
+use crate::relay::{bind_udp_socket, Config, SharedConfig};
+use crate::service::RelayService;
+
+type HmacSha1 = Hmac<Sha1>;
+
+const MAGIC_COOKIE: u32 = 0x2112_A442;
+const HEADER_LEN: usize = 20;
+const TRANSACTION_ID_LEN: usize = 12;
+/// Length of a MESSAGE-INTEGRITY attribute, header included.
+const INTEGRITY_LEN: usize = 4 + 20;
+
+// methods
+const BINDING: u16 = 0x001;
+const ALLOCATE: u16 = 0x003;
+const REFRESH: u16 = 0x004;
+const SEND: u16 = 0x006;
+const DATA: u16 = 0x007;
+const CREATE_PERMISSION: u16 = 0x008;
+
+// classes, as bits of the message type
+const REQUEST: u16 = 0x000;
+const INDICATION: u16 = 0x010;
+const SUCCESS: u16 = 0x100;
+const ERROR: u16 = 0x110;
+
+// attributes
+const USERNAME: u16 = 0x0006;
+const MESSAGE_INTEGRITY: u16 = 0x0008;
+const ERROR_CODE: u16 = 0x0009;
+const LIFETIME: u16 = 0x000D;
+const XOR_PEER_ADDRESS: u16 = 0x0012;
+const DATA_VALUE: u16 = 0x0013;
+const REALM: u16 = 0x0014;
+const NONCE: u16 = 0x0015;
+const XOR_RELAYED_ADDRESS: u16 = 0x0016;
+const REQUESTED_TRANSPORT: u16 = 0x0019;
+const XOR_MAPPED_ADDRESS: u16 = 0x0020;
+
+const UDP_TRANSPORT: u8 = 17;
+
+/// Lifetime of an allocation that did not ask for a longer one.
+pub const DEFAULT_ALLOCATION_LIFETIME: Duration = Duration::from_secs(600);
+/// Longest lifetime granted to an allocation.
+pub const MAX_ALLOCATION_LIFETIME: Duration = Duration::from_secs(3600);
+/// How long a permission lets a peer send to an allocation.
+pub const PERMISSION_LIFETIME: Duration = Duration::from_secs(300);
+
+/// Whether `buffer` is a STUN message rather than a relay message.
+pub(crate) fn is_stun(buffer: &[u8]) -> bool {
+    buffer.len() >= HEADER_LEN
+        && buffer[0] & 0xc0 == 0
+        && buffer[4..8] == MAGIC_COOKIE.to_be_bytes()
+        && usize::from(u16::from_be_bytes([buffer[2], buffer[3]])) + HEADER_LEN == buffer.len()
+}
+
+struct Attribute<'a> {
+    kind: u16,
+    value: &'a [u8],
+    /// Offset of the attribute header in the message.
+    offset: usize,
+}
+
+struct Message<'a> {
+    method: u16,
+    class: u16,
+    transaction: &'a [u8],
+    attributes: Vec<Attribute<'a>>,
+    raw: &'a [u8],
+}
+
+impl<'a> Message<'a> {
+    fn parse(raw: &'a [u8]) -> Option<Message<'a>> {
+        let kind = u16::from_be_bytes([raw[0], raw[1]]);
+        let mut attributes = Vec::new();
+        let mut offset = HEADER_LEN;
+        while offset < raw.len() {
+            let header = raw.get(offset..offset + 4)?;
+            let len = usize::from(u16::from_be_bytes([header[2], header[3]]));
+            attributes.push(Attribute {
+                kind: u16::from_be_bytes([header[0], header[1]]),
+                value: raw.get(offset + 4..offset + 4 + len)?,
+                offset,
+            });
+            offset += 4 + len.next_multiple_of(4);
+        }
+        Some(Message {
+            // methods above 0xf, interleaved with the class bits, are not supported
+            method: kind & !ERROR,
+            class: kind & ERROR,
+            transaction: &raw[8..HEADER_LEN],
+            attributes,
+            raw,
+        })
+    }
+
+    fn attribute(&self, kind: u16) -> Option<&'a [u8]> {
+        self.attributes
+            .iter()
+            .find(|attribute| attribute.kind == kind)
+            .map(|attribute| attribute.value)
+    }
+
+    /// Checks the MESSAGE-INTEGRITY attribute against `key`.
+    fn is_authentic(&self, key: &[u8]) -> bool {
+        let Some(integrity) = self
+            .attributes
+            .iter()
+            .find(|attribute| attribute.kind == MESSAGE_INTEGRITY)
+        else {
+            return false;
+        };
+        // the MAC covers the message up to the attribute, with a length ending after it
+        let mut signed = self.raw[..integrity.offset].to_vec();
+        let len = (integrity.offset + INTEGRITY_LEN - HEADER_LEN) as u16;
+        signed[2..4].copy_from_slice(&len.to_be_bytes());
+        let mut mac = HmacSha1::new_from_slice(key).expect("HMAC can take key of any size");
+        mac.update(&signed);
+        mac.verify_slice(integrity.value).is_ok()
+    }
+}
+
+struct MessageBuilder {
+    buf: Vec<u8>,
+}
+
+impl MessageBuilder {
+    fn new(method: u16, class: u16, transaction: &[u8]) -> MessageBuilder {
+        let mut buf = Vec::with_capacity(128);
+        buf.extend_from_slice(&(method | class).to_be_bytes());
+        buf.extend_from_slice(&[0, 0]);
+        buf.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+        buf.extend_from_slice(transaction);
+        MessageBuilder { buf }
+    }
+
+    fn attribute(mut self, kind: u16, value: &[u8]) -> MessageBuilder {
+        self.buf.extend_from_slice(&kind.to_be_bytes());
+        self.buf
+            .extend_from_slice(&(value.len() as u16).to_be_bytes());
+        self.buf.extend_from_slice(value);
+        self.buf.resize(self.buf.len().next_multiple_of(4), 0);
+        let len = (self.buf.len() - HEADER_LEN) as u16;
+        self.buf[2..4].copy_from_slice(&len.to_be_bytes());
+        self
+    }
+
+    fn address(self, kind: u16, addr: &SocketAddr) -> MessageBuilder {
+        let transaction = self.buf[8..HEADER_LEN].to_vec();
+        self.attribute(kind, &xor_address(addr, &transaction))
+    }
+
+    fn error(self, code: u16, reason: &str) -> MessageBuilder {
+        let value = [
+            &[0, 0, (code / 100) as u8, (code % 100) as u8][..],
+            reason.as_bytes(),
+        ]
+        .concat();
+        self.attribute(ERROR_CODE, &value)
+    }
+
+    /// Appends MESSAGE-INTEGRITY keyed with `key`, which must come last.
+    fn sign(mut self, key: &[u8]) -> Vec<u8> {
+        let len = (self.buf.len() + INTEGRITY_LEN - HEADER_LEN) as u16;
+        self.buf[2..4].copy_from_slice(&len.to_be_bytes());
+        let mut mac = HmacSha1::new_from_slice(key).expect("HMAC can take key of any size");
+        mac.update(&self.buf);
+        let mac = mac.finalize().into_bytes();
+        self.attribute(MESSAGE_INTEGRITY, &mac).buf
+    }
+
+    fn finish(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+fn xor_address(addr: &SocketAddr, transaction: &[u8]) -> Vec<u8> {
+    let port = (addr.port() ^ (MAGIC_COOKIE >> 16) as u16).to_be_bytes();
+    match addr.ip().to_canonical() {
+        IpAddr::V4(ip) => {
+            let ip = (u32::from(ip) ^ MAGIC_COOKIE).to_be_bytes();
+            [&[0, 1][..], &port, &ip].concat()
+        }
+        IpAddr::V6(ip) => {
+            let mask = [&MAGIC_COOKIE.to_be_bytes()[..], transaction].concat();
+            let ip: Vec<u8> = ip.octets().iter().zip(&mask).map(|(a, b)| a ^ b).collect();
+            [&[0, 2][..], &port, &ip].concat()
+        }
+    }
+}
+
+fn parse_xor_address(value: &[u8], transaction: &[u8]) -> Option<SocketAddr> {
+    let port = u16::from_be_bytes(value.get(2..4)?.try_into().ok()?) ^ (MAGIC_COOKIE >> 16) as u16;
+    let ip: IpAddr = match value.get(1)? {
+        1 => {
+            let ip = u32::from_be_bytes(value.get(4..8)?.try_into().ok()?);
+            Ipv4Addr::from(ip ^ MAGIC_COOKIE).into()
+        }
+        2 => {
+            let mask = [&MAGIC_COOKIE.to_be_bytes()[..], transaction].concat();
+            let octets: [u8; 16] = value.get(4..20)?.try_into().ok()?;
+            let octets: Vec<u8> = octets.iter().zip(&mask).map(|(a, b)| a ^ b).collect();
+            Ipv6Addr::from(<[u8; 16]>::try_from(octets).ok()?).into()
+        }
+        _ => return None,
+    };
+    Some(SocketAddr::new(ip, port))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn from_hex(hex: &[u8]) -> Option<Vec<u8>> {
+    hex.chunks(2)
+        .map(|pair| u8::from_str_radix(str::from_utf8(pair).ok()?, 16).ok())
+        .collect()
+}
+
+/// A relayed address granted to a TURN client.
+#[derive(Debug)]
+pub(crate) struct Allocation {
+    relayed: Arc<UdpSocket>,
+    /// Name of the key the client authenticated with.
+    pub(crate) key: String,
+    /// Transaction of the Allocate request, answered again if retransmitted.
+    transaction: Vec<u8>,
+    lifetime: Duration,
+    refreshed: ExpiringTimer,
+    /// Peers allowed to send to the relayed address, by IP.
+    permissions: HashMap<IpAddr, ExpiringTimer>,
+    relay_task: AbortHandle,
+}
+
+impl Drop for Allocation {
+    fn drop(&mut self) {
+        self.relay_task.abort();
+    }
+}
+
+impl Allocation {
+    fn permits(&self, ip: IpAddr) -> bool {
+        self.permissions
+            .get(&ip.to_canonical())
+            .is_some_and(|timer| !timer.is_expired(PERMISSION_LIFETIME))
+    }
+
+    /// Whether the allocation outlived its lifetime, dropping expired permissions otherwise.
+    pub(crate) fn expire(&mut self) -> bool {
+        self.permissions
+            .retain(|_, timer| !timer.is_expired(PERMISSION_LIFETIME));
+        self.refreshed.is_expired(self.lifetime)
+    }
+}
+
+/// Answers a STUN message from `from`.
+pub(crate) fn process(
+    config: &Config,
+    registry: &mut RelayService,
+    socket: &Arc<UdpSocket>,
+    buffer: &[u8],
+    from: &SocketAddr,
+) {
+    let Some(message) = Message::parse(buffer) else {
+        debug!("Dropping malformed STUN message from {from}");
+        return;
+    };
+    match (message.method, message.class) {
+        (BINDING, REQUEST) => {
+            let response = MessageBuilder::new(BINDING, SUCCESS, message.transaction)
+                .address(XOR_MAPPED_ADDRESS, from)
+                .finish();
+            send_to(socket, &response, from);
+        }
+        (SEND, INDICATION) => relay_to_peer(config, registry, &message, from),
+        (ALLOCATE | REFRESH | CREATE_PERMISSION, REQUEST) => {
+            let Some((name, key)) = authenticate(config, registry, socket, &message, from) else {
+                return;
+            };
+            let response = match message.method {
+                ALLOCATE => allocate(config, registry, socket, &message, from, &name),
+                REFRESH => refresh(registry, &message, from, &name),
+                _ => create_permission(config, registry, &message, from, &name),
+            };
+            send_to(socket, &response.sign(&key), from);
+        }
+        (method, REQUEST) => {
+            let response = MessageBuilder::new(method, ERROR, message.transaction)
+                .error(400, "Bad Request")
+                .finish();
+            send_to(socket, &response, from);
+        }
+        _ => trace!("Ignoring STUN message from {from}"),
+    }
+}
+
+/// Checks the long-term credentials of a request, returning the name of the key
+/// it authenticated with and the derived HMAC key, or answering with an error.
+fn authenticate(
+    config: &Config,
+    registry: &mut RelayService,
+    socket: &Arc<UdpSocket>,
+    message: &Message,
+    from: &SocketAddr,
+) -> Option<(String, [u8; 16])> {
+    let challenge = |code, reason| {
+        let nonce = to_hex(&registry.challenger.issue(from));
+        MessageBuilder::new(message.method, ERROR, message.transaction)
+            .error(code, reason)
+            .attribute(REALM, config.turn_realm.as_bytes())
+            .attribute(NONCE, nonce.as_bytes())
+            .finish()
+    };
+    let (Some(username), Some(nonce)) = (message.attribute(USERNAME), message.attribute(NONCE))
+    else {
+        debug!("Challenging TURN request from {from}");
+        send_to(socket, &challenge(401, "Unauthorized"), from);
+        return None;
+    };
+    if !from_hex(nonce).is_some_and(|nonce| registry.challenger.is_valid(&nonce, from)) {
+        debug!("Rejecting TURN request from {from} with a stale nonce");
+        send_to(socket, &challenge(438, "Stale Nonce"), from);
+        return None;
+    }
+    let authenticated = str::from_utf8(username).ok().and_then(|name| {
+        let psk = config.psk(name)?;
+        let key: [u8; 16] = Md5::digest(format!("{name}:{}:{psk}", config.turn_realm)).into();
+        message.is_authentic(&key).then(|| (name.to_owned(), key))
+    });
+    if authenticated.is_none() {
+        debug!("Aborting as the TURN credentials of {from} do not match");
+        registry.counters.rejected_bad_psk += 1;
+        send_to(socket, &challenge(401, "Unauthorized"), from);
+    }
+    authenticated
+}
+
+/// IPv4 address of this host on the route to `client`, to advertise as relayed address.
+fn local_ip_towards(client: &SocketAddr) -> io::Result<Ipv4Addr> {
+    let IpAddr::V4(ip) = client.ip().to_canonical() else {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "IPv6 clients are not supported",
+        ));
+    };
+    let probe = std::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    // connecting a UDP socket only looks up the route, sending nothing
+    probe.connect((ip, client.port()))?;
+    match probe.local_addr()?.ip() {
+        IpAddr::V4(ip) => Ok(ip),
+        IpAddr::V6(_) => unreachable!("socket is bound to an IPv4 address"),
+    }
+}
+
+/// Opens a relayed address on the address and interface the relay is bound to.
+fn bind_relayed_socket(config: &Config) -> io::Result<UdpSocket> {
+    let addr = SocketAddr::new(config.bind.ip(), 0);
+    UdpSocket::from_std(bind_udp_socket(addr, false, config.interface.as_deref())?)
+}
+
+/// Address advertised for `relayed`: its own, or `ip` when it is bound to all addresses.
+fn relayed_address(relayed: &UdpSocket, ip: Ipv4Addr) -> io::Result<SocketAddr> {
+    let local = relayed.local_addr()?;
+    Ok(match local.ip().to_canonical() {
+        bound if bound.is_unspecified() => SocketAddr::new(ip.into(), local.port()),
+        bound => SocketAddr::new(bound, local.port()),
+    })
+}
+
+/// Whether clients may exchange datagrams with `peer` through their allocation:
+/// never the relay host itself, nor an address outside the allowed peer ranges.
+fn is_reachable(config: &Config, peer: &SocketAddr) -> bool {
+    let ip = peer.ip().to_canonical();
+    !ip.is_loopback() && !ip.is_unspecified() && config.is_peer_allowed(ip)
+}
+
+fn allocate(
+    config: &Config,
+    registry: &mut RelayService,
+    socket: &Arc<UdpSocket>,
+    message: &Message,
+    from: &SocketAddr,
+    key: &str,
+) -> MessageBuilder {
+    let error = |code, reason| {
+        MessageBuilder::new(ALLOCATE, ERROR, message.transaction).error(code, reason)
+    };
+    let lifetime = message
+        .attribute(LIFETIME)
+        .and_then(|value| Some(u32::from_be_bytes(value.try_into().ok()?)))
+        .map_or(DEFAULT_ALLOCATION_LIFETIME, |secs| {
+            Duration::from_secs(secs.into())
+        })
+        .clamp(DEFAULT_ALLOCATION_LIFETIME, MAX_ALLOCATION_LIFETIME);
+    let success = |relayed: &SocketAddr, lifetime: Duration| {
+        MessageBuilder::new(ALLOCATE, SUCCESS, message.transaction)
+            .address(XOR_RELAYED_ADDRESS, relayed)
+            .attribute(LIFETIME, &(lifetime.as_secs() as u32).to_be_bytes())
+            .address(XOR_MAPPED_ADDRESS, from)
+    };
+
+    if let Some(allocation) = registry.allocations.get(from) {
+        if allocation.transaction != message.transaction {
+            return error(437, "Allocation Mismatch");
+        }
+        // a retransmission, as the client missed the answer
+        return match local_ip_towards(from).and_then(|ip| relayed_address(&allocation.relayed, ip))
+        {
+            Ok(relayed) => success(&relayed, allocation.lifetime),
+            Err(_) => error(500, "Server Error"),
+        };
+    }
+    match message.attribute(REQUESTED_TRANSPORT) {
+        Some([UDP_TRANSPORT, ..]) => (),
+        Some(_) => return error(442, "Unsupported Transport Protocol"),
+        None => return error(400, "Bad Request"),
+    }
+    if !registry.rate_limiter.check(config, from.ip()) {
+        registry.counters.rate_limited += 1;
+        return error(486, "Allocation Quota Reached");
+    }
+    if registry.at_session_limit(config, key) {
+        debug!("Aborting as key '{key}' reached its session limit");
+        registry.counters.rejected_session_limit += 1;
+        return error(486, "Allocation Quota Reached");
+    }
+    if config
+        .max_sessions
+        .is_some_and(|max| registry.sessions() >= max)
+    {
+        debug!("Aborting as the relay reached its session limit");
+        registry.counters.rejected_max_sessions += 1;
+        return error(486, "Allocation Quota Reached");
+    }
+    let ip = match local_ip_towards(from) {
+        Ok(ip) => ip,
+        Err(e) => {
+            debug!("Cannot allocate for {from}: {e}");
+            return error(440, "Address Family not Supported");
+        }
+    };
+    let relayed = match bind_relayed_socket(config) {
+        Ok(relayed) => Arc::new(relayed),
+        Err(e) => {
+            warn!("Cannot open relayed socket for {from}: {e}");
+            return error(508, "Insufficient Capacity");
+        }
+    };
+    let relayed_addr = match relayed_address(&relayed, ip) {
+        Ok(addr) => addr,
+        Err(e) => {
+            warn!("Cannot open relayed socket for {from}: {e}");
+            return error(508, "Insufficient Capacity");
+        }
+    };
+    let relay_task = tokio::spawn(relay_from_peers(
+        registry.handle.clone(),
+        registry.config.clone(),
+        socket.clone(),
+        relayed.clone(),
+        *from,
+        config.recv_buffer_size,
+    ));
+    info!("Allocated {relayed_addr} for {from} (key '{key}')");
+    registry.allocations.insert(
+        *from,
+        Allocation {
+            relayed,
+            key: key.to_owned(),
+            transaction: message.transaction.to_vec(),
+            lifetime,
+            refreshed: ExpiringTimer::new(),
+            permissions: HashMap::new(),
+            relay_task: relay_task.abort_handle(),
+        },
+    );
+    success(&relayed_addr, lifetime)
+}
+
+fn refresh(
+    registry: &mut RelayService,
+    message: &Message,
+    from: &SocketAddr,
+    key: &str,
+) -> MessageBuilder {
+    let Some(allocation) = registry
+        .allocations
+        .get_mut(from)
+        .filter(|allocation| allocation.key == key)
+    else {
+        return MessageBuilder::new(REFRESH, ERROR, message.transaction)
+            .error(437, "Allocation Mismatch");
+    };
+    let requested = message
+        .attribute(LIFETIME)
+        .and_then(|value| Some(u32::from_be_bytes(value.try_into().ok()?)));
+    let lifetime = match requested {
+        Some(0) => {
+            info!("Released TURN allocation of {from}");
+            registry.allocations.remove(from);
+            Duration::ZERO
+        }
+        requested => {
+            allocation.lifetime = requested
+                .map_or(DEFAULT_ALLOCATION_LIFETIME, |secs| {
+                    Duration::from_secs(secs.into())
+                })
+                .clamp(DEFAULT_ALLOCATION_LIFETIME, MAX_ALLOCATION_LIFETIME);
+            allocation.refreshed.access();
+            allocation.lifetime
+        }
+    };
+    MessageBuilder::new(REFRESH, SUCCESS, message.transaction)
+        .attribute(LIFETIME, &(lifetime.as_secs() as u32).to_be_bytes())
+}
+
+fn create_permission(
+    config: &Config,
+    registry: &mut RelayService,
+    message: &Message,
+    from: &SocketAddr,
+    key: &str,
+) -> MessageBuilder {
+    let Some(allocation) = registry
+        .allocations
+        .get_mut(from)
+        .filter(|allocation| allocation.key == key)
+    else {
+        return MessageBuilder::new(CREATE_PERMISSION, ERROR, message.transaction)
+            .error(437, "Allocation Mismatch");
+    };
+    let peers: Option<Vec<SocketAddr>> = message
+        .attributes
+        .iter()
+        .filter(|attribute| attribute.kind == XOR_PEER_ADDRESS)
+        .map(|attribute| parse_xor_address(attribute.value, message.transaction))
+        .collect();
+    let Some(peers) = peers.filter(|peers| !peers.is_empty()) else {
+        return MessageBuilder::new(CREATE_PERMISSION, ERROR, message.transaction)
+            .error(400, "Bad Request");
+    };
+    if let Some(peer) = peers.iter().find(|peer| !is_reachable(config, peer)) {
+        debug!(
+            "Refusing to let {} reach the allocation of {from}",
+            peer.ip()
+        );
+        return MessageBuilder::new(CREATE_PERMISSION, ERROR, message.transaction)
+            .error(403, "Forbidden");
+    }
+    for peer in peers {
+        debug!("Permitting {} to reach the allocation of {from}", peer.ip());
+        allocation
+            .permissions
+            .insert(peer.ip().to_canonical(), ExpiringTimer::new());
+    }
+    MessageBuilder::new(CREATE_PERMISSION, SUCCESS, message.transaction)
+}
+
+/// Sends the data of a Send indication from the relayed address of `from`.
+fn relay_to_peer(
+    config: &Config,
+    registry: &mut RelayService,
+    message: &Message,
+    from: &SocketAddr,
+) {
+    let Some(allocation) = registry.allocations.get(from) else {
+        trace!("Dropping Send indication from {from} without allocation");
+        return;
+    };
+    let peer = message
+        .attribute(XOR_PEER_ADDRESS)
+        .and_then(|value| parse_xor_address(value, message.transaction));
+    let (Some(peer), Some(data)) = (peer, message.attribute(DATA_VALUE)) else {
+        trace!("Dropping malformed Send indication from {from}");
+        return;
+    };
+    if !allocation.permits(peer.ip()) || !is_reachable(config, &peer) {
+        trace!("Dropping Send indication from {from} to {peer} without permission");
+        return;
+    }
+    match SockRef::from(&*allocation.relayed).send_to(data, &peer.into()) {
+        Ok(_) => (),
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock => (),
+        Err(e) => debug!("Cannot relay datagram from {from} to {peer}: {e}"),
+    }
+    registry.counters.relayed_packets[Side::First as usize] += 1;
+    registry.counters.relayed_bytes[Side::First as usize] += data.len() as u64;
+    trace!("Relaying TURN datagram {from} => {peer}");
+}
+
+/// Wraps datagrams arriving on the relayed address of `client` in Data
+/// indications, for the peers it gave permission to.
+async fn relay_from_peers(
+    registry: Weak<Mutex<RelayService>>,
+    config: SharedConfig,
+    socket: Arc<UdpSocket>,
+    relayed: Arc<UdpSocket>,
+    client: SocketAddr,
+    buf_size: usize,
+) {
+    let mut buf = vec![0u8; buf_size];
+    loop {
+        let (n, peer) = match relayed.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(e) => {
+                debug!("Cannot receive datagram for {client}: {e}");
+                continue;
+            }
+        };
+        if !is_reachable(&config.borrow(), &peer) {
+            trace!("Dropping datagram from disallowed peer {peer} to {client}");
+            continue;
+        }
+        let Some(registry) = registry.upgrade() else {
+            return;
+        };
+        let mut service = registry.lock().expect("Registry lock poisoned");
+        let Some(allocation) = service.allocations.get(&client) else {
+            return;
+        };
+        if !allocation.permits(peer.ip()) {
+            trace!("Dropping datagram from {peer} without permission to reach {client}");
+            continue;
+        }
+        let transaction: [u8; TRANSACTION_ID_LEN] = rand::random();
+        let indication = MessageBuilder::new(DATA, INDICATION, &transaction)
+            .address(XOR_PEER_ADDRESS, &peer)
+            .attribute(DATA_VALUE, &buf[..n])
+            .finish();
+        service.counters.relayed_packets[Side::Second as usize] += 1;
+        service.counters.relayed_bytes[Side::Second as usize] += n as u64;
+        send_to(&socket, &indication, &client);
+        trace!("Relaying TURN datagram {peer} => {client}");
+    }
+}
@@ -0,0 +1,628 @@
+//! Relay loop on io_uring for Linux, enabled with
+//! [`Config::io_uring`](crate::Config::io_uring) in builds with the `io-uring`
+//! feature.
+//!
+//! Each relay socket is served by a ring on a thread of its own instead of a
+//! task of the portable loop:
+//!
+//! - a single multishot `recvmsg` receives datagrams straight into a ring of
+//!   buffers provided to the kernel, so that receiving takes neither a system
+//!   call per batch nor any re-arming per datagram;
+//! - a batch (whatever was received meanwhile, up to [`BUFFERS`] datagrams) is
+//!   processed as by the portable loop, and the datagrams it relays are
+//!   submitted as `sendmsg` entries on the relay sockets, which are registered
+//!   with the ring;
+//! - each iteration takes one `io_uring_enter` call, submitting the sends of a
+//!   batch and waiting for the next datagrams. From Linux 6.12 on, while
+//!   datagrams arrive faster than relayed one at a time, the call waits up to
+//!   [`BATCH_WAIT`] for a batch of [`BATCH`] datagrams once the first one
+//!   arrived, trading that much latency for fewer, larger batches.
+//!
+//! The ring needs Linux 6.0 or later. A socket whose ring cannot be set up, or
+//! fails, is served by the portable loop instead.
+
+use std::alloc::{self, Layout};
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::mem::{self, ManuallyDrop};
+use std::net::SocketAddr;
+use std::ops::Range;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::ptr;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+use io_uring::types::{BufRingEntry, Fd, Fixed, RecvMsgOut, SubmitArgs};
+use io_uring::{cqueue, opcode, squeue, IoUring};
+use socket2::SockAddr;
+use tokio::net::UdpSocket;
+use tokio::sync::oneshot;
+use tracing::{debug, trace, warn};
+
+use crate::batch::{sys, Outbox, Queued};
+use crate::capture;
+use crate::relay::{self, Registry, SharedConfig};
+
+/// Buffers provided to the kernel, i.e. most datagrams received per batch.
+const BUFFERS: u16 = 256;
+/// Submission queue entries, i.e. most sends submitted per system call.
+const ENTRIES: u32 = 1024;
+/// Datagrams a wait collects, for up to [`BATCH_WAIT`] once the first arrived.
+const BATCH: usize = 32;
+/// Longest wait for a full [`BATCH`], in microseconds.
+const BATCH_WAIT: u32 = 50;
+const BUFFER_GROUP: u16 = 0;
+/// Length of the `io_uring_recvmsg_out` header the kernel writes before the
+/// address of a datagram.
+const RECVMSG_OUT_LEN: usize = 16;
+
+// user data of the entries other than sends, which carry their batch and index
+const RECV: u64 = u64::MAX;
+const WAKE: u64 = u64::MAX - 1;
+const CANCEL: u64 = u64::MAX - 2;
+
+/// Datagram received into a provided buffer: its buffer, the range of its
+/// payload in the buffer, and its sender.
+type Received = (u16, Range<usize>, SocketAddr);
+
+/// Layout of the ring of buffer entries, which the kernel wants page-aligned,
+/// checked as the crate builds.
+const RING_LAYOUT: Layout =
+    match Layout::from_size_align(BUFFERS as usize * mem::size_of::<BufRingEntry>(), 4096) {
+        Ok(layout) => layout,
+        Err(_) => panic!("invalid buffer ring layout"),
+    };
+
+/// Ring of buffers provided to the kernel for receiving.
+struct Buffers {
+    /// Page-aligned ring of [`BUFFERS`] entries, shared with the kernel.
+    ring: *mut BufRingEntry,
+    data: Vec<u8>,
+    /// Size of each buffer.
+    size: usize,
+    tail: u16,
+}
+
+impl Buffers {
+    fn new(size: usize) -> Buffers {
+        // SAFETY: the layout has a non-zero size
+        let ring = unsafe { alloc::alloc_zeroed(RING_LAYOUT) };
+        if ring.is_null() {
+            alloc::handle_alloc_error(RING_LAYOUT);
+        }
+        let mut buffers = Buffers {
+            ring: ring.cast(),
+            data: vec![0u8; usize::from(BUFFERS) * size],
+            size,
+            tail: 0,
+        };
+        for bid in 0..BUFFERS {
+            buffers.provide(bid);
+        }
+        buffers.publish();
+        buffers
+    }
+
+    fn get(&self, bid: u16) -> &[u8] {
+        let start = usize::from(bid) * self.size;
+        &self.data[start..start + self.size]
+    }
+
+    /// Hands buffer `bid` back to the kernel, from the next [`Buffers::publish`].
+    fn provide(&mut self, bid: u16) {
+        let addr = self.data[usize::from(bid) * self.size..].as_mut_ptr();
+        // SAFETY: the index is within the ring, whose entries are plain data;
+        // the kernel only reads those past the published tail
+        let entry = unsafe { &mut *self.ring.add(usize::from(self.tail % BUFFERS)) };
+        entry.set_addr(addr as u64);
+        entry.set_len(self.size as u32);
+        entry.set_bid(bid);
+        self.tail = self.tail.wrapping_add(1);
+    }
+
+    fn publish(&self) {
+        // SAFETY: the tail is the field of the first entry the kernel reads
+        // atomically, and the ring is aligned for it
+        let tail = unsafe { &*BufRingEntry::tail(self.ring).cast::<AtomicU16>() };
+        tail.store(self.tail, Ordering::Release);
+    }
+
+    /// Parses the datagram the kernel wrote to buffer `bid`, `len` bytes with
+    /// the headers of the multishot `header`.
+    fn parse(&self, bid: u16, len: usize, header: &libc::msghdr) -> Option<Received> {
+        let buffer = &self.get(bid)[..len];
+        let Ok(message) = RecvMsgOut::parse(buffer, header) else {
+            debug!("Dropping a datagram the kernel reported without its headers");
+            return None;
+        };
+        // SAFETY: sockaddr_storage is plain data, for which all zeroes is a valid value
+        let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+        let name = message.name_data();
+        // SAFETY: the name is no longer than the msg_namelen of a sockaddr_storage
+        unsafe {
+            ptr::copy_nonoverlapping(
+                name.as_ptr(),
+                ptr::from_mut(&mut storage).cast::<u8>(),
+                name.len(),
+            );
+        }
+        let Some(from) = sys::to_socket_addr(&storage) else {
+            debug!("Dropping datagram from an unsupported address family");
+            return None;
+        };
+        if message.is_payload_truncated() {
+            debug!("Dropping datagram from {from} longer than the receive buffer");
+            return None;
+        }
+        let start = message.payload_data().as_ptr() as usize - buffer.as_ptr() as usize;
+        Some((bid, start..start + message.payload_data().len(), from))
+    }
+}
+
+impl Drop for Buffers {
+    fn drop(&mut self) {
+        // SAFETY: allocated in `Buffers::new` with the same layout
+        unsafe { alloc::dealloc(self.ring.cast(), RING_LAYOUT) };
+    }
+}
+
+/// Datagrams of a batch being sent, kept until the kernel completed each one.
+struct Sends {
+    messages: Vec<Queued>,
+    headers: Vec<libc::msghdr>,
+    /// Datagrams, destinations and buffers the headers point to.
+    _memory: (Vec<u8>, Vec<SockAddr>, Vec<libc::iovec>),
+    /// Sends submitted and not completed yet.
+    pending: usize,
+}
+
+impl Sends {
+    fn new(data: Vec<u8>, messages: Vec<Queued>) -> Sends {
+        let addrs: Vec<SockAddr> = messages.iter().map(|(_, to, _)| (*to).into()).collect();
+        let mut iovecs: Vec<libc::iovec> = messages
+            .iter()
+            .map(|(_, _, range)| libc::iovec {
+                // the kernel only reads from the buffer
+                iov_base: data[range.clone()].as_ptr().cast_mut().cast(),
+                iov_len: range.len(),
+            })
+            .collect();
+        let headers = iovecs
+            .iter_mut()
+            .zip(&addrs)
+            .map(|(iov, addr)| {
+                // SAFETY: msghdr is plain data, for which all zeroes is a valid value
+                let mut header: libc::msghdr = unsafe { mem::zeroed() };
+                header.msg_name = addr.as_ptr().cast_mut().cast();
+                header.msg_namelen = addr.len();
+                header.msg_iov = iov;
+                header.msg_iovlen = 1;
+                header
+            })
+            .collect();
+        // moving the vectors into the struct keeps their heap buffers in place
+        Sends {
+            messages,
+            headers,
+            _memory: (data, addrs, iovecs),
+            pending: 0,
+        }
+    }
+}
+
+/// Ring serving one relay socket, see [`crate::uring`].
+pub(crate) struct RelayRing {
+    ring: IoUring,
+    socket: Arc<UdpSocket>,
+    /// The socket among the registered files.
+    fixed: Fixed,
+    /// Raw descriptors of the relay sockets, at their index among the files
+    /// registered with the ring.
+    fds: Vec<RawFd>,
+    /// Leaked rather than freed if the ring cannot be drained, see `Drop`.
+    buffers: ManuallyDrop<Buffers>,
+    /// Header of the multishot `recvmsg`, which the kernel reads as long as
+    /// it receives.
+    header: Box<libc::msghdr>,
+    /// Event file waking the loop up to stop, and the buffer its counter is read into.
+    wake: (Arc<OwnedFd>, Box<u64>),
+    /// Batches being sent, by batch number.
+    sends: HashMap<u32, Sends>,
+    next_batch: u32,
+    receiving: bool,
+    waking: bool,
+    stopping: bool,
+    /// Whether the last batch had several datagrams, so that waiting for more
+    /// pays off.
+    busy: bool,
+    outbox: Outbox,
+}
+
+// SAFETY: the raw pointers only point into memory owned by the ring itself,
+// which only the thread serving it uses
+unsafe impl Send for RelayRing {}
+
+impl fmt::Debug for RelayRing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RelayRing")
+            .field("socket", &self.socket)
+            .finish_non_exhaustive()
+    }
+}
+
+impl RelayRing {
+    /// Sets up a ring receiving datagrams of up to `size` bytes on `socket`,
+    /// and sending on `sockets`, the relay sockets it may relay to.
+    pub(crate) fn new(
+        socket: &Arc<UdpSocket>,
+        sockets: &[Arc<UdpSocket>],
+        size: usize,
+    ) -> io::Result<RelayRing> {
+        let ring = IoUring::new(ENTRIES)?;
+        let fds: Vec<RawFd> = sockets.iter().map(|socket| socket.as_raw_fd()).collect();
+        let fixed = fixed(&fds, socket)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not a relay socket"))?;
+        ring.submitter().register_files(&fds)?;
+        // SAFETY: sockaddr_storage and msghdr are plain data, for which all
+        // zeroes is a valid value
+        let mut header: Box<libc::msghdr> = Box::new(unsafe { mem::zeroed() });
+        header.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        let buffers =
+            Buffers::new(RECVMSG_OUT_LEN + mem::size_of::<libc::sockaddr_storage>() + size);
+        // SAFETY: the ring of buffers lives as long as the ring, see `Drop`
+        unsafe {
+            ring.submitter().register_buf_ring_with_flags(
+                buffers.ring as u64,
+                BUFFERS,
+                BUFFER_GROUP,
+                0,
+            )?;
+        }
+        // SAFETY: eventfd takes no pointer
+        let wake = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC) };
+        if wake < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(RelayRing {
+            ring,
+            socket: socket.clone(),
+            fixed,
+            fds,
+            buffers: ManuallyDrop::new(buffers),
+            header,
+            // SAFETY: the descriptor was just opened, and is owned by nothing else
+            wake: (Arc::new(unsafe { OwnedFd::from_raw_fd(wake) }), Box::new(0)),
+            sends: HashMap::new(),
+            next_batch: 0,
+            receiving: false,
+            waking: false,
+            stopping: false,
+            busy: false,
+            outbox: Outbox::default(),
+        })
+    }
+
+    /// Serves the socket on a thread of its own until the returned thread is
+    /// dropped, or the ring fails, which the returned receiver then gets.
+    pub(crate) fn spawn(
+        self,
+        config: SharedConfig,
+        registry: Registry,
+    ) -> io::Result<(RingThread, oneshot::Receiver<io::Error>)> {
+        let wake = self.wake.0.clone();
+        let (failed, failure) = oneshot::channel();
+        let thread = thread::Builder::new()
+            .name("udprelay-uring".to_owned())
+            .spawn(move || {
+                if let Err(e) = self.run(&config, &registry) {
+                    let _ = failed.send(e);
+                }
+            })?;
+        let thread = RingThread {
+            wake,
+            thread: Some(thread),
+        };
+        Ok((thread, failure))
+    }
+
+    fn run(mut self, config: &SharedConfig, registry: &Registry) -> io::Result<()> {
+        let mut completions = Vec::new();
+        let mut batch = Vec::with_capacity(usize::from(BUFFERS));
+        let mut failed = Vec::new();
+        while !self.stopping {
+            self.arm()?;
+            self.wait()?;
+            completions.extend(self.ring.completion());
+            let mut error = None;
+            // every completion is handled, so that draining the ring knows what is left
+            for completion in completions.drain(..) {
+                if let Err(e) = self.complete(&completion, &mut batch, &mut failed) {
+                    error.get_or_insert(e);
+                }
+            }
+            if let Some(e) = error {
+                return Err(e);
+            }
+            self.busy = batch.len() > 1;
+            if !batch.is_empty() {
+                relay::process_batch(
+                    &config.borrow(),
+                    registry,
+                    &self.socket,
+                    batch
+                        .iter()
+                        .map(|(bid, range, from)| (&self.buffers.get(*bid)[range.clone()], *from)),
+                    &mut self.outbox,
+                );
+                for (bid, _, _) in batch.drain(..) {
+                    self.buffers.provide(bid);
+                }
+                self.buffers.publish();
+                self.send()?;
+            }
+            if !failed.is_empty() {
+                registry
+                    .lock()
+                    .expect("Registry lock poisoned")
+                    .record_send_failures(&config.borrow(), &failed);
+                failed.clear();
+            }
+        }
+        Ok(())
+    }
+
+    /// Queues the multishot `recvmsg` and the read of the event file waking the
+    /// loop up, unless they are still in flight.
+    fn arm(&mut self) -> io::Result<()> {
+        if !self.receiving {
+            let entry =
+                opcode::RecvMsgMulti::new(self.fixed, ptr::from_ref(&*self.header), BUFFER_GROUP)
+                    .build()
+                    .user_data(RECV);
+            self.push(&entry)?;
+            self.receiving = true;
+        }
+        if !self.waking {
+            let entry = opcode::Read::new(
+                Fd(self.wake.0.as_raw_fd()),
+                ptr::from_mut(&mut *self.wake.1).cast(),
+                mem::size_of::<u64>() as u32,
+            )
+            .build()
+            .user_data(WAKE);
+            self.push(&entry)?;
+            self.waking = true;
+        }
+        Ok(())
+    }
+
+    /// Submits the queued entries and waits for a completion, or for a batch
+    /// when busy and the kernel supports it.
+    fn wait(&mut self) -> io::Result<()> {
+        // sends mostly complete as they are submitted, and must not count
+        let sends: usize = self.sends.values().map(|sends| sends.pending).sum();
+        waited(
+            if self.busy && self.ring.params().is_feature_min_timeout() {
+                let args = SubmitArgs::new().min_wait_usec(BATCH_WAIT);
+                self.ring.submitter().submit_with_args(sends + BATCH, &args)
+            } else {
+                self.ring.submit_and_wait(sends + 1)
+            },
+        )
+    }
+
+    /// Index of `socket` among the files registered with the ring.
+    fn push(&mut self, entry: &squeue::Entry) -> io::Result<()> {
+        loop {
+            // SAFETY: entries only point into memory owned by the ring, kept
+            // until the kernel completed them (see `Drop`)
+            if unsafe { self.ring.submission().push(entry) }.is_ok() {
+                return Ok(());
+            }
+            // the queue is full: hand its entries over to the kernel
+            self.ring.submit()?;
+        }
+    }
+
+    /// Handles a completion, adding a received datagram to `batch` and the
+    /// destination of a failed send to `failed`.
+    fn complete(
+        &mut self,
+        completion: &cqueue::Entry,
+        batch: &mut Vec<Received>,
+        failed: &mut Vec<SocketAddr>,
+    ) -> io::Result<()> {
+        let result = completion.result();
+        match completion.user_data() {
+            RECV => {
+                if !cqueue::more(completion.flags()) {
+                    self.receiving = false;
+                }
+                if result < 0 {
+                    return match -result {
+                        // buffers run out when datagrams arrive faster than
+                        // processed; they wait in the socket until re-armed
+                        libc::ENOBUFS => Ok(()),
+                        // ICMP errors for earlier sends; the session expires as usual
+                        libc::ECONNREFUSED | libc::ECONNRESET | libc::ECANCELED => Ok(()),
+                        errno => Err(io::Error::from_raw_os_error(errno)),
+                    };
+                }
+                let Some(bid) = cqueue::buffer_select(completion.flags()) else {
+                    return Ok(());
+                };
+                match self.buffers.parse(bid, result as usize, &self.header) {
+                    Some(received) => batch.push(received),
+                    None => {
+                        self.buffers.provide(bid);
+                        self.buffers.publish();
+                    }
+                }
+            }
+            WAKE => {
+                self.waking = false;
+                self.stopping = true;
+            }
+            CANCEL => (),
+            user_data => {
+                let (number, index) = ((user_data >> 32) as u32, user_data as u32 as usize);
+                let Some(sends) = self.sends.get_mut(&number) else {
+                    return Err(io::Error::other("completion of an unknown send"));
+                };
+                // a full send buffer drops the datagram as the network would
+                if result < 0 && -result != libc::EAGAIN {
+                    let to = sends.messages[index].1;
+                    debug!(
+                        "Cannot send datagram to {to}: {}",
+                        io::Error::from_raw_os_error(-result)
+                    );
+                    failed.push(to);
+                }
+                sends.pending -= 1;
+                if sends.pending == 0 {
+                    self.sends.remove(&number);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Queues the datagrams of the outbox, submitted with the next wait.
+    fn send(&mut self) -> io::Result<()> {
+        let (data, messages) = self.outbox.take();
+        if messages.is_empty() {
+            return Ok(());
+        }
+        for (socket, to, range) in &messages {
+            capture::sent(socket, to, &data[range.clone()]);
+        }
+        let number = self.next_batch;
+        // batch numbers stay clear of the user data of the other entries
+        self.next_batch = (self.next_batch + 1) & 0x7fff_ffff;
+        let mut sends = Sends::new(data, messages);
+        let entries: Vec<squeue::Entry> = sends
+            .messages
+            .iter()
+            .zip(&sends.headers)
+            .enumerate()
+            .map(|(index, ((socket, _, _), header))| {
+                let send = match fixed(&self.fds, socket) {
+                    Some(fixed) => opcode::SendMsg::new(fixed, header),
+                    None => opcode::SendMsg::new(Fd(socket.as_raw_fd()), header),
+                };
+                send.flags(libc::MSG_DONTWAIT as u32)
+                    .build()
+                    .user_data(u64::from(number) << 32 | index as u64)
+            })
+            .collect();
+        trace!("Sending {} datagrams", entries.len());
+        let mut pushed = Ok(());
+        for entry in entries {
+            pushed = self.push(&entry);
+            if pushed.is_err() {
+                break;
+            }
+            sends.pending += 1;
+        }
+        // kept as long as any of them is in flight, even if the others failed
+        if sends.pending > 0 {
+            self.sends.insert(number, sends);
+        }
+        pushed
+    }
+}
+
+impl RelayRing {
+    /// Cancels the receive and the read of the event file, and waits until every entry
+    /// completed, after which the kernel no longer uses the memory of the ring.
+    fn drain(&mut self) -> io::Result<()> {
+        for user_data in [RECV, WAKE] {
+            let entry = opcode::AsyncCancel::new(user_data)
+                .build()
+                .user_data(CANCEL);
+            self.push(&entry)?;
+        }
+        let mut batch = Vec::new();
+        let mut failed = Vec::new();
+        while self.receiving || self.waking || !self.sends.is_empty() {
+            waited(self.ring.submit_and_wait(1))?;
+            let completions: Vec<cqueue::Entry> = self.ring.completion().collect();
+            for completion in &completions {
+                // errors of the receive being cancelled do not matter anymore
+                let _ = self.complete(completion, &mut batch, &mut failed);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for RelayRing {
+    fn drop(&mut self) {
+        match self.drain() {
+            Ok(()) => {
+                // closing the ring releases its files in the background, which
+                // would keep the sockets bound for a while
+                if let Err(e) = self.ring.submitter().unregister_files() {
+                    debug!("Cannot unregister the sockets of a ring: {e}");
+                }
+                // SAFETY: the buffers are not used afterwards, by the kernel either
+                unsafe { ManuallyDrop::drop(&mut self.buffers) }
+            }
+            Err(e) => {
+                debug!("Leaking the memory of a ring that cannot be drained: {e}");
+                mem::forget(mem::take(&mut self.sends));
+                mem::forget(mem::replace(&mut self.wake.1, Box::new(0)));
+                mem::forget(mem::replace(
+                    &mut self.header,
+                    Box::new(unsafe { mem::zeroed() }),
+                ));
+            }
+        }
+    }
+}
+
+/// Index of `socket` among the files registered as `fds`.
+fn fixed(fds: &[RawFd], socket: &UdpSocket) -> Option<Fixed> {
+    let fd = socket.as_raw_fd();
+    let index = fds.iter().position(|&registered| registered == fd)?;
+    Some(Fixed(index as u32))
+}
+
+/// Result of waiting for completions, which are reaped as usual after an
+/// interruption, a full completion queue or a batch that is not full (ETIME).
+fn waited(result: io::Result<usize>) -> io::Result<()> {
+    match result {
+        Err(e) if e.kind() == io::ErrorKind::Interrupted => Ok(()),
+        Err(e) if matches!(e.raw_os_error(), Some(libc::EBUSY | libc::ETIME)) => Ok(()),
+        result => result.map(drop),
+    }
+}
+
+/// Thread serving a relay socket on its ring, stopped and joined when dropped,
+/// by when the ring released the socket.
+#[derive(Debug)]
+pub(crate) struct RingThread {
+    wake: Arc<OwnedFd>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Drop for RingThread {
+    fn drop(&mut self) {
+        let one = 1u64;
+        // SAFETY: writes the 8 bytes of a counter increment to the event file
+        unsafe {
+            libc::write(
+                self.wake.as_raw_fd(),
+                ptr::from_ref(&one).cast(),
+                mem::size_of::<u64>(),
+            )
+        };
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                warn!("The io_uring relay thread panicked");
+            }
+        }
+    }
+}